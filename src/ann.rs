@@ -0,0 +1,527 @@
+//! Índice ANN (HNSW) em memória para a busca por embedding.
+//!
+//! Um índice por DB, populado a partir do SQLite e atualizado pelo worker.
+//! O SQL continua sendo a fonte da verdade (filtros, archived, importância):
+//! o índice só gera candidatos, e é reconstruído quando o DB muda por fora.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rusqlite::Connection;
+use tracing::info;
+
+use crate::embedding::decode_embedding;
+use crate::simd::dot;
+
+/// Vizinhos por nó nos níveis superiores (nível 0 usa o dobro)
+const M: usize = 16;
+const EF_CONSTRUCTION: usize = 100;
+const MAX_LEVEL: usize = 16;
+
+/// Candidato devolvido pelo índice
+#[derive(Debug, Clone)]
+pub struct AnnHit {
+    pub memory_id: String,
+    pub chunk: bool,
+    pub similarity: f64,
+}
+
+struct Node {
+    memory_id: String,
+    chunk: bool,
+    /// Vetor normalizado: cosine vira produto escalar
+    vector: Vec<f32>,
+    /// Vizinhos por nível (0..=nível do nó)
+    links: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored {
+    sim: f32,
+    idx: usize,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sim
+            .total_cmp(&other.sim)
+            .then_with(|| other.idx.cmp(&self.idx))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn normalize(v: &[f32]) -> Option<Vec<f32>> {
+    let norm = dot(v, v).sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return None;
+    }
+    Some(v.iter().map(|x| x / norm).collect())
+}
+
+/// Grafo HNSW sobre vetores de memórias e chunks.
+/// Remoção é lógica (tombstone); o grafo é refeito quando os removidos dominam.
+pub struct HnswIndex {
+    dim: usize,
+    nodes: Vec<Node>,
+    entry: Option<usize>,
+    max_level: usize,
+    by_memory: HashMap<String, Vec<usize>>,
+    live: usize,
+    rng: u64,
+}
+
+impl HnswIndex {
+    pub fn new(dim: usize) -> Self {
+        Self {
+            dim,
+            nodes: Vec::new(),
+            entry: None,
+            max_level: 0,
+            by_memory: HashMap::new(),
+            live: 0,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// Nível aleatório com distribuição geométrica (mL = 1/ln M), xorshift determinístico
+    fn random_level(&mut self) -> usize {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        let uniform = ((r >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        let level = (-uniform.ln() / (M as f64).ln()).floor() as usize;
+        level.min(MAX_LEVEL)
+    }
+
+    fn sim(&self, query: &[f32], idx: usize) -> f32 {
+        dot(query, &self.nodes[idx].vector)
+    }
+
+    pub fn insert(&mut self, memory_id: &str, chunk: bool, vector: &[f32]) {
+        if vector.len() != self.dim {
+            return;
+        }
+        let Some(vector) = normalize(vector) else {
+            return;
+        };
+        let level = self.random_level();
+        let idx = self.nodes.len();
+        self.nodes.push(Node {
+            memory_id: memory_id.to_string(),
+            chunk,
+            vector,
+            links: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.by_memory.entry(memory_id.to_string()).or_default().push(idx);
+        self.live += 1;
+
+        let Some(mut ep) = self.entry else {
+            self.entry = Some(idx);
+            self.max_level = level;
+            return;
+        };
+
+        let query = self.nodes[idx].vector.clone();
+        for l in (level + 1..=self.max_level).rev() {
+            ep = self.greedy(&query, ep, l);
+        }
+        let mut entry_points = vec![ep];
+        for l in (0..=level.min(self.max_level)).rev() {
+            let found = self.search_layer(&query, &entry_points, EF_CONSTRUCTION, l);
+            let max_links = if l == 0 { 2 * M } else { M };
+            let neighbors: Vec<usize> = found.iter().take(M).map(|s| s.idx).collect();
+            for &n in &neighbors {
+                self.nodes[n].links[l].push(idx);
+                if self.nodes[n].links[l].len() > max_links {
+                    self.prune(n, l, max_links);
+                }
+            }
+            self.nodes[idx].links[l] = neighbors;
+            entry_points = found.into_iter().map(|s| s.idx).collect();
+        }
+
+        if level > self.max_level {
+            self.max_level = level;
+            self.entry = Some(idx);
+        }
+    }
+
+    /// Mantém só os `max_links` vizinhos mais próximos de `node` no nível
+    fn prune(&mut self, node: usize, level: usize, max_links: usize) {
+        let base = &self.nodes[node].vector;
+        let mut scored: Vec<Scored> = self.nodes[node].links[level]
+            .iter()
+            .map(|&n| Scored {
+                sim: dot(base, &self.nodes[n].vector),
+                idx: n,
+            })
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        scored.truncate(max_links);
+        self.nodes[node].links[level] = scored.into_iter().map(|s| s.idx).collect();
+    }
+
+    fn greedy(&self, query: &[f32], mut ep: usize, level: usize) -> usize {
+        let mut best = self.sim(query, ep);
+        loop {
+            let mut changed = false;
+            for &n in &self.nodes[ep].links[level] {
+                let s = self.sim(query, n);
+                if s > best {
+                    best = s;
+                    ep = n;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return ep;
+            }
+        }
+    }
+
+    /// Busca em largura limitada (ef) num nível; resultado do mais ao menos similar
+    fn search_layer(&self, query: &[f32], entry_points: &[usize], ef: usize, level: usize) -> Vec<Scored> {
+        let mut visited: HashSet<usize> = entry_points.iter().copied().collect();
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for &e in entry_points {
+            let s = Scored {
+                sim: self.sim(query, e),
+                idx: e,
+            };
+            candidates.push(s);
+            results.push(Reverse(s));
+            if results.len() > ef {
+                results.pop();
+            }
+        }
+
+        while let Some(current) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0.sim).unwrap_or(f32::MIN);
+            if current.sim < worst && results.len() >= ef {
+                break;
+            }
+            for &n in &self.nodes[current.idx].links[level] {
+                if !visited.insert(n) {
+                    continue;
+                }
+                let s = Scored {
+                    sim: self.sim(query, n),
+                    idx: n,
+                };
+                let worst = results.peek().map(|r| r.0.sim).unwrap_or(f32::MIN);
+                if results.len() < ef || s.sim > worst {
+                    candidates.push(s);
+                    results.push(Reverse(s));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut out: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+        out.sort_by(|a, b| b.cmp(a));
+        out
+    }
+
+    /// Top-k vizinhos aproximados (ignora removidos)
+    pub fn search(&self, query: &[f32], k: usize, ef: usize) -> Vec<AnnHit> {
+        let Some(mut ep) = self.entry else {
+            return vec![];
+        };
+        if query.len() != self.dim {
+            return vec![];
+        }
+        let Some(query) = normalize(query) else {
+            return vec![];
+        };
+        for l in (1..=self.max_level).rev() {
+            ep = self.greedy(&query, ep, l);
+        }
+        self.search_layer(&query, &[ep], ef.max(k), 0)
+            .into_iter()
+            .filter(|s| !self.nodes[s.idx].deleted)
+            .take(k)
+            .map(|s| {
+                let node = &self.nodes[s.idx];
+                AnnHit {
+                    memory_id: node.memory_id.clone(),
+                    chunk: node.chunk,
+                    similarity: s.sim as f64,
+                }
+            })
+            .collect()
+    }
+
+    /// Remove todos os vetores (memória + chunks) de uma memória
+    pub fn remove_memory(&mut self, memory_id: &str) {
+        for idx in self.by_memory.remove(memory_id).unwrap_or_default() {
+            if !self.nodes[idx].deleted {
+                self.nodes[idx].deleted = true;
+                self.live -= 1;
+            }
+        }
+        if self.nodes.len() > 2 * self.live + 64 {
+            self.rebuild();
+        }
+    }
+
+    /// Refaz o grafo só com os nós vivos (descarta tombstones)
+    fn rebuild(&mut self) {
+        let mut fresh = HnswIndex::new(self.dim);
+        for node in self.nodes.iter().filter(|n| !n.deleted) {
+            fresh.insert(&node.memory_id, node.chunk, &node.vector);
+        }
+        *self = fresh;
+    }
+}
+
+/// Geração dos vetores do DB, usada para detectar mudanças feitas por fora (outro
+/// processo, hook): uma leitura no schema_meta por busca em vez de contar linhas
+fn db_signature(conn: &Connection) -> i64 {
+    crate::storage::vector_generation(conn)
+}
+
+/// Carrega todos os vetores do DB com a dimensão dada (outras dimensões ficam de fora)
+fn load_index(conn: &Connection, dim: usize) -> HnswIndex {
+    let mut index = HnswIndex::new(dim);
+    for (sql, chunk) in [
+        ("SELECT id, embedding FROM memories WHERE embedding IS NOT NULL AND deleted_at IS NULL", false),
+        (
+            "SELECT c.memory_id, c.embedding FROM memory_chunks c JOIN memories m ON m.id = c.memory_id \
+             WHERE c.embedding IS NOT NULL AND m.deleted_at IS NULL",
+            true,
+        ),
+    ] {
+        let Ok(mut stmt) = conn.prepare(sql) else {
+            continue;
+        };
+        let Ok(rows) = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        }) else {
+            continue;
+        };
+        for (id, blob) in rows.flatten() {
+            if let Some(v) = decode_embedding(&blob, dim) {
+                index.insert(&id, chunk, &v);
+            }
+        }
+    }
+    index
+}
+
+struct DbIndex {
+    hnsw: HnswIndex,
+    signature: i64,
+}
+
+/// Índices ANN por DB (chave = caminho do DB), compartilhados entre server e worker
+#[derive(Default)]
+pub struct AnnRegistry {
+    indexes: Mutex<HashMap<String, Arc<Mutex<DbIndex>>>>,
+}
+
+/// Índice de um DB específico, como a busca o recebe
+pub struct AnnScope<'a> {
+    pub registry: &'a AnnRegistry,
+    pub db_key: &'a str,
+}
+
+impl AnnRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn slot(&self, db_key: &str) -> Option<Arc<Mutex<DbIndex>>> {
+        self.indexes.lock().ok()?.get(db_key).cloned()
+    }
+
+    /// (Re)constrói o índice de um DB a partir do SQLite. Retorna quantos vetores entraram.
+    pub fn build(&self, db_key: &str, conn: &Connection, dim: usize) -> usize {
+        let started = Instant::now();
+        let signature = db_signature(conn);
+        let hnsw = load_index(conn, dim);
+        let len = hnsw.len();
+        info!("ANN index for {}: {} vectors in {:?}", db_key, len, started.elapsed());
+        if let Ok(mut map) = self.indexes.lock() {
+            map.insert(db_key.to_string(), Arc::new(Mutex::new(DbIndex { hnsw, signature })));
+        }
+        len
+    }
+
+    /// Top-k candidatos do índice do DB, reconstruindo se o DB mudou por fora
+    /// (ou a dimensão da query é outra). None = sem índice utilizável, use o scan linear.
+    pub fn candidates(&self, db_key: &str, conn: &Connection, query: &[f32], k: usize) -> Option<Vec<AnnHit>> {
+        let signature = db_signature(conn);
+        let fresh = self.slot(db_key).is_some_and(|slot| {
+            slot.lock()
+                .map(|idx| idx.signature == signature && idx.hnsw.dim() == query.len())
+                .unwrap_or(false)
+        });
+        if !fresh {
+            self.build(db_key, conn, query.len());
+        }
+        let slot = self.slot(db_key)?;
+        let idx = slot.lock().ok()?;
+        if idx.hnsw.is_empty() {
+            return None;
+        }
+        Some(idx.hnsw.search(query, k, (2 * k).max(64)))
+    }
+
+    /// Worker: troca os vetores das memórias reescritas. Só mexe em índices já construídos;
+    /// os demais são montados na primeira busca.
+    pub fn apply(&self, db_key: &str, conn: &Connection, replaced: &[&str], vectors: &[(&str, bool, &[f32])]) {
+        let Some(slot) = self.slot(db_key) else {
+            return;
+        };
+        let Ok(mut idx) = slot.lock() else {
+            return;
+        };
+        for id in replaced {
+            idx.hnsw.remove_memory(id);
+        }
+        for (id, chunk, v) in vectors {
+            idx.hnsw.insert(id, *chunk, v);
+        }
+        idx.signature = db_signature(conn);
+    }
+
+    /// Tira uma memória do índice (delete/update feitos por este processo)
+    pub fn remove_memory(&self, db_key: &str, conn: &Connection, memory_id: &str) {
+        self.apply(db_key, conn, &[memory_id], &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_vectors(n: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        (0..n)
+            .map(|_| {
+                (0..dim)
+                    .map(|_| {
+                        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                        ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hnsw_recall_against_brute_force() {
+        let dim = 16;
+        let data = random_vectors(600, dim, 7);
+        let mut index = HnswIndex::new(dim);
+        for (i, v) in data.iter().enumerate() {
+            index.insert(&format!("m{}", i), false, v);
+        }
+        assert_eq!(index.len(), 600);
+
+        let queries = random_vectors(20, dim, 99);
+        let k = 10;
+        let mut found = 0;
+        for q in &queries {
+            let qn = normalize(q).unwrap();
+            let mut exact: Vec<(f32, usize)> = data
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (dot(&qn, &normalize(v).unwrap()), i))
+                .collect();
+            exact.sort_by(|a, b| b.0.total_cmp(&a.0));
+            let truth: HashSet<String> = exact.iter().take(k).map(|(_, i)| format!("m{}", i)).collect();
+            found += index
+                .search(q, k, 64)
+                .iter()
+                .filter(|h| truth.contains(&h.memory_id))
+                .count();
+        }
+        let recall = found as f64 / (queries.len() * k) as f64;
+        assert!(recall >= 0.9, "recall {}", recall);
+    }
+
+    #[test]
+    fn test_hnsw_remove_memory() {
+        let dim = 8;
+        let data = random_vectors(100, dim, 3);
+        let mut index = HnswIndex::new(dim);
+        for (i, v) in data.iter().enumerate() {
+            index.insert(&format!("m{}", i), false, v);
+        }
+        index.insert("m0", true, &data[1]);
+
+        let hits = index.search(&data[0], 1, 32);
+        assert_eq!(hits[0].memory_id, "m0");
+
+        index.remove_memory("m0");
+        assert_eq!(index.len(), 99);
+        assert!(index.search(&data[0], 10, 64).iter().all(|h| h.memory_id != "m0"));
+    }
+
+    #[test]
+    fn test_registry_rebuilds_on_external_vector_writes() {
+        let path = std::env::temp_dir().join(format!("mcp-memory-ann-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = crate::storage::init_db(&path).unwrap();
+        let save = |content: &str| {
+            crate::storage::save_memory(&conn, "note", content, "", &Default::default()).unwrap().id
+        };
+        let (a, b) = (save("first vector"), save("second vector"));
+        let set_vector = |conn: &Connection, id: &str, v: &[f32]| {
+            conn.execute(
+                "UPDATE memories SET embedding = ? WHERE id = ?",
+                rusqlite::params![crate::embedding::compress_embedding(v), id],
+            )
+            .unwrap();
+        };
+        set_vector(&conn, &a, &[1.0, 0.0]);
+
+        let registry = AnnRegistry::new();
+        let hits = registry.candidates("db", &conn, &[0.0, 1.0], 5).unwrap();
+        assert_eq!(hits.len(), 1);
+
+        // Acesso não mexe em vetor: a geração fica
+        let generation = crate::storage::vector_generation(&conn);
+        crate::storage::update_access_count(&conn, &a);
+        assert_eq!(crate::storage::vector_generation(&conn), generation);
+
+        // Outro processo grava um vetor: a próxima busca reconstrói o índice
+        let other = crate::storage::open_connection(&path).unwrap();
+        set_vector(&other, &b, &[0.0, 1.0]);
+        let hits = registry.candidates("db", &conn, &[0.0, 1.0], 5).unwrap();
+        assert_eq!(hits[0].memory_id, b);
+
+        // Lixeira também conta
+        other.execute("UPDATE memories SET deleted_at = datetime('now') WHERE id = ?", [&b]).unwrap();
+        let hits = registry.candidates("db", &conn, &[0.0, 1.0], 5).unwrap();
+        assert!(hits.iter().all(|h| h.memory_id != b));
+    }
+}
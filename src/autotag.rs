@@ -3,7 +3,7 @@
 //! - Tipo de ação: detect keywords (fix/bug → bugfix, implement → feature, etc)
 //! - Paths/arquivos: extrai extensões de arquivo
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 // Tecnologias conhecidas (case-insensitive match)
 const TECH_KEYWORDS: &[(&str, &str)] = &[
@@ -115,7 +115,7 @@ fn has_compound_keyword(lower: &str, keyword: &str) -> bool {
 }
 
 /// Extrai tags automaticamente do conteúdo.
-/// Retorna Vec<String> com tags únicas, ordenadas, máximo 15.
+/// Retorna `Vec<String>` com tags únicas, ordenadas, máximo 15.
 /// Usa word boundary para evitar falsos positivos (ex: "rest" não matcha "interest").
 pub fn extract_tags(content: &str) -> Vec<String> {
     let mut tags = HashSet::new();
@@ -161,22 +161,48 @@ pub fn extract_tags(content: &str) -> Vec<String> {
     result
 }
 
+/// MCP_TAG_LOWERCASE (padrão ligado); `0`/`false`/`no` preserva a grafia da primeira ocorrência
+pub fn tag_lowercase_from_env() -> bool {
+    !matches!(
+        std::env::var("MCP_TAG_LOWERCASE").ok().as_deref().map(str::trim),
+        Some("0" | "false" | "no")
+    )
+}
+
+/// Normaliza uma tag: trim, espaços internos colapsados num só e, com `lowercase`, minúsculas
+pub fn normalize_tag(tag: &str, lowercase: bool) -> String {
+    let collapsed = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+    if lowercase {
+        collapsed.to_lowercase()
+    } else {
+        collapsed
+    }
+}
+
+/// Se `tags` (comma-separated) tem `tag` como tag inteira, sem caixa: "rapid" não contém "api"
+pub fn contains_tag(tags: &str, tag: &str) -> bool {
+    let wanted = normalize_tag(tag, true);
+    tags.split(',').any(|t| normalize_tag(t, true) == wanted)
+}
+
 /// Merge auto-tags com tags manuais (comma-separated string).
-/// Retorna string comma-separated sem duplicatas.
+/// Retorna string comma-separated sem duplicatas, com as tags normalizadas.
 pub fn merge_tags(manual_tags: &str, auto_tags: &[String]) -> String {
-    let mut all = HashSet::new();
-    for t in manual_tags.split(',') {
-        let trimmed = t.trim();
-        if !trimmed.is_empty() {
-            all.insert(trimmed.to_string());
+    merge_tags_with(manual_tags, auto_tags, tag_lowercase_from_env())
+}
+
+/// `merge_tags` com a opção de minúsculas explícita. A deduplicação ignora caixa mesmo
+/// sem `lowercase` (os filtros comparam em minúsculas); vence a primeira grafia vista.
+pub fn merge_tags_with(manual_tags: &str, auto_tags: &[String], lowercase: bool) -> String {
+    let mut all: BTreeMap<String, String> = BTreeMap::new();
+    let manual = manual_tags.split(',').map(str::to_string);
+    for t in manual.chain(auto_tags.iter().cloned()) {
+        let tag = normalize_tag(&t, lowercase);
+        if !tag.is_empty() {
+            all.entry(tag.to_lowercase()).or_insert(tag);
         }
     }
-    for t in auto_tags {
-        all.insert(t.clone());
-    }
-    let mut result: Vec<String> = all.into_iter().collect();
-    result.sort();
-    result.join(",")
+    all.into_values().collect::<Vec<_>>().join(",")
 }
 
 #[cfg(test)]
@@ -213,6 +239,23 @@ mod tests {
         assert!(merged.contains("redis"));
     }
 
+    #[test]
+    fn test_merge_tags_normalizes_and_dedupes() {
+        assert_eq!(merge_tags_with("API, api , api", &[], true), "api");
+        assert_eq!(merge_tags_with(" Design   System ,design system", &[], true), "design system");
+        assert_eq!(merge_tags_with("API, api ,Rust", &["rust".to_string()], false), "API,Rust");
+        assert_eq!(merge_tags_with(" , ,", &[], true), "");
+    }
+
+    #[test]
+    fn test_contains_tag_matches_whole_tags_only() {
+        assert!(!contains_tag("rapid,backend", "api"));
+        assert!(!contains_tag("happy", "app"));
+        assert!(contains_tag("rapid, API ,backend", "api"));
+        assert!(contains_tag("my  project", "My Project"));
+        assert!(!contains_tag("", "api"));
+    }
+
     #[test]
     fn test_empty_content() {
         let tags = extract_tags("");
@@ -1,10 +1,69 @@
+/// Tamanho e overlap padrão do chunking por palavras (iguais ao Python)
+pub const DEFAULT_CHUNK_SIZE: usize = 400;
+pub const DEFAULT_CHUNK_OVERLAP: usize = 80;
+
+/// Tamanho/overlap do chunking por palavras (MCP_CHUNK_SIZE / MCP_CHUNK_OVERLAP)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    pub size: usize,
+    pub overlap: usize,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_CHUNK_SIZE,
+            overlap: DEFAULT_CHUNK_OVERLAP,
+        }
+    }
+}
+
+impl ChunkConfig {
+    /// Lê do ambiente; combinação inválida (size 0 ou overlap >= size) volta ao padrão com aviso
+    pub fn from_env() -> Self {
+        let read = |key: &str, default: usize| {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(default)
+        };
+        let config = Self {
+            size: read("MCP_CHUNK_SIZE", DEFAULT_CHUNK_SIZE),
+            overlap: read("MCP_CHUNK_OVERLAP", DEFAULT_CHUNK_OVERLAP),
+        };
+        match config.validate() {
+            Ok(()) => config,
+            Err(e) => {
+                tracing::warn!("Invalid chunk config: {} (using {}/{})", e, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_OVERLAP);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.size == 0 {
+            return Err("MCP_CHUNK_SIZE must be at least 1".into());
+        }
+        if self.overlap >= self.size {
+            return Err(format!(
+                "MCP_CHUNK_OVERLAP ({}) must be smaller than MCP_CHUNK_SIZE ({})",
+                self.overlap, self.size
+            ));
+        }
+        Ok(())
+    }
+}
+
 /// Divide texto em chunks com overlap por contagem de palavras.
 /// Idêntico ao Python: chunk_text(text, 400, 80)
 pub fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
     let words: Vec<&str> = text.split_whitespace().collect();
-    if words.len() <= chunk_size {
+    // chunk_size 0 é degenerado: o texto vai inteiro, como se coubesse
+    if words.len() <= chunk_size || chunk_size == 0 {
         return vec![text.to_string()];
     }
+    // overlap >= chunk_size zeraria (loop infinito) ou estouraria o passo: mínimo 1 palavra
+    let stride = chunk_size.saturating_sub(overlap).max(1);
 
     let mut chunks = Vec::new();
     let mut start = 0;
@@ -15,12 +74,131 @@ pub fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String>
         if end >= words.len() {
             break;
         }
-        start += chunk_size - overlap;
+        start += stride;
+    }
+
+    chunks
+}
+
+/// Linha de heading markdown: 1 a 6 `#` seguidos de espaço (ou fim de linha)
+fn is_heading(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+    (1..=6).contains(&hashes)
+        && trimmed[hashes..].chars().next().is_none_or(|c| c == ' ')
+}
+
+/// Conteúdo parece markdown estruturado (tem pelo menos uma linha de heading)
+pub fn looks_like_markdown(text: &str) -> bool {
+    text.lines().any(is_heading)
+}
+
+/// Chunking por seções markdown: cada heading abre uma seção; seções que excedem
+/// `chunk_size` palavras são sub-divididas com `chunk_text`, e cada sub-chunk
+/// recebe o heading da seção como prefixo para manter o contexto.
+/// Texto que cabe inteiro em um chunk não é dividido (mesmo comportamento de `chunk_text`).
+pub fn chunk_markdown(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+    if text.split_whitespace().count() <= chunk_size {
+        return vec![text.to_string()];
+    }
+
+    // (heading, linhas do corpo); o preâmbulo antes do primeiro heading não tem heading
+    let mut sections: Vec<(Option<&str>, Vec<&str>)> = vec![(None, Vec::new())];
+    for line in text.lines() {
+        if is_heading(line) {
+            sections.push((Some(line.trim()), Vec::new()));
+        } else if let Some(last) = sections.last_mut() {
+            last.1.push(line);
+        }
+    }
+
+    let mut chunks = Vec::new();
+    for (heading, lines) in sections {
+        let body = lines.join("\n");
+        let body = body.trim();
+        if body.is_empty() {
+            if let Some(h) = heading {
+                chunks.push(h.to_string());
+            }
+            continue;
+        }
+        let body_words = body.split_whitespace().count();
+        match heading {
+            Some(h) if body_words <= chunk_size => chunks.push(format!("{}\n{}", h, body)),
+            Some(h) => {
+                for sub in chunk_text(body, chunk_size, overlap) {
+                    chunks.push(format!("{}\n{}", h, sub));
+                }
+            }
+            None => chunks.extend(chunk_text(body, chunk_size, overlap)),
+        }
     }
 
     chunks
 }
 
+/// Chunks por tokens do modelo: janelas de `chunk_tokens` tokens com `overlap`.
+/// `offsets` são os intervalos em bytes de cada token no texto (saída do tokenizer);
+/// cada chunk é a fatia original do primeiro ao último token da janela, então
+/// nenhum chunk passa da janela do modelo, mesmo com CJK ou "palavras" gigantes.
+pub fn chunk_by_tokens(text: &str, offsets: &[(usize, usize)], chunk_tokens: usize, overlap: usize) -> Vec<String> {
+    if offsets.len() <= chunk_tokens || chunk_tokens == 0 {
+        return vec![text.to_string()];
+    }
+    let stride = chunk_tokens.saturating_sub(overlap).max(1);
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < offsets.len() {
+        let end = (start + chunk_tokens).min(offsets.len());
+        if let Some(chunk) = text.get(offsets[start].0..offsets[end - 1].1) {
+            chunks.push(chunk.to_string());
+        }
+        if end >= offsets.len() {
+            break;
+        }
+        start += stride;
+    }
+
+    chunks
+}
+
+/// Como o worker divide o conteúdo (MCP_CHUNK_MODE)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkMode {
+    /// Palavras (paridade com o Python), markdown-aware
+    #[default]
+    Words,
+    /// Tokens do tokenizer do modelo: todo chunk cabe na janela
+    Tokens,
+}
+
+impl ChunkMode {
+    /// MCP_CHUNK_MODE=tokens liga o modo por tokens; qualquer outro valor fica em palavras
+    pub fn from_env() -> Self {
+        match std::env::var("MCP_CHUNK_MODE") {
+            Ok(v) if v.trim().eq_ignore_ascii_case("tokens") => ChunkMode::Tokens,
+            _ => ChunkMode::Words,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChunkMode::Words => "words",
+            ChunkMode::Tokens => "tokens",
+        }
+    }
+}
+
+/// Escolhe o chunker: markdown-aware se o conteúdo tem headings, senão por palavras
+pub fn chunk_content(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+    if looks_like_markdown(text) {
+        chunk_markdown(text, chunk_size, overlap)
+    } else {
+        chunk_text(text, chunk_size, overlap)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -50,4 +228,60 @@ mod tests {
         let chunks = chunk_text(text, 4, 2);
         assert_eq!(chunks.len(), 1);
     }
+
+    #[test]
+    fn test_chunk_config_validation() {
+        assert!(ChunkConfig::default().validate().is_ok());
+        assert!(ChunkConfig { size: 100, overlap: 100 }.validate().is_err());
+        assert!(ChunkConfig { size: 0, overlap: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn test_degenerate_overlap_does_not_overflow() {
+        let text = "a b c d e f";
+        // Passo não positivo vira 1 palavra
+        assert_eq!(chunk_text(text, 4, 4), ["a b c d", "b c d e", "c d e f"]);
+        assert_eq!(chunk_text(text, 4, 10), ["a b c d", "b c d e", "c d e f"]);
+        assert_eq!(chunk_text(text, 0, 0), [text]);
+    }
+
+    #[test]
+    fn test_markdown_detection() {
+        assert!(looks_like_markdown("intro\n## Setup\nsteps"));
+        assert!(!looks_like_markdown("issue #42 fixed"));
+        assert!(!looks_like_markdown("#hashtag only"));
+    }
+
+    #[test]
+    fn test_markdown_chunks_follow_sections() {
+        let text = "# Alpha\na b c\n# Beta\nd e f g h i";
+        let chunks = chunk_markdown(text, 4, 1);
+        assert_eq!(chunks[0], "# Alpha\na b c");
+        // Seção Beta excede 4 palavras: sub-split com heading em cada pedaço
+        assert_eq!(chunks[1], "# Beta\nd e f g");
+        assert_eq!(chunks[2], "# Beta\ng h i");
+        assert_eq!(chunks.len(), 3);
+    }
+
+    #[test]
+    fn test_markdown_short_text_single_chunk() {
+        let text = "# Title\nshort body";
+        assert_eq!(chunk_markdown(text, 400, 80), vec![text.to_string()]);
+    }
+
+    #[test]
+    fn test_token_chunks_follow_offsets() {
+        // Sem espaços: por palavras seria um chunk só, por tokens divide
+        let text = "東京都の天気は晴れです";
+        let offsets: Vec<(usize, usize)> = text.char_indices().map(|(i, c)| (i, i + c.len_utf8())).collect();
+        let chunks = chunk_by_tokens(text, &offsets, 4, 1);
+        assert_eq!(chunks, ["東京都の", "の天気は", "は晴れで", "です"]);
+        assert_eq!(chunk_text(text, 4, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_token_chunks_short_text_single_chunk() {
+        let text = "short text";
+        assert_eq!(chunk_by_tokens(text, &[(0, 5), (6, 10)], 200, 40), vec![text.to_string()]);
+    }
 }
@@ -0,0 +1,52 @@
+//! Compressão zstd do conteúdo de memórias grandes (logs e arquivos colados).
+//!
+//! Com `MCP_COMPRESS_THRESHOLD` (bytes; padrão 0 = desligado), conteúdo a partir desse
+//! tamanho vai comprimido para a coluna `content_zstd` e `content` fica vazio. Toda
+//! leitura passa pela função SQL `memory_content(content, content_zstd)`, registrada
+//! em cada conexão pelo `storage`, então busca, FTS, dedup e export veem o texto original.
+//!
+//! Limite: o `snippet()` do FTS lê a coluna crua; para linhas comprimidas o snippet cai
+//! no chunk mais parecido ou no começo do conteúdo.
+
+use anyhow::Result;
+
+/// Nível do zstd: 3 é o padrão da lib, bom equilíbrio para texto
+const LEVEL: i32 = 3;
+
+/// MCP_COMPRESS_THRESHOLD (padrão 0 = não comprime)
+pub fn threshold_from_env() -> usize {
+    std::env::var("MCP_COMPRESS_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Bytes comprimidos quando o texto passa do limite e a compressão de fato economiza
+pub fn compress_if_large(text: &str, threshold: usize) -> Option<Vec<u8>> {
+    if threshold == 0 || text.len() < threshold {
+        return None;
+    }
+    zstd::bulk::compress(text.as_bytes(), LEVEL)
+        .ok()
+        .filter(|bytes| bytes.len() < text.len())
+}
+
+pub fn decompress(bytes: &[u8]) -> Result<String> {
+    let raw = zstd::stream::decode_all(bytes)?;
+    Ok(String::from_utf8(raw)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_roundtrip_above_threshold() {
+        let log = "2024-05-01 INFO request handled in 12ms\n".repeat(200);
+        assert_eq!(compress_if_large(&log, 0), None);
+        assert_eq!(compress_if_large(&log[..100], 1024), None);
+        let packed = compress_if_large(&log, 1024).unwrap();
+        assert!(packed.len() < log.len() / 10);
+        assert_eq!(decompress(&packed).unwrap(), log);
+    }
+}
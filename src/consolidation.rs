@@ -10,10 +10,43 @@ use crate::storage;
 #[derive(Debug, Default)]
 pub struct ConsolidationResult {
     pub conversations_consolidated: usize,
+    /// Sessões conversation arquivadas dentro dos resumos
+    pub sessions_merged: usize,
     pub similar_merged: usize,
     pub archived: usize,
 }
 
+/// Quando um projeto tem conversations suficientes para virar resumo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConversationPolicy {
+    /// Mínimo de sessões no projeto (MCP_CONSOLIDATE_MIN_SESSIONS, padrão 5)
+    pub min_sessions: usize,
+    /// Sessões mais novas que ficam fora do resumo (MCP_CONSOLIDATE_KEEP_RECENT, padrão 0)
+    pub keep_recent: usize,
+}
+
+impl Default for ConversationPolicy {
+    fn default() -> Self {
+        Self { min_sessions: 5, keep_recent: 0 }
+    }
+}
+
+impl ConversationPolicy {
+    pub fn from_env() -> Self {
+        let read = |key: &str, default: usize| {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let defaults = Self::default();
+        Self {
+            min_sessions: read("MCP_CONSOLIDATE_MIN_SESSIONS", defaults.min_sessions),
+            keep_recent: read("MCP_CONSOLIDATE_KEEP_RECENT", defaults.keep_recent),
+        }
+    }
+}
+
 /// Extrai nome do projeto do conteúdo da sessão (formato "[project-name] Session ...")
 fn extract_project_from_content(content: &str) -> String {
     if let Some(start) = content.find('[') {
@@ -27,48 +60,56 @@ fn extract_project_from_content(content: &str) -> String {
     "unknown".to_string()
 }
 
-/// Consolida memórias de conversation por projeto.
-/// Agrupa pelo nome do projeto extraído do conteúdo (não por tags exatas).
-/// Se >= 5 sessões do mesmo projeto, gera resumo consolidado.
-pub fn consolidate_conversations(conn: &Connection) -> usize {
-    let mut consolidated = 0usize;
+/// Grupo de sessões de um projeto que vira um resumo consolidado
+#[derive(Debug, Clone)]
+pub struct ConversationGroup {
+    pub project: String,
+    pub session_ids: Vec<String>,
+    pub summary: String,
+}
+
+/// Planeja a consolidação de conversations sem gravar nada (usado também no dry run),
+/// com a política do ambiente (ver `plan_conversation_groups_with`).
+pub fn plan_conversation_groups(conn: &Connection) -> Vec<ConversationGroup> {
+    plan_conversation_groups_with(conn, &ConversationPolicy::from_env())
+}
 
+/// Agrupa pelo nome do projeto extraído do conteúdo (não por tags exatas);
+/// projetos com >= `min_sessions` sessões geram resumo das mais antigas, deixando as
+/// `keep_recent` mais novas intactas. Ordem por nome do projeto.
+pub fn plan_conversation_groups_with(conn: &Connection, policy: &ConversationPolicy) -> Vec<ConversationGroup> {
     // Buscar TODAS as conversations não-arquivadas
-    let mut stmt = match conn.prepare(
-        "SELECT id, content, tags, created_at FROM memories \
-         WHERE type = 'conversation' AND archived = 0 \
-         ORDER BY created_at ASC"
-    ) {
+    let mut stmt = match conn.prepare(&format!(
+        "SELECT m.id, memory_content(m.content, m.content_zstd) FROM memories m \
+         WHERE m.type = 'conversation' AND m.archived = 0 AND m.deleted_at IS NULL AND {} \
+         ORDER BY m.created_at ASC, m.rowid ASC",
+        crate::storage::NOT_EXPIRED_SQL
+    )) {
         Ok(s) => s,
-        Err(_) => return 0,
+        Err(_) => return vec![],
     };
 
-    let all_sessions: Vec<(String, String, String, String)> = stmt
-        .query_map([], |row| {
-            Ok((
-                row.get(0)?,
-                row.get(1)?,
-                row.get::<_, Option<String>>(2)?.unwrap_or_default(),
-                row.get::<_, Option<String>>(3)?.unwrap_or_default(),
-            ))
-        })
+    let all_sessions: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
         .ok()
         .map(|r| r.flatten().collect())
         .unwrap_or_default();
 
     // Agrupar por projeto extraído do conteúdo
-    let mut by_project: HashMap<String, Vec<(String, String, String)>> = HashMap::new();
-    for (id, content, tags, _created_at) in &all_sessions {
-        let project = extract_project_from_content(content);
-        by_project.entry(project).or_default().push((
-            id.clone(),
-            content.clone(),
-            tags.clone(),
-        ));
+    let mut by_project: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for (id, content) in all_sessions {
+        let project = extract_project_from_content(&content);
+        by_project.entry(project).or_default().push((id, content));
     }
 
-    for (project_name, sessions) in &by_project {
-        if sessions.len() < 5 || project_name == "unknown" {
+    let mut groups = Vec::new();
+    for (project_name, mut sessions) in by_project {
+        if sessions.len() < policy.min_sessions || project_name == "unknown" {
+            continue;
+        }
+        // Mais novas ficam de fora; resumo de uma sessão só não vale a pena
+        sessions.truncate(sessions.len().saturating_sub(policy.keep_recent));
+        if sessions.len() < 2 {
             continue;
         }
 
@@ -76,9 +117,8 @@ pub fn consolidate_conversations(conn: &Connection) -> usize {
         let mut all_topics = Vec::new();
         let mut all_tools: std::collections::HashSet<String> = std::collections::HashSet::new();
         let mut all_files: std::collections::HashSet<String> = std::collections::HashSet::new();
-        let session_ids: Vec<String> = sessions.iter().map(|s| s.0.clone()).collect();
 
-        for (_, content, _) in sessions {
+        for (_, content) in &sessions {
             for line in content.lines() {
                 if let Some(stripped) = line.strip_prefix("Tools: ") {
                     for tool in stripped.split(", ") {
@@ -109,20 +149,38 @@ pub fn consolidate_conversations(conn: &Connection) -> usize {
             unique_topics.iter().take(20).map(|t| format!("  - {}", t)).collect::<Vec<_>>().join("\n"),
         );
 
-        let auto_tags = crate::autotag::extract_tags(&summary);
-        let base_tags = format!("consolidated,conversation,{}", project_name);
+        groups.push(ConversationGroup {
+            project: project_name,
+            session_ids: sessions.into_iter().map(|(id, _)| id).collect(),
+            summary,
+        });
+    }
+    groups.sort_by(|a, b| a.project.cmp(&b.project));
+    groups
+}
+
+/// Consolida memórias de conversation por projeto (ver `plan_conversation_groups`).
+/// Retorna (resumos criados, sessões arquivadas).
+pub fn consolidate_conversations(conn: &Connection) -> (usize, usize) {
+    let mut consolidated = 0usize;
+    let mut sessions_merged = 0usize;
+
+    for group in plan_conversation_groups(conn) {
+        let auto_tags = crate::autotag::extract_tags(&group.summary);
+        let base_tags = format!("consolidated,conversation,{}", group.project);
         let final_tags = crate::autotag::merge_tags(&base_tags, &auto_tags);
 
         // Salvar resumo consolidado
-        let consolidated_id = storage::generate_id(&summary, "consolidated");
+        let consolidated_id = storage::generate_id(&group.summary, "consolidated");
+        let (stored, zstd) = storage::content_columns(&group.summary);
         let _ = conn.execute(
-            "INSERT OR REPLACE INTO memories (id, type, content, tags, updated_at, importance) \
-             VALUES (?, 'consolidated', ?, ?, datetime('now'), 0.7)",
-            rusqlite::params![consolidated_id, summary, final_tags],
+            "INSERT OR REPLACE INTO memories (id, type, content, content_zstd, tags, updated_at, importance) \
+             VALUES (?, 'consolidated', ?, ?, ?, datetime('now'), 0.7)",
+            rusqlite::params![consolidated_id, stored, zstd, final_tags],
         );
 
         // Arquivar sessões originais e criar edges supersedes
-        for session_id in &session_ids {
+        for session_id in &group.session_ids {
             let _ = conn.execute(
                 "UPDATE memories SET archived = 1 WHERE id = ?",
                 rusqlite::params![session_id],
@@ -131,9 +189,10 @@ pub fn consolidate_conversations(conn: &Connection) -> usize {
         }
 
         consolidated += 1;
+        sessions_merged += group.session_ids.len();
     }
 
-    consolidated
+    (consolidated, sessions_merged)
 }
 
 /// Consolida memórias similares (não-conversation) com Jaccard 0.6-0.84.
@@ -143,11 +202,12 @@ pub fn consolidate_similar(conn: &Connection) -> usize {
     let mut merged = 0usize;
 
     // Buscar memórias não-conversation, não-archived, não-consolidated
-    let mut stmt = match conn.prepare(
-        "SELECT id, type, content, updated_at FROM memories \
-         WHERE type NOT IN ('conversation', 'consolidated') AND archived = 0 \
-         ORDER BY updated_at DESC"
-    ) {
+    let mut stmt = match conn.prepare(&format!(
+        "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.updated_at FROM memories m \
+         WHERE m.type NOT IN ('conversation', 'consolidated') AND m.archived = 0 AND m.deleted_at IS NULL AND {} \
+         ORDER BY m.updated_at DESC",
+        crate::storage::NOT_EXPIRED_SQL
+    )) {
         Ok(s) => s,
         Err(_) => return 0,
     };
@@ -192,9 +252,10 @@ pub fn consolidate_similar(conn: &Connection) -> usize {
                         items[j].0,
                         &items[j].1.chars().take(300).collect::<String>()
                     );
+                    let (stored, zstd) = storage::content_columns(&merged_content);
                     let _ = conn.execute(
-                        "UPDATE memories SET content = ?, updated_at = datetime('now') WHERE id = ?",
-                        rusqlite::params![merged_content, items[i].0],
+                        "UPDATE memories SET content = ?, content_zstd = ?, updated_at = datetime('now') WHERE id = ?",
+                        rusqlite::params![stored, zstd, items[i].0],
                     );
 
                     // Arquivar o mais antigo
@@ -214,13 +275,14 @@ pub fn consolidate_similar(conn: &Connection) -> usize {
 
 /// Executa consolidação completa
 pub fn run_consolidation(conn: &Connection) -> ConsolidationResult {
-    let conversations_consolidated = consolidate_conversations(conn);
+    let (conversations_consolidated, sessions_merged) = consolidate_conversations(conn);
     let similar_merged = consolidate_similar(conn);
 
     ConsolidationResult {
         conversations_consolidated,
+        sessions_merged,
         similar_merged,
-        archived: conversations_consolidated + similar_merged,
+        archived: sessions_merged + similar_merged,
     }
 }
 
@@ -237,3 +299,33 @@ fn dedup_topics(topics: &[String], threshold: f64) -> Vec<String> {
     }
     unique
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversation_policy_keeps_recent_sessions() {
+        let path = std::env::temp_dir().join(format!("mcp-memory-consolidation-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = storage::init_db(&path).unwrap();
+        for i in 0..6 {
+            let content = format!("[billing] Session {}\nTools: Edit\nTopics:\n  - invoice rounding step {}", i, i);
+            conn.execute(
+                "INSERT INTO memories (id, type, content, tags, created_at) \
+                 VALUES (?, 'conversation', ?, 'conversation', datetime('now', ?))",
+                rusqlite::params![format!("s{}", i), content, format!("-{} days", 10 - i)],
+            )
+            .unwrap();
+        }
+
+        let below = ConversationPolicy { min_sessions: 7, keep_recent: 0 };
+        assert!(plan_conversation_groups_with(&conn, &below).is_empty());
+
+        let policy = ConversationPolicy { min_sessions: 5, keep_recent: 2 };
+        let groups = plan_conversation_groups_with(&conn, &policy);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].session_ids, ["s0", "s1", "s2", "s3"]);
+        assert!(groups[0].summary.starts_with("[billing] Consolidated (4 sessions)"));
+    }
+}
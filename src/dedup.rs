@@ -1,40 +1,170 @@
 use std::collections::HashSet;
 use rusqlite::Connection;
 
+use crate::embedding::decode_embedding;
+use crate::search::dot_similarity;
+
 /// Similaridade Jaccard por palavras
 pub fn jaccard_sim(text_a: &str, text_b: &str) -> f64 {
-    let a_lower = text_a.to_lowercase();
-    let b_lower = text_b.to_lowercase();
-    let words_a: HashSet<&str> = a_lower.split_whitespace().collect();
-    let words_b: HashSet<&str> = b_lower.split_whitespace().collect();
-    if words_a.is_empty() || words_b.is_empty() {
+    jaccard_sim_with(text_a, text_b, JaccardMode::Words)
+}
+
+/// Como o texto vira conjunto para o Jaccard (MCP_JACCARD_MODE no dedup)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JaccardMode {
+    /// Palavras separadas por espaço, só em minúsculas (o comportamento original)
+    #[default]
+    Words,
+    /// Palavras sem pontuação nas pontas nem apóstrofos (`don't` = `dont`) e sem acentos
+    Normalized,
+    /// N-gramas de caracteres do texto normalizado: tolera erros de digitação e
+    /// palavras coladas/separadas, e reordenação mantém a maior parte dos gramas
+    Shingles(usize),
+}
+
+impl JaccardMode {
+    const DEFAULT_SHINGLE: usize = 3;
+
+    /// `words`, `normalized`, `shingles` ou `shingles:N`; inválido cai em `words` com aviso
+    pub fn from_env() -> Self {
+        match std::env::var("MCP_JACCARD_MODE") {
+            Ok(raw) => Self::parse(&raw).unwrap_or_else(|| {
+                tracing::warn!("Invalid MCP_JACCARD_MODE '{}', using words", raw);
+                Self::Words
+            }),
+            Err(_) => Self::Words,
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim().to_lowercase();
+        match raw.split_once(':') {
+            Some(("shingles", n)) => n.trim().parse().ok().filter(|n| *n > 0).map(Self::Shingles),
+            Some(_) => None,
+            None => match raw.as_str() {
+                "words" => Some(Self::Words),
+                "normalized" => Some(Self::Normalized),
+                "shingles" => Some(Self::Shingles(Self::DEFAULT_SHINGLE)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Jaccard sobre os conjuntos que `mode` extrai de cada texto
+pub fn jaccard_sim_with(text_a: &str, text_b: &str, mode: JaccardMode) -> f64 {
+    let (set_a, set_b) = (token_set(text_a, mode), token_set(text_b, mode));
+    if set_a.is_empty() || set_b.is_empty() {
         return 0.0;
     }
-    let intersection = words_a.intersection(&words_b).count();
-    let union = words_a.union(&words_b).count();
+    let intersection = set_a.intersection(&set_b).count();
+    let union = set_a.union(&set_b).count();
     intersection as f64 / union as f64
 }
 
-/// Verifica se memória similar já existe. Retorna ID existente ou None.
+fn token_set(text: &str, mode: JaccardMode) -> HashSet<String> {
+    match mode {
+        JaccardMode::Words => text.to_lowercase().split_whitespace().map(str::to_string).collect(),
+        JaccardMode::Normalized => normalized_words(text).collect(),
+        JaccardMode::Shingles(n) => {
+            let joined: Vec<char> = normalized_words(text).collect::<Vec<_>>().join(" ").chars().collect();
+            match joined.len() {
+                0 => HashSet::new(),
+                // Texto mais curto que o grama: ele inteiro é o único elemento
+                len if len <= n => HashSet::from([joined.into_iter().collect()]),
+                _ => joined.windows(n).map(|w| w.iter().collect()).collect(),
+            }
+        }
+    }
+}
+
+/// Minúsculas, sem acentos, sem apóstrofos e sem pontuação nas pontas da palavra
+/// (pontuação interna fica: `v1.2`, `foo_bar`, `a/b`)
+fn normalized_words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split_whitespace().filter_map(|word| {
+        let folded: String = word
+            .chars()
+            .filter(|c| !matches!(c, '\'' | '’' | '`'))
+            .flat_map(char::to_lowercase)
+            .map(fold_accent)
+            .collect();
+        let trimmed = folded.trim_matches(|c: char| !c.is_alphanumeric());
+        (!trimmed.is_empty()).then(|| trimmed.to_string())
+    })
+}
+
+/// Acentos latinos mais comuns (pt/es/fr/de) para a letra base; o resto passa igual
+fn fold_accent(c: char) -> char {
+    match c {
+        'á' | 'à' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'é' | 'è' | 'ê' | 'ë' => 'e',
+        'í' | 'ì' | 'î' | 'ï' => 'i',
+        'ó' | 'ò' | 'ô' | 'õ' | 'ö' => 'o',
+        'ú' | 'ù' | 'û' | 'ü' => 'u',
+        'ç' => 'c',
+        'ñ' => 'n',
+        'ý' | 'ÿ' => 'y',
+        _ => c,
+    }
+}
+
+/// Threshold padrão de dedup (cosine ou Jaccard)
+pub const DEFAULT_THRESHOLD: f64 = 0.85;
+
+/// Resolve o threshold pedido pelo usuário: ausente ou <= 0.0 usa o default,
+/// valores acima de 1.0 são limitados a 1.0 (só exact match)
+pub fn effective_threshold(requested: Option<f64>) -> f64 {
+    match requested {
+        Some(t) if t > 0.0 => t.min(1.0),
+        _ => DEFAULT_THRESHOLD,
+    }
+}
+
+/// Duplicata encontrada e o método que a identificou
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateMatch {
+    pub id: String,
+    /// "exact", "embedding" ou "jaccard"
+    pub method: &'static str,
+    pub similarity: f64,
+}
+
+/// Verifica se memória similar já existe. Retorna a duplicata ou None.
 /// Passo 1: exact match por content+type
-/// Passo 2: FTS rough match + Jaccard refinement
+/// Passo 2: FTS rough match + refinamento por candidato:
+///   cosine (se temos embedding do conteúdo novo e do candidato, mesma dimensão)
+///   ou Jaccard como fallback rápido. Pulado quando threshold >= 1.0 (só exact).
 pub fn find_duplicate(
     conn: &Connection,
     content: &str,
     mem_type: &str,
     threshold: f64,
-) -> Option<String> {
+    embedding: Option<&[f32]>,
+) -> Option<DuplicateMatch> {
     // Passo 1: exact match
+    // Expirada não absorve o save: o prune a apagaria junto com o conteúdo novo
     let mut stmt = conn
-        .prepare("SELECT id FROM memories WHERE type = ? AND content = ?")
+        .prepare(&format!(
+            "SELECT m.id FROM memories m WHERE m.type = ? AND memory_content(m.content, m.content_zstd) = ? \
+             AND m.deleted_at IS NULL AND {}",
+            crate::storage::NOT_EXPIRED_SQL
+        ))
         .ok()?;
     if let Ok(id) = stmt.query_row(rusqlite::params![mem_type, content], |row| {
         row.get::<_, String>(0)
     }) {
-        return Some(id);
+        return Some(DuplicateMatch {
+            id,
+            method: "exact",
+            similarity: 1.0,
+        });
+    }
+
+    if threshold >= 1.0 {
+        return None;
     }
 
-    // Passo 2: FTS rough + Jaccard
+    // Passo 2: FTS rough + cosine/Jaccard
     let tokens: Vec<&str> = content.split_whitespace().take(20).collect();
     let fts_terms: Vec<&str> = tokens.into_iter().filter(|t| t.len() > 2).collect();
     if fts_terms.is_empty() {
@@ -47,28 +177,63 @@ pub fn find_duplicate(
         .collect::<Vec<_>>()
         .join(" OR ");
 
-    let sql = "SELECT m.id, m.content FROM memories_fts f \
-               JOIN memories m ON f.rowid = m.rowid \
-               WHERE m.type = ? AND memories_fts MATCH ? LIMIT 10";
+    let sql = format!(
+        "SELECT m.id, memory_content(m.content, m.content_zstd), m.embedding FROM memories_fts f \
+         JOIN memories m ON f.rowid = m.rowid \
+         WHERE m.type = ? AND m.deleted_at IS NULL AND {} AND memories_fts MATCH ? LIMIT 10",
+        crate::storage::NOT_EXPIRED_SQL
+    );
 
-    let mut stmt = conn.prepare(sql).ok()?;
-    let rows: Vec<(String, String)> = stmt
+    let mut stmt = conn.prepare(&sql).ok()?;
+    let rows: Vec<(String, String, Option<Vec<u8>>)> = stmt
         .query_map(rusqlite::params![mem_type, fts_query], |row| {
-            Ok((row.get(0)?, row.get(1)?))
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
         })
         .ok()?
         .filter_map(|r| r.ok())
         .collect();
 
-    for (id, existing_content) in rows {
-        if jaccard_sim(content, &existing_content) >= threshold {
-            return Some(id);
+    let jaccard_mode = JaccardMode::from_env();
+    for (id, existing_content, blob) in rows {
+        // Dimensão diferente (outro modelo) cai no Jaccard
+        let stored = match (embedding, blob) {
+            (Some(new_emb), Some(b)) => decode_embedding(&b, new_emb.len()),
+            _ => None,
+        };
+        let (method, sim) = match (embedding, stored) {
+            // Ambos normalizados (convenção de embedding.rs): produto escalar = cosseno
+            (Some(new_emb), Some(stored)) => ("embedding", dot_similarity(new_emb, &stored)),
+            _ => ("jaccard", jaccard_sim_with(content, &existing_content, jaccard_mode)),
+        };
+        if sim >= threshold {
+            return Some(DuplicateMatch {
+                id,
+                method,
+                similarity: sim,
+            });
         }
     }
 
     None
 }
 
+/// Dedup consultando outros scopes: uma duplicata por DB (nome do scope, match).
+/// Só leitura — quem chama decide o que fazer; nada é movido entre scopes.
+pub fn find_cross_scope_duplicates(
+    siblings: &[(String, &Connection)],
+    content: &str,
+    mem_type: &str,
+    threshold: f64,
+    embedding: Option<&[f32]>,
+) -> Vec<(String, DuplicateMatch)> {
+    siblings
+        .iter()
+        .filter_map(|(scope, conn)| {
+            find_duplicate(conn, content, mem_type, threshold, embedding).map(|m| (scope.clone(), m))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,4 +260,110 @@ mod tests {
         assert_eq!(jaccard_sim("", "hello"), 0.0);
         assert_eq!(jaccard_sim("hello", ""), 0.0);
     }
+
+    #[test]
+    fn test_jaccard_normalized_ignores_punctuation_and_accents() {
+        let (a, b) = ("Don't forget: use WAL mode, always!", "dont forget use wal mode always");
+        assert!(jaccard_sim(a, b) < 0.5);
+        assert_eq!(jaccard_sim_with(a, b, JaccardMode::Normalized), 1.0);
+        assert_eq!(
+            jaccard_sim_with("Configuração do índice.", "configuracao do indice", JaccardMode::Normalized),
+            1.0
+        );
+        // Pontuação interna é conteúdo
+        assert!(jaccard_sim_with("bump to v1.2", "bump to v12", JaccardMode::Normalized) < 1.0);
+    }
+
+    #[test]
+    fn test_jaccard_shingles_tolerate_small_edits() {
+        let mode = JaccardMode::Shingles(3);
+        let (a, b) = ("retry the payment webhook", "retry the payments web-hook");
+        assert!(jaccard_sim_with(a, b, mode) > jaccard_sim_with(a, b, JaccardMode::Normalized));
+        assert_eq!(jaccard_sim_with("ok", "ok", mode), 1.0);
+        assert_eq!(jaccard_sim_with("!!!", "ok", mode), 0.0);
+        assert_eq!(JaccardMode::parse("shingles:4"), Some(JaccardMode::Shingles(4)));
+        assert_eq!(JaccardMode::parse("Shingles"), Some(JaccardMode::Shingles(3)));
+        assert_eq!(JaccardMode::parse("shingles:0"), None);
+        assert_eq!(JaccardMode::parse("fuzzy"), None);
+    }
+
+    #[test]
+    fn test_effective_threshold() {
+        assert_eq!(effective_threshold(None), DEFAULT_THRESHOLD);
+        assert_eq!(effective_threshold(Some(0.0)), DEFAULT_THRESHOLD);
+        assert_eq!(effective_threshold(Some(0.7)), 0.7);
+        assert_eq!(effective_threshold(Some(3.0)), 1.0);
+    }
+
+    fn test_db(name: &str) -> Connection {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-memory-dedup-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        crate::storage::init_db(&path).unwrap()
+    }
+
+    #[test]
+    fn test_find_duplicate_by_embedding() {
+        let conn = test_db("embedding");
+        conn.execute(
+            "INSERT INTO memories (id, type, content, tags, embedding) VALUES ('a', 'decision', ?, '', ?)",
+            rusqlite::params![
+                "use Postgres for the DB",
+                crate::embedding::compress_embedding(&crate::embedding::l2_normalized(vec![0.9, 0.1, 0.0]))
+            ],
+        )
+        .unwrap();
+
+        let paraphrase = "we decided on Postgres as our database";
+        // Sem embedding: Jaccard baixo, não é duplicata
+        assert_eq!(find_duplicate(&conn, paraphrase, "decision", 0.85, None), None);
+
+        // Com embedding próximo: duplicata semântica
+        let query = crate::embedding::l2_normalized(vec![0.88, 0.12, 0.01]);
+        let found = find_duplicate(&conn, paraphrase, "decision", 0.85, Some(&query)).unwrap();
+        assert_eq!(found.id, "a");
+        assert_eq!(found.method, "embedding");
+
+        // Dimensão diferente: cai no Jaccard
+        assert_eq!(find_duplicate(&conn, paraphrase, "decision", 0.85, Some(&[0.9, 0.1])), None);
+    }
+
+    #[test]
+    fn test_cross_scope_duplicates() {
+        let global = test_db("cross-global");
+        let personality = test_db("cross-personality");
+        global
+            .execute(
+                "INSERT INTO memories (id, type, content, tags) VALUES ('g1', 'decision', 'deploy with blue green releases', '')",
+                [],
+            )
+            .unwrap();
+        let siblings = [("global".to_string(), &global), ("personality".to_string(), &personality)];
+
+        let found = find_cross_scope_duplicates(&siblings, "deploy with blue green releases", "decision", 0.85, None);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, "global");
+        assert_eq!(found[0].1.id, "g1");
+        assert!(find_cross_scope_duplicates(&siblings, "unrelated caching note", "decision", 0.85, None).is_empty());
+    }
+
+    #[test]
+    fn test_threshold_one_is_exact_only() {
+        let conn = test_db("exact-only");
+        conn.execute(
+            "INSERT INTO memories (id, type, content, tags) VALUES ('a', 'note', 'alpha beta gamma', '')",
+            [],
+        )
+        .unwrap();
+        // Mesmas palavras em outra ordem: Jaccard 1.0, mas não é exact match
+        assert!(find_duplicate(&conn, "gamma beta alpha", "note", 0.85, None).is_some());
+        assert_eq!(find_duplicate(&conn, "gamma beta alpha", "note", 1.0, None), None);
+        assert_eq!(
+            find_duplicate(&conn, "alpha beta gamma", "note", 1.0, None).unwrap().method,
+            "exact"
+        );
+    }
 }
@@ -1,3 +1,5 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use anyhow::Result;
 use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
@@ -5,65 +7,602 @@ use half::f16;
 use rusqlite::Connection;
 use sha2::{Sha256, Digest};
 use tokio::sync::mpsc;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-/// Wrapper para fastembed TextEmbedding (thread-safe via Mutex)
+use crate::ann::AnnRegistry;
+use crate::chunking::{ChunkConfig, ChunkMode, chunk_by_tokens, chunk_content};
+
+/// Modelo padrão (chave no embedding_cache e no schema_meta)
+pub const DEFAULT_MODEL_NAME: &str = "all-MiniLM-L6-v2";
+pub const DEFAULT_DIMENSION: usize = 384;
+
+/// Mapeia nomes aceitos em MCP_EMBEDDING_MODEL para (nome canônico, variante fastembed, dimensão)
+pub fn parse_model_name(name: &str) -> Option<(&'static str, EmbeddingModel, usize)> {
+    let model = match name.trim().to_lowercase().as_str() {
+        "all-minilm-l6-v2" | "minilm" => ("all-MiniLM-L6-v2", EmbeddingModel::AllMiniLML6V2, 384),
+        "all-minilm-l12-v2" => ("all-MiniLM-L12-v2", EmbeddingModel::AllMiniLML12V2, 384),
+        "bge-small-en-v1.5" | "bge-small" => ("bge-small-en-v1.5", EmbeddingModel::BGESmallENV15, 384),
+        "bge-base-en-v1.5" | "bge-base" => ("bge-base-en-v1.5", EmbeddingModel::BGEBaseENV15, 768),
+        "nomic-embed-text" | "nomic-embed-text-v1.5" => {
+            ("nomic-embed-text-v1.5", EmbeddingModel::NomicEmbedTextV15, 768)
+        }
+        "multilingual-e5-small" => ("multilingual-e5-small", EmbeddingModel::MultilingualE5Small, 384),
+        "paraphrase-multilingual-minilm-l12-v2" => {
+            ("paraphrase-multilingual-MiniLM-L12-v2", EmbeddingModel::ParaphraseMLMiniLML12V2, 384)
+        }
+        _ => return None,
+    };
+    Some(model)
+}
+
+/// Modelos extras para comparação A/B (MCP_EMBEDDING_EXTRA_MODELS, separados por
+/// vírgula). Desconhecidos e repetições do principal são ignorados com aviso.
+pub fn extra_models_from_env(primary: &str) -> Vec<(&'static str, EmbeddingModel, usize)> {
+    let Ok(raw) = std::env::var("MCP_EMBEDDING_EXTRA_MODELS") else {
+        return Vec::new();
+    };
+    let mut models: Vec<(&'static str, EmbeddingModel, usize)> = Vec::new();
+    for name in raw.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match parse_model_name(name) {
+            Some(model) if model.0 != primary && models.iter().all(|m| m.0 != model.0) => models.push(model),
+            Some(_) => {}
+            None => warn!("Unknown model '{}' in MCP_EMBEDDING_EXTRA_MODELS, ignored", name),
+        }
+    }
+    models
+}
+
+/// MCP_OFFLINE=1: o modelo só carrega do cache local, nunca é baixado
+pub fn offline_from_env() -> bool {
+    std::env::var("MCP_OFFLINE")
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// MCP_EMBED_TAGS=1: o embedding principal usa "type: tags: content" em vez do conteúdo puro
+pub fn embed_tags_from_env() -> bool {
+    std::env::var("MCP_EMBED_TAGS")
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Nome do modo de texto gravado em `storage::EMBEDDING_TEXT_KEY`
+pub fn embedding_text_mode(embed_tags: bool) -> &'static str {
+    if embed_tags {
+        "type_tags_content"
+    } else {
+        "content"
+    }
+}
+
+/// Valor gravado em `storage::EMBEDDING_CODE_KEY`
+pub fn code_preprocess_mode(code_prep: bool) -> &'static str {
+    if code_prep {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Texto composto do embedding com tags: "decision: sqlite, wal: Use WAL mode".
+/// Sem tags fica só "type: content".
+pub fn embedding_text(mem_type: &str, tags: &str, content: &str) -> String {
+    let tags: Vec<&str> = tags.split(',').map(str::trim).filter(|t| !t.is_empty()).collect();
+    if tags.is_empty() {
+        format!("{}: {}", mem_type, content)
+    } else {
+        format!("{}: {}: {}", mem_type, tags.join(", "), content)
+    }
+}
+
+/// Texto do embedding principal de um save ainda não gravado, o mesmo que o worker
+/// monta depois: com `embed_tags`, usa as tags que o save grava (pedidas + auto-tags).
+/// O dedup compara com os vetores gravados, então precisa estar no mesmo espaço.
+pub fn save_text(mem_type: &str, tags: &str, content: &str, embed_tags: bool) -> String {
+    if !embed_tags {
+        return content.to_string();
+    }
+    let final_tags = crate::autotag::merge_tags(tags, &crate::autotag::extract_tags(content));
+    embedding_text(mem_type, &final_tags, content)
+}
+
+/// Texto do embedding principal do job: o conteúdo ou, com `embed_tags`, o composto
+/// com type e tags lidos da linha. Os chunks continuam só do conteúdo.
+fn document_text(conn: &Connection, job: &EmbeddingJob, embed_tags: bool) -> String {
+    if !embed_tags {
+        return job.content.clone();
+    }
+    conn.query_row(
+        "SELECT type, ifnull(tags, '') FROM memories WHERE id = ?",
+        [&job.record_id],
+        |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)),
+    )
+    .map(|(mem_type, tags)| embedding_text(&mem_type, &tags, &job.content))
+    .unwrap_or_else(|_| job.content.clone())
+}
+
+/// Cache onde o fastembed procura (e baixa) os modelos: HF_HOME tem precedência
+/// sobre FASTEMBED_CACHE_DIR, como no `pull_from_hf` dele
+pub fn model_cache_dir() -> std::path::PathBuf {
+    std::env::var("HF_HOME")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|_| fastembed::get_cache_dir().into())
+}
+
+/// Arquivos do modelo ausentes no cache, no layout do hf-hub
+/// (`models--org--name/refs/main` aponta o snapshot com os arquivos)
+pub fn missing_model_files(cache_dir: &std::path::Path, model_type: &EmbeddingModel) -> Result<Vec<String>> {
+    let info = TextEmbedding::get_model_info(model_type)?;
+    let mut files = vec![info.model_file.clone()];
+    files.extend(info.additional_files.iter().cloned());
+    files.extend(
+        ["tokenizer.json", "config.json", "special_tokens_map.json", "tokenizer_config.json"].map(String::from),
+    );
+
+    let repo = cache_dir.join(format!("models--{}", info.model_code.replace('/', "--")));
+    let snapshot = std::fs::read_to_string(repo.join("refs").join("main"))
+        .ok()
+        .map(|commit| repo.join("snapshots").join(commit.trim()));
+    Ok(files
+        .into_iter()
+        .filter(|file| !snapshot.as_ref().is_some_and(|dir| dir.join(file).exists()))
+        .collect())
+}
+
+/// Máximo de instâncias do modelo (MCP_EMBEDDING_WORKERS)
+const MAX_WORKERS: usize = 8;
+
+/// Workers paralelos de embedding (MCP_EMBEDDING_WORKERS, padrão 1).
+/// Cada worker ativo carrega a própria cópia do modelo (~90MB no MiniLM, ~440MB
+/// nos 768d) na primeira vez que precisa dela: mais workers = mais RAM em troca
+/// de vazão quando a fila enche. Com 1, o comportamento é o de sempre.
+pub fn workers_from_env() -> usize {
+    std::env::var("MCP_EMBEDDING_WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1usize)
+        .clamp(1, MAX_WORKERS)
+}
+
+/// Vagas no canal do worker (MCP_EMBEDDING_QUEUE_SIZE, padrão 1024). Com o canal
+/// cheio os jobs não se perdem (ver `enqueue_job`), só esperam o retry.
+pub fn queue_size_from_env() -> usize {
+    std::env::var("MCP_EMBEDDING_QUEUE_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1024usize)
+        .max(1)
+}
+
+/// Wrapper para fastembed TextEmbedding. `embed` precisa de `&mut`, então cada
+/// instância fica atrás do próprio Mutex; chamadas concorrentes usam slots diferentes.
 pub struct EmbeddingEngine {
+    model_name: &'static str,
     model_type: EmbeddingModel,
-    model: std::sync::Mutex<Option<TextEmbedding>>,
+    dimension: usize,
+    /// Slots do modelo, carregados sob demanda (um por worker)
+    models: Vec<std::sync::Mutex<Option<TextEmbedding>>>,
+    /// Próximo slot a esperar quando todos estão ocupados
+    next_slot: AtomicUsize,
+    /// Formato dos embeddings gravados (MCP_EMBEDDING_FORMAT)
+    format: EmbeddingFormat,
+    /// Cache de embeddings (compartilhado entre scopes quando configurado)
+    cache: EmbeddingCache,
+    /// Chunking por palavras ou por tokens (MCP_CHUNK_MODE)
+    chunk_mode: ChunkMode,
+    /// Tamanho/overlap do chunking por palavras (MCP_CHUNK_SIZE / MCP_CHUNK_OVERLAP)
+    chunk_config: ChunkConfig,
+    /// Tokenizer do modelo sem truncation/padding, copiado no primeiro load
+    tokenizer: std::sync::OnceLock<tokenizers::Tokenizer>,
+    /// Total de chamadas ao modelo (embed/embed_batch), para medir o batching
+    calls: AtomicUsize,
+    /// Embeddings das últimas queries de busca (memory_context repete muito)
+    query_cache: QueryCache,
+    /// Modo só texto: o modelo não carregou (offline, download falhou) e não é
+    /// tentado de novo até reiniciar; busca fica só no FTS
+    text_only: AtomicBool,
+    /// Preparo de conteúdo com cara de código antes do modelo (MCP_CODE_PREPROCESS)
+    code_prep: bool,
+    /// Modelos extras (comparação A/B): o worker grava os vetores deles em
+    /// `model_embeddings` e a busca usa quando o modelo é pedido
+    extra: Vec<EmbeddingEngine>,
 }
 
 impl EmbeddingEngine {
+    /// Modelo vem de MCP_EMBEDDING_MODEL; nome desconhecido cai no padrão com aviso
     pub fn new() -> Result<Self> {
-        Self::with_model(EmbeddingModel::AllMiniLML6V2)
+        let (name, model_type, dimension) = match std::env::var("MCP_EMBEDDING_MODEL") {
+            Ok(raw) if !raw.trim().is_empty() => parse_model_name(&raw).unwrap_or_else(|| {
+                warn!(
+                    "Unknown MCP_EMBEDDING_MODEL '{}', using {}",
+                    raw, DEFAULT_MODEL_NAME
+                );
+                (DEFAULT_MODEL_NAME, EmbeddingModel::AllMiniLML6V2, DEFAULT_DIMENSION)
+            }),
+            _ => (DEFAULT_MODEL_NAME, EmbeddingModel::AllMiniLML6V2, DEFAULT_DIMENSION),
+        };
+        info!("Embedding model: {} ({} dims)", name, dimension);
+        Self::with_model(name, model_type, dimension)
+    }
+
+    pub fn with_model(
+        model_name: &'static str,
+        model_type: EmbeddingModel,
+        dimension: usize,
+    ) -> Result<Self> {
+        Ok(Self::build(model_name, model_type, dimension))
     }
 
-    pub fn with_model(model_type: EmbeddingModel) -> Result<Self> {
-        Ok(Self {
+    fn build(model_name: &'static str, model_type: EmbeddingModel, dimension: usize) -> Self {
+        Self {
+            model_name,
             model_type,
-            model: std::sync::Mutex::new(None),
-        })
+            dimension,
+            models: vec![std::sync::Mutex::new(None)],
+            next_slot: AtomicUsize::new(0),
+            format: EmbeddingFormat::from_env(),
+            cache: EmbeddingCache::local_only(),
+            chunk_mode: ChunkMode::from_env(),
+            chunk_config: ChunkConfig::from_env(),
+            tokenizer: std::sync::OnceLock::new(),
+            calls: AtomicUsize::new(0),
+            query_cache: QueryCache::new(QUERY_CACHE_SIZE),
+            text_only: AtomicBool::new(false),
+            code_prep: crate::preprocess::enabled_from_env(),
+            extra: Vec::new(),
+        }
     }
 
-    fn with_model_lock<T>(&self, f: impl FnOnce(&mut TextEmbedding) -> Result<T>) -> Result<T> {
-        let mut guard = self
-            .model
+    /// Engine sem modelo: toda chamada falha na hora e a busca fica só no FTS
+    pub fn text_only() -> Self {
+        let engine = Self::build(DEFAULT_MODEL_NAME, EmbeddingModel::AllMiniLML6V2, DEFAULT_DIMENSION);
+        engine.text_only.store(true, Ordering::Relaxed);
+        engine
+    }
+
+    /// `false` em modo só texto (modelo indisponível)
+    pub fn is_available(&self) -> bool {
+        !self.text_only.load(Ordering::Relaxed)
+    }
+
+    /// Nome canônico do modelo ativo (chave do cache e do schema_meta)
+    pub fn model_name(&self) -> &'static str {
+        self.model_name
+    }
+
+    /// Engines dos modelos extras: um slot cada, cache por DB (chaveado pelo modelo)
+    pub fn with_extra_models(mut self, models: Vec<(&'static str, EmbeddingModel, usize)>) -> Self {
+        self.extra = models
+            .into_iter()
+            .map(|(name, model_type, dimension)| Self::build(name, model_type, dimension))
+            .collect();
+        self
+    }
+
+    pub fn extra_models(&self) -> &[EmbeddingEngine] {
+        &self.extra
+    }
+
+    /// Engine do modelo pedido (principal ou extra), por nome aceito em `parse_model_name`
+    pub fn engine_for(&self, name: &str) -> Option<&EmbeddingEngine> {
+        let (canonical, _, _) = parse_model_name(name)?;
+        if canonical == self.model_name {
+            return Some(self);
+        }
+        self.extra.iter().find(|e| e.model_name == canonical)
+    }
+
+    /// Usa o cache compartilhado em vez da tabela de cada DB
+    pub fn with_cache(mut self, cache: EmbeddingCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Número de instâncias do modelo que podem rodar ao mesmo tempo
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.models = (0..workers.clamp(1, MAX_WORKERS))
+            .map(|_| std::sync::Mutex::new(None))
+            .collect();
+        self
+    }
+
+    pub fn workers(&self) -> usize {
+        self.models.len()
+    }
+
+    pub fn cache(&self) -> &EmbeddingCache {
+        &self.cache
+    }
+
+    pub fn format(&self) -> EmbeddingFormat {
+        self.format
+    }
+
+    /// Dimensão dos vetores do modelo ativo
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Primeiro slot livre; se todos ocupados, espera num deles (round-robin)
+    fn acquire_slot(&self) -> Result<std::sync::MutexGuard<'_, Option<TextEmbedding>>> {
+        for slot in &self.models {
+            if let Ok(guard) = slot.try_lock() {
+                return Ok(guard);
+            }
+        }
+        let idx = self.next_slot.fetch_add(1, Ordering::Relaxed) % self.models.len();
+        self.models[idx]
             .lock()
-            .map_err(|e| anyhow::anyhow!("lock: {}", e))?;
-        if guard.is_none() {
+            .map_err(|e| anyhow::anyhow!("lock: {}", e))
+    }
+
+    /// Carrega o modelo no slot se ainda não estiver carregado
+    fn ensure_loaded<'a>(&self, slot: &'a mut Option<TextEmbedding>) -> Result<&'a mut TextEmbedding> {
+        if slot.is_none() {
+            if !self.is_available() {
+                anyhow::bail!("embeddings disabled (text-only mode)");
+            }
             info!("Carregando modelo de embedding ({:?})...", self.model_type);
-            let model = TextEmbedding::try_new(
-                InitOptions::new(self.model_type.clone()).with_show_download_progress(true),
-            )?;
+            let offline = offline_from_env();
+            if offline {
+                // Falha na hora em vez de tentar o download (que trava sem rede)
+                let cache_dir = model_cache_dir();
+                let missing = missing_model_files(&cache_dir, &self.model_type)?;
+                if !missing.is_empty() {
+                    self.text_only.store(true, Ordering::Relaxed);
+                    let e = anyhow::anyhow!(
+                        "MCP_OFFLINE is set and model {} is not in the local cache {} (missing: {}). \
+                         Preload it by running once with network access, or point FASTEMBED_CACHE_DIR/HF_HOME \
+                         at a copied cache; running text-only (FTS) from now on",
+                        self.model_name,
+                        cache_dir.display(),
+                        missing.join(", ")
+                    );
+                    warn!("{}", e);
+                    return Err(e);
+                }
+            }
+            let model = match TextEmbedding::try_new(
+                InitOptions::new(self.model_type.clone()).with_show_download_progress(!offline),
+            ) {
+                Ok(model) => model,
+                Err(e) => {
+                    // Sem rede o download trava em cada tentativa: desiste de vez
+                    warn!("Embedding model failed to load, running text-only (FTS) from now on: {}", e);
+                    self.text_only.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            };
             info!("Modelo de embedding carregado");
-            *guard = Some(model);
+            self.tokenizer.get_or_init(|| {
+                // O do fastembed trunca na janela do modelo: para contar tokens, sem limite
+                let mut tokenizer = model.tokenizer.clone();
+                let _ = tokenizer.with_truncation(None);
+                tokenizer.with_padding(None);
+                tokenizer
+            });
+            *slot = Some(model);
         }
-        let model = guard
-            .as_mut()
-            .ok_or_else(|| anyhow::anyhow!("embedding model unavailable"))?;
+        slot.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("embedding model unavailable"))
+    }
+
+    fn with_model_lock<T>(&self, f: impl FnOnce(&mut TextEmbedding) -> Result<T>) -> Result<T> {
+        let mut guard = self.acquire_slot()?;
+        let model = self.ensure_loaded(&mut guard)?;
+        self.calls.fetch_add(1, Ordering::Relaxed);
         f(model)
     }
 
+    /// Tokenizer do modelo; carrega o modelo se preciso (o worker vai usá-lo de qualquer jeito)
+    fn tokenizer(&self) -> Option<&tokenizers::Tokenizer> {
+        if let Some(tokenizer) = self.tokenizer.get() {
+            return Some(tokenizer);
+        }
+        let mut guard = self.acquire_slot().ok()?;
+        if let Err(e) = self.ensure_loaded(&mut guard) {
+            warn!("Cannot load tokenizer for chunking: {}", e);
+            return None;
+        }
+        self.tokenizer.get()
+    }
+
+    pub fn chunk_mode(&self) -> ChunkMode {
+        self.chunk_mode
+    }
+
+    pub fn chunk_config(&self) -> ChunkConfig {
+        self.chunk_config
+    }
+
+    /// Divide o conteúdo para os embeddings de chunk. No modo tokens, cai para
+    /// palavras se o tokenizer não estiver disponível.
+    pub fn chunk(&self, text: &str) -> Vec<String> {
+        const TOKEN_CHUNK_SIZE: usize = 200;
+        const TOKEN_CHUNK_OVERLAP: usize = 40;
+
+        if self.chunk_mode == ChunkMode::Tokens {
+            let encoding = self.tokenizer().and_then(|t| t.encode(text, false).ok());
+            if let Some(encoding) = encoding {
+                return chunk_by_tokens(text, encoding.get_offsets(), TOKEN_CHUNK_SIZE, TOKEN_CHUNK_OVERLAP);
+            }
+        }
+        chunk_content(text, self.chunk_config.size, self.chunk_config.overlap)
+    }
+
+    pub fn code_preprocessing(&self) -> bool {
+        self.code_prep
+    }
+
+    /// Modelo na chave do embedding_cache: com preprocess de código o mesmo texto
+    /// gera outro vetor, então os dois modos não compartilham entradas
+    pub fn cache_model(&self) -> String {
+        if self.code_prep {
+            format!("{}+code", self.model_name)
+        } else {
+            self.model_name.to_string()
+        }
+    }
+
+    /// Texto que vai para o modelo: código passa por `preprocess`; o resto, como está.
+    /// Cache e DB continuam chaveados pelo texto original (o modo entra em `cache_model`).
+    fn model_input(&self, text: &str) -> String {
+        self.code_prep
+            .then(|| crate::preprocess::prepare_for_embedding(text))
+            .flatten()
+            .unwrap_or_else(|| text.to_string())
+    }
+
+    /// Vetores saem sempre normalizados (L2 = 1): é a convenção de tudo que é gravado
     pub fn embed(&self, text: &str) -> Result<Vec<f32>> {
-        let results = self.with_model_lock(|model| model.embed(vec![text.to_string()], None))?;
-        Ok(results.into_iter().next().unwrap_or_default())
+        let input = self.model_input(text);
+        let results = self.with_model_lock(|model| model.embed(vec![input], None))?;
+        Ok(results.into_iter().next().map(l2_normalized).unwrap_or_default())
     }
 
     pub fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
-        self.with_model_lock(|model| model.embed(texts, None))
+        let inputs: Vec<String> = texts.iter().map(|t| self.model_input(t)).collect();
+        let results = self.with_model_lock(|model| model.embed(inputs, None))?;
+        Ok(results.into_iter().map(l2_normalized).collect())
+    }
+
+    /// Embedding de query de busca, via LRU em memória antes de ir ao modelo.
+    /// Chave é o texto exato; o engine tem um modelo só, então não entra na chave.
+    pub fn embed_query(&self, query: &str) -> Result<Vec<f32>> {
+        if let Some(hit) = self.query_cache.get(query) {
+            return Ok(hit);
+        }
+        let embedding = self.embed(query)?;
+        self.query_cache.insert(query, embedding.clone());
+        Ok(embedding)
+    }
+
+    pub fn query_cache(&self) -> &QueryCache {
+        &self.query_cache
+    }
+
+    /// Embed barato: só roda se algum slot já estiver carregado e livre.
+    /// Nunca dispara o load lazy nem espera o worker liberar o lock.
+    pub fn embed_if_loaded(&self, text: &str) -> Option<Vec<f32>> {
+        let mut guard = self
+            .models
+            .iter()
+            .filter_map(|slot| slot.try_lock().ok())
+            .find(|guard| guard.is_some())?;
+        let model = guard.as_mut()?;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        model
+            .embed(vec![self.model_input(text)], None)
+            .ok()?
+            .into_iter()
+            .next()
+            .map(l2_normalized)
     }
+
+    /// Algum slot já tem o modelo carregado (slot ocupado conta: está embedando)
+    pub fn is_loaded(&self) -> bool {
+        self.models
+            .iter()
+            .any(|slot| slot.try_lock().map(|guard| guard.is_some()).unwrap_or(true))
+    }
+
+    pub fn model_calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    /// Embeda `SELF_TEST_TEXT` direto no modelo (sem caches); carrega o modelo se preciso
+    pub fn self_test(&self) -> Result<SelfTest> {
+        if !self.is_available() {
+            anyhow::bail!("model unavailable (text-only mode)");
+        }
+        let start = std::time::Instant::now();
+        let vector = self.embed(SELF_TEST_TEXT)?;
+        Ok(SelfTest {
+            dimension: vector.len(),
+            norm: crate::simd::dot(&vector, &vector).sqrt(),
+            millis: start.elapsed().as_millis(),
+        })
+    }
+}
+
+/// Texto fixo do self-test de `memory_diagnostics`
+pub const SELF_TEST_TEXT: &str = "Embedding self-test: the quick brown fox jumps over the lazy dog.";
+
+/// Resultado do self-test do modelo
+#[derive(Debug, Clone)]
+pub struct SelfTest {
+    pub dimension: usize,
+    pub norm: f32,
+    pub millis: u128,
+}
+
+impl SelfTest {
+    /// Vetor com a dimensão do modelo e normalizado (L2 ≈ 1, finito)
+    pub fn healthy(&self, expected_dim: usize) -> bool {
+        self.dimension == expected_dim && self.norm.is_finite() && (self.norm - 1.0).abs() < 1e-3
+    }
+}
+
+// ---- Normalização ----
+
+/// Normaliza para norma L2 = 1 (vetor nulo ou inválido volta como está).
+/// Embeddings gravados seguem essa convenção, então busca e dedup usam só o produto escalar.
+pub fn l2_normalized(mut v: Vec<f32>) -> Vec<f32> {
+    let norm = crate::simd::dot(&v, &v).sqrt();
+    if norm > 0.0 && norm.is_finite() {
+        for x in v.iter_mut() {
+            *x /= norm;
+        }
+    }
+    v
+}
+
+/// Migração única (via `storage::init_db`): renormaliza os blobs de memórias e chunks
+/// gravados por versões que não normalizavam, convertendo f32 legado para f16.
+pub fn normalize_stored_embeddings(conn: &Connection) -> usize {
+    // Dimensão do modelo registrado decide entre f16 e f32 legado (mesma regra do decode)
+    let dim = crate::storage::get_meta(conn, crate::storage::EMBEDDING_MODEL_KEY)
+        .and_then(|m| parse_model_name(&m))
+        .map(|(_, _, dim)| dim)
+        .unwrap_or(DEFAULT_DIMENSION);
+
+    let mut count = 0usize;
+    for table in ["memories", "memory_chunks"] {
+        let rows: Vec<(String, Vec<u8>)> = match conn
+            .prepare(&format!("SELECT id, embedding FROM {} WHERE embedding IS NOT NULL", table))
+        {
+            Ok(mut stmt) => stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map(|rows| rows.flatten().collect())
+                .unwrap_or_default(),
+            Err(_) => continue,
+        };
+        for (id, blob) in rows {
+            // Dimensão desconhecida (outro modelo): f16 é o formato de tudo que não é legado
+            let vector = decode_embedding(&blob, dim).unwrap_or_else(|| decompress_embedding(&blob));
+            if vector.is_empty() {
+                continue;
+            }
+            let _ = conn.execute(
+                &format!("UPDATE {} SET embedding = ? WHERE id = ?", table),
+                rusqlite::params![compress_embedding(&l2_normalized(vector)), id],
+            );
+            count += 1;
+        }
+    }
+    count
 }
 
 // ---- Embedding compression (f16) ----
 
-/// Comprime Vec<f32> para bytes f16 (50% menos espaço)
+/// Comprime `Vec<f32>` para bytes f16 (50% menos espaço)
 pub fn compress_embedding(v: &[f32]) -> Vec<u8> {
     v.iter()
         .flat_map(|&f| f16::from_f32(f).to_le_bytes())
         .collect()
 }
 
-/// Descomprime bytes f16 para Vec<f32>
+/// Descomprime bytes f16 para `Vec<f32>`
 pub fn decompress_embedding(bytes: &[u8]) -> Vec<f32> {
     bytes
         .chunks_exact(2)
@@ -71,6 +610,63 @@ pub fn decompress_embedding(bytes: &[u8]) -> Vec<f32> {
         .collect()
 }
 
+// ---- Quantização int8 ----
+
+/// Formato dos blobs de memórias e chunks (o embedding_cache fica sempre em f16)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingFormat {
+    F16,
+    /// Escala f32 + 1 byte por dimensão: ~50% menor que f16
+    Int8,
+}
+
+impl EmbeddingFormat {
+    /// MCP_EMBEDDING_FORMAT=int8 liga a quantização; qualquer outro valor fica em f16
+    pub fn from_env() -> Self {
+        match std::env::var("MCP_EMBEDDING_FORMAT") {
+            Ok(v) if v.trim().eq_ignore_ascii_case("int8") => Self::Int8,
+            _ => Self::F16,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::F16 => "f16",
+            Self::Int8 => "int8",
+        }
+    }
+
+    pub fn encode(self, v: &[f32]) -> Vec<u8> {
+        match self {
+            Self::F16 => compress_embedding(v),
+            Self::Int8 => quantize_int8(v),
+        }
+    }
+}
+
+/// Tamanho de um blob int8: escala (4 bytes) + 1 byte por dimensão
+pub fn int8_blob_len(dim: usize) -> usize {
+    dim + 4
+}
+
+/// Quantização escalar simétrica: escala = max|x| / 127, gravada na frente
+pub fn quantize_int8(v: &[f32]) -> Vec<u8> {
+    let max = v.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+    let scale = if max > 0.0 && max.is_finite() { max / 127.0 } else { 1.0 };
+    let mut out = Vec::with_capacity(int8_blob_len(v.len()));
+    out.extend_from_slice(&scale.to_le_bytes());
+    out.extend(v.iter().map(|&x| ((x / scale).round().clamp(-127.0, 127.0) as i8) as u8));
+    out
+}
+
+pub fn dequantize_int8(bytes: &[u8]) -> Vec<f32> {
+    let Some((head, body)) = bytes.split_first_chunk::<4>() else {
+        return vec![];
+    };
+    let scale = f32::from_le_bytes(*head);
+    body.iter().map(|&b| b as i8 as f32 * scale).collect()
+}
+
 /// Lê bytes e detecta automaticamente formato (f16 ou f32) baseado no tamanho
 /// 384 dims: f16 = 768 bytes, f32 = 1536 bytes
 pub fn bytes_to_f32(bytes: &[u8]) -> Vec<f32> {
@@ -102,6 +698,31 @@ pub fn bytes_to_f32(bytes: &[u8]) -> Vec<f32> {
     vec![]
 }
 
+/// Decodifica um blob sabendo a dimensão esperada (f16, int8, ou f32 legado).
+/// f32 legado só existe para o modelo padrão de 384 dims; aceitar em outras
+/// dimensões confundiria um f16 de 2*dim com um f32 de dim.
+/// None se o tamanho não bate: vetor de outro modelo.
+pub fn decode_embedding(bytes: &[u8], dim: usize) -> Option<Vec<f32>> {
+    if dim == 0 {
+        return None;
+    }
+    if bytes.len() == dim * 2 {
+        return Some(decompress_embedding(bytes));
+    }
+    if bytes.len() == int8_blob_len(dim) {
+        return Some(dequantize_int8(bytes));
+    }
+    if dim == DEFAULT_DIMENSION && bytes.len() == dim * 4 {
+        return Some(
+            bytes
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+        );
+    }
+    None
+}
+
 // ---- Cache ----
 
 pub fn get_cached_embedding(conn: &Connection, text: &str, model: &str) -> Option<Vec<f32>> {
@@ -112,7 +733,8 @@ pub fn get_cached_embedding(conn: &Connection, text: &str, model: &str) -> Optio
     let blob: Vec<u8> = stmt
         .query_row(rusqlite::params![text_hash, model], |row| row.get(0))
         .ok()?;
-    Some(bytes_to_f32(&blob))
+    // Entradas de versões antigas podem não estar normalizadas
+    Some(l2_normalized(bytes_to_f32(&blob)))
 }
 
 pub fn store_cached_embedding(conn: &Connection, text: &str, model: &str, embedding: &[f32]) {
@@ -125,6 +747,154 @@ pub fn store_cached_embedding(conn: &Connection, text: &str, model: &str, embedd
     );
 }
 
+/// Entradas do LRU de queries: queries repetem bem menos que documentos
+const QUERY_CACHE_SIZE: usize = 256;
+
+/// LRU em memória de embeddings de query. Uso por tick: a entrada com o menor
+/// tick sai quando enche (scan linear, barato com 256 entradas).
+pub struct QueryCache {
+    capacity: usize,
+    inner: std::sync::Mutex<QueryCacheInner>,
+}
+
+#[derive(Default)]
+struct QueryCacheInner {
+    entries: HashMap<String, (Vec<f32>, u64)>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: std::sync::Mutex::new(QueryCacheInner::default()),
+        }
+    }
+
+    pub fn get(&self, query: &str) -> Option<Vec<f32>> {
+        let mut inner = self.inner.lock().ok()?;
+        inner.tick += 1;
+        let tick = inner.tick;
+        let hit = inner.entries.get_mut(query).map(|(embedding, used)| {
+            *used = tick;
+            embedding.clone()
+        });
+        if hit.is_some() {
+            inner.hits += 1;
+        } else {
+            inner.misses += 1;
+        }
+        hit
+    }
+
+    pub fn insert(&self, query: &str, embedding: Vec<f32>) {
+        let Ok(mut inner) = self.inner.lock() else { return };
+        inner.tick += 1;
+        let tick = inner.tick;
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(query) {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        inner.entries.insert(query.to_string(), (embedding, tick));
+    }
+
+    /// (entradas, hits, misses)
+    pub fn stats(&self) -> (usize, u64, u64) {
+        self.inner
+            .lock()
+            .map(|inner| (inner.entries.len(), inner.hits, inner.misses))
+            .unwrap_or_default()
+    }
+}
+
+/// Cache de embeddings num único cache.db para todos os scopes: o mesmo texto
+/// em project e personality é calculado uma vez só. Se o cache.db não abrir,
+/// cai para a tabela `embedding_cache` do próprio DB (comportamento antigo).
+pub struct EmbeddingCache {
+    shared: Option<std::sync::Mutex<Connection>>,
+}
+
+impl EmbeddingCache {
+    pub fn open(path: &std::path::Path) -> Self {
+        match crate::storage::open_cache_db(path) {
+            Ok(conn) => {
+                info!("Shared embedding cache: {}", path.display());
+                Self { shared: Some(std::sync::Mutex::new(conn)) }
+            }
+            Err(e) => {
+                warn!("Cannot open shared embedding cache {}: {} (using per-DB cache)", path.display(), e);
+                Self::local_only()
+            }
+        }
+    }
+
+    /// Sem cache compartilhado: cada DB usa a própria tabela
+    pub fn local_only() -> Self {
+        Self { shared: None }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared.is_some()
+    }
+
+    /// Procura no compartilhado e, como fallback, na tabela do DB (entradas antigas)
+    pub fn get(&self, local: &Connection, text: &str, model: &str) -> Option<Vec<f32>> {
+        if let Some(shared) = &self.shared {
+            if let Ok(conn) = shared.lock() {
+                if let Some(emb) = get_cached_embedding(&conn, text, model) {
+                    return Some(emb);
+                }
+            }
+        }
+        get_cached_embedding(local, text, model)
+    }
+
+    pub fn store(&self, local: &Connection, text: &str, model: &str, embedding: &[f32]) {
+        self.store_batch(local, model, &[(text, embedding)]);
+    }
+
+    /// Grava várias entradas; no compartilhado, numa transação só
+    pub fn store_batch(&self, local: &Connection, model: &str, entries: &[(&str, &[f32])]) {
+        if let Some(shared) = &self.shared {
+            if let Ok(mut conn) = shared.lock() {
+                if let Ok(tx) = conn.transaction() {
+                    for (text, emb) in entries {
+                        store_cached_embedding(&tx, text, model, emb);
+                    }
+                    if tx.commit().is_ok() {
+                        return;
+                    }
+                }
+            }
+        }
+        for (text, emb) in entries {
+            store_cached_embedding(local, text, model, emb);
+        }
+    }
+
+    /// Entradas no cache compartilhado (None sem ele)
+    pub fn shared_entries(&self) -> Option<i64> {
+        let conn = self.shared.as_ref()?.lock().ok()?;
+        conn.query_row("SELECT COUNT(*) FROM embedding_cache", [], |r| r.get(0)).ok()
+    }
+
+    /// Poda do compartilhado (MCP_CACHE_MAX_ENTRIES); o dos DBs vai no compact_db
+    pub fn prune_shared(&self) -> i64 {
+        match self.shared.as_ref().and_then(|s| s.lock().ok()) {
+            Some(conn) => crate::storage::prune_embedding_cache(&conn),
+            None => 0,
+        }
+    }
+}
+
 fn compute_text_hash(text: &str, model: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(format!("{}:{}", model, text).as_bytes());
@@ -137,218 +907,493 @@ pub struct EmbeddingJob {
     pub db_path: String,
     pub record_id: String,
     pub content: String,
+    /// Chamada de tool que gerou o job (vazio no reindex do start e nos retries):
+    /// aparece nos logs do worker para ligar a falha ao save de origem
+    pub request_id: String,
+}
+
+/// Progresso do worker: jobs enfileirados e concluídos desde o start, e os DBs
+/// onde o retry procura falhas persistidas
+#[derive(Default)]
+pub struct JobProgress {
+    queued: AtomicUsize,
+    completed: AtomicUsize,
+    retry_dbs: std::sync::Mutex<HashSet<String>>,
+}
+
+impl JobProgress {
+    pub fn record_queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_completed(&self, n: usize) {
+        self.completed.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// (enfileirados, concluídos)
+    pub fn snapshot(&self) -> (usize, usize) {
+        (
+            self.queued.load(Ordering::Relaxed),
+            self.completed.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Inclui o DB na varredura de retry do worker
+    pub fn watch_db(&self, db_path: &str) {
+        let mut dbs = self.retry_dbs.lock().unwrap_or_else(|e| e.into_inner());
+        if !dbs.contains(db_path) {
+            dbs.insert(db_path.to_string());
+        }
+    }
+
+    fn watched_dbs(&self) -> Vec<String> {
+        self.retry_dbs.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
 }
 
 pub fn start_background_worker(
     engine: Arc<EmbeddingEngine>,
+    ann: Arc<AnnRegistry>,
+    progress: Arc<JobProgress>,
 ) -> mpsc::Sender<EmbeddingJob> {
-    let (tx, mut rx) = mpsc::channel::<EmbeddingJob>(1024);
+    let (tx, mut rx) = mpsc::channel::<EmbeddingJob>(queue_size_from_env());
 
     tokio::spawn(async move {
-        info!("Background embedding worker started (batch mode)");
+        // Um permit por instância do modelo: batches além disso esperam na fila
+        let permits = Arc::new(tokio::sync::Semaphore::new(engine.workers()));
+        info!(
+            "Background embedding worker started (batch mode, {} workers)",
+            engine.workers()
+        );
+
+        const BATCH_SIZE: usize = 16;
+        let mut retry_tick = tokio::time::interval(RETRY_INTERVAL);
+        retry_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
         loop {
-            // Espera pelo primeiro job
-            let first = match rx.recv().await {
-                Some(job) => job,
-                None => break,
+            // Só tira jobs do canal quando há worker livre (mantém o batching)
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                break;
             };
+            let (batch, from_queue) = tokio::select! {
+                job = rx.recv() => {
+                    // Espera pelo primeiro job
+                    let Some(first) = job else { break };
 
-            // Coleta mais jobs disponíveis no canal (até BATCH_SIZE)
-            const BATCH_SIZE: usize = 16;
-            let mut batch = vec![first];
-            while batch.len() < BATCH_SIZE {
-                match rx.try_recv() {
-                    Ok(job) => batch.push(job),
-                    Err(_) => break,
+                    // Coleta mais jobs disponíveis no canal (até BATCH_SIZE)
+                    let mut batch = vec![first];
+                    while batch.len() < BATCH_SIZE {
+                        match rx.try_recv() {
+                            Ok(job) => batch.push(job),
+                            Err(_) => break,
+                        }
+                    }
+                    (batch, true)
+                }
+                _ = retry_tick.tick() => {
+                    let dbs = progress.watched_dbs();
+                    let retries = tokio::task::spawn_blocking(move || due_retry_jobs(&dbs, BATCH_SIZE))
+                        .await
+                        .unwrap_or_default();
+                    if retries.is_empty() {
+                        continue;
+                    }
+                    info!("Retrying {} failed embedding jobs", retries.len());
+                    (retries, false)
                 }
+            };
+            for job in &batch {
+                progress.watch_db(&job.db_path);
+            }
+            // Modo só texto: jobs novos viram falhas para o próximo start com modelo;
+            // os retries já estão na tabela e ficam lá
+            if !engine.is_available() {
+                if from_queue {
+                    let count = batch.len();
+                    let _ = tokio::task::spawn_blocking(move || defer_text_only(&batch)).await;
+                    progress.record_completed(count);
+                }
+                continue;
             }
 
-            let engine = engine.clone();
-            let batch_len = batch.len();
-            tokio::task::spawn_blocking(move || {
-                process_embedding_batch(&engine, &batch);
-            })
-            .await
-            .ok();
+            let worker_engine = engine.clone();
+            let worker_ann = ann.clone();
+            let worker_progress = progress.clone();
+            // Span com os request ids das chamadas de origem (retries não têm)
+            let mut requests: Vec<&str> =
+                batch.iter().map(|job| job.request_id.as_str()).filter(|id| !id.is_empty()).collect();
+            requests.dedup();
+            let span = tracing::info_span!("embed_batch", requests = %requests.join(","));
+            tokio::spawn(async move {
+                let batch_len = batch.len();
+                let calls_before = worker_engine.model_calls();
+                let engine = worker_engine.clone();
+                let batch_span = span.clone();
+                tokio::task::spawn_blocking(move || {
+                    let _enter = batch_span.enter();
+                    process_embedding_batch(&engine, &worker_ann, &batch);
+                })
+                .await
+                .ok();
+                drop(permit);
+                // Conta o batch mesmo com erro: o job saiu da fila (retries não passaram por ela)
+                if from_queue {
+                    worker_progress.record_completed(batch_len);
+                }
 
-            if batch_len > 1 {
-                info!("Processed embedding batch of {} jobs", batch_len);
-            }
+                if batch_len > 1 {
+                    let _enter = span.enter();
+                    // Com vários workers a contagem inclui chamadas dos batches concorrentes
+                    info!(
+                        "Processed embedding batch of {} jobs ({} model calls)",
+                        batch_len,
+                        worker_engine.model_calls().saturating_sub(calls_before)
+                    );
+                }
+            });
         }
     });
 
     tx
 }
 
-/// Processa batch de jobs — usa embed_batch para textos principais, embed individual para chunks
-fn process_embedding_batch(engine: &EmbeddingEngine, jobs: &[EmbeddingJob]) {
+/// Intervalo entre varreduras de falhas com backoff vencido
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Jobs a refazer: falhas persistidas com backoff vencido, até `limit` no total
+fn due_retry_jobs(db_paths: &[String], limit: usize) -> Vec<EmbeddingJob> {
+    let mut jobs = Vec::new();
+    for db_path in db_paths {
+        if jobs.len() >= limit {
+            break;
+        }
+        let Ok(conn) = crate::storage::open_connection(std::path::Path::new(db_path)) else {
+            continue;
+        };
+        let due = crate::storage::due_embedding_failures(&conn, limit - jobs.len()).unwrap_or_default();
+        jobs.extend(due.into_iter().map(|(record_id, content)| EmbeddingJob {
+            db_path: db_path.clone(),
+            record_id,
+            content,
+            request_id: String::new(),
+        }));
+    }
+    jobs
+}
+
+/// Enfileira o job no worker. Com o canal cheio, grava o job como falha para o
+/// retry periódico em vez de descartar, e inclui o DB na varredura do retry: o
+/// worker pode nunca ter recebido um job dele. Retorna se o job entrou na fila.
+pub fn enqueue_job(tx: &mpsc::Sender<EmbeddingJob>, progress: &JobProgress, job: EmbeddingJob) -> bool {
+    match tx.try_send(job) {
+        Ok(()) => {
+            progress.record_queued();
+            true
+        }
+        Err(mpsc::error::TrySendError::Full(job)) => {
+            warn!(request_id = %job.request_id, "Embedding queue full, deferring {} to retry", job.record_id);
+            let deferred = crate::storage::open_connection(std::path::Path::new(&job.db_path)).and_then(|conn| {
+                crate::storage::record_embedding_failure(&conn, &job.record_id, &job.db_path, "queue full")
+            });
+            match deferred {
+                Ok(()) => progress.watch_db(&job.db_path),
+                Err(e) => warn!(
+                    request_id = %job.request_id,
+                    "Cannot defer embedding job for {}: {} (memory_reindex picks it up)",
+                    job.record_id,
+                    e
+                ),
+            }
+            false
+        }
+        Err(mpsc::error::TrySendError::Closed(job)) => {
+            warn!(request_id = %job.request_id, "Embedding worker stopped, job for {} not queued", job.record_id);
+            false
+        }
+    }
+}
+
+/// Persiste a falha para o retry; se nem isso der certo, fica só o log
+fn record_failure(conn: &Connection, job: &EmbeddingJob, error: &anyhow::Error) {
+    warn!(request_id = %job.request_id, "Embedding job error for {}: {}", job.record_id, error);
+    if let Err(e) =
+        crate::storage::record_embedding_failure(conn, &job.record_id, &job.db_path, &error.to_string())
+    {
+        warn!("Cannot record embedding failure for {}: {}", job.record_id, e);
+    }
+}
+
+/// Modo só texto: os jobs viram falhas persistidas, que o retry de um start
+/// com modelo refaz (inclusive no DB de projeto, que o reindex do start não varre)
+pub fn defer_text_only(jobs: &[EmbeddingJob]) {
+    let mut by_db: HashMap<&str, Vec<&EmbeddingJob>> = HashMap::new();
+    for job in jobs {
+        by_db.entry(job.db_path.as_str()).or_default().push(job);
+    }
+    for (db_path, db_jobs) in by_db {
+        let conn = match crate::storage::open_connection(std::path::Path::new(db_path)) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Cannot open DB {}: {}", db_path, e);
+                continue;
+            }
+        };
+        for job in db_jobs {
+            if let Err(e) = crate::storage::record_embedding_failure(
+                &conn,
+                &job.record_id,
+                &job.db_path,
+                "model unavailable (text-only mode)",
+            ) {
+                warn!("Cannot record embedding failure for {}: {}", job.record_id, e);
+            }
+        }
+    }
+}
+
+/// Processa batch de jobs — conteúdos e chunks sem cache de todos os jobs do mesmo DB
+/// vão numa única chamada de embed_batch; a gravação é uma transação por DB
+fn process_embedding_batch(engine: &EmbeddingEngine, ann: &AnnRegistry, jobs: &[EmbeddingJob]) {
     // Agrupar por db_path para abrir cada conexão uma vez
-    let mut by_db: std::collections::HashMap<String, Vec<&EmbeddingJob>> = std::collections::HashMap::new();
+    let mut by_db: HashMap<String, Vec<&EmbeddingJob>> = HashMap::new();
     for job in jobs {
         by_db.entry(job.db_path.clone()).or_default().push(job);
     }
 
     for (db_path, db_jobs) in &by_db {
-        let conn = match Connection::open(db_path) {
+        let mut conn = match crate::storage::open_connection(std::path::Path::new(db_path)) {
             Ok(c) => c,
             Err(e) => {
                 warn!("Cannot open DB {}: {}", db_path, e);
                 continue;
             }
         };
-        let _ = conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;");
+        let _ = conn.execute_batch("PRAGMA journal_mode=WAL;");
 
-        let model_name = "all-MiniLM-L6-v2";
+        let cache_model = engine.cache_model();
+        let embed_tags = embed_tags_from_env();
 
-        // Separar jobs que precisam de embedding (não cached) vs cached
-        let mut needs_embedding: Vec<(&EmbeddingJob, usize)> = Vec::new();
-        let mut cached: Vec<(&EmbeddingJob, Vec<f32>)> = Vec::new();
+        // Textos de cada job: texto principal + chunks do conteúdo (só quando há mais de um)
+        let plans: Vec<Plan> = db_jobs
+            .iter()
+            .map(|job| {
+                let chunks = engine.chunk(&job.content);
+                let document = document_text(&conn, job, embed_tags);
+                (*job, document, if chunks.len() > 1 { chunks } else { Vec::new() })
+            })
+            .collect();
 
-        for (idx, job) in db_jobs.iter().enumerate() {
-            if let Some(emb) = get_cached_embedding(&conn, &job.content, model_name) {
-                cached.push((job, emb));
-            } else {
-                needs_embedding.push((job, idx));
+        // Resolve o cache e junta os textos que faltam, sem repetição
+        let mut vectors: HashMap<String, Vec<f32>> = HashMap::new();
+        let mut missing: Vec<String> = Vec::new();
+        let mut seen: HashSet<&str> = HashSet::new();
+        for (_, document, chunks) in &plans {
+            for text in std::iter::once(document).chain(chunks.iter()) {
+                if !seen.insert(text.as_str()) {
+                    continue;
+                }
+                match engine.cache().get(&conn, text, &cache_model) {
+                    Some(emb) => {
+                        vectors.insert(text.clone(), emb);
+                    }
+                    None => missing.push(text.clone()),
+                }
             }
         }
 
-        // Batch embed os que não estão no cache
-        if !needs_embedding.is_empty() {
-            let texts: Vec<String> = needs_embedding.iter().map(|(j, _)| j.content.clone()).collect();
-            match engine.embed_batch(&texts) {
+        // Uma única chamada ao modelo para tudo que não estava no cache
+        if !missing.is_empty() {
+            match engine.embed_batch(&missing) {
                 Ok(embeddings) => {
-                    for (i, emb) in embeddings.into_iter().enumerate() {
-                        let job = needs_embedding[i].0;
-                        store_cached_embedding(&conn, &job.content, model_name, &emb);
-                        save_embedding_to_record(&conn, job, &emb, engine, model_name);
+                    for (text, emb) in missing.iter().zip(embeddings) {
+                        vectors.insert(text.clone(), emb);
                     }
                 }
                 Err(e) => {
                     // Fallback: tentar individualmente
                     warn!("Batch embed failed, falling back to individual: {}", e);
-                    for (job, _) in &needs_embedding {
+                    for (job, _, _) in &plans {
                         if let Err(e) = process_embedding_job(engine, job) {
-                            warn!("Embedding job error for {}: {}", job.record_id, e);
+                            record_failure(&conn, job, &e);
                         }
                     }
+                    continue;
                 }
             }
         }
 
-        // Processar cached
-        for (job, emb) in &cached {
-            save_embedding_to_record(&conn, job, emb, engine, model_name);
+        let stored = match write_embedding_batch(&mut conn, engine, embed_tags, &plans, &vectors, &missing) {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("Cannot write embedding batch to {}: {}", db_path, e);
+                for (job, _, _) in &plans {
+                    record_failure(&conn, job, &e);
+                }
+                continue;
+            }
+        };
+        // Os que ficaram para trás (conteúdo mudou) não mexem no ANN nem nos modelos extras
+        let plans: Vec<Plan> = plans.into_iter().zip(stored).filter_map(|(p, w)| w.then_some(p)).collect();
+
+        // Índice ANN acompanha o que acabou de ser gravado
+        let replaced: Vec<&str> = plans.iter().map(|(job, _, _)| job.record_id.as_str()).collect();
+        let mut written: Vec<(&str, bool, &[f32])> = Vec::new();
+        for (job, document, chunks) in &plans {
+            let Some(emb) = vectors.get(document) else {
+                continue;
+            };
+            written.push((job.record_id.as_str(), false, emb.as_slice()));
+            for chunk in chunks {
+                if let Some(chunk_emb) = vectors.get(chunk) {
+                    written.push((job.record_id.as_str(), true, chunk_emb.as_slice()));
+                }
+            }
+        }
+        ann.apply(db_path, &conn, &replaced, &written);
+        for (job, _, chunks) in &plans {
+            debug!(request_id = %job.request_id, "Embedded {} ({} chunks)", job.record_id, chunks.len());
         }
+        write_extra_embeddings(engine, &conn, &plans);
     }
 }
 
-/// Salva embedding no record + processa chunks se necessário
-fn save_embedding_to_record(
-    conn: &Connection,
-    job: &EmbeddingJob,
-    embedding: &[f32],
-    engine: &EmbeddingEngine,
-    model_name: &str,
-) {
-    use crate::chunking::chunk_text;
-
-    let blob = compress_embedding(embedding);
-    let _ = conn.execute(
-        "UPDATE memories SET embedding = ? WHERE id = ?",
-        rusqlite::params![blob, job.record_id],
-    );
-
-    // Chunk conteúdos longos
-    let chunks = chunk_text(&job.content, 400, 80);
-    if chunks.len() > 1 {
-        let _ = conn.execute(
-            "DELETE FROM memory_chunks WHERE memory_id = ?",
-            rusqlite::params![job.record_id],
-        );
-
-        for (idx, chunk) in chunks.iter().enumerate() {
-            let chunk_id = format!("{}_c{}", job.record_id, idx);
-            let chunk_emb = if let Some(cached) = get_cached_embedding(conn, chunk, model_name) {
-                cached
-            } else {
-                match engine.embed(chunk) {
-                    Ok(emb) => {
-                        store_cached_embedding(conn, chunk, model_name, &emb);
-                        emb
-                    }
-                    Err(_) => continue,
-                }
-            };
-            let chunk_blob = compress_embedding(&chunk_emb);
-            let _ = conn.execute(
-                "INSERT OR REPLACE INTO memory_chunks \
-                 (id, memory_id, chunk_index, chunk_text, embedding) \
-                 VALUES (?, ?, ?, ?, ?)",
-                rusqlite::params![chunk_id, job.record_id, idx as i64, chunk, chunk_blob],
-            );
+/// Vetores dos modelos extras, só do texto principal (sem chunks). Falha aqui fica no log:
+/// o embedding principal já foi gravado e o `memory_reindex` completa o que faltar.
+fn write_extra_embeddings(engine: &EmbeddingEngine, conn: &Connection, plans: &[Plan]) {
+    let texts: Vec<String> = plans.iter().map(|(_, document, _)| document.clone()).collect();
+    for extra in engine.extra_models() {
+        let vectors = match extra.embed_batch(&texts) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Extra model {} embed failed: {}", extra.model_name(), e);
+                continue;
+            }
+        };
+        let rows: Vec<(&str, Vec<u8>)> = plans
+            .iter()
+            .zip(&vectors)
+            .map(|((job, _, _), emb)| (job.record_id.as_str(), extra.format().encode(emb)))
+            .collect();
+        if let Err(e) = crate::storage::write_model_embeddings(conn, extra.model_name(), &rows) {
+            warn!("Cannot write {} embeddings: {}", extra.model_name(), e);
         }
     }
 }
 
-fn process_embedding_job(engine: &EmbeddingEngine, job: &EmbeddingJob) -> Result<()> {
-    use crate::chunking::chunk_text;
+/// Job com o texto do embedding principal e os chunks do conteúdo
+type Plan<'a> = (&'a EmbeddingJob, String, Vec<String>);
 
-    let conn = Connection::open(&job.db_path)?;
-    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;")?;
+/// Grava cache, embeddings principais e chunks de todos os jobs numa única transação.
+/// Job cujo conteúdo mudou depois de enfileirado (update/merge no meio do embed) não
+/// grava nada: o job do conteúdo novo cuida dele. Devolve, por plano, se foi gravado.
+fn write_embedding_batch(
+    conn: &mut Connection,
+    engine: &EmbeddingEngine,
+    embed_tags: bool,
+    plans: &[Plan],
+    vectors: &HashMap<String, Vec<f32>>,
+    new_texts: &[String],
+) -> Result<Vec<bool>> {
+    let model_name = engine.model_name();
+    let format = engine.format();
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    crate::storage::record_embedding_model(&tx, model_name);
+    crate::storage::record_embedding_format(&tx, format.name());
+    crate::storage::record_embedding_text(&tx, embedding_text_mode(embed_tags));
+    crate::storage::record_embedding_code(&tx, code_preprocess_mode(engine.code_preprocessing()));
 
-    let model_name = "all-MiniLM-L6-v2";
+    let new_entries: Vec<(&str, &[f32])> = new_texts
+        .iter()
+        .filter_map(|text| Some((text.as_str(), vectors.get(text)?.as_slice())))
+        .collect();
+    engine.cache().store_batch(&tx, &engine.cache_model(), &new_entries);
 
-    // Check cache
-    let embedding = if let Some(cached) = get_cached_embedding(&conn, &job.content, model_name) {
-        cached
-    } else {
-        let emb = engine.embed(&job.content)?;
-        store_cached_embedding(&conn, &job.content, model_name, &emb);
-        emb
-    };
-
-    // Salva como f16 comprimido
-    let blob = compress_embedding(&embedding);
-    conn.execute(
-        "UPDATE memories SET embedding = ? WHERE id = ?",
-        rusqlite::params![blob, job.record_id],
-    )?;
+    let mut written = Vec::with_capacity(plans.len());
+    for (job, document, chunks) in plans {
+        let Some(embedding) = vectors.get(document) else {
+            written.push(false);
+            continue;
+        };
+        let updated = tx.execute(
+            "UPDATE memories SET embedding = ? WHERE id = ? AND memory_content(content, content_zstd) = ?",
+            rusqlite::params![format.encode(embedding), job.record_id, job.content],
+        )?;
+        written.push(updated > 0);
+        if updated == 0 {
+            debug!(request_id = %job.request_id, "Skipped {}: content changed since queued", job.record_id);
+            continue;
+        }
+        crate::storage::clear_embedding_failure(&tx, &job.record_id)?;
 
-    // Chunk conteúdos longos
-    let chunks = chunk_text(&job.content, 400, 80);
-    if chunks.len() > 1 {
-        conn.execute(
+        // Sempre limpa: conteúdo que encolheu para um chunk só não pode herdar
+        // os chunks da versão longa
+        tx.execute(
             "DELETE FROM memory_chunks WHERE memory_id = ?",
             rusqlite::params![job.record_id],
         )?;
-
         for (idx, chunk) in chunks.iter().enumerate() {
+            let Some(chunk_emb) = vectors.get(chunk) else {
+                continue;
+            };
             let chunk_id = format!("{}_c{}", job.record_id, idx);
-            let chunk_emb =
-                if let Some(cached) = get_cached_embedding(&conn, chunk, model_name) {
-                    cached
-                } else {
-                    let emb = engine.embed(chunk)?;
-                    store_cached_embedding(&conn, chunk, model_name, &emb);
-                    emb
-                };
-            let chunk_blob = compress_embedding(&chunk_emb);
-            conn.execute(
+            tx.execute(
                 "INSERT OR REPLACE INTO memory_chunks \
                  (id, memory_id, chunk_index, chunk_text, embedding) \
                  VALUES (?, ?, ?, ?, ?)",
-                rusqlite::params![chunk_id, job.record_id, idx as i64, chunk, chunk_blob],
+                rusqlite::params![chunk_id, job.record_id, idx as i64, chunk, format.encode(chunk_emb)],
             )?;
         }
     }
 
+    tx.commit()?;
+    Ok(written)
+}
+
+/// Um job por vez (fallback do batch). Calcula tudo antes e grava numa transação:
+/// se o processo cair no meio, a memória fica com o estado antigo inteiro. Os modelos
+/// extras vêm depois, como no batch.
+fn process_embedding_job(engine: &EmbeddingEngine, job: &EmbeddingJob) -> Result<()> {
+    let mut conn = crate::storage::open_connection(std::path::Path::new(&job.db_path))?;
+    conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+    let cache_model = engine.cache_model();
+    let embed_tags = embed_tags_from_env();
+    let document = document_text(&conn, job, embed_tags);
+    let chunks = engine.chunk(&job.content);
+    let chunks = if chunks.len() > 1 { chunks } else { Vec::new() };
+
+    let mut vectors: HashMap<String, Vec<f32>> = HashMap::new();
+    let mut new_texts: Vec<String> = Vec::new();
+    for text in std::iter::once(&document).chain(chunks.iter()) {
+        if vectors.contains_key(text) {
+            continue;
+        }
+        let emb = match engine.cache().get(&conn, text, &cache_model) {
+            Some(cached) => cached,
+            None => {
+                new_texts.push(text.clone());
+                engine.embed(text)?
+            }
+        };
+        vectors.insert(text.clone(), emb);
+    }
+
+    let plans = [(job, document, chunks)];
+    if write_embedding_batch(&mut conn, engine, embed_tags, &plans, &vectors, &new_texts)? == [true] {
+        write_extra_embeddings(engine, &conn, &plans);
+    }
     Ok(())
 }
 
 /// Migra embeddings legados (f32) para f16 em background
 pub fn migrate_embeddings_to_f16(conn: &Connection) -> usize {
     let mut count = 0usize;
+    // 1536 bytes só é f32 legado com o modelo de 384 dims; em DB de modelo 768 é f16
+    if let Some(stored) = crate::storage::get_meta(conn, crate::storage::EMBEDDING_MODEL_KEY) {
+        if parse_model_name(&stored).is_some_and(|(_, _, dim)| dim != DEFAULT_DIMENSION) {
+            return 0;
+        }
+    }
     // Detecta embeddings f32 (1536 bytes para 384 dims)
     if let Ok(mut stmt) = conn.prepare(
         "SELECT id, embedding FROM memories WHERE embedding IS NOT NULL AND length(embedding) = 1536"
@@ -397,3 +1442,364 @@ pub fn migrate_embeddings_to_f16(conn: &Connection) -> usize {
 
     count
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("mcp-memory-emb-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_self_test_health_and_text_only() {
+        let ok = SelfTest { dimension: 384, norm: 1.0, millis: 3 };
+        assert!(ok.healthy(384));
+        assert!(!ok.healthy(768));
+        assert!(!SelfTest { norm: 0.0, ..ok.clone() }.healthy(384));
+        assert!(!SelfTest { norm: f32::NAN, ..ok }.healthy(384));
+        assert!(EmbeddingEngine::text_only().self_test().is_err());
+    }
+
+    #[test]
+    fn test_document_text_includes_type_and_tags_when_enabled() {
+        let path = temp_path("embed-tags");
+        let conn = crate::storage::init_db(&path).unwrap();
+        let saved = crate::storage::save_memory(&conn, "decision", "Use WAL mode", "sqlite,wal", &Default::default())
+            .unwrap();
+        let job = EmbeddingJob {
+            db_path: path.to_string_lossy().to_string(),
+            record_id: saved.id,
+            content: "Use WAL mode".to_string(),
+            request_id: String::new(),
+        };
+        assert_eq!(document_text(&conn, &job, false), "Use WAL mode");
+        assert!(document_text(&conn, &job, true).starts_with("decision: sqlite, wal"));
+        assert_eq!(embedding_text("note", " , ", "plain"), "note: plain");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_missing_model_files_follows_hf_cache_layout() {
+        let cache = temp_path("model-cache");
+        let _ = std::fs::remove_dir_all(&cache);
+        let model = EmbeddingModel::AllMiniLML6V2;
+        let all = missing_model_files(&cache, &model).unwrap();
+        assert!(all.iter().any(|f| f.ends_with(".onnx")) && all.contains(&"tokenizer.json".to_string()));
+
+        let info = TextEmbedding::get_model_info(&model).unwrap();
+        let repo = cache.join(format!("models--{}", info.model_code.replace('/', "--")));
+        std::fs::create_dir_all(repo.join("refs")).unwrap();
+        std::fs::write(repo.join("refs").join("main"), "abc123\n").unwrap();
+        let snapshot = repo.join("snapshots").join("abc123");
+        for file in &all[1..] {
+            let path = snapshot.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        assert_eq!(missing_model_files(&cache, &model).unwrap(), vec![all[0].clone()]);
+
+        let path = snapshot.join(&all[0]);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"").unwrap();
+        assert!(missing_model_files(&cache, &model).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&cache);
+    }
+
+    #[test]
+    fn test_text_only_jobs_wait_for_retry() {
+        let path = temp_path("text-only-defer");
+        let conn = crate::storage::init_db(&path).unwrap();
+        let db_path = path.to_string_lossy().to_string();
+        let id = crate::storage::save_memory(&conn, "note", "saved offline", "", &Default::default()).unwrap().id;
+
+        let job = EmbeddingJob {
+            db_path: db_path.clone(),
+            record_id: id.clone(),
+            content: "saved offline".into(),
+            request_id: String::new(),
+        };
+        defer_text_only(&[job]);
+        conn.execute("UPDATE embedding_failures SET next_retry_at = datetime('now', '-1 second')", [])
+            .unwrap();
+        let retried: Vec<String> = due_retry_jobs(&[db_path], 10).into_iter().map(|j| j.record_id).collect();
+        assert_eq!(retried, [id]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_full_queue_defers_jobs_to_retry() {
+        let path = temp_path("queue-full");
+        let conn = crate::storage::init_db(&path).unwrap();
+        let db_path = path.to_string_lossy().to_string();
+        let ids: Vec<String> = (0..3)
+            .map(|i| {
+                crate::storage::save_memory(&conn, "note", &format!("burst note {}", i), "", &Default::default())
+                    .unwrap()
+                    .id
+            })
+            .collect();
+
+        let (tx, _rx) = mpsc::channel::<EmbeddingJob>(1);
+        let progress = Arc::new(JobProgress::default());
+        let queued: Vec<bool> = ids
+            .iter()
+            .map(|id| {
+                let job = EmbeddingJob {
+                    db_path: db_path.clone(),
+                    record_id: id.clone(),
+                    content: String::new(),
+                    request_id: String::new(),
+                };
+                enqueue_job(&tx, &progress, job)
+            })
+            .collect();
+        assert_eq!(queued, [true, false, false]);
+        assert_eq!(progress.snapshot(), (1, 0));
+
+        // Os que não couberam voltam pelo retry do worker
+        conn.execute("UPDATE embedding_failures SET next_retry_at = datetime('now', '-1 second')", [])
+            .unwrap();
+        let mut retried: Vec<String> = due_retry_jobs(&[db_path], 10).into_iter().map(|j| j.record_id).collect();
+        retried.sort();
+        let mut expected = ids[1..].to_vec();
+        expected.sort();
+        assert_eq!(retried, expected);
+
+        // Worker real que nunca recebeu job deste DB: o primeiro tick do retry já os
+        // embeda (vetores no cache, sem carregar o modelo)
+        let engine = Arc::new(EmbeddingEngine::with_model("test", EmbeddingModel::AllMiniLML6V2, 2).unwrap());
+        for i in 1..3 {
+            engine.cache().store(&conn, &format!("burst note {}", i), "test", &[0.6, 0.8]);
+        }
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let embedded = runtime.block_on(async {
+            let _tx = start_background_worker(engine, Arc::new(AnnRegistry::new()), progress);
+            for _ in 0..100 {
+                let embedded: i64 = conn
+                    .query_row("SELECT COUNT(*) FROM memories WHERE embedding IS NOT NULL", [], |r| r.get(0))
+                    .unwrap();
+                if embedded == 2 {
+                    return true;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            false
+        });
+        assert!(embedded);
+        let failures: i64 = conn.query_row("SELECT COUNT(*) FROM embedding_failures", [], |r| r.get(0)).unwrap();
+        assert_eq!(failures, 0);
+    }
+
+    #[test]
+    fn test_save_text_matches_worker_document() {
+        let conn = crate::storage::init_db(&temp_path("save-text")).unwrap();
+        let content = "Use tokio mpsc channels for the embedding queue";
+        let saved = crate::storage::save_memory(&conn, "decision", content, "Queue,project:demo", &Default::default())
+            .unwrap();
+        let job = EmbeddingJob {
+            db_path: String::new(),
+            record_id: saved.id,
+            content: content.to_string(),
+            request_id: String::new(),
+        };
+        assert_eq!(save_text("decision", "Queue,project:demo", content, true), document_text(&conn, &job, true));
+        assert_eq!(save_text("decision", "Queue,project:demo", content, false), content);
+    }
+
+    #[test]
+    fn test_code_preprocessing_has_its_own_cache_key() {
+        let local = crate::storage::init_db(&temp_path("code-cache")).unwrap();
+        let mut engine = EmbeddingEngine::with_model("test", EmbeddingModel::AllMiniLML6V2, 2).unwrap();
+        engine.code_prep = false;
+        engine.cache().store(&local, "fn parse() {}", &engine.cache_model(), &[0.6, 0.8]);
+        engine.code_prep = true;
+        assert_eq!(engine.cache_model(), "test+code");
+        assert!(engine.cache().get(&local, "fn parse() {}", &engine.cache_model()).is_none());
+    }
+
+    #[test]
+    fn test_engine_for_resolves_primary_and_extra_models() {
+        let extra = parse_model_name("bge-base").unwrap();
+        let engine = EmbeddingEngine::text_only().with_extra_models(vec![extra]);
+        assert_eq!(engine.engine_for("minilm").map(|e| e.model_name()), Some(DEFAULT_MODEL_NAME));
+        let bge = engine.engine_for("BGE-BASE-EN-V1.5").unwrap();
+        assert_eq!((bge.model_name(), bge.dimension()), ("bge-base-en-v1.5", 768));
+        assert!(engine.engine_for("nomic-embed-text").is_none());
+        assert!(engine.engine_for("no-such-model").is_none());
+    }
+
+    #[test]
+    fn test_text_only_engine_fails_fast() {
+        let engine = EmbeddingEngine::text_only();
+        assert!(!engine.is_available());
+        assert!(engine.embed("anything").is_err());
+        assert!(engine.embed_query("anything").is_err());
+        assert_eq!(engine.model_calls(), 0);
+    }
+
+    #[test]
+    fn test_query_cache_evicts_least_recently_used() {
+        let cache = QueryCache::new(2);
+        cache.insert("a", vec![1.0]);
+        cache.insert("b", vec![2.0]);
+        // "a" usado por último: "b" é quem sai
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+        cache.insert("c", vec![3.0]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), Some(vec![3.0]));
+        assert_eq!(cache.stats(), (2, 2, 1));
+    }
+
+    #[test]
+    fn test_shared_cache_across_scopes() {
+        let project = crate::storage::init_db(&temp_path("project")).unwrap();
+        let personality = crate::storage::init_db(&temp_path("personality")).unwrap();
+        let cache = EmbeddingCache::open(&temp_path("cache"));
+        assert!(cache.is_shared());
+
+        let emb = l2_normalized(vec![0.6, 0.8]);
+        cache.store(&project, "same text", "m", &emb);
+        // Outro scope reaproveita; nada foi parar na tabela local
+        assert!(cache.get(&personality, "same text", "m").is_some());
+        assert!(get_cached_embedding(&project, "same text", "m").is_none());
+        assert_eq!(cache.shared_entries(), Some(1));
+
+        // Entrada antiga só no DB ainda é encontrada
+        store_cached_embedding(&project, "legacy", "m", &emb);
+        assert!(cache.get(&project, "legacy", "m").is_some());
+    }
+
+    #[test]
+    fn test_cache_falls_back_to_local() {
+        let local = crate::storage::init_db(&temp_path("fallback")).unwrap();
+        // Diretório no lugar do arquivo: não abre
+        let cache = EmbeddingCache::open(&std::env::temp_dir());
+        assert!(!cache.is_shared());
+        cache.store(&local, "text", "m", &[1.0, 0.0]);
+        assert!(get_cached_embedding(&local, "text", "m").is_some());
+        assert_eq!(cache.shared_entries(), None);
+    }
+
+    #[test]
+    fn test_chunk_write_is_atomic() {
+        let mut conn = crate::storage::init_db(&temp_path("atomic")).unwrap();
+        let id = crate::storage::save_memory(&conn, "note", "long memory", "", &Default::default())
+            .unwrap()
+            .id;
+        conn.execute(
+            "INSERT INTO memory_chunks (id, memory_id, chunk_index, chunk_text) VALUES (?, ?, 0, 'old')",
+            rusqlite::params![format!("{}_c0", id), id],
+        )
+        .unwrap();
+        // Falha simulada no terceiro chunk
+        conn.execute_batch(
+            "CREATE TRIGGER fail_chunk BEFORE INSERT ON memory_chunks WHEN NEW.chunk_index = 2
+             BEGIN SELECT RAISE(ABORT, 'simulated failure'); END;",
+        )
+        .unwrap();
+
+        let engine = EmbeddingEngine::with_model("test", EmbeddingModel::AllMiniLML6V2, 2).unwrap();
+        let job = EmbeddingJob {
+            record_id: id.clone(),
+            content: "long memory".to_string(),
+            db_path: String::new(),
+            request_id: String::new(),
+        };
+        let chunks: Vec<String> = (0..4).map(|i| format!("chunk {}", i)).collect();
+        let vectors: HashMap<String, Vec<f32>> = std::iter::once(&job.content)
+            .chain(chunks.iter())
+            .map(|t| (t.clone(), vec![0.6, 0.8]))
+            .collect();
+
+        assert!(write_embedding_batch(&mut conn, &engine, false, &[(&job, job.content.clone(), chunks)], &vectors, &[]).is_err());
+        let chunk_texts: Vec<String> = conn
+            .prepare("SELECT chunk_text FROM memory_chunks WHERE memory_id = ?")
+            .unwrap()
+            .query_map([&id], |r| r.get(0))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(chunk_texts, ["old"]);
+        let embedded: bool = conn
+            .query_row("SELECT embedding IS NOT NULL FROM memories WHERE id = ?", [&id], |r| r.get(0))
+            .unwrap();
+        assert!(!embedded);
+    }
+
+    #[test]
+    fn test_shrunk_content_drops_old_chunks() {
+        let mut conn = crate::storage::init_db(&temp_path("shrink")).unwrap();
+        let id = crate::storage::save_memory(&conn, "note", "long memory", "", &Default::default())
+            .unwrap()
+            .id;
+        let engine = EmbeddingEngine::with_model("test", EmbeddingModel::AllMiniLML6V2, 2).unwrap();
+        let vectors = |texts: &[&String]| -> HashMap<String, Vec<f32>> {
+            texts.iter().map(|t| (t.to_string(), vec![0.6, 0.8])).collect()
+        };
+        let chunk_count = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM memory_chunks WHERE memory_id = ?", [&id], |r| r.get(0))
+                .unwrap()
+        };
+
+        let long = EmbeddingJob {
+            record_id: id.clone(),
+            content: "long memory".into(),
+            db_path: String::new(),
+            request_id: String::new(),
+        };
+        let chunks: Vec<String> = (0..3).map(|i| format!("chunk {}", i)).collect();
+        let mut texts: Vec<&String> = chunks.iter().collect();
+        texts.push(&long.content);
+        write_embedding_batch(&mut conn, &engine, false, &[(&long, long.content.clone(), chunks.clone())], &vectors(&texts), &[]).unwrap();
+        assert_eq!(chunk_count(&conn), 3);
+
+        // Update via dedup para conteúdo curto: um chunk só, nada a reinserir
+        conn.execute("UPDATE memories SET content = 'short' WHERE id = ?", [&id]).unwrap();
+        let short = EmbeddingJob {
+            record_id: id.clone(),
+            content: "short".into(),
+            db_path: String::new(),
+            request_id: String::new(),
+        };
+        write_embedding_batch(&mut conn, &engine, false, &[(&short, short.content.clone(), Vec::new())], &vectors(&[&short.content]), &[]).unwrap();
+        assert_eq!(chunk_count(&conn), 0);
+    }
+
+    #[test]
+    fn test_stale_job_does_not_overwrite_newer_content() {
+        let mut conn = crate::storage::init_db(&temp_path("stale")).unwrap();
+        let id = crate::storage::save_memory(&conn, "note", "old text", "", &Default::default())
+            .unwrap()
+            .id;
+        let engine = EmbeddingEngine::with_model("test", EmbeddingModel::AllMiniLML6V2, 2).unwrap();
+        let stale = EmbeddingJob {
+            record_id: id.clone(),
+            content: "old text".into(),
+            db_path: String::new(),
+            request_id: String::new(),
+        };
+        let chunks = vec!["old".to_string(), "text".to_string()];
+        let vectors: HashMap<String, Vec<f32>> = std::iter::once(&stale.content)
+            .chain(chunks.iter())
+            .map(|t| (t.clone(), vec![0.6, 0.8]))
+            .collect();
+
+        // Update chega enquanto o job antigo está no modelo
+        crate::storage::update_memory(&conn, &id, Some("new text"), None).unwrap();
+        let stored = write_embedding_batch(&mut conn, &engine, false, &[(&stale, stale.content.clone(), chunks)], &vectors, &[]).unwrap();
+        assert_eq!(stored, [false]);
+        let (embedded, chunk_count): (bool, i64) = conn
+            .query_row(
+                "SELECT embedding IS NOT NULL, (SELECT COUNT(*) FROM memory_chunks WHERE memory_id = m.id) \
+                 FROM memories m WHERE id = ?",
+                [&id],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert!(!embedded);
+        assert_eq!(chunk_count, 0);
+    }
+}
@@ -2,10 +2,12 @@
 ///
 /// Captura eventos via stdin (JSON):
 /// - UserPromptSubmit: acumula pergunta do usuário + salva no DB imediatamente
+/// - PreToolUse/PostToolUse: acumula tools e arquivos dos inputs (sem tocar o DB)
 /// - Stop: atualiza memória da sessão com resumo + transcript do assistente
 ///
 /// Uma memória por sessão (UPSERT com ID determinístico).
 /// Formato estruturado: extrai tools, arquivos, tópicos, auto-tags.
+/// Segredos (chaves, tokens, senhas) são redigidos antes de qualquer gravação.
 /// Salva em personality.db (sempre) e project.db (se cwd disponível).
 use std::collections::HashSet;
 use std::io::Read;
@@ -14,19 +16,15 @@ use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-mod autotag;
-#[allow(dead_code)]
-mod chunking;
-#[allow(dead_code)]
-mod dedup;
-#[allow(dead_code)]
-mod embedding;
-#[allow(dead_code)]
-mod search;
-#[allow(dead_code)]
-mod storage;
+use mcp_memory_rust::{autotag, redact, storage, summarize};
 
 const MAX_TURNS: usize = 20;
+/// Limite de arquivos por sessão
+const MAX_FILES: usize = 20;
+/// A partir de quantos turnos a sessão vira resumo extrativo (MCP_HOOK_SUMMARY=0 desliga)
+const SUMMARY_MIN_TURNS: usize = 8;
+/// Limite de chars da resposta do assistente guardada no turno
+const MAX_RESPONSE_CHARS: usize = 1000;
 
 // ---- Structs ----
 
@@ -38,6 +36,11 @@ struct HookInput {
     prompt: Option<String>,
     stop_hook_active_tools: Option<Vec<ToolInfo>>,
     transcript: Option<Vec<TranscriptMessage>>,
+    /// Stop do Claude Code: JSONL da conversa (uma entrada por linha)
+    transcript_path: Option<String>,
+    /// PreToolUse/PostToolUse: tool chamada e seus argumentos
+    tool_name: Option<String>,
+    tool_input: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,6 +62,11 @@ struct SessionData {
     cwd: String,
     tools: Vec<String>,
     files: Vec<String>,
+    /// Branch e commit curto do cwd (vazios fora de repo git ou em detached HEAD)
+    #[serde(default)]
+    branch: String,
+    #[serde(default)]
+    commit: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,11 +86,9 @@ fn session_file_path() -> PathBuf {
         .join(".current_session.json")
 }
 
+/// Mesma resolução do server (MCP_DATA_DIR / XDG_DATA_HOME / ~/.mcp-memoria)
 fn personality_db_path() -> PathBuf {
-    let home = dirs::home_dir().expect("home dir");
-    home.join(".mcp-memoria")
-        .join("data")
-        .join("personality.db")
+    storage::MemoryPaths::new().expect("data dir").personality_db
 }
 
 fn session_memory_id(session_id: &str) -> String {
@@ -121,56 +127,150 @@ fn save_session(session: &SessionData) {
 
 // ---- Extract helpers ----
 
+/// Candidato a path: tem separador (`/` ou `\`), não é URL e termina num nome
+/// com extensão plausível (1-8 alfanuméricos com ao menos uma letra)
+fn looks_like_path(w: &str) -> bool {
+    if w.len() < 4 || w.contains("://") || w.starts_with("www.") || w.contains('@') {
+        return false;
+    }
+    let Some(name) = w.rsplit(['/', '\\']).next().filter(|n| n.len() < w.len()) else {
+        return false;
+    };
+    match name.rsplit_once('.') {
+        Some((stem, ext)) => {
+            !stem.is_empty()
+                && (1..=8).contains(&ext.len())
+                && ext.chars().all(|c| c.is_ascii_alphanumeric())
+                && ext.chars().any(|c| c.is_ascii_alphabetic())
+        }
+        None => false,
+    }
+}
+
+/// Paths citados no texto: absolutos, relativos (src/main.rs, ./foo/bar.py),
+/// `~/` e Windows (`C:\dir\file.txt`). Sufixo `:linha[:coluna]` é removido;
+/// dedup sem diferenciar maiúsculas, no máximo MAX_FILES.
 fn extract_files(text: &str) -> Vec<String> {
-    let mut files = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
     for word in text.split_whitespace() {
-        let w = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '/' && c != '.' && c != '_' && c != '-');
-        // Aceita paths absolutos (/src/main.rs) e relativos (src/main.rs, ./config.yaml)
-        let is_path = (w.starts_with('/') || w.starts_with("./") || w.contains('/'))
-            && w.contains('.')
-            && w.len() > 3;
-        if is_path {
-            files.insert(w.to_string());
+        let w = word.trim_matches(|c: char| {
+            !c.is_alphanumeric() && !matches!(c, '/' | '\\' | '.' | '_' | '-' | '~' | ':')
+        });
+        let w = w.trim_end_matches(['.', ':']);
+        // src/main.rs:42:7 → src/main.rs
+        let w = match w.split_once(':') {
+            Some((path, pos)) if pos.split(':').all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit())) => path,
+            _ => w,
+        };
+        if looks_like_path(w) && seen.insert(w.to_lowercase()) {
+            files.push(w.to_string());
         }
     }
-    let mut result: Vec<String> = files.into_iter().collect();
-    result.sort();
-    result.truncate(20);
-    result
+    files.sort_by_key(|f| f.to_lowercase());
+    files.truncate(MAX_FILES);
+    files
+}
+
+/// Texto de um content de mensagem: string ou array de content blocks (só os `text`)
+fn message_text(content: &serde_json::Value) -> Option<String> {
+    let text = match content {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(arr) => arr
+            .iter()
+            .filter_map(|item| {
+                if item.get("type")?.as_str()? == "text" {
+                    item.get("text")?.as_str().map(|s| s.to_string())
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => return None,
+    };
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn truncate_response(text: &str) -> String {
+    text.chars().take(MAX_RESPONSE_CHARS).collect()
 }
 
 /// Extrai texto do último assistant message no transcript
 fn extract_assistant_response(transcript: &[TranscriptMessage]) -> Option<String> {
     // Percorre de trás pra frente buscando último assistant
-    for msg in transcript.iter().rev() {
-        if msg.role.as_deref() == Some("assistant") {
-            if let Some(content) = &msg.content {
-                let text = match content {
-                    serde_json::Value::String(s) => s.clone(),
-                    serde_json::Value::Array(arr) => {
-                        // Array de content blocks — extrair texto
-                        arr.iter()
-                            .filter_map(|item| {
-                                if item.get("type")?.as_str()? == "text" {
-                                    item.get("text")?.as_str().map(|s| s.to_string())
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect::<Vec<_>>()
-                            .join(" ")
-                    }
-                    _ => continue,
-                };
-                if !text.is_empty() {
-                    // Truncar a 1000 chars
-                    let truncated: String = text.chars().take(1000).collect();
-                    return Some(truncated);
-                }
-            }
+    transcript
+        .iter()
+        .rev()
+        .filter(|msg| msg.role.as_deref() == Some("assistant"))
+        .find_map(|msg| msg.content.as_ref().and_then(message_text))
+        .map(|text| truncate_response(&text))
+}
+
+/// Resposta do último turno a partir do JSONL de `transcript_path`. O Claude Code
+/// grava cada content block numa entrada própria, então junta os textos do
+/// assistente desde o último prompt do usuário (tool_result não conta como prompt).
+fn extract_response_from_transcript_file(path: &Path) -> Option<String> {
+    let data = std::fs::read_to_string(path).ok()?;
+    let mut parts = Vec::new();
+    for line in data.lines().rev() {
+        let Ok(entry) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        let Some(message) = entry.get("message") else {
+            continue;
+        };
+        let content = message.get("content").unwrap_or(&serde_json::Value::Null);
+        match message.get("role").and_then(|r| r.as_str()) {
+            Some("assistant") => parts.extend(message_text(content)),
+            Some("user") if message_text(content).is_some() => break,
+            _ => {}
         }
     }
-    None
+    if parts.is_empty() {
+        return None;
+    }
+    parts.reverse();
+    Some(truncate_response(&parts.join("\n")))
+}
+
+/// Branch atual e commit curto do repo em `cwd`; None fora de um repo git.
+/// Em detached HEAD o branch volta vazio.
+fn git_info(cwd: &str) -> Option<(String, String)> {
+    let git = |args: &[&str]| -> Option<String> {
+        let out = std::process::Command::new("git")
+            .arg("-C")
+            .arg(cwd)
+            .args(args)
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()?;
+        let value = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        (out.status.success() && !value.is_empty()).then_some(value)
+    };
+    if cwd.is_empty() {
+        return None;
+    }
+    let commit = git(&["rev-parse", "--short", "HEAD"])?;
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"])
+        .filter(|b| b != "HEAD")
+        .unwrap_or_default();
+    Some((branch, commit))
+}
+
+/// Redige segredos e loga só os nomes das regras que dispararam
+fn redact_secrets(text: &str, what: &str) -> String {
+    let (redacted, hits) = redact::Redactor::from_env().redact(text);
+    if !hits.is_empty() {
+        eprintln!(
+            "[Memory Hook] Redacted {} secret(s) from {}: {}",
+            hits.len(),
+            what,
+            hits.join(", ")
+        );
+    }
+    redacted
 }
 
 // ---- Build content ----
@@ -182,6 +282,12 @@ fn build_session_content(session: &SessionData) -> String {
         session.turns.len()
     )];
 
+    match (session.branch.as_str(), session.commit.as_str()) {
+        ("", "") => {}
+        ("", commit) => lines.push(format!("Git: detached @ {}", commit)),
+        (branch, commit) => lines.push(format!("Git: {} @ {}", branch, commit)),
+    }
+
     if !session.tools.is_empty() {
         let tools: Vec<&str> = session.tools.iter().take(20).map(|s| s.as_str()).collect();
         lines.push(format!("Tools: {}", tools.join(", ")));
@@ -192,6 +298,67 @@ fn build_session_content(session: &SessionData) -> String {
         lines.push(format!("Files: {}", files.join(", ")));
     }
 
+    if summary_enabled() && session.turns.len() >= SUMMARY_MIN_TURNS {
+        push_summary(session, &mut lines);
+    } else {
+        push_topics(session, &mut lines);
+    }
+
+    // Incluir última resposta do assistente se disponível
+    let assistant_turns: Vec<&Turn> = session.turns.iter()
+        .filter(|t| t.role == "assistant" && t.content.len() > 20)
+        .collect();
+    if let Some(last) = assistant_turns.last() {
+        let truncated: String = last.content.chars().take(500).collect();
+        lines.push(format!("Last response: {}", truncated));
+    }
+
+    lines.join("\n")
+}
+
+fn summary_enabled() -> bool {
+    !matches!(
+        std::env::var("MCP_HOOK_SUMMARY").as_deref().map(str::trim),
+        Ok("0" | "false" | "off")
+    )
+}
+
+/// Sessão longa: palavras-chave + prompts e respostas mais representativos (TF-IDF)
+fn push_summary(session: &SessionData, lines: &mut Vec<String>) {
+    let texts: Vec<&str> = session.turns.iter().map(|t| t.content.as_str()).collect();
+    let keywords = summarize::keywords(&texts, 10);
+    if !keywords.is_empty() {
+        lines.push(format!("Keywords: {}", keywords.join(", ")));
+    }
+
+    let by_role = |role: &str| -> Vec<&str> {
+        session.turns.iter().filter(|t| t.role == role).map(|t| t.content.as_str()).collect()
+    };
+    let prompts = by_role("user");
+    let picked = summarize::representative(&prompts, 5);
+    if !picked.is_empty() {
+        lines.push("Key topics:".to_string());
+        for i in picked {
+            let truncated: String = prompts[i].chars().take(300).collect();
+            lines.push(format!("  - {}", truncated));
+        }
+    }
+
+    // A última resposta já sai inteira logo abaixo
+    let responses = by_role("assistant");
+    let earlier = &responses[..responses.len().saturating_sub(1)];
+    let picked = summarize::representative(earlier, 2);
+    if !picked.is_empty() {
+        lines.push("Key points:".to_string());
+        for i in picked {
+            let truncated: String = earlier[i].chars().take(300).collect();
+            lines.push(format!("  - {}", truncated));
+        }
+    }
+}
+
+/// Sessão curta (ou resumo desligado): prompts do usuário, deduplicados
+fn push_topics(session: &SessionData, lines: &mut Vec<String>) {
     // User prompts (deduplicados)
     let mut seen = HashSet::new();
     let mut topics = Vec::new();
@@ -211,17 +378,6 @@ fn build_session_content(session: &SessionData) -> String {
             lines.push(format!("  - {}", t));
         }
     }
-
-    // Incluir última resposta do assistente se disponível
-    let assistant_turns: Vec<&Turn> = session.turns.iter()
-        .filter(|t| t.role == "assistant" && t.content.len() > 20)
-        .collect();
-    if let Some(last) = assistant_turns.last() {
-        let truncated: String = last.content.chars().take(500).collect();
-        lines.push(format!("Last response: {}", truncated));
-    }
-
-    lines.join("\n")
 }
 
 // ---- DB save ----
@@ -241,18 +397,23 @@ fn upsert_session_to_db(db_path: &Path, mem_id: &str, content: &str, tags: &str)
         )
         .unwrap_or(false);
 
+    // Conversas longas vão comprimidas acima de MCP_COMPRESS_THRESHOLD
+    let (stored, zstd) = storage::content_columns(content);
+    // Prazo conta da última atividade da sessão (MCP_CONVERSATION_TTL_DAYS)
+    let expires_at = storage::expires_at_after(storage::conversation_ttl_from_env());
     if exists {
         conn.execute(
-            "UPDATE memories SET content = ?, tags = ?, \
-             updated_at = datetime('now'), embedding = NULL WHERE id = ?",
-            rusqlite::params![content, tags, mem_id],
+            "UPDATE memories SET content = ?, content_zstd = ?, tags = ?, \
+             updated_at = datetime('now'), embedding = NULL, expires_at = ? WHERE id = ?",
+            rusqlite::params![stored, zstd, tags, expires_at, mem_id],
         )
         .is_ok()
+            && storage::clear_model_embeddings(&conn, mem_id).is_ok()
     } else {
         conn.execute(
-            "INSERT INTO memories (id, type, content, tags, importance) \
-             VALUES (?, 'conversation', ?, ?, 0.3)",
-            rusqlite::params![mem_id, content, tags],
+            "INSERT INTO memories (id, type, content, content_zstd, tags, importance, expires_at) \
+             VALUES (?, 'conversation', ?, ?, ?, 0.3, ?)",
+            rusqlite::params![mem_id, stored, zstd, tags, expires_at],
         )
         .is_ok()
     }
@@ -265,10 +426,18 @@ fn save_to_db(session: &SessionData) -> Option<String> {
 
     let mem_id = session_memory_id(&session.session_id);
     let content = build_session_content(session);
+    // Mesmo limite dos saves pelas tools (MCP_MAX_CONTENT_LEN); aqui não há a quem recusar
+    let content = storage::truncate_content(&content, storage::max_content_len_from_env()).to_string();
 
     // Auto-tag do conteúdo da sessão
     let auto_tags = autotag::extract_tags(&content);
-    let base_tags = format!("conversation,claude-code,{},auto-saved", session.project);
+    let mut base_tags = format!("conversation,claude-code,{},auto-saved", session.project);
+    if !session.branch.is_empty() {
+        base_tags.push_str(&format!(",branch:{}", session.branch));
+    }
+    if !session.commit.is_empty() {
+        base_tags.push_str(&format!(",commit:{}", session.commit));
+    }
     let tags = autotag::merge_tags(&base_tags, &auto_tags);
 
     // 1. Salva no personality.db (sempre)
@@ -288,35 +457,93 @@ fn save_to_db(session: &SessionData) -> Option<String> {
 
 // ---- Event handlers ----
 
-fn handle_user_prompt(input: &HookInput) {
+/// Sessão atual; um session_id diferente do salvo começa uma nova
+fn load_session_for(input: &HookInput) -> SessionData {
     let session_id = input.session_id.as_deref().unwrap_or("unknown");
+    let session = load_session();
+    if session.session_id == session_id {
+        return session;
+    }
     let cwd = input.cwd.as_deref().unwrap_or("");
     let project = std::path::Path::new(cwd)
         .file_name()
         .map(|n| n.to_string_lossy().to_string())
         .unwrap_or_else(|| "no-project".to_string());
+    SessionData {
+        session_id: session_id.to_string(),
+        project,
+        cwd: cwd.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Adiciona arquivo à sessão (dedup sem diferenciar maiúsculas, até MAX_FILES)
+fn add_file(session: &mut SessionData, file: String) {
+    if session.files.len() < MAX_FILES
+        && !session.files.iter().any(|known| known.eq_ignore_ascii_case(&file))
+    {
+        session.files.push(file);
+    }
+}
+
+/// Argumentos de tools que apontam diretamente para um arquivo (Edit, Write, Read...)
+const TOOL_PATH_KEYS: &[&str] = &["file_path", "notebook_path", "path"];
+/// Argumentos de texto livre onde paths podem aparecer (Bash, Grep...)
+const TOOL_TEXT_KEYS: &[&str] = &["command", "pattern", "glob"];
+
+/// Registra tool e arquivos de um PreToolUse/PostToolUse na sessão
+fn record_tool_use(session: &mut SessionData, input: &HookInput) {
+    if let Some(name) = input.tool_name.as_deref().filter(|n| !n.is_empty()) {
+        if !session.tools.iter().any(|t| t == name) {
+            session.tools.push(name.to_string());
+        }
+    }
+    let Some(args) = input.tool_input.as_ref() else {
+        return;
+    };
+    for key in TOOL_PATH_KEYS {
+        if let Some(path) = args.get(*key).and_then(|v| v.as_str()).map(str::trim) {
+            if !path.is_empty() {
+                add_file(session, path.to_string());
+            }
+        }
+    }
+    for key in TOOL_TEXT_KEYS {
+        if let Some(text) = args.get(*key).and_then(|v| v.as_str()) {
+            for f in extract_files(text) {
+                add_file(session, f);
+            }
+        }
+    }
+}
+
+fn handle_tool_use(input: &HookInput) {
+    let mut session = load_session_for(input);
+    let (tools, files) = (session.tools.len(), session.files.len());
+    record_tool_use(&mut session, input);
+    // Só o arquivo de sessão: o DB é atualizado no próximo prompt ou Stop
+    if session.tools.len() != tools || session.files.len() != files {
+        save_session(&session);
+    }
+}
+
+fn handle_user_prompt(input: &HookInput) {
+    let cwd = input.cwd.as_deref().unwrap_or("");
     let prompt = match &input.prompt {
-        Some(p) if !p.is_empty() => p,
+        Some(p) if !p.is_empty() => redact_secrets(p, "prompt"),
         _ => return,
     };
 
-    let mut session = load_session();
+    let mut session = load_session_for(input);
 
-    // Reset se nova sessão
-    if session.session_id != session_id {
-        session = SessionData {
-            session_id: session_id.to_string(),
-            project: project.clone(),
-            cwd: cwd.to_string(),
-            ..Default::default()
-        };
-    }
+    // Branch/commit podem mudar no meio da sessão: vale o do último prompt
+    let (branch, commit) = git_info(cwd).unwrap_or_default();
+    session.branch = branch;
+    session.commit = commit;
 
     // Extrai files
-    for f in extract_files(prompt) {
-        if !session.files.contains(&f) {
-            session.files.push(f);
-        }
+    for f in extract_files(&prompt) {
+        add_file(&mut session, f);
     }
 
     // Adiciona turno
@@ -358,16 +585,21 @@ fn handle_stop(input: &HookInput) {
         }
     }
 
-    // Extrair resposta do assistente do transcript
-    let assistant_content = if let Some(transcript) = &input.transcript {
-        extract_assistant_response(transcript)
-    } else {
-        None
-    };
+    // Extrair resposta do assistente: transcript inline ou arquivo do Claude Code
+    let assistant_content = input
+        .transcript
+        .as_deref()
+        .and_then(extract_assistant_response)
+        .or_else(|| {
+            input
+                .transcript_path
+                .as_deref()
+                .and_then(|p| extract_response_from_transcript_file(Path::new(p)))
+        });
 
     // Turno do assistente — agora com conteúdo real se disponível
     let content = if let Some(response) = &assistant_content {
-        response.clone()
+        redact_secrets(response, "response")
     } else {
         let tool_names: Vec<String> = input
             .stop_hook_active_tools
@@ -402,7 +634,7 @@ fn handle_stop(input: &HookInput) {
         "[Memory Hook] Updated session memory {} ({} turns, transcript: {})",
         mem_id.unwrap_or_else(|| "none".into()),
         session.turns.len(),
-        if input.transcript.is_some() { "yes" } else { "no" }
+        if assistant_content.is_some() { "yes" } else { "no" }
     );
 }
 
@@ -426,7 +658,122 @@ fn main() {
 
     match hook_data.hook_event_name.as_deref() {
         Some("UserPromptSubmit") => handle_user_prompt(&hook_data),
+        Some("PreToolUse" | "PostToolUse") => handle_tool_use(&hook_data),
         Some("Stop") => handle_stop(&hook_data),
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_git_info_in_session() {
+        assert!(git_info("").is_none());
+        assert!(git_info("/nonexistent/mcp-memory-dir").is_none());
+
+        let dir = std::env::temp_dir().join(format!("mcp-memory-git-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let git = |args: &[&str]| {
+            std::process::Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(["-c", "user.name=t", "-c", "user.email=t@t", "-c", "commit.gpgsign=false"])
+                .args(args)
+                .output()
+                .is_ok_and(|o| o.status.success())
+        };
+        if git(&["init", "-q", "-b", "feature/x"]) && git(&["commit", "-q", "--allow-empty", "-m", "init"]) {
+            let (branch, commit) = git_info(&dir.to_string_lossy()).unwrap();
+            assert_eq!(branch, "feature/x");
+            assert!(commit.len() >= 7);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut session = SessionData { project: "demo".into(), ..Default::default() };
+        assert!(!build_session_content(&session).contains("Git:"));
+        session.branch = "feature/x".into();
+        session.commit = "abc1234".into();
+        assert!(build_session_content(&session).contains("Git: feature/x @ abc1234"));
+    }
+
+    #[test]
+    fn test_long_session_is_summarized() {
+        let turn = |role: &str, content: &str| Turn {
+            role: role.into(),
+            content: content.into(),
+            timestamp: String::new(),
+        };
+        let mut session = SessionData { project: "demo".into(), ..Default::default() };
+        for i in 0..SUMMARY_MIN_TURNS / 2 {
+            session.turns.push(turn("user", "ok"));
+            session.turns.push(turn("assistant", &format!("done with step {}", i)));
+        }
+        session.turns[2] = turn("user", "migrate the sqlite schema to add a metadata column with json validation");
+        let content = build_session_content(&session);
+        assert!(content.contains("Keywords: "), "{}", content);
+        assert!(content.contains("Key topics:\n  - migrate the sqlite schema"), "{}", content);
+        assert!(!content.contains("Topics:\n"));
+    }
+
+    #[test]
+    fn test_record_tool_use() {
+        let event = |json: &str| -> HookInput { serde_json::from_str(json).unwrap() };
+        let mut session = SessionData::default();
+        for json in [
+            r#"{"hook_event_name":"PreToolUse","tool_name":"Edit","tool_input":{"file_path":"/repo/src/main.rs","old_string":"a"}}"#,
+            r#"{"hook_event_name":"PostToolUse","tool_name":"Edit","tool_input":{"file_path":"/repo/src/main.rs"},"tool_response":{}}"#,
+            r#"{"hook_event_name":"PostToolUse","tool_name":"Bash","tool_input":{"command":"cargo test --manifest-path ./crates/core/Cargo.toml"}}"#,
+            r#"{"hook_event_name":"PostToolUse","tool_name":"WebFetch","tool_input":{"url":"https://example.com/a.html"}}"#,
+        ] {
+            record_tool_use(&mut session, &event(json));
+        }
+        assert_eq!(session.tools, ["Edit", "Bash", "WebFetch"]);
+        assert_eq!(session.files, ["/repo/src/main.rs", "./crates/core/Cargo.toml"]);
+    }
+
+    #[test]
+    fn test_extract_files() {
+        let files = extract_files(
+            "check src/main.rs and ./foo/bar.py, then `~/notes/todo.md` (also SRC/Main.rs) \
+             plus C:\\Users\\me\\config.toml and src/hook.rs:42:7 — see https://example.com/docs/page.html \
+             or www.example.com/a.js; and/or 1/2.5",
+        );
+        assert_eq!(
+            files,
+            ["./foo/bar.py", "C:\\Users\\me\\config.toml", "src/hook.rs", "src/main.rs", "~/notes/todo.md"]
+        );
+
+        let many: String = (0..30).map(|i| format!("dir/file{}.rs ", i)).collect();
+        assert_eq!(extract_files(&many).len(), MAX_FILES);
+    }
+
+    #[test]
+    fn test_response_from_transcript_file() {
+        let path = std::env::temp_dir().join(format!("mcp-memory-transcript-{}.jsonl", std::process::id()));
+        let lines = [
+            r#"{"type":"user","message":{"role":"user","content":"old question"}}"#,
+            r#"{"type":"assistant","message":{"role":"assistant","content":[{"type":"text","text":"old answer"}]}}"#,
+            r#"{"type":"user","message":{"role":"user","content":[{"type":"text","text":"why is the build failing?"}]}}"#,
+            r#"{"type":"assistant","message":{"role":"assistant","content":[{"type":"text","text":"Let me check."}]}}"#,
+            r#"{"type":"assistant","message":{"role":"assistant","content":[{"type":"tool_use","name":"Bash","input":{}}]}}"#,
+            r#"{"type":"user","message":{"role":"user","content":[{"type":"tool_result","content":"error"}]}}"#,
+            r#"{"type":"assistant","message":{"role":"assistant","content":[{"type":"text","text":"The lockfile is stale."}]}}"#,
+            "not json",
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let response = extract_response_from_transcript_file(&path).unwrap();
+        assert_eq!(response, "Let me check.\nThe lockfile is stale.");
+
+        std::fs::write(&path, format!(
+            r#"{{"message":{{"role":"assistant","content":"{}"}}}}"#,
+            "x".repeat(MAX_RESPONSE_CHARS + 50)
+        ))
+        .unwrap();
+        assert_eq!(extract_response_from_transcript_file(&path).unwrap().chars().count(), MAX_RESPONSE_CHARS);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,37 @@
+//! Motor de memória do MCP server como biblioteca.
+//!
+//! Os binários (`mcp-memory-rust` e `mcp-memory-hook`) são wrappers finos sobre
+//! estes módulos; outros programas podem usar o mesmo storage e a mesma busca.
+//!
+//! Contratos principais:
+//! - [`storage::init_db`] abre/cria um DB com schema e migrações aplicados;
+//!   [`storage::save_memory`] grava com auto-tags e dedup.
+//! - Embeddings gravados são normalizados (L2 = 1): [`search::dot_similarity`]
+//!   é o cosine para eles; [`search::cosine_similarity`] serve para vetores quaisquer.
+//! - [`search::search_hybrid`] funde FTS5 (BM25) e embedding; sem embedding da
+//!   query, só texto.
+//! - [`chunking::chunk_text`] divide por palavras com overlap (paridade com o Python).
+//! - [`embedding::EmbeddingEngine`] carrega o modelo fastembed sob demanda.
+//!
+//! ```no_run
+//! use mcp_memory_rust::{search, storage};
+//!
+//! let conn = storage::init_db(std::path::Path::new("/tmp/memories.db"))?;
+//! storage::save_memory(&conn, "decision", "Use WAL mode for SQLite", "sqlite", &Default::default())?;
+//! let hits = search::search_fts(&conn, "sqlite", 5, &Default::default());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod ann;
+pub mod autotag;
+pub mod chunking;
+pub mod compress;
+pub mod consolidation;
+pub mod dedup;
+pub mod embedding;
+pub mod preprocess;
+pub mod redact;
+pub mod search;
+pub mod simd;
+pub mod storage;
+pub mod summarize;
@@ -1,27 +1,33 @@
-mod autotag;
-mod chunking;
-mod consolidation;
-mod dedup;
-mod embedding;
-mod search;
-mod storage;
-
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::Result;
 use rmcp::{
     ErrorData as McpError, ServerHandler, ServiceExt,
-    handler::server::{router::tool::ToolRouter, wrapper::Parameters},
-    model::{CallToolResult, Content, ServerCapabilities, ServerInfo},
-    schemars, tool, tool_handler, tool_router,
-    transport::stdio,
+    handler::server::{
+        router::{prompt::PromptRouter, tool::ToolRouter},
+        wrapper::Parameters,
+    },
+    handler::server::tool::ToolCallContext,
+    model::{
+        CallToolRequestParams, CallToolResult, Content, GetPromptRequestParams, GetPromptResult,
+        ListPromptsResult, ListToolsResult, PaginatedRequestParams, PromptMessage, PromptMessageRole,
+        ServerCapabilities, ServerInfo, Tool,
+    },
+    prompt, prompt_handler, prompt_router, schemars,
+    service::RequestContext,
+    tool, tool_router, RoleServer,
+    transport::{
+        StreamableHttpServerConfig, StreamableHttpService, stdio,
+        streamable_http_server::session::local::LocalSessionManager,
+    },
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use tracing::info;
+use tracing::{debug, info, Instrument};
 
-use embedding::{EmbeddingEngine, EmbeddingJob};
+use mcp_memory_rust::{ann, autotag, consolidation, dedup, embedding, search, storage};
+use embedding::{EmbeddingEngine, EmbeddingJob, JobProgress};
 use storage::MemoryPaths;
 
 // ---- Tool Parameter Structs ----
@@ -44,24 +50,162 @@ pub struct SaveParams {
     #[schemars(description = "Project name (auto-detected if not provided)")]
     #[serde(default)]
     pub project_name: String,
+    #[schemars(description = "Dedup similarity threshold (optional, default 0.85). 1.0 = exact match only")]
+    #[serde(default)]
+    pub dedup_threshold: Option<f64>,
+    #[schemars(description = "Structured metadata as a JSON object, e.g. {\"source\": \"https://...\", \"confidence\": 0.9} (optional)")]
+    #[serde(default)]
+    pub metadata: Option<String>,
+    #[schemars(description = "Also check other scopes for a near-duplicate; if one exists, report it instead of saving a second copy (default false)")]
+    #[serde(default)]
+    pub cross_scope: bool,
+    #[schemars(description = "Comma-separated scopes checked by cross_scope (default: every scope other than the target)")]
+    #[serde(default)]
+    pub check_scopes: Option<String>,
+    #[schemars(description = "On duplicate: replace (default) overwrites it, merge appends new lines and unions tags, keep leaves it untouched")]
+    #[serde(default)]
+    pub dedup_strategy: Option<String>,
+    #[schemars(description = "Output format: markdown (default) or json (the stored record: id, content, tags, created_at, updated_at)")]
+    #[serde(default)]
+    pub format: Option<String>,
+    #[schemars(description = "Client key for exactly-once saves (optional): the memory ID is derived from it instead of from content + time, so a retry with the same key updates that memory (per dedup_strategy) instead of inserting a new one")]
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    #[schemars(description = "Expire the memory after this many days (optional, default never). Expired memories drop out of search and list right away and are deleted by memory_compact")]
+    #[serde(default)]
+    pub ttl_days: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SaveBatchItem {
+    #[schemars(description = "What to save")]
+    pub content: String,
+    #[schemars(
+        description = "Type: decision, pattern, preference, architecture, implementation, solution, todo, note"
+    )]
+    #[serde(default = "default_type")]
+    pub r#type: String,
+    #[schemars(description = "Comma-separated tags")]
+    #[serde(default)]
+    pub tags: String,
+    #[schemars(description = "Structured metadata as a JSON object (optional)")]
+    #[serde(default)]
+    pub metadata: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SaveBatchParams {
+    #[schemars(description = "Memories to save (max 100)")]
+    pub items: Vec<SaveBatchItem>,
+    #[schemars(description = "Scope for all items: global, project, personality")]
+    #[serde(default = "default_scope_project")]
+    pub scope: String,
+    #[schemars(description = "Project name (auto-detected if not provided)")]
+    #[serde(default)]
+    pub project_name: String,
+    #[schemars(description = "Dedup similarity threshold (optional, default 0.85). 1.0 = exact match only")]
+    #[serde(default)]
+    pub dedup_threshold: Option<f64>,
+    #[schemars(description = "On duplicate: replace (default) overwrites it, merge appends new lines and unions tags, keep leaves it untouched")]
+    #[serde(default)]
+    pub dedup_strategy: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct SearchParams {
-    #[schemars(description = "Search term")]
+    #[schemars(description = "Search term. Supports \"exact phrases\" and AND / OR / NOT (uppercase); plain words are OR-ed. Ignored when regex is set")]
+    #[serde(default)]
     pub query: String,
+    #[schemars(description = "Rust regex matched against content instead of ranked search, for identifiers FTS splits (e.g. foo::bar, UUIDs). Results newest first (optional)")]
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[schemars(description = "With regex, also match it against tags (default false)")]
+    #[serde(default)]
+    pub regex_tags: bool,
     #[schemars(description = "Scope: global, project, personality, both, all")]
     #[serde(default = "default_scope_both")]
     pub scope: String,
     #[schemars(description = "Max results")]
     #[serde(default = "default_limit_5")]
     pub limit: usize,
+    #[schemars(description = "Filter by type (optional)")]
+    #[serde(default)]
+    pub r#type: Option<String>,
+    #[schemars(description = "Filter by comma-separated tags, all must match (optional)")]
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[schemars(description = "Only memories created on/after this date (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS, optional)")]
+    #[serde(default)]
+    pub since: Option<String>,
+    #[schemars(description = "Only memories created on/before this date; a date-only value includes the whole day (optional)")]
+    #[serde(default)]
+    pub until: Option<String>,
+    #[schemars(description = "Minimum relevance (optional). Results scoring below it are dropped")]
+    #[serde(default)]
+    pub min_relevance: Option<f64>,
+    #[schemars(description = "Favor recent memories via temporal decay (default true). With false, relevance is the raw weighted cosine/BM25 score, comparable regardless of age")]
+    #[serde(default)]
+    pub recency: Option<bool>,
+    #[schemars(description = "Diversity re-ranking (MMR lambda, 0..1, optional): lower values push near-duplicate results down; 1.0 is pure relevance")]
+    #[serde(default)]
+    pub diversity: Option<f64>,
+    #[schemars(description = "Return a short snippet around the matched terms (or the best-matching chunk) instead of the full content (default false)")]
+    #[serde(default)]
+    pub snippet: Option<bool>,
+    #[schemars(description = "Also list memories linked to each result (default false)")]
+    #[serde(default)]
+    pub include_links: bool,
+    #[schemars(description = "Filter on metadata keys as a JSON object of exact values, e.g. {\"author\": \"ana\"} (optional)")]
+    #[serde(default)]
+    pub metadata_filter: Option<String>,
+    #[schemars(description = "Output format: markdown (default) or json")]
+    #[serde(default)]
+    pub format: Option<String>,
+    #[schemars(description = "Search the project DB of this directory instead of the current one (optional)")]
+    #[serde(default)]
+    pub project_path: Option<String>,
+    #[schemars(description = "Embedding model to search against: the active one (default) or one listed in MCP_EMBEDDING_EXTRA_MODELS, to compare recall between models")]
+    #[serde(default)]
+    pub model: Option<String>,
+    #[schemars(description = "Show how each score was built: raw and normalized BM25, cosine, importance, fusion result, age and decay multiplier, scope weight (default false)")]
+    #[serde(default)]
+    pub explain: bool,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct ContextParams {
     #[schemars(description = "Current context or user question")]
     pub query: String,
+    #[schemars(description = "Scope: global, project, personality, both (default), all. 'all' adds personality memories from other projects")]
+    #[serde(default = "default_scope_both")]
+    pub scope: String,
+    #[schemars(description = "Max results (default 8)")]
+    #[serde(default = "default_limit_8")]
+    pub limit: usize,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CountParams {
+    #[schemars(description = "What to check for, e.g. the gist of a memory about to be saved")]
+    pub query: String,
+    #[schemars(description = "Scope: global, project, personality, both, all")]
+    #[serde(default = "default_scope_both")]
+    pub scope: String,
+    #[schemars(description = "Only count results scoring at least this relevance (optional; without it every search hit counts)")]
+    #[serde(default)]
+    pub min_relevance: Option<f64>,
+    #[schemars(description = "Max results considered (default 10)")]
+    #[serde(default = "default_limit_10")]
+    pub limit: usize,
+    #[schemars(description = "Filter by type (optional)")]
+    #[serde(default)]
+    pub r#type: Option<String>,
+    #[schemars(description = "Filter by comma-separated tags, all must match (optional)")]
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[schemars(description = "Output format: markdown (default) or json")]
+    #[serde(default)]
+    pub format: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -69,16 +213,51 @@ pub struct ListParams {
     #[schemars(description = "Filter by type (optional)")]
     #[serde(default)]
     pub r#type: Option<String>,
+    #[schemars(description = "Filter by comma-separated tags, all must match (optional)")]
+    #[serde(default)]
+    pub tags: Option<String>,
     #[schemars(description = "Scope: global, project, personality, both, all")]
     #[serde(default = "default_scope_both")]
     pub scope: String,
     #[schemars(description = "Max results")]
     #[serde(default = "default_limit_10")]
     pub limit: usize,
+    #[schemars(description = "Only memories created on/after this date (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS, optional)")]
+    #[serde(default)]
+    pub since: Option<String>,
+    #[schemars(description = "Only memories created on/before this date; a date-only value includes the whole day (optional)")]
+    #[serde(default)]
+    pub until: Option<String>,
+    #[schemars(description = "Skip this many results (pagination, newest first)")]
+    #[serde(default)]
+    pub offset: Option<usize>,
+    #[schemars(description = "Output format: markdown (default) or json")]
+    #[serde(default)]
+    pub format: Option<String>,
+    #[schemars(description = "List the project DB of this directory instead of the current one (optional)")]
+    #[serde(default)]
+    pub project_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct TagsParams {
+    #[schemars(description = "Scope: global, project, personality, both, all")]
+    #[serde(default = "default_scope_all")]
+    pub scope: String,
+    #[schemars(description = "Max tags to return (default 100)")]
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[schemars(description = "Output format: markdown (default) or json")]
+    #[serde(default)]
+    pub format: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct StatsParams {}
+pub struct StatsParams {
+    #[schemars(description = "Output format: markdown (default) or json")]
+    #[serde(default)]
+    pub format: Option<String>,
+}
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct DeleteParams {
@@ -87,6 +266,89 @@ pub struct DeleteParams {
     #[schemars(description = "Scope: global, project, personality")]
     #[serde(default = "default_scope_project")]
     pub scope: String,
+    #[schemars(description = "Delete permanently instead of moving to the trash (default false)")]
+    #[serde(default)]
+    pub purge: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DeleteBulkParams {
+    #[schemars(description = "Scope: global, project, personality")]
+    #[serde(default = "default_scope_project")]
+    pub scope: String,
+    #[schemars(description = "Delete memories of this type (optional, but type or tags is required)")]
+    #[serde(default)]
+    pub r#type: Option<String>,
+    #[schemars(description = "Delete memories having all these comma-separated tags (optional)")]
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[schemars(description = "Must be true to actually delete; otherwise only reports how many would be deleted")]
+    #[serde(default)]
+    pub confirm: bool,
+    #[schemars(description = "Delete permanently instead of moving to the trash (default false)")]
+    #[serde(default)]
+    pub purge: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RestoreParams {
+    #[schemars(description = "Memory ID to restore from the trash")]
+    pub id: String,
+    #[schemars(description = "Scope: global, project, personality")]
+    #[serde(default = "default_scope_project")]
+    pub scope: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetParams {
+    #[schemars(description = "Memory ID")]
+    pub id: String,
+    #[schemars(description = "Scope: global, project, personality, both, all")]
+    #[serde(default = "default_scope_all")]
+    pub scope: String,
+    #[schemars(description = "List memories linked to this one (default true)")]
+    #[serde(default = "default_true")]
+    pub include_links: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RelatedParams {
+    #[schemars(description = "ID of the memory to find related memories for")]
+    pub id: String,
+    #[schemars(description = "Scope to search in: global, project, personality, both, all")]
+    #[serde(default = "default_scope_all")]
+    pub scope: String,
+    #[schemars(description = "Max results")]
+    #[serde(default = "default_limit_5")]
+    pub limit: usize,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct UpdateParams {
+    #[schemars(description = "Memory ID to update")]
+    pub id: String,
+    #[schemars(description = "New content (optional). Re-queues the embedding")]
+    #[serde(default)]
+    pub content: Option<String>,
+    #[schemars(description = "New comma-separated tags (optional). Replaces existing tags")]
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[schemars(description = "Scope: global, project, personality")]
+    #[serde(default = "default_scope_project")]
+    pub scope: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ExportParams {
+    #[schemars(description = "Scope: global, project, personality, both, all")]
+    #[serde(default = "default_scope_all")]
+    pub scope: String,
+    #[schemars(description = "Output format: json (default) or markdown")]
+    #[serde(default)]
+    pub format: Option<String>,
+    #[schemars(description = "Write the export to this file instead of returning it (optional)")]
+    #[serde(default)]
+    pub output_path: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -96,11 +358,21 @@ pub struct ReindexParams {
     pub scope: String,
 }
 
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DiagnosticsParams {
+    #[schemars(description = "Run the model self-test even if the model is not loaded yet; loading can take seconds (default false: skip it)")]
+    #[serde(default)]
+    pub load_model: bool,
+}
+
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct CompactParams {
     #[schemars(description = "Scope: personality, project, global")]
     #[serde(default = "default_scope_personality")]
     pub scope: String,
+    #[schemars(description = "Only report what would change (TTL, decay, cache pruning, space reclaimed, conversation groups memory_consolidate would merge); nothing is modified (default false)")]
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -110,13 +382,33 @@ pub struct ConsolidateParams {
     pub scope: String,
 }
 
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct VerifyParams {
+    #[schemars(description = "Scope: personality, project, global, both, all (default all)")]
+    #[serde(default = "default_scope_all")]
+    pub scope: String,
+    #[schemars(description = "Rebuild the FTS index of scopes that drifted (default false: only report)")]
+    #[serde(default)]
+    pub repair: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct MoveParams {
+    #[schemars(description = "Memory ID to move")]
+    pub id: String,
+    #[schemars(description = "Current scope: global, project, personality")]
+    pub from_scope: String,
+    #[schemars(description = "Destination scope: global, project, personality")]
+    pub to_scope: String,
+}
+
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct LinkParams {
     #[schemars(description = "Source memory ID")]
     pub from_id: String,
     #[schemars(description = "Target memory ID")]
     pub to_id: String,
-    #[schemars(description = "Relation: relates_to, supersedes, derived_from")]
+    #[schemars(description = "Relation: relates_to, supersedes, derived_from, implements")]
     #[serde(default = "default_relation")]
     pub relation: String,
     #[schemars(description = "Scope of the memories")]
@@ -124,6 +416,24 @@ pub struct LinkParams {
     pub scope: String,
 }
 
+// ---- Prompt Argument Structs ----
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RecallPromptArgs {
+    #[schemars(description = "What to recall (default: architecture, decisions, patterns and conventions)")]
+    pub topic: Option<String>,
+    #[schemars(description = "Absolute path of the project directory (default: the server's working directory)")]
+    pub project_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DecisionsPromptArgs {
+    #[schemars(description = "Restrict to decisions about this topic (default: all)")]
+    pub topic: Option<String>,
+    #[schemars(description = "Scope: project, personality, global, both, all (default: all)")]
+    pub scope: Option<String>,
+}
+
 // ---- Defaults ----
 fn default_type() -> String { "note".into() }
 fn default_scope_project() -> String { "project".into() }
@@ -131,27 +441,151 @@ fn default_scope_both() -> String { "both".into() }
 fn default_scope_all() -> String { "all".into() }
 fn default_scope_personality() -> String { "personality".into() }
 fn default_limit_5() -> usize { 5 }
+fn default_limit_8() -> usize { 8 }
 fn default_limit_10() -> usize { 10 }
 fn default_relation() -> String { "relates_to".into() }
+fn default_true() -> bool { true }
 
-// ---- Scope weights for cross-scope merge ----
-fn scope_weight(scope: &str) -> f64 {
-    match scope {
-        "project" => 1.0,
-        "personality" => 0.85,
-        "global" => 0.7,
-        _ => 0.8,
+/// `format = "json"` pede saída estruturada; ausente ou qualquer outro valor mantém markdown
+fn wants_json(format: Option<&str>) -> bool {
+    matches!(format, Some(f) if f.eq_ignore_ascii_case("json"))
+}
+
+/// Item de resultado com o scope de origem (JSON output)
+#[derive(Serialize)]
+struct Scoped<'a, T: Serialize> {
+    scope: &'a str,
+    #[serde(flatten)]
+    item: &'a T,
+}
+
+/// `api (12), backend (5)`
+fn format_tag_counts(tags: &[(String, i64)]) -> String {
+    tags.iter()
+        .map(|(tag, n)| format!("{} ({})", tag, n))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Links de uma memória em markdown: `→` para saída, `←` para entrada
+fn format_links(links: &[storage::LinkedMemory]) -> String {
+    let mut out = String::new();
+    for l in links {
+        let arrow = if l.direction == "outgoing" { "→" } else { "←" };
+        let preview: String = l.content.chars().take(80).collect();
+        out.push_str(&format!(
+            "- {} {} `{}` [{}] {}\n",
+            arrow, l.relation, l.id, l.mem_type, preview
+        ));
+    }
+    out
+}
+
+/// Ajustes por chamada de `do_search_parallel`
+#[derive(Debug, Clone, Copy)]
+struct SearchOptions {
+    /// Descarta resultados abaixo deste score
+    min_relevance: Option<f64>,
+    /// Temporal decay no merge
+    recency: bool,
+    /// Lambda do MMR (1.0 = só relevância, sem re-ranking)
+    diversity: Option<f64>,
+    /// Preenche `snippet`/`truncated` dos resultados
+    snippet: bool,
+    /// Modelo extra para o lado embedding (None = principal)
+    model: Option<&'static str>,
+    /// Mantém a decomposição do score (`SearchResult::explain`)
+    explain: bool,
+    /// Conta acesso dos resultados devolvidos (recall do agente, não contagens/listagens)
+    track_access: bool,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            min_relevance: None,
+            recency: true,
+            diversity: None,
+            snippet: false,
+            model: None,
+            explain: false,
+            track_access: false,
+        }
     }
 }
 
 // ---- MCP Server ----
 
+tokio::task_local! {
+    /// Id curto da chamada de tool em andamento; vai nos jobs de embedding que ela gera
+    static REQUEST_ID: String;
+}
+
+/// Ids sequenciais por processo: curtos e suficientes para casar linhas do mesmo log
+fn next_request_id() -> String {
+    static NEXT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+    format!("{:06x}", NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+}
+
 #[derive(Clone)]
 pub struct MemoryServer {
     paths: Arc<MemoryPaths>,
     embedding_engine: Arc<EmbeddingEngine>,
     job_sender: mpsc::Sender<EmbeddingJob>,
+    progress: Arc<JobProgress>,
+    ann: Arc<ann::AnnRegistry>,
+    /// Conexões reaproveitadas entre chamadas (schema só na primeira abertura)
+    db: Arc<storage::ConnectionPool>,
+    scoring: search::ScoringConfig,
+    /// Buscas do agente contam acesso (access_count/importance); a CLI não
+    track_access: bool,
     tool_router: ToolRouter<Self>,
+    prompt_router: PromptRouter<Self>,
+}
+
+/// Scope canônico (`storage::canonical_scope`) ou a resposta de erro da tool
+fn scope_or_error(scope: &str) -> Result<&'static str, CallToolResult> {
+    storage::canonical_scope(scope)
+        .map_err(|e| CallToolResult::success(vec![Content::text(format!("Error: {}.", e))]))
+}
+
+/// Scope de uma escrita: vai para um DB só, então both/all são recusados em vez
+/// de cair em personality sem aviso
+fn write_scope_or_error(scope: &str) -> Result<&'static str, CallToolResult> {
+    match scope_or_error(scope)? {
+        "both" | "all" => Err(CallToolResult::success(vec![Content::text(format!(
+            "Error: scope '{}' reads several databases; writes need global, project, or personality.",
+            scope.trim()
+        ))])),
+        canonical => Ok(canonical),
+    }
+}
+
+/// Para personality scope, adiciona project name nas tags
+fn tags_with_project(scope: &str, tags: &str, project_name: &str) -> String {
+    if scope != "personality" {
+        return tags.to_string();
+    }
+    let project_name = if project_name.is_empty() {
+        std::env::var("MCP_PROJECT_DIR")
+            .or_else(|_| std::env::var("CLAUDE_CWD"))
+            .ok()
+            .and_then(|p| {
+                std::path::Path::new(&p)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+            })
+            .unwrap_or_else(|| "no-project".to_string())
+    } else {
+        project_name.to_string()
+    };
+    if project_name.is_empty() || autotag::contains_tag(tags, &project_name) {
+        tags.to_string()
+    } else if tags.is_empty() {
+        project_name
+    } else {
+        format!("{},{}", tags, project_name)
+    }
 }
 
 #[tool_router]
@@ -160,33 +594,53 @@ impl MemoryServer {
         paths: MemoryPaths,
         engine: Arc<EmbeddingEngine>,
         job_sender: mpsc::Sender<EmbeddingJob>,
+        progress: Arc<JobProgress>,
+        ann: Arc<ann::AnnRegistry>,
     ) -> Self {
         Self {
             paths: Arc::new(paths),
             embedding_engine: engine,
             job_sender,
+            progress,
+            ann,
+            db: Arc::new(storage::ConnectionPool::default()),
+            scoring: search::ScoringConfig::from_env(),
+            track_access: true,
             tool_router: Self::tool_router(),
+            prompt_router: Self::prompt_router(),
         }
     }
 
-    /// Cross-scope parallel search com tokio::join! e scope weights
+    /// Cross-scope parallel search com tokio::join! e scope weights.
+    /// Retorna os resultados e quantos foram descartados por `min_relevance`.
     async fn do_search_parallel(
         &self,
         query: String,
-        scope: String,
+        dbs: Vec<(String, PathBuf)>,
         limit: usize,
-    ) -> Vec<(String, search::SearchResult)> {
-        let dbs = storage::resolve_scope_dbs(&scope, &self.paths);
+        filters: search::SearchFilters,
+        opts: SearchOptions,
+    ) -> (Vec<(String, search::SearchResult)>, usize) {
         let engine = self.embedding_engine.clone();
+        let db_by_scope: std::collections::HashMap<String, PathBuf> = dbs.iter().cloned().collect();
 
         // Compute embedding once (blocking)
         let query_clone = query.clone();
-        let query_emb = tokio::task::spawn_blocking(move || {
-            engine.embed(&query_clone).ok()
-        })
-        .await
-        .ok()
-        .flatten();
+        let model = opts.model;
+        let query_emb = if engine.is_available() {
+            tokio::task::spawn_blocking(move || {
+                let engine = match model {
+                    Some(name) => engine.engine_for(name)?,
+                    None => &engine,
+                };
+                engine.embed_query(&query_clone).ok()
+            })
+                .await
+                .ok()
+                .flatten()
+        } else {
+            None
+        };
 
         // Parallelizar buscas por scope
         let mut handles = Vec::new();
@@ -197,25 +651,57 @@ impl MemoryServer {
             let query = query.clone();
             let query_emb = query_emb.clone();
             let scope_name = scope_name.clone();
+            let filters = filters.clone();
+            let ann = self.ann.clone();
+            let db = self.db.clone();
+            let scoring = self.scoring.with_recency(opts.recency);
+            // MMR precisa de candidatos além do limit para ter o que trocar
+            let pool = if opts.diversity.is_some() { limit * 3 } else { limit };
 
             handles.push(tokio::task::spawn_blocking(move || {
-                let conn = match storage::init_db(&db_path) {
+                let conn = match db.get(&db_path) {
                     Ok(c) => c,
                     Err(_) => return vec![],
                 };
+                let db_key = db_path.to_string_lossy();
+                let ann_scope = ann::AnnScope {
+                    registry: &ann,
+                    db_key: &db_key,
+                };
+                let source = match opts.model {
+                    Some(model) => search::VectorSource::Model(model),
+                    None => search::VectorSource::Primary(Some(&ann_scope)),
+                };
                 let results = search::search_hybrid(
                     &conn,
                     &query,
                     query_emb.as_deref(),
-                    limit,
+                    pool,
+                    &filters,
+                    &scoring,
+                    source,
                 );
-                let weight = scope_weight(&scope_name);
+                // Snippet de chunk e MMR leem vetores do modelo principal
+                let primary_emb = query_emb.as_deref().filter(|_| opts.model.is_none());
+                let weight = scoring.scope_weights.weight(&scope_name);
                 results
                     .into_iter()
                     .map(|mut r| {
+                        if opts.snippet {
+                            search::attach_snippet(&conn, &query, primary_emb, &mut r);
+                        }
                         r.relevance *= weight;
                         r.relevance = (r.relevance * 10000.0).round() / 10000.0;
-                        (scope_name.clone(), r)
+                        match r.explain.as_mut() {
+                            Some(e) if opts.explain => e.scope_weight = weight,
+                            _ => r.explain = None,
+                        }
+                        let emb = match (primary_emb, opts.diversity) {
+                            (Some(q), Some(_)) => storage::get_embedding_blob(&conn, &r.id)
+                                .and_then(|b| embedding::decode_embedding(&b, q.len())),
+                            _ => None,
+                        };
+                        (scope_name.clone(), r, emb)
                     })
                     .collect::<Vec<_>>()
             }));
@@ -228,9 +714,92 @@ impl MemoryServer {
             }
         }
 
-        all_results.sort_by(|a, b| b.1.relevance.partial_cmp(&a.1.relevance).unwrap());
+        all_results.sort_by(|a, b| search::cmp_results(&a.1, &b.1));
+
+        // Corte de relevância antes do truncate, para não completar o limite com lixo
+        let mut filtered = 0usize;
+        if let Some(min) = opts.min_relevance {
+            let before = all_results.len();
+            all_results.retain(|(_, r, _)| r.relevance >= min);
+            filtered = before - all_results.len();
+        }
+
+        // MMR depois do merge entre scopes: reordenar antes seria desfeito pelo sort
+        let order: Vec<usize> = match opts.diversity.filter(|lambda| *lambda < 1.0) {
+            Some(lambda) => {
+                let relevance: Vec<f64> = all_results.iter().map(|(_, r, _)| r.relevance).collect();
+                let embeddings: Vec<Option<&[f32]>> =
+                    all_results.iter().map(|(_, _, e)| e.as_deref()).collect();
+                search::mmr_order(&relevance, &embeddings, lambda, limit)
+            }
+            None => (0..all_results.len().min(limit)).collect(),
+        };
+        let mut slots: Vec<Option<(String, search::SearchResult)>> =
+            all_results.into_iter().map(|(scope, r, _)| Some((scope, r))).collect();
+        let results: Vec<(String, search::SearchResult)> = order.into_iter().filter_map(|i| slots[i].take()).collect();
+
+        // Só o que a chamada devolve conta acesso: candidatos extras do MMR e vizinhos
+        // do grafo que ficaram de fora não
+        if opts.track_access && !results.is_empty() {
+            let hits: Vec<(PathBuf, String)> = results
+                .iter()
+                .filter_map(|(scope, r)| Some((db_by_scope.get(scope)?.clone(), r.id.clone())))
+                .collect();
+            let db = self.db.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                for (db_path, id) in &hits {
+                    if let Ok(conn) = db.get(db_path) {
+                        storage::update_access_count(&conn, id);
+                    }
+                }
+            })
+            .await;
+        }
+        (results, filtered)
+    }
+
+    /// Busca por regex em paralelo por scope; mais recentes primeiro no merge.
+    /// O bool indica que algum scope parou no limite de tempo.
+    async fn do_search_regex(
+        &self,
+        re: regex::Regex,
+        include_tags: bool,
+        dbs: Vec<(String, PathBuf)>,
+        limit: usize,
+        filters: search::SearchFilters,
+    ) -> (Vec<(String, search::SearchResult)>, bool) {
+        let re = Arc::new(re);
+        let mut handles = Vec::new();
+        for (scope_name, db_path) in dbs {
+            if !db_path.exists() && scope_name == "project" {
+                continue;
+            }
+            let re = re.clone();
+            let filters = filters.clone();
+            let db = self.db.clone();
+            handles.push(tokio::task::spawn_blocking(move || {
+                let conn = match db.get(&db_path) {
+                    Ok(c) => c,
+                    Err(_) => return (vec![], false),
+                };
+                let (results, timed_out) =
+                    search::search_regex(&conn, &re, include_tags, limit, &filters, search::REGEX_SCAN_BUDGET);
+                let results: Vec<_> = results.into_iter().map(|r| (scope_name.clone(), r)).collect();
+                (results, timed_out)
+            }));
+        }
+
+        let mut all_results = Vec::new();
+        let mut limited = false;
+        for handle in handles {
+            if let Ok((results, timed_out)) = handle.await {
+                all_results.extend(results);
+                limited |= timed_out;
+            }
+        }
+        all_results.sort_by(|a, b| b.1.created_at.cmp(&a.1.created_at).then_with(|| a.1.id.cmp(&b.1.id)));
         all_results.truncate(limit);
-        all_results
+        (all_results, limited)
     }
 
     fn queue_embedding(&self, db_path: &Path, record_id: &str, content: &str) -> bool {
@@ -238,31 +807,164 @@ impl MemoryServer {
             db_path: db_path.to_string_lossy().to_string(),
             record_id: record_id.to_string(),
             content: content.to_string(),
+            request_id: REQUEST_ID.try_with(|id| id.clone()).unwrap_or_default(),
         };
-        if let Err(e) = self.job_sender.try_send(job) {
-            tracing::warn!("Embedding queue full, job for {} dropped: {}", record_id, e);
+        // Modo só texto: fica como falha pendente, refeita pelo retry do próximo start com modelo
+        if !self.embedding_engine.is_available() {
+            embedding::defer_text_only(&[job]);
             return false;
         }
-        true
+        embedding::enqueue_job(&self.job_sender, &self.progress, job)
     }
 
-    fn resolve_save_db(&self, scope: &str) -> Option<PathBuf> {
-        match scope {
-            "global" => Some(self.paths.global_db.clone()),
-            "personality" => Some(self.paths.personality_db.clone()),
-            "project" => MemoryPaths::project_db_path(),
-            _ => Some(self.paths.personality_db.clone()),
+    /// Vizinhos por embedding de um vetor já conhecido (sem passar pelo modelo),
+    /// em paralelo por scope, com scope weights e sem o `exclude_id`.
+    async fn related_by_embedding(
+        &self,
+        query_emb: Vec<f32>,
+        scope: &str,
+        limit: usize,
+        exclude_id: &str,
+    ) -> Vec<(String, search::SearchResult)> {
+        let query_emb = Arc::new(query_emb);
+        let mut handles = Vec::new();
+        for (scope_name, db_path) in storage::resolve_scope_dbs(scope, &self.paths) {
+            if !db_path.exists() && scope_name == "project" {
+                continue;
+            }
+            let query_emb = query_emb.clone();
+            let ann = self.ann.clone();
+            let db = self.db.clone();
+            let min_sim = self.scoring.min_sim;
+            let weight = self.scoring.scope_weights.weight(&scope_name);
+            handles.push(tokio::task::spawn_blocking(move || {
+                let conn = match db.get(&db_path) {
+                    Ok(c) => c,
+                    Err(_) => return vec![],
+                };
+                let db_key = db_path.to_string_lossy();
+                let ann_scope = ann::AnnScope {
+                    registry: &ann,
+                    db_key: &db_key,
+                };
+                let filters = search::SearchFilters::default();
+                // +1: o próprio alvo costuma vir em primeiro
+                let results = search::search_embedding_ann(&conn, &ann_scope, &query_emb, limit + 1, &filters, min_sim)
+                    .unwrap_or_else(|| search::search_embedding(&conn, &query_emb, limit + 1, &filters, min_sim));
+                results
+                    .into_iter()
+                    .map(|mut r| {
+                        r.relevance = (r.relevance * weight * 10000.0).round() / 10000.0;
+                        (scope_name.clone(), r)
+                    })
+                    .collect::<Vec<_>>()
+            }));
         }
-    }
-
-    // ---- Tools ----
 
-    #[tool(description = "USE AUTOMATICALLY at the start of each conversation. Returns relevant memories for the current context (project + global). Works as an automatic 'recall'.")]
-    async fn memory_context(
-        &self,
-        Parameters(params): Parameters<ContextParams>,
-    ) -> Result<CallToolResult, McpError> {
-        let results = self.do_search_parallel(params.query, "both".into(), 8).await;
+        let mut all_results = Vec::new();
+        for handle in handles {
+            if let Ok(results) = handle.await {
+                all_results.extend(results);
+            }
+        }
+        all_results.retain(|(_, r)| r.id != exclude_id);
+        all_results.sort_by(|a, b| search::cmp_results(&a.1, &b.1));
+        all_results.truncate(limit);
+        all_results
+    }
+
+    /// Embedding do save para o dedup, só se sair barato: cache hit ou modelo já
+    /// carregado. Texto igual ao do worker (com MCP_EMBED_TAGS, type e tags entram),
+    /// então quando computado aqui vai pro cache e o worker reaproveita.
+    fn cheap_embedding(&self, conn: &rusqlite::Connection, mem_type: &str, tags: &str, content: &str) -> Option<Vec<f32>> {
+        let text = embedding::save_text(mem_type, tags, content, embedding::embed_tags_from_env());
+        let cache = self.embedding_engine.cache();
+        let cache_model = self.embedding_engine.cache_model();
+        if let Some(cached) = cache.get(conn, &text, &cache_model) {
+            return Some(cached);
+        }
+        let emb = self.embedding_engine.embed_if_loaded(&text)?;
+        cache.store(conn, &text, &cache_model, &emb);
+        Some(emb)
+    }
+
+    /// Links de uma memória no DB do scope onde ela foi encontrada
+    fn links_in_scope(&self, scope: &str, id: &str) -> Vec<storage::LinkedMemory> {
+        storage::resolve_scope_dbs(scope, &self.paths)
+            .into_iter()
+            .filter(|(_, p)| p.exists())
+            .filter_map(|(_, p)| self.db.get(&p).ok())
+            .map(|conn| storage::get_links(&conn, id))
+            .find(|links| !links.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// DBs do scope; `project_path` troca o projeto do cwd pelo de outro diretório
+    fn scope_dbs(&self, scope: &str, project_path: Option<&str>) -> Result<Vec<(String, PathBuf)>, String> {
+        match project_path.filter(|p| !p.trim().is_empty()) {
+            Some(dir) => {
+                let project_db = MemoryPaths::project_db_at(dir)?;
+                Ok(storage::resolve_scope_dbs_with(scope, &self.paths, Some(project_db)))
+            }
+            None => Ok(storage::resolve_scope_dbs(scope, &self.paths)),
+        }
+    }
+
+    /// DBs existentes dos scopes a consultar no dedup cross-scope (sem o DB de destino)
+    fn sibling_scope_dbs(&self, target: &Path, check_scopes: Option<&str>) -> Result<Vec<(String, PathBuf)>, String> {
+        let names: Vec<&str> = match check_scopes.filter(|s| !s.trim().is_empty()) {
+            Some(raw) => raw.split(',').map(str::trim).filter(|s| !s.is_empty()).collect(),
+            None => vec!["global", "personality", "project"],
+        };
+        let mut dbs = Vec::new();
+        for name in names {
+            // Aliases valem como nos outros tools; both/all não são um scope só
+            let scope = match storage::canonical_scope(name) {
+                Ok(scope @ ("global" | "personality" | "project")) => scope,
+                _ => return Err(format!("unknown scope '{}' in check_scopes", name)),
+            };
+            for (scope, path) in storage::resolve_scope_dbs(scope, &self.paths) {
+                if path != target && path.exists() && !dbs.iter().any(|(_, p)| p == &path) {
+                    dbs.push((scope, path));
+                }
+            }
+        }
+        Ok(dbs)
+    }
+
+    fn resolve_save_db(&self, scope: &str) -> Option<PathBuf> {
+        match scope {
+            "global" => Some(self.paths.global_db.clone()),
+            "personality" => Some(self.paths.personality_db.clone()),
+            "project" => MemoryPaths::project_db_path(),
+            // both/all não chegam aqui: `write_scope_or_error` recusa antes
+            _ => None,
+        }
+    }
+
+    // ---- Tools ----
+
+    #[tool(description = "USE AUTOMATICALLY at the start of each conversation. Returns relevant memories for the current context (project + global by default; tune with scope and limit). Works as an automatic 'recall'.")]
+    async fn memory_context(
+        &self,
+        Parameters(params): Parameters<ContextParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope,
+            Err(e) => return Ok(e),
+        };
+        let (results, _) = self
+            .do_search_parallel(
+                params.query,
+                storage::resolve_scope_dbs(scope, &self.paths),
+                params.limit,
+                Default::default(),
+                SearchOptions {
+                    track_access: self.track_access,
+                    ..Default::default()
+                },
+            )
+            .await;
 
         if results.is_empty() {
             return Ok(CallToolResult::success(vec![Content::text(
@@ -270,11 +972,15 @@ impl MemoryServer {
             )]));
         }
 
+        // Roda em toda conversa: conteúdo cortado por resultado e no total para não
+        // encher o contexto com logs colados (memory_search segue com o conteúdo inteiro)
+        let (per_result, total) = search::context_budget_from_env();
+        let excerpts = search::fit_context(results.iter().map(|(_, r)| r.content.as_str()), per_result, total);
         let mut output = "## Memory Context\n\n".to_string();
-        for (scope, r) in &results {
+        for ((scope, r), excerpt) in results.iter().zip(&excerpts) {
             output.push_str(&format!(
                 "**[{}:{}]** {}\n",
-                scope, r.mem_type, r.content
+                scope, r.mem_type, excerpt
             ));
         }
         output.push_str("\n---\n_Use this context to inform your responses._");
@@ -285,45 +991,246 @@ impl MemoryServer {
     #[tool(description = "Search specific memories when you need detailed information about past decisions, patterns, or preferences. Use 'personality' scope to find similar implementations from other projects.")]
     async fn memory_search(
         &self,
-        Parameters(params): Parameters<SearchParams>,
+        Parameters(mut params): Parameters<SearchParams>,
     ) -> Result<CallToolResult, McpError> {
-        let results = self.do_search_parallel(params.query, params.scope, params.limit).await;
-
-        if results.is_empty() {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        let filters = match (search::SearchFilters {
+            mem_type: params.r#type,
+            tags: storage::parse_tag_filter(params.tags.as_deref().unwrap_or("")),
+            ..Default::default()
+        })
+        .with_date_range(params.since.as_deref(), params.until.as_deref())
+        .and_then(|f| f.with_metadata(params.metadata_filter.as_deref()))
+        {
+            Ok(f) => f,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}", e))])),
+        };
+        if params.diversity.is_some_and(|d| !(0.0..=1.0).contains(&d)) {
             return Ok(CallToolResult::success(vec![Content::text(
-                "No memories found.",
+                "Error: diversity must be between 0 and 1.",
             )]));
         }
+        // Principal vira None: o caminho de sempre (ANN, chunks)
+        let model = match params.model.as_deref().map(|m| self.embedding_engine.engine_for(m)) {
+            None => None,
+            Some(Some(engine)) if engine.model_name() == self.embedding_engine.model_name() => None,
+            Some(Some(engine)) => Some(engine.model_name()),
+            Some(None) => {
+                let mut available = vec![self.embedding_engine.model_name()];
+                available.extend(self.embedding_engine.extra_models().iter().map(|e| e.model_name()));
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: model '{}' is not configured. Available: {} (extra models via MCP_EMBEDDING_EXTRA_MODELS).",
+                    params.model.unwrap_or_default(),
+                    available.join(", ")
+                ))]));
+            }
+        };
+        let dbs = match self.scope_dbs(&params.scope, params.project_path.as_deref()) {
+            Ok(dbs) => dbs,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))])),
+        };
+        let mut scan_note = String::new();
+        let (results, filtered) = if let Some(pattern) = params.regex.as_deref() {
+            let re = match search::compile_search_regex(pattern) {
+                Ok(re) => re,
+                Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))])),
+            };
+            let (results, timed_out) = self
+                .do_search_regex(re, params.regex_tags, dbs, params.limit, filters)
+                .await;
+            if timed_out {
+                scan_note = "Regex scan hit its time limit; older memories may be missing.".into();
+            }
+            (results, 0)
+        } else {
+            self.do_search_parallel(
+                params.query,
+                dbs,
+                params.limit,
+                filters,
+                SearchOptions {
+                    min_relevance: params.min_relevance,
+                    recency: params.recency.unwrap_or(true),
+                    diversity: params.diversity,
+                    snippet: params.snippet.unwrap_or(false),
+                    model,
+                    explain: params.explain,
+                    track_access: self.track_access,
+                },
+            )
+            .await
+        };
+
+        let links: Vec<Vec<storage::LinkedMemory>> = if params.include_links {
+            results.iter().map(|(scope, r)| self.links_in_scope(scope, &r.id)).collect()
+        } else {
+            vec![]
+        };
+
+        if wants_json(params.format.as_deref()) {
+            let mut items = Vec::with_capacity(results.len());
+            for (i, (scope, r)) in results.iter().enumerate() {
+                let mut item = serde_json::to_value(Scoped { scope, item: r }).unwrap_or_default();
+                if let (Some(l), Some(obj)) = (links.get(i), item.as_object_mut()) {
+                    obj.insert("links".into(), serde_json::to_value(l).unwrap_or_default());
+                }
+                items.push(item);
+            }
+            return Ok(CallToolResult::success(vec![Content::json(serde_json::json!({
+                "results": items,
+                "filtered": filtered,
+                "scan_limited": !scan_note.is_empty(),
+            }))?]));
+        }
+
+        let filtered_info = match params.min_relevance {
+            Some(min) if filtered > 0 => {
+                format!("{} result(s) below min_relevance {} filtered out.", filtered, min)
+            }
+            _ => scan_note,
+        };
+
+        if results.is_empty() {
+            let output = if filtered_info.is_empty() {
+                "No memories found.".to_string()
+            } else {
+                format!("No memories found ({})", filtered_info)
+            };
+            return Ok(CallToolResult::success(vec![Content::text(output)]));
+        }
 
         let mut output = format!("## Memories ({})\n\n", results.len());
-        for (scope, r) in &results {
+        for (i, (scope, r)) in results.iter().enumerate() {
+            let via = match (r.chunk_hit, r.chunk_index) {
+                (true, Some(index)) => format!(" via chunk {}", index),
+                (true, None) => " via chunk".to_string(),
+                _ => String::new(),
+            };
             output.push_str(&format!(
-                "**[{}] {}** (relevance: {}, method: {})\n{}\n",
+                "**[{}] {}** (relevance: {}, method: {}{}, fts: {}, emb: {})\n{}\n",
                 scope.to_uppercase(),
                 r.mem_type,
                 r.relevance,
                 r.method,
-                r.content
+                via,
+                r.fts_score,
+                r.emb_score,
+                r.snippet.as_deref().unwrap_or(&r.content)
             ));
+            if r.truncated {
+                output.push_str(&format!("_Snippet — full text: memory_get `{}`_\n", r.id));
+            }
+            if let Some(e) = &r.explain {
+                output.push_str(&format!(
+                    "_Explain: bm25 {} (norm {}), cosine {}, importance {}, {} fusion {} × decay {} ({} days) × scope {} = {}_\n",
+                    e.bm25_raw,
+                    e.bm25_normalized,
+                    e.cosine,
+                    e.importance,
+                    e.fusion,
+                    e.fused,
+                    e.decay,
+                    e.days_old,
+                    e.scope_weight,
+                    r.relevance
+                ));
+            }
             if !r.tags.is_empty() {
                 output.push_str(&format!("_Tags: {}_\n", r.tags));
             }
+            if let Some(l) = links.get(i).filter(|l| !l.is_empty()) {
+                output.push_str("_Links:_\n");
+                output.push_str(&format_links(l));
+            }
             output.push('\n');
         }
+        if !filtered_info.is_empty() {
+            output.push_str(&format!("_{}_\n", filtered_info));
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(description = "Check whether memories about something already exist without reading them: runs the same hybrid search as memory_search and returns only how many results pass min_relevance, with their IDs, types and scores. Use before saving or for conditional logic.")]
+    async fn memory_count(
+        &self,
+        Parameters(mut params): Parameters<CountParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        let filters = search::SearchFilters {
+            mem_type: params.r#type,
+            tags: storage::parse_tag_filter(params.tags.as_deref().unwrap_or("")),
+            ..Default::default()
+        };
+        let (results, _) = self
+            .do_search_parallel(
+                params.query,
+                storage::resolve_scope_dbs(&params.scope, &self.paths),
+                params.limit,
+                filters,
+                SearchOptions {
+                    min_relevance: params.min_relevance,
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        if wants_json(params.format.as_deref()) {
+            let items: Vec<serde_json::Value> = results
+                .iter()
+                .map(|(scope, r)| {
+                    serde_json::json!({
+                        "id": r.id,
+                        "type": r.mem_type,
+                        "scope": scope,
+                        "relevance": r.relevance,
+                    })
+                })
+                .collect();
+            return Ok(CallToolResult::success(vec![Content::json(serde_json::json!({
+                "count": results.len(),
+                "results": items,
+            }))?]));
+        }
 
+        let threshold = params
+            .min_relevance
+            .map(|min| format!(" with relevance >= {}", min))
+            .unwrap_or_default();
+        let mut output = format!("{} matching memories{}", results.len(), threshold);
+        if results.len() == params.limit {
+            output.push_str(&format!(" (limit {} reached)", params.limit));
+        }
+        output.push('\n');
+        for (scope, r) in &results {
+            output.push_str(&format!("- `{}` {} [{}] relevance {}\n", r.id, r.mem_type, scope, r.relevance));
+        }
         Ok(CallToolResult::success(vec![Content::text(output)]))
     }
 
     #[tool(description = "Save important decision, pattern, or implementation. Auto-tags are extracted automatically. Use after: (1) making architecture decisions, (2) defining code patterns, (3) learning user preferences, (4) implementing new features.")]
     fn memory_save(
         &self,
-        Parameters(params): Parameters<SaveParams>,
+        Parameters(mut params): Parameters<SaveParams>,
     ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
         if params.content.is_empty() {
             return Ok(CallToolResult::success(vec![Content::text(
                 "Error: empty content.",
             )]));
         }
+        if let Err(e) = storage::check_content_len(&params.content, storage::max_content_len_from_env()) {
+            return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))]));
+        }
 
         let db_path = match self.resolve_save_db(&params.scope) {
             Some(p) => p,
@@ -333,34 +1240,36 @@ impl MemoryServer {
                 )]));
             }
         };
+        if params.scope == "project" {
+            if let Err(e) = MemoryPaths::check_project_db(&db_path) {
+                return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))]));
+            }
+        }
+
+        let metadata = match storage::normalize_metadata(params.metadata.as_deref().unwrap_or("")) {
+            Ok(m) => m,
+            Err(e) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))]));
+            }
+        };
 
-        let mut tags = params.tags.clone();
+        let dedup_strategy = match storage::DedupStrategy::parse(params.dedup_strategy.as_deref().unwrap_or("")) {
+            Ok(s) => s,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))])),
+        };
 
-        // Para personality scope, adiciona project name nas tags
-        if params.scope == "personality" {
-            let project_name = if params.project_name.is_empty() {
-                std::env::var("MCP_PROJECT_DIR")
-                    .or_else(|_| std::env::var("CLAUDE_CWD"))
-                    .ok()
-                    .and_then(|p| {
-                        std::path::Path::new(&p)
-                            .file_name()
-                            .map(|n| n.to_string_lossy().to_string())
-                    })
-                    .unwrap_or_else(|| "no-project".to_string())
-            } else {
-                params.project_name.clone()
-            };
-            if !project_name.is_empty() && !tags.contains(&project_name) {
-                tags = if tags.is_empty() {
-                    project_name
-                } else {
-                    format!("{},{}", tags, project_name)
-                };
+        let tags = tags_with_project(&params.scope, &params.tags, &params.project_name);
+
+        let sibling_dbs = if params.cross_scope {
+            match self.sibling_scope_dbs(&db_path, params.check_scopes.as_deref()) {
+                Ok(dbs) => dbs,
+                Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))])),
             }
-        }
+        } else {
+            Vec::new()
+        };
 
-        let conn = match storage::init_db(&db_path) {
+        let conn = match self.db.get(&db_path) {
             Ok(c) => c,
             Err(e) => {
                 return Ok(CallToolResult::success(vec![Content::text(format!(
@@ -370,26 +1279,116 @@ impl MemoryServer {
             }
         };
 
-        match storage::save_memory(&conn, &params.r#type, &params.content, &tags) {
+        let content_embedding = self.cheap_embedding(&conn, &params.r#type, &tags, &params.content);
+
+        // Duplicata em outro scope: só avisa, não grava a cópia nem mexe no outro DB
+        if !sibling_dbs.is_empty() {
+            let sibling_conns: Vec<(String, storage::PooledConnection)> = sibling_dbs
+                .into_iter()
+                .filter_map(|(scope, path)| Some((scope, self.db.get(&path).ok()?)))
+                .collect();
+            let siblings: Vec<(String, &rusqlite::Connection)> =
+                sibling_conns.iter().map(|(scope, c)| (scope.clone(), &**c)).collect();
+            let conflicts = dedup::find_cross_scope_duplicates(
+                &siblings,
+                &params.content,
+                &params.r#type,
+                dedup::effective_threshold(params.dedup_threshold),
+                content_embedding.as_deref(),
+            );
+            if !conflicts.is_empty() {
+                let mut output = format!(
+                    "Not saved ({}): near-duplicate already exists in another scope\n",
+                    params.scope
+                );
+                for (scope, m) in &conflicts {
+                    output.push_str(&format!(
+                        "- [{}] `{}` ({} similarity {:.2})\n",
+                        scope, m.id, m.method, m.similarity
+                    ));
+                }
+                output.push_str("_Save with cross_scope=false to keep a separate copy in this scope._");
+                return Ok(CallToolResult::success(vec![Content::text(output)]));
+            }
+        }
+
+        match storage::save_memory(
+            &conn,
+            &params.r#type,
+            &params.content,
+            &tags,
+            &storage::SaveOptions {
+                embedding: content_embedding.as_deref(),
+                dedup_threshold: params.dedup_threshold,
+                metadata: metadata.as_deref(),
+                dedup_strategy,
+                idempotency_key: params.idempotency_key.as_deref().filter(|k| !k.trim().is_empty()),
+                ttl_days: params.ttl_days,
+                max_content_len: storage::max_content_len_from_env(),
+            },
+        ) {
             Ok(result) => {
-                let queued = self.queue_embedding(&db_path, &result.id, &params.content);
-                let dedup_info = if result.dedup == "updated" {
-                    "\n- Dedup: updated existing (similar found)"
+                // keep: o existente não mudou, o embedding dele continua valendo
+                let kept = result.dedup == "kept";
+                let queued = !kept && self.queue_embedding(&db_path, &result.id, &result.record.content);
+                let dedup_info = match result.dedup.as_str() {
+                    "updated" => format!(
+                        "\n- Dedup: updated existing via {} (similar found via {})",
+                        match dedup_strategy {
+                            storage::DedupStrategy::Merge => "merge",
+                            _ => "replace",
+                        },
+                        result.dedup_method
+                    ),
+                    "kept" => format!(
+                        "\n- Dedup: kept existing unchanged (similar found via {})",
+                        result.dedup_method
+                    ),
+                    _ => String::new(),
+                };
+                let embedding_info = if kept {
+                    "unchanged".to_string()
+                } else if queued {
+                    format!("queued ({})", self.embedding_engine.format().name())
+                } else if !self.embedding_engine.is_available() {
+                    "disabled (text-only mode; embedded on the next start with the model)".to_string()
                 } else {
-                    ""
+                    "deferred: worker queue full (retried automatically)".to_string()
                 };
-                let embedding_info = if queued {
-                    "queued (f16 compressed)"
+                let record = &result.record;
+                if wants_json(params.format.as_deref()) {
+                    let mut value = serde_json::to_value(record).unwrap_or_default();
+                    value["scope"] = params.scope.clone().into();
+                    value["dedup"] = result.dedup.clone().into();
+                    value["dedup_method"] = result.dedup_method.clone().into();
+                    value["embedding"] = embedding_info.into();
+                    if let Some(expires_at) = &result.expires_at {
+                        value["expires_at"] = expires_at.clone().into();
+                    }
+                    return Ok(CallToolResult::success(vec![Content::json(value)?]));
+                }
+                // Duplicata: o conteúdo gravado não é o enviado
+                let content_info = if result.dedup == "new" {
+                    String::new()
                 } else {
-                    "not queued: worker queue full"
+                    format!("\n- Stored content: {}", record.content)
+                };
+                let expires_info = match &result.expires_at {
+                    Some(at) => format!("\n- Expires: {}", at),
+                    None => String::new(),
                 };
                 Ok(CallToolResult::success(vec![Content::text(format!(
-                    "Memory saved ({})\n- Type: {}\n- ID: {}\n- Tags: auto-enriched\n- Embedding: {}{}",
+                    "Memory saved ({})\n- Type: {}\n- ID: {}\n- Tags: {}\n- Created: {}\n- Updated: {}{}\n- Embedding: {}{}{}",
                     params.scope,
-                    params.r#type,
+                    record.mem_type,
                     result.id,
+                    record.tags,
+                    record.created_at,
+                    record.updated_at.as_deref().unwrap_or(&record.created_at),
+                    expires_info,
                     embedding_info,
-                    dedup_info
+                    dedup_info,
+                    content_info
                 ))]))
             }
             Err(e) => Ok(CallToolResult::success(vec![Content::text(format!(
@@ -399,46 +1398,208 @@ impl MemoryServer {
         }
     }
 
+    #[tool(description = "Save many memories at once (e.g. facts extracted from a document) in a single transaction. Dedup runs per item; returns id and new/updated for each.")]
+    fn memory_save_batch(
+        &self,
+        Parameters(mut params): Parameters<SaveBatchParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        const MAX_BATCH_ITEMS: usize = 100;
+
+        if params.items.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text("Error: no items.")]));
+        }
+        if params.items.len() > MAX_BATCH_ITEMS {
+            return Ok(CallToolResult::success(vec![Content::text(format!(
+                "Error: too many items ({}, max {}).",
+                params.items.len(),
+                MAX_BATCH_ITEMS
+            ))]));
+        }
+
+        let Some(db_path) = self.resolve_save_db(&params.scope) else {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: project not detected. Use scope='personality' or 'global'.",
+            )]));
+        };
+        if params.scope == "project" {
+            if let Err(e) = MemoryPaths::check_project_db(&db_path) {
+                return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))]));
+            }
+        }
+        let dedup_strategy = match storage::DedupStrategy::parse(params.dedup_strategy.as_deref().unwrap_or("")) {
+            Ok(s) => s,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))])),
+        };
+
+        // Validação antes de abrir a transação: item inválido não entra, os outros sim
+        let mut skipped: Vec<(usize, String)> = Vec::new();
+        let mut positions: Vec<usize> = Vec::new();
+        let mut items: Vec<storage::BatchItem> = Vec::new();
+        let max_len = storage::max_content_len_from_env();
+        for (i, item) in params.items.iter().enumerate() {
+            if item.content.trim().is_empty() {
+                skipped.push((i, "empty content".into()));
+                continue;
+            }
+            if let Err(e) = storage::check_content_len(&item.content, max_len) {
+                skipped.push((i, e));
+                continue;
+            }
+            let metadata = match storage::normalize_metadata(item.metadata.as_deref().unwrap_or("")) {
+                Ok(m) => m,
+                Err(e) => {
+                    skipped.push((i, e));
+                    continue;
+                }
+            };
+            positions.push(i);
+            items.push(storage::BatchItem {
+                mem_type: item.r#type.clone(),
+                content: item.content.clone(),
+                tags: tags_with_project(&params.scope, &item.tags, &params.project_name),
+                embedding: None,
+                metadata,
+            });
+        }
+
+        let conn = match self.db.get(&db_path) {
+            Ok(c) => c,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}", e))])),
+        };
+        for item in &mut items {
+            item.embedding = self.cheap_embedding(&conn, &item.mem_type, &item.tags, &item.content);
+        }
+        let results = match storage::save_memory_batch(&conn, &items, params.dedup_threshold, dedup_strategy, max_len) {
+            Ok(r) => r,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}", e))])),
+        };
+
+        // Jobs seguidos na fila: o worker junta tudo em poucas chamadas de embed_batch
+        let mut queued = 0;
+        for result in results.iter().filter(|r| r.dedup != "kept") {
+            if self.queue_embedding(&db_path, &result.id, &result.record.content) {
+                queued += 1;
+            }
+        }
+
+        let count = |outcome: &str| results.iter().filter(|r| r.dedup == outcome).count();
+        let (created, updated, kept) = (count("new"), count("updated"), count("kept"));
+        let mut lines: Vec<(usize, String)> = positions
+            .iter()
+            .zip(&results)
+            .map(|(&i, r)| {
+                let line = match r.dedup.as_str() {
+                    "new" => format!("`{}` new", r.id),
+                    outcome => format!("`{}` {} (similar found via {})", r.id, outcome, r.dedup_method),
+                };
+                (i, line)
+            })
+            .collect();
+        lines.extend(skipped.iter().map(|(i, e)| (*i, format!("skipped: {}", e))));
+        lines.sort_by_key(|(i, _)| *i);
+
+        let mut output = format!(
+            "## Batch saved ({})\n\n- New: {}\n- Updated: {}\n- Kept: {}\n- Skipped: {}\n- Embeddings queued: {}/{}\n\n",
+            params.scope,
+            created,
+            updated,
+            kept,
+            skipped.len(),
+            queued,
+            results.len() - kept
+        );
+        for (i, line) in lines {
+            output.push_str(&format!("{}. {}\n", i + 1, line));
+        }
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
     #[tool(description = "List recent memories. Useful to review decision history or find past implementations.")]
     fn memory_list(
         &self,
-        Parameters(params): Parameters<ListParams>,
+        Parameters(mut params): Parameters<ListParams>,
     ) -> Result<CallToolResult, McpError> {
-        let dbs = storage::resolve_scope_dbs(&params.scope, &self.paths);
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        let dbs = match self.scope_dbs(&params.scope, params.project_path.as_deref()) {
+            Ok(dbs) => dbs,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))])),
+        };
         let mut all_results = Vec::new();
 
-        // Busca mais do que o limite por scope para poder fazer merge+sort+truncate
-        let per_scope_limit = (params.limit * 2) as i64;
+        // Cada scope devolve offset+limit na mesma ordenação do merge (created_at DESC, id),
+        // então aplicar offset/limit depois do merge não pula nem duplica entre scopes
+        let offset = params.offset.unwrap_or(0);
+        let per_scope_limit = (offset + params.limit) as i64;
+        let filters = match (search::SearchFilters {
+            mem_type: params.r#type,
+            tags: storage::parse_tag_filter(params.tags.as_deref().unwrap_or("")),
+            ..Default::default()
+        })
+        .with_date_range(params.since.as_deref(), params.until.as_deref())
+        {
+            Ok(f) => f,
+            Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}", e))])),
+        };
         for (scope_name, db_path) in dbs {
             if !db_path.exists() && scope_name == "project" {
                 continue;
             }
-            let conn = match storage::init_db(&db_path) {
+            let conn = match self.db.get(&db_path) {
                 Ok(c) => c,
                 Err(_) => continue,
             };
-            let mems = storage::list_memories(
-                &conn,
-                params.r#type.as_deref(),
-                per_scope_limit,
-            )
-            .unwrap_or_default();
+            let mems = storage::list_memories(&conn, &filters, per_scope_limit, 0)
+                .unwrap_or_default();
             for m in mems {
                 all_results.push((scope_name.clone(), m));
             }
         }
 
+        // Sort por created_at DESC (tie-break por id) e paginar sobre o merge
+        all_results.sort_by(|a, b| {
+            b.1.created_at
+                .cmp(&a.1.created_at)
+                .then_with(|| a.1.id.cmp(&b.1.id))
+        });
+        let all_results: Vec<_> = all_results
+            .into_iter()
+            .skip(offset)
+            .take(params.limit)
+            .collect();
+
+        if wants_json(params.format.as_deref()) {
+            let items: Vec<Scoped<storage::MemoryRecord>> = all_results
+                .iter()
+                .map(|(scope, r)| Scoped { scope, item: r })
+                .collect();
+            return Ok(CallToolResult::success(vec![Content::json(serde_json::json!({
+                "results": items,
+                "offset": offset,
+            }))?]));
+        }
+
         if all_results.is_empty() {
             return Ok(CallToolResult::success(vec![Content::text(
                 "No memories found.",
             )]));
         }
 
-        // Sort por created_at DESC e truncar ao limite global
-        all_results.sort_by(|a, b| b.1.created_at.cmp(&a.1.created_at));
-        all_results.truncate(params.limit);
-
-        let mut output = format!("## Memories ({})\n\n", all_results.len());
+        let mut output = if offset > 0 {
+            format!(
+                "## Memories ({}-{})\n\n",
+                offset + 1,
+                offset + all_results.len()
+            )
+        } else {
+            format!("## Memories ({})\n\n", all_results.len())
+        };
         for (scope, r) in &all_results {
             let truncated: String = r.content.chars().take(80).collect();
             let ellipsis = if r.content.len() > 80 { "..." } else { "" };
@@ -449,163 +1610,1077 @@ impl MemoryServer {
             if !r.tags.is_empty() {
                 output.push_str(&format!("  _Tags: {}_\n", r.tags));
             }
-            output.push_str(&format!("  `{}` | {}\n\n", r.id, r.created_at));
+            match r.updated_at.as_deref().filter(|u| *u != r.created_at) {
+                Some(updated) => output.push_str(&format!(
+                    "  `{}` | created {} | updated {}\n\n",
+                    r.id, r.created_at, updated
+                )),
+                None => output.push_str(&format!("  `{}` | created {}\n\n", r.id, r.created_at)),
+            }
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(description = "List the tags already in use with how many memories carry each, most frequent first. Check it before tagging to reuse existing tags instead of inventing variants.")]
+    fn memory_tags(
+        &self,
+        Parameters(mut params): Parameters<TagsParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        let mut counts: std::collections::HashMap<String, i64> = std::collections::HashMap::new();
+        for (scope_name, db_path) in storage::resolve_scope_dbs(&params.scope, &self.paths) {
+            if !db_path.exists() && scope_name == "project" {
+                continue;
+            }
+            let Ok(conn) = self.db.get(&db_path) else {
+                continue;
+            };
+            for (tag, n) in storage::tag_counts(&conn) {
+                *counts.entry(tag).or_insert(0) += n;
+            }
+        }
+        let distinct = counts.len();
+        let mut tags = storage::sort_tag_counts(counts);
+        tags.truncate(params.limit.unwrap_or(100));
+
+        if wants_json(params.format.as_deref()) {
+            let items: Vec<serde_json::Value> = tags
+                .iter()
+                .map(|(tag, count)| serde_json::json!({ "tag": tag, "count": count }))
+                .collect();
+            return Ok(CallToolResult::success(vec![Content::json(serde_json::json!({
+                "tags": items,
+                "distinct": distinct,
+            }))?]));
         }
 
+        if tags.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text("No tags found.")]));
+        }
+        let mut output = format!("## Tags ({} distinct, {})\n\n", distinct, params.scope);
+        for (tag, count) in &tags {
+            output.push_str(&format!("- {} ({})\n", tag, count));
+        }
+        if tags.len() < distinct {
+            output.push_str(&format!("\n_{} more not shown (raise limit)._\n", distinct - tags.len()));
+        }
         Ok(CallToolResult::success(vec![Content::text(output)]))
     }
 
-    #[tool(description = "Show memory statistics (total, indexed, edges, archived, by type).")]
+    #[tool(description = "Show memory statistics per scope and across all scopes (total, indexed, edges, archived, trash, by type, top tags).")]
     fn memory_stats(
         &self,
-        Parameters(_params): Parameters<StatsParams>,
+        Parameters(params): Parameters<StatsParams>,
     ) -> Result<CallToolResult, McpError> {
-        let mut output = "## Memory Statistics\n\n".to_string();
+        let mut all_stats = Vec::new();
+        let dim = self.embedding_engine.dimension();
 
         for (label, db_path) in [
-            ("Global", &self.paths.global_db),
-            ("Personality", &self.paths.personality_db),
+            ("Global", self.paths.global_db.clone()),
+            ("Personality", self.paths.personality_db.clone()),
         ] {
-            let conn = match storage::init_db(db_path) {
+            let conn = match self.db.get(&db_path) {
                 Ok(c) => c,
                 Err(_) => continue,
             };
-            let stats = storage::get_stats(&conn);
+            all_stats.push((label, db_path, storage::get_stats(&conn, dim)));
+        }
+
+        if let Some(project_db) = MemoryPaths::project_db_path() {
+            if project_db.exists() {
+                if let Ok(conn) = self.db.get(&project_db) {
+                    let stats = storage::get_stats(&conn, dim);
+                    all_stats.push(("Project", project_db, stats));
+                }
+            }
+        }
+
+        if wants_json(params.format.as_deref()) {
+            let scopes: Vec<serde_json::Value> = all_stats
+                .iter()
+                .map(|(label, path, stats)| {
+                    let mut value = serde_json::to_value(stats).unwrap_or_default();
+                    value["scope"] = label.to_lowercase().into();
+                    value["path"] = path.display().to_string().into();
+                    value
+                })
+                .collect();
+            let totals = storage::aggregate_stats(all_stats.iter().map(|(_, _, s)| s));
+            return Ok(CallToolResult::success(vec![Content::json(serde_json::json!({
+                "scopes": scopes,
+                "all_scopes": totals,
+            }))?]));
+        }
+
+        let active_model = self.embedding_engine.model_name();
+        let mut output = "## Memory Statistics\n\n".to_string();
+        for (label, db_path, stats) in &all_stats {
             output.push_str(&format!(
-                "**{}** ({}):\n- Total: {}\n- Archived: {}\n- Indexed: {}\n- Chunks: {}\n- Edges: {}\n- Cache: {}\n- By type: {:?}\n\n",
+                "**{}** ({}):\n- Total: {}\n- Archived: {}\n- Trash: {}\n- Indexed: {}\n- Chunks: {}\n- Edges: {}\n- Cache: {}\n- By type: {:?}\n",
                 label,
                 db_path.display(),
                 stats.total,
                 stats.archived,
+                stats.trashed,
                 stats.indexed,
                 stats.chunks,
                 stats.edges,
                 stats.cache_entries,
                 stats.by_type,
             ));
-        }
-
-        if let Some(project_db) = MemoryPaths::project_db_path() {
-            if project_db.exists() {
-                if let Ok(conn) = storage::init_db(&project_db) {
-                    let stats = storage::get_stats(&conn);
+            if stats.expired > 0 {
+                output.push_str(&format!("- Expired (awaiting prune): {}\n", stats.expired));
+            }
+            if !stats.by_tag.is_empty() {
+                output.push_str(&format!("- By tag: {}\n", format_tag_counts(&stats.by_tag)));
+            }
+            let fts_expected = stats.total + stats.expired + stats.archived;
+            if stats.fts_rows == fts_expected {
+                output.push_str(&format!("- FTS rows: {}\n", stats.fts_rows));
+            } else {
+                output.push_str(&format!(
+                    "- FTS rows: {} (expected {}) — run memory_verify with repair=true\n",
+                    stats.fts_rows, fts_expected
+                ));
+            }
+            output.push_str(&format!("- Schema: v{}\n", stats.schema_version));
+            if let Some(format) = stats.embedding_format.as_deref() {
+                output.push_str(&format!("- Embedding format: {}\n", format));
+            }
+            if let Some(model) = stats.embedding_model.as_deref() {
+                if model != active_model {
+                    output.push_str(&format!(
+                        "- Warning: embeddings from {} (active: {}) — run memory_reindex\n",
+                        model, active_model
+                    ));
+                }
+            }
+            if let Some(text) = stats.embedding_text.as_deref() {
+                let active_text = embedding::embedding_text_mode(embedding::embed_tags_from_env());
+                if text != active_text {
+                    output.push_str(&format!(
+                        "- Warning: embeddings built from {} (active: {}, MCP_EMBED_TAGS) — run memory_reindex\n",
+                        text, active_text
+                    ));
+                }
+            }
+            if let Some(code) = stats.embedding_code.as_deref() {
+                let active_code = embedding::code_preprocess_mode(self.embedding_engine.code_preprocessing());
+                if code != active_code {
                     output.push_str(&format!(
-                        "**Project** ({}):\n- Total: {}\n- Archived: {}\n- Indexed: {}\n- Chunks: {}\n- Edges: {}\n- Cache: {}\n- By type: {:?}\n\n",
-                        project_db.display(), stats.total, stats.archived, stats.indexed,
-                        stats.chunks, stats.edges, stats.cache_entries, stats.by_type,
+                        "- Warning: embeddings built with code preprocessing {} (active: {}, MCP_CODE_PREPROCESS) — run memory_reindex\n",
+                        code, active_code
                     ));
                 }
             }
+            if stats.dimension_mismatches > 0 {
+                output.push_str(&format!(
+                    "- Warning: {} embeddings with unexpected dimension (expected {}) — run memory_reindex\n",
+                    stats.dimension_mismatches, dim
+                ));
+            }
+            if !stats.model_vectors.is_empty() {
+                let counts: Vec<String> = stats.model_vectors.iter().map(|(m, n)| format!("{}: {}", m, n)).collect();
+                output.push_str(&format!("- Extra model vectors: {}\n", counts.join(", ")));
+            }
+            if stats.embedding_failures > 0 {
+                output.push_str(&format!(
+                    "- Embedding failures: {} (retried automatically; memory_reindex forces a retry)\n",
+                    stats.embedding_failures
+                ));
+            }
+            output.push('\n');
+        }
+
+        if all_stats.len() > 1 {
+            let totals = storage::aggregate_stats(all_stats.iter().map(|(_, _, s)| s));
+            output.push_str(&format!(
+                "**All scopes**:\n- Total: {}\n- Archived: {}\n- Trash: {}\n- Indexed: {}\n- Chunks: {}\n",
+                totals.total, totals.archived, totals.trashed, totals.indexed, totals.chunks,
+            ));
+            if totals.expired > 0 {
+                output.push_str(&format!("- Expired (awaiting prune): {}\n", totals.expired));
+            }
+            if !totals.by_tag.is_empty() {
+                output.push_str(&format!("- By tag: {}\n", format_tag_counts(&totals.by_tag)));
+            }
+            output.push('\n');
         }
 
         output.push_str("**Config v0.3**:\n");
-        output.push_str("- Embeddings: f16 compressed (50% less storage)\n");
-        output.push_str("- Model: all-MiniLM-L6-v2\n");
-        output.push_str("- Search: hybrid (vector=0.7, text=0.3) + importance boost + graph 1-hop\n");
-        output.push_str("- Scope weights: project=1.0, personality=0.85, global=0.7\n");
-        output.push_str("- Temporal decay: 0.15\n");
-        output.push_str("- Dedup threshold: 0.85\n");
+        output.push_str(&format!(
+            "- Embeddings: {} (MCP_EMBEDDING_FORMAT)\n",
+            self.embedding_engine.format().name()
+        ));
+        if !self.embedding_engine.is_available() {
+            output.push_str("- Embeddings: disabled (text-only mode, model failed to load; search uses FTS only)\n");
+        }
+        output.push_str(&format!(
+            "- Embedding workers: {} (MCP_EMBEDDING_WORKERS)\n",
+            self.embedding_engine.workers()
+        ));
+        output.push_str(&format!("- Model: {}\n", active_model));
+        let extra_models: Vec<&str> = self.embedding_engine.extra_models().iter().map(|e| e.model_name()).collect();
+        if !extra_models.is_empty() {
+            output.push_str(&format!(
+                "- Extra models: {} (MCP_EMBEDDING_EXTRA_MODELS; memory_search model=...)\n",
+                extra_models.join(", ")
+            ));
+        }
+        output.push_str(&format!(
+            "- Search: hybrid, {} fusion (vector={:.2}, text={:.2}) + importance boost + graph 1-hop\n",
+            self.scoring.fusion.name(),
+            self.scoring.weights.vector,
+            self.scoring.weights.text
+        ));
+        output.push_str("- Vector index: HNSW in memory per DB (linear scan fallback)\n");
+        let chunk_config = self.embedding_engine.chunk_config();
+        output.push_str(&format!(
+            "- Chunking: {} (MCP_CHUNK_MODE; words {}/{} via MCP_CHUNK_SIZE/MCP_CHUNK_OVERLAP, tokens 200/40)\n",
+            self.embedding_engine.chunk_mode().name(),
+            chunk_config.size,
+            chunk_config.overlap
+        ));
+        match self.embedding_engine.cache().shared_entries() {
+            Some(n) => output.push_str(&format!("- Embedding cache: shared cache.db ({} entries)\n", n)),
+            None => output.push_str("- Embedding cache: per DB\n"),
+        }
+        output.push_str(&format!(
+            "- Code preprocessing: {} (MCP_CODE_PREPROCESS)\n",
+            if self.embedding_engine.code_preprocessing() { "on" } else { "off" }
+        ));
+        let (queries, hits, misses) = self.embedding_engine.query_cache().stats();
+        output.push_str(&format!(
+            "- Query embedding cache: {} entries ({} hits, {} misses)\n",
+            queries, hits, misses
+        ));
+        output.push_str(&format!(
+            "- Scope weights: {} (MCP_SCOPE_WEIGHT_*)\n",
+            self.scoring.scope_weights.describe()
+        ));
+        output.push_str(&format!("- Temporal decay: {}\n", self.scoring.decay.describe()));
+        output.push_str(&format!("- Embedding min similarity: {}\n", self.scoring.min_sim));
+        output.push_str("- Dedup threshold: 0.85 (cosine when embedding available, else Jaccard)\n");
         output.push_str("- Auto-tagging: enabled (~100 tech keywords)\n");
         output.push_str("- Consolidation: available (memory_consolidate)\n");
 
         Ok(CallToolResult::success(vec![Content::text(output)]))
     }
 
-    #[tool(description = "Remove a memory by ID.")]
-    fn memory_delete(
+    #[tool(description = "Export all memories of a scope (including archived) as JSON or Markdown, without embeddings. Returns the export or writes it to output_path.")]
+    fn memory_export(
+        &self,
+        Parameters(mut params): Parameters<ExportParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        let markdown = match params.format.as_deref().map(|f| f.to_lowercase()) {
+            None => false,
+            Some(f) if f == "json" => false,
+            Some(f) if f == "markdown" || f == "md" => true,
+            Some(f) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: unknown format '{}'. Use json or markdown.",
+                    f
+                ))]));
+            }
+        };
+
+        let mut exported: Vec<(String, Vec<storage::MemoryDetail>)> = Vec::new();
+        for (scope_name, db_path) in storage::resolve_scope_dbs(&params.scope, &self.paths) {
+            if !db_path.exists() && scope_name == "project" {
+                continue;
+            }
+            let conn = match self.db.get(&db_path) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            match storage::export_memories(&conn) {
+                Ok(memories) => exported.push((scope_name, memories)),
+                Err(e) => tracing::warn!("Export failed for {}: {}", scope_name, e),
+            }
+        }
+        let total: usize = exported.iter().map(|(_, m)| m.len()).sum();
+
+        let body = if markdown {
+            let mut output = "# Memory Export\n".to_string();
+            for (scope_name, memories) in &exported {
+                output.push_str(&format!("\n## {} ({})\n", scope_name, memories.len()));
+                let mut current_type = "";
+                for m in memories {
+                    if m.mem_type != current_type {
+                        current_type = &m.mem_type;
+                        output.push_str(&format!("\n### {}\n", current_type));
+                    }
+                    output.push_str(&format!(
+                        "\n#### `{}`{}\n\n- Tags: {}\n- Created: {}\n- Updated: {}\n{}\n{}\n",
+                        m.id,
+                        if m.archived { " (archived)" } else { "" },
+                        if m.tags.is_empty() { "-" } else { &m.tags },
+                        m.created_at,
+                        m.updated_at,
+                        m.metadata.as_ref().map(|v| format!("- Metadata: {}\n", v)).unwrap_or_default(),
+                        m.content,
+                    ));
+                }
+            }
+            output
+        } else {
+            let items: Vec<Scoped<storage::MemoryDetail>> = exported
+                .iter()
+                .flat_map(|(scope, memories)| memories.iter().map(move |m| Scoped { scope, item: m }))
+                .collect();
+            serde_json::to_string_pretty(&serde_json::json!({ "memories": items }))
+                .unwrap_or_default()
+        };
+
+        if let Some(path) = params.output_path.as_deref().filter(|p| !p.is_empty()) {
+            return Ok(CallToolResult::success(vec![Content::text(
+                match std::fs::write(path, &body) {
+                    Ok(()) => format!("Exported {} memories to {}", total, path),
+                    Err(e) => format!("Error: cannot write {}: {}", path, e),
+                },
+            )]));
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(body)]))
+    }
+
+    #[tool(description = "Delete a memory by ID. Moves it to the trash by default (recoverable with memory_restore); purge=true removes it permanently.")]
+    fn memory_delete(
+        &self,
+        Parameters(mut params): Parameters<DeleteParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        if params.id.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: ID required.",
+            )]));
+        }
+
+        let db_path = match self.resolve_save_db(&params.scope) {
+            Some(p) => p,
+            None => {
+                return Ok(CallToolResult::success(vec![Content::text(
+                    "Error: project not detected.",
+                )]));
+            }
+        };
+
+        let conn = match self.db.get(&db_path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: {}",
+                    e
+                ))]));
+            }
+        };
+
+        let deleted = if params.purge {
+            storage::purge_memory(&conn, &params.id)
+        } else {
+            storage::soft_delete_memory(&conn, &params.id)
+        };
+
+        if deleted.unwrap_or(false) {
+            self.ann.remove_memory(&db_path.to_string_lossy(), &conn, &params.id);
+            let output = if params.purge {
+                format!("Memory {} permanently deleted.", params.id)
+            } else {
+                format!(
+                    "Memory {} moved to the trash. Use memory_restore to undo, or memory_delete with purge=true to remove it for good.",
+                    params.id
+                )
+            };
+            Ok(CallToolResult::success(vec![Content::text(output)]))
+        } else {
+            Ok(CallToolResult::success(vec![Content::text(format!(
+                "Memory {} not found.",
+                params.id
+            ))]))
+        }
+    }
+
+    #[tool(description = "Delete all memories of a scope matching a type and/or tags (same tag matching as memory_list). Dry run unless confirm=true: reports how many would be deleted. Moves them to the trash unless purge=true.")]
+    fn memory_delete_bulk(
+        &self,
+        Parameters(mut params): Parameters<DeleteBulkParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        let filters = search::SearchFilters {
+            mem_type: params.r#type.clone(),
+            tags: storage::parse_tag_filter(params.tags.as_deref().unwrap_or("")),
+            ..Default::default()
+        };
+        if filters.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: type or tags required (refusing to delete a whole scope).",
+            )]));
+        }
+
+        let db_path = match self.resolve_save_db(&params.scope) {
+            Some(p) => p,
+            None => {
+                return Ok(CallToolResult::success(vec![Content::text(
+                    "Error: project not detected.",
+                )]));
+            }
+        };
+
+        let conn = match self.db.get(&db_path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: {}",
+                    e
+                ))]));
+            }
+        };
+
+        let ids = match storage::delete_matching(&conn, &filters, params.purge, params.confirm) {
+            Ok(ids) => ids,
+            Err(e) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: {}",
+                    e
+                ))]));
+            }
+        };
+
+        if !params.confirm {
+            return Ok(CallToolResult::success(vec![Content::text(format!(
+                "Dry run: {} memories in '{}' would be deleted. Call again with confirm=true to delete them.",
+                ids.len(),
+                params.scope
+            ))]));
+        }
+
+        let db_key = db_path.to_string_lossy();
+        for id in &ids {
+            self.ann.remove_memory(&db_key, &conn, id);
+        }
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "{} memories {} from '{}'.",
+            ids.len(),
+            if params.purge { "permanently deleted" } else { "moved to the trash" },
+            params.scope
+        ))]))
+    }
+
+    #[tool(description = "Restore a memory from the trash (undo a memory_delete without purge).")]
+    fn memory_restore(
+        &self,
+        Parameters(mut params): Parameters<RestoreParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        if params.id.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: ID required.",
+            )]));
+        }
+
+        let db_path = match self.resolve_save_db(&params.scope) {
+            Some(p) => p,
+            None => {
+                return Ok(CallToolResult::success(vec![Content::text(
+                    "Error: project not detected.",
+                )]));
+            }
+        };
+
+        let conn = match self.db.get(&db_path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: {}",
+                    e
+                ))]));
+            }
+        };
+
+        // O índice ANN percebe a volta pela contagem de embeddings e se reconstrói
+        match storage::restore_memory(&conn, &params.id) {
+            Ok(true) => Ok(CallToolResult::success(vec![Content::text(format!(
+                "Memory {} restored.",
+                params.id
+            ))])),
+            Ok(false) => Ok(CallToolResult::success(vec![Content::text(format!(
+                "Memory {} not found in the trash.",
+                params.id
+            ))])),
+            Err(e) => Ok(CallToolResult::success(vec![Content::text(format!(
+                "Error: {}",
+                e
+            ))])),
+        }
+    }
+
+    #[tool(description = "Fetch a single memory by ID with its full content, tags and timestamps.")]
+    fn memory_get(
+        &self,
+        Parameters(mut params): Parameters<GetParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        if params.id.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: ID required.",
+            )]));
+        }
+
+        // Primeiro match na ordem de resolução do scope
+        for (scope_name, db_path) in storage::resolve_scope_dbs(&params.scope, &self.paths) {
+            if !db_path.exists() && scope_name == "project" {
+                continue;
+            }
+            let conn = match self.db.get(&db_path) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            if let Ok(Some(m)) = storage::get_memory(&conn, &params.id) {
+                let mut output = format!(
+                    "## Memory `{}`\n\n- Scope: {}\n- Type: {}\n- Tags: {}\n- Created: {}\n- Updated: {}\n- Importance: {:.2}\n- Accesses: {}\n- Embedding: {}\n",
+                    m.id,
+                    scope_name,
+                    m.mem_type,
+                    if m.tags.is_empty() { "-" } else { &m.tags },
+                    m.created_at,
+                    m.updated_at,
+                    m.importance,
+                    m.access_count,
+                    if m.has_embedding { "indexed" } else { "pending" },
+                );
+                if m.archived {
+                    output.push_str("- Archived: yes\n");
+                }
+                if let Some(deleted_at) = &m.deleted_at {
+                    output.push_str(&format!("- Deleted: {} (in trash, use memory_restore)\n", deleted_at));
+                }
+                if let Some(expires_at) = &m.expires_at {
+                    output.push_str(&format!("- Expires: {}\n", expires_at));
+                }
+                if let Some(metadata) = &m.metadata {
+                    output.push_str(&format!("- Metadata: {}\n", metadata));
+                }
+                output.push_str(&format!("\n{}\n", m.content));
+                if params.include_links {
+                    let links = storage::get_links(&conn, &m.id);
+                    if !links.is_empty() {
+                        output.push_str(&format!("\n### Links ({})\n\n", links.len()));
+                        output.push_str(&format_links(&links));
+                    }
+                }
+                return Ok(CallToolResult::success(vec![Content::text(output)]));
+            }
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Memory {} not found in scope '{}'.",
+            params.id, params.scope
+        ))]))
+    }
+
+    #[tool(description = "Find memories similar to a given memory (\"more like this\"), by its stored embedding. Falls back to a hybrid text search on its content if it has no embedding yet.")]
+    async fn memory_related(
+        &self,
+        Parameters(mut params): Parameters<RelatedParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        if params.id.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: ID required.",
+            )]));
+        }
+
+        // Alvo pode estar em qualquer scope, independente de onde se busca
+        let mut target = None;
+        for (_, db_path) in storage::resolve_scope_dbs("all", &self.paths) {
+            if !db_path.exists() {
+                continue;
+            }
+            let Ok(conn) = self.db.get(&db_path) else {
+                continue;
+            };
+            if let Ok(Some(m)) = storage::get_memory(&conn, &params.id) {
+                let emb = storage::get_embedding_blob(&conn, &params.id)
+                    .and_then(|b| embedding::decode_embedding(&b, self.embedding_engine.dimension()));
+                target = Some((m, emb));
+                break;
+            }
+        }
+        let Some((memory, stored_emb)) = target else {
+            return Ok(CallToolResult::success(vec![Content::text(format!(
+                "Memory {} not found.",
+                params.id
+            ))]));
+        };
+
+        let (results, method) = match stored_emb {
+            Some(emb) => (
+                self.related_by_embedding(emb, &params.scope, params.limit, &params.id).await,
+                "embedding",
+            ),
+            None => {
+                let (mut results, _) = self
+                    .do_search_parallel(
+                        memory.content.clone(),
+                        storage::resolve_scope_dbs(&params.scope, &self.paths),
+                        params.limit + 1,
+                        Default::default(),
+                        SearchOptions::default(),
+                    )
+                    .await;
+                results.retain(|(_, r)| r.id != params.id);
+                results.truncate(params.limit);
+                (results, "hybrid text (no embedding yet)")
+            }
+        };
+
+        if results.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(format!(
+                "No memories related to {} found.",
+                params.id
+            ))]));
+        }
+
+        let mut output = format!(
+            "## Related to `{}` ({}, via {})\n\n",
+            params.id,
+            results.len(),
+            method
+        );
+        for (scope, r) in &results {
+            output.push_str(&format!(
+                "**[{}] {}** `{}` (relevance: {})\n{}\n",
+                scope.to_uppercase(),
+                r.mem_type,
+                r.id,
+                r.relevance,
+                r.content
+            ));
+            if !r.tags.is_empty() {
+                output.push_str(&format!("_Tags: {}_\n", r.tags));
+            }
+            output.push('\n');
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(description = "Edit an existing memory in place (keeps its ID). Changing content re-queues the embedding; changing only tags keeps it.")]
+    fn memory_update(
+        &self,
+        Parameters(mut params): Parameters<UpdateParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        if params.id.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: ID required.",
+            )]));
+        }
+        if params.content.is_none() && params.tags.is_none() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: nothing to update (provide content and/or tags).",
+            )]));
+        }
+        if params.content.as_deref() == Some("") {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: empty content.",
+            )]));
+        }
+        if let Some(content) = params.content.as_deref() {
+            if let Err(e) = storage::check_content_len(content, storage::max_content_len_from_env()) {
+                return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}.", e))]));
+            }
+        }
+
+        let db_path = match self.resolve_save_db(&params.scope) {
+            Some(p) => p,
+            None => {
+                return Ok(CallToolResult::success(vec![Content::text(
+                    "Error: project not detected.",
+                )]));
+            }
+        };
+
+        let conn = match self.db.get(&db_path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: {}",
+                    e
+                ))]));
+            }
+        };
+
+        match storage::update_memory(
+            &conn,
+            &params.id,
+            params.content.as_deref(),
+            params.tags.as_deref(),
+        ) {
+            Ok(Some(result)) => {
+                let embedding_info = match params.content.as_deref() {
+                    Some(content) if result.content_changed => {
+                        self.ann.remove_memory(&db_path.to_string_lossy(), &conn, &params.id);
+                        if self.queue_embedding(&db_path, &params.id, content) {
+                            "re-queued"
+                        } else {
+                            "cleared, deferred: worker queue full (retried automatically)"
+                        }
+                    }
+                    // Com MCP_EMBED_TAGS as tags fazem parte do texto do embedding
+                    _ if params.tags.is_some() && embedding::embed_tags_from_env() => {
+                        let record = storage::get_memory_record(&conn, &params.id).ok().flatten();
+                        match record {
+                            Some(record) if self.queue_embedding(&db_path, &params.id, &record.content) => "re-queued (tags)",
+                            _ => "kept",
+                        }
+                    }
+                    _ => "kept",
+                };
+                Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Memory {} updated ({})\n- Content: {}\n- Tags: {}\n- Embedding: {}",
+                    params.id,
+                    params.scope,
+                    if result.content_changed { "changed" } else { "unchanged" },
+                    result.tags,
+                    embedding_info
+                ))]))
+            }
+            Ok(None) => Ok(CallToolResult::success(vec![Content::text(format!(
+                "Memory {} not found in scope '{}'.",
+                params.id, params.scope
+            ))])),
+            Err(e) => Ok(CallToolResult::success(vec![Content::text(format!(
+                "Error: {}",
+                e
+            ))])),
+        }
+    }
+
+    #[tool(description = "Move a memory to another scope (e.g. project -> global), keeping its ID, timestamps, importance and embeddings. Links to other memories are not carried over.")]
+    fn memory_move(
         &self,
-        Parameters(params): Parameters<DeleteParams>,
+        Parameters(mut params): Parameters<MoveParams>,
     ) -> Result<CallToolResult, McpError> {
         if params.id.is_empty() {
             return Ok(CallToolResult::success(vec![Content::text(
                 "Error: ID required.",
             )]));
         }
+        for scope in [&mut params.from_scope, &mut params.to_scope] {
+            *scope = match write_scope_or_error(scope) {
+                Ok(canonical) => canonical.to_string(),
+                Err(e) => return Ok(e),
+            };
+        }
+        if params.from_scope == params.to_scope {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: from_scope and to_scope are the same.",
+            )]));
+        }
 
-        let db_path = match self.resolve_save_db(&params.scope) {
-            Some(p) => p,
-            None => {
-                return Ok(CallToolResult::success(vec![Content::text(
-                    "Error: project not detected.",
-                )]));
+        let (Some(src_path), Some(dst_path)) =
+            (self.resolve_save_db(&params.from_scope), self.resolve_save_db(&params.to_scope))
+        else {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: project not detected.",
+            )]));
+        };
+        let (src, dst) = match (self.db.get(&src_path), self.db.get(&dst_path)) {
+            (Ok(s), Ok(d)) => (s, d),
+            (Err(e), _) | (_, Err(e)) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: {}",
+                    e
+                ))]));
             }
         };
 
-        let conn = match storage::init_db(&db_path) {
-            Ok(c) => c,
+        let moved = match storage::move_memory(&src, &dst, &params.id) {
+            Ok(Some(m)) => m,
+            Ok(None) => {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Memory {} not found in scope '{}'.",
+                    params.id, params.from_scope
+                ))]));
+            }
             Err(e) => {
                 return Ok(CallToolResult::success(vec![Content::text(format!(
-                    "Error: {}",
+                    "Error moving memory: {}",
                     e
                 ))]));
             }
         };
 
-        let deleted = conn
-            .execute(
-                "DELETE FROM memories WHERE id = ?",
-                rusqlite::params![params.id],
-            )
-            .unwrap_or(0);
+        // Índice da origem perde a memória; o do destino se reconstrói na próxima busca
+        self.ann.remove_memory(&src_path.to_string_lossy(), &src, &params.id);
+        let queued = !moved.has_embedding && self.queue_embedding(&dst_path, &params.id, &moved.content);
 
-        if deleted > 0 {
-            Ok(CallToolResult::success(vec![Content::text(format!(
-                "Memory {} deleted.",
-                params.id
-            ))]))
-        } else {
-            Ok(CallToolResult::success(vec![Content::text(format!(
-                "Memory {} not found.",
-                params.id
-            ))]))
+        let mut output = format!(
+            "Memory {} moved: {} -> {} ({})",
+            params.id,
+            params.from_scope,
+            params.to_scope,
+            dst_path.display()
+        );
+        if moved.chunks > 0 {
+            output.push_str(&format!("\n- Chunks: {}", moved.chunks));
+        }
+        if moved.model_vectors > 0 {
+            output.push_str(&format!("\n- Extra model vectors: {}", moved.model_vectors));
+        }
+        if queued {
+            output.push_str("\n- Embedding: queued");
+        }
+        if moved.links_dropped > 0 {
+            output.push_str(&format!("\n- Links dropped: {}", moved.links_dropped));
         }
+        Ok(CallToolResult::success(vec![Content::text(output)]))
     }
 
-    #[tool(description = "Reindex all memories that don't have embeddings yet.")]
+    #[tool(description = "Reindex all memories that don't have embeddings yet. If the embedding model changed (MCP_EMBEDDING_MODEL) or MCP_EMBED_TAGS was toggled, existing embeddings are discarded and rebuilt.")]
     fn memory_reindex(
         &self,
-        Parameters(params): Parameters<ReindexParams>,
+        Parameters(mut params): Parameters<ReindexParams>,
     ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        if !self.embedding_engine.is_available() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "Error: embeddings are disabled (text-only mode: the model failed to load). Restart the server once it can load the model.",
+            )]));
+        }
         let dbs = storage::resolve_scope_dbs(&params.scope, &self.paths);
         let mut total = 0usize;
-        let mut dropped = 0usize;
+        let mut deferred = 0usize;
         let mut details = Vec::new();
 
         for (scope_name, db_path) in dbs {
             if !db_path.exists() && scope_name == "project" {
                 continue;
             }
-            let conn = match storage::init_db(&db_path) {
+            let conn = match self.db.get(&db_path) {
                 Ok(c) => c,
                 Err(_) => continue,
             };
-            let unindexed = storage::get_unindexed_memories(&conn).unwrap_or_default();
+            // Modelo trocado: vetores antigos são incompatíveis, descarta e refaz tudo
+            let active_model = self.embedding_engine.model_name();
+            if let Some(stored) = storage::get_meta(&conn, storage::EMBEDDING_MODEL_KEY) {
+                if stored != active_model {
+                    match storage::reset_embeddings(&conn, active_model) {
+                        Ok(cleared) => details.push(format!(
+                            "- {}: model changed {} → {}, cleared {} embeddings",
+                            scope_name, stored, active_model, cleared
+                        )),
+                        Err(e) => tracing::warn!("Cannot reset embeddings in {}: {}", scope_name, e),
+                    }
+                }
+            }
+            // MCP_EMBED_TAGS trocado: o texto dos vetores mudou, refaz tudo (sem registro = "content")
+            let active_text = embedding::embedding_text_mode(embedding::embed_tags_from_env());
+            let stored_text = storage::get_meta(&conn, storage::EMBEDDING_TEXT_KEY).unwrap_or_else(|| "content".to_string());
+            if stored_text != active_text {
+                let reset = storage::reset_embeddings(&conn, active_model)
+                    .and_then(|cleared| storage::set_meta(&conn, storage::EMBEDDING_TEXT_KEY, active_text).map(|_| cleared));
+                match reset {
+                    Ok(cleared) => details.push(format!(
+                        "- {}: embedding text changed {} → {}, cleared {} embeddings",
+                        scope_name, stored_text, active_text, cleared
+                    )),
+                    Err(e) => tracing::warn!("Cannot reset embeddings in {}: {}", scope_name, e),
+                }
+            }
+            // MCP_CODE_PREPROCESS trocado: vetores de código vieram de outro texto (sem registro = "off")
+            let active_code = embedding::code_preprocess_mode(self.embedding_engine.code_preprocessing());
+            let stored_code = storage::get_meta(&conn, storage::EMBEDDING_CODE_KEY).unwrap_or_else(|| "off".to_string());
+            if stored_code != active_code {
+                let reset = storage::reset_embeddings(&conn, active_model)
+                    .and_then(|cleared| storage::set_meta(&conn, storage::EMBEDDING_CODE_KEY, active_code).map(|_| cleared));
+                match reset {
+                    Ok(cleared) => details.push(format!(
+                        "- {}: code preprocessing changed {} → {}, cleared {} embeddings",
+                        scope_name, stored_code, active_code, cleared
+                    )),
+                    Err(e) => tracing::warn!("Cannot reset embeddings in {}: {}", scope_name, e),
+                }
+            }
+            // Sobras de outro modelo (DB sem modelo registrado): refaz só as incompatíveis
+            match storage::clear_dimension_mismatches(&conn, self.embedding_engine.dimension()) {
+                Ok(0) => {}
+                Ok(cleared) => details.push(format!(
+                    "- {}: cleared {} embeddings with unexpected dimension",
+                    scope_name, cleared
+                )),
+                Err(e) => tracing::warn!("Cannot clear mismatched embeddings in {}: {}", scope_name, e),
+            }
+            let mut unindexed = storage::get_unindexed_memories(&conn).unwrap_or_default();
+            // Modelos extras adicionados depois: o job refaz o principal pelo cache e grava os extras
+            let mut seen: std::collections::HashSet<String> = unindexed.iter().map(|(id, _)| id.clone()).collect();
+            for extra in self.embedding_engine.extra_models() {
+                for (id, content) in storage::missing_model_embeddings(&conn, extra.model_name()).unwrap_or_default() {
+                    if seen.insert(id.clone()) {
+                        unindexed.push((id, content));
+                    }
+                }
+            }
             let count = unindexed.len();
             let mut queued_here = 0usize;
             for (id, content) in unindexed {
                 if self.queue_embedding(&db_path, &id, &content) {
                     queued_here += 1;
                 } else {
-                    dropped += 1;
+                    deferred += 1;
                 }
             }
             total += count;
-            details.push(format!("- {}: {} queued, {} dropped", scope_name, queued_here, count.saturating_sub(queued_here)));
+            details.push(format!("- {}: {} queued, {} deferred", scope_name, queued_here, count.saturating_sub(queued_here)));
         }
 
         Ok(CallToolResult::success(vec![Content::text(format!(
-            "## Reindex Started\n\nQueued {} memories for embedding (f16).\n- Dropped: {}\n{}\n\nWorker processing in background.",
+            "## Reindex Started\n\nQueued {} memories for embedding ({}).\n- Deferred (queue full, retried automatically): {}\n{}\n\nWorker processing in background — check progress with memory_reindex_status.",
             total,
-            dropped,
+            self.embedding_engine.format().name(),
+            deferred,
             details.join("\n")
         ))]))
     }
 
-    #[tool(description = "Compact database: VACUUM + FTS rebuild + TTL cleanup + importance decay.")]
+    #[tool(description = "Show embedding worker progress: jobs queued, completed, remaining backlog and completion percentage.")]
+    fn memory_reindex_status(&self) -> Result<CallToolResult, McpError> {
+        let (queued, completed) = self.progress.snapshot();
+        let remaining = queued.saturating_sub(completed);
+        let percent = if queued == 0 {
+            100.0
+        } else {
+            completed.min(queued) as f64 * 100.0 / queued as f64
+        };
+        let state = if remaining == 0 { "idle" } else { "processing" };
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "## Reindex Status\n\n- Worker: {}\n- Queued: {}\n- Completed: {}\n- Remaining: {}\n- Progress: {:.1}%",
+            state, queued, completed, remaining, percent
+        ))]))
+    }
+
+    #[tool(description = "Diagnose the embedding setup in one call: embeds a fixed test string (dimension, norm, latency) when the model is loaded (load_model=true loads it first), shows the active model, indexed vs unindexed memories per scope, embedding cache size and whether the background worker is alive.")]
+    async fn memory_diagnostics(
+        &self,
+        Parameters(params): Parameters<DiagnosticsParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let engine = &self.embedding_engine;
+        let dim = engine.dimension();
+        let mut output = format!(
+            "## Diagnostics\n\n### Embedding\n- Model: {} (dimension {}, format {})\n",
+            engine.model_name(),
+            dim,
+            engine.format().name()
+        );
+        // Carregar o modelo leva segundos: só com pedido explícito, e fora do executor
+        let self_test = if engine.is_available() && !engine.is_loaded() && !params.load_model {
+            None
+        } else {
+            let engine = engine.clone();
+            Some(
+                tokio::task::spawn_blocking(move || engine.self_test())
+                    .await
+                    .unwrap_or_else(|e| Err(anyhow::anyhow!("self-test task failed: {}", e))),
+            )
+        };
+        match self_test {
+            None => output.push_str("- Self-test: skipped (model not loaded yet; load_model=true loads and tests it)\n"),
+            Some(Ok(test)) => output.push_str(&format!(
+                "- Self-test: {} — dimension {}, norm {:.4}, {} ms\n",
+                if test.healthy(dim) { "OK" } else { "FAILED" },
+                test.dimension,
+                test.norm,
+                test.millis
+            )),
+            Some(Err(e)) => output.push_str(&format!("- Self-test: FAILED — {}\n", e)),
+        }
+
+        output.push_str("\n### Corpus\n");
+        let project_db = MemoryPaths::project_db_path();
+        for (label, db_path) in [
+            ("global", Some(self.paths.global_db.clone())),
+            ("personality", Some(self.paths.personality_db.clone())),
+            ("project", project_db),
+        ] {
+            let Some(db_path) = db_path.filter(|p| p.exists()) else {
+                output.push_str(&format!("- {}: no database\n", label));
+                continue;
+            };
+            let conn = match self.db.get(&db_path) {
+                Ok(c) => c,
+                Err(e) => {
+                    output.push_str(&format!("- {}: cannot open ({})\n", label, e));
+                    continue;
+                }
+            };
+            let stats = storage::get_stats(&conn, dim);
+            output.push_str(&format!(
+                "- {}: {} memories, {} indexed, {} unindexed, {} failed (retrying), local cache {}\n",
+                label,
+                stats.total,
+                stats.indexed,
+                (stats.total - stats.indexed).max(0),
+                stats.embedding_failures,
+                stats.cache_entries
+            ));
+        }
+
+        let cache = match engine.cache().shared_entries() {
+            Some(n) => format!("{} entries (shared, {})", n, self.paths.cache_db.display()),
+            None => "per-DB only (shared cache.db unavailable)".to_string(),
+        };
+        output.push_str(&format!("\n### Embedding cache\n- {}\n", cache));
+
+        let (queued, completed) = self.progress.snapshot();
+        output.push_str(&format!(
+            "\n### Worker\n- Status: {}\n- Queued: {}\n- Completed: {}\n- Remaining: {}\n",
+            if self.job_sender.is_closed() { "stopped" } else { "alive" },
+            queued,
+            completed,
+            queued.saturating_sub(completed)
+        ));
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(description = "Compact database: VACUUM + FTS rebuild + deletion of expired memories (ttl_days) + TTL cleanup + importance decay + embedding cache pruning (MCP_CACHE_MAX_ENTRIES). dry_run=true previews the changes, including the conversation groups memory_consolidate would merge.")]
     fn memory_compact(
         &self,
-        Parameters(params): Parameters<CompactParams>,
+        Parameters(mut params): Parameters<CompactParams>,
     ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
         let db_path = match self.resolve_save_db(&params.scope) {
             Some(p) => p,
             None => {
@@ -615,7 +2690,7 @@ impl MemoryServer {
             }
         };
 
-        let conn = match storage::init_db(&db_path) {
+        let conn = match self.db.get(&db_path) {
             Ok(c) => c,
             Err(e) => {
                 return Ok(CallToolResult::success(vec![Content::text(format!(
@@ -625,11 +2700,47 @@ impl MemoryServer {
             }
         };
 
+        if params.dry_run {
+            let preview = match storage::compact_preview(&conn, &params.scope) {
+                Ok(p) => p,
+                Err(e) => return Ok(CallToolResult::success(vec![Content::text(format!("Error: {}", e))])),
+            };
+            let mut output = format!(
+                "## Compaction Preview ({}, dry run)\n\n- Expired memories to delete: {}\n- TTL would apply to: {} memories\n- Importance would decay: {}\n- Cache entries to prune: {}\n- Estimated space reclaimed by VACUUM: {:.1} KB\n",
+                params.scope,
+                preview.result.expired_pruned,
+                preview.result.ttl_applied,
+                preview.result.decayed,
+                preview.result.cache_pruned,
+                preview.reclaimable_bytes as f64 / 1024.0
+            );
+            let groups = consolidation::plan_conversation_groups(&conn);
+            if groups.is_empty() {
+                output.push_str("\nNo conversation groups ready for memory_consolidate (see MCP_CONSOLIDATE_MIN_SESSIONS).\n");
+            } else {
+                output.push_str(&format!(
+                    "\n### Conversations memory_consolidate would merge ({} projects)\n",
+                    groups.len()
+                ));
+                for group in &groups {
+                    output.push_str(&format!(
+                        "\n**{}**: {} sessions\n```\n{}\n```\n",
+                        group.project,
+                        group.session_ids.len(),
+                        group.summary
+                    ));
+                }
+            }
+            output.push_str("\nNothing was changed.");
+            return Ok(CallToolResult::success(vec![Content::text(output)]));
+        }
+
         match storage::compact_db(&conn, &params.scope) {
             Ok(result) => {
+                let shared_pruned = self.embedding_engine.cache().prune_shared();
                 Ok(CallToolResult::success(vec![Content::text(format!(
-                    "## Compaction Complete\n\n- TTL applied: {} memories\n- Importance decayed: {}\n- VACUUM + FTS rebuild done.",
-                    result.ttl_applied, result.decayed
+                    "## Compaction Complete\n\n- Expired memories deleted: {}\n- TTL applied: {} memories\n- Importance decayed: {}\n- Cache entries pruned: {}\n- VACUUM + FTS rebuild done.",
+                    result.expired_pruned, result.ttl_applied, result.decayed, result.cache_pruned + shared_pruned
                 ))]))
             }
             Err(e) => Ok(CallToolResult::success(vec![Content::text(format!(
@@ -638,11 +2749,15 @@ impl MemoryServer {
         }
     }
 
-    #[tool(description = "Consolidate memories: merge similar entries, summarize conversation sessions by project, archive old duplicates. Reduces noise and improves search quality.")]
+    #[tool(description = "Consolidate memories: merge similar entries, summarize conversation sessions by project, archive old duplicates. Projects with MCP_CONSOLIDATE_MIN_SESSIONS (default 5) conversations get one summary; the newest MCP_CONSOLIDATE_KEEP_RECENT (default 0) stay verbatim. Originals are archived, not deleted. Reduces noise and improves search quality.")]
     fn memory_consolidate(
         &self,
-        Parameters(params): Parameters<ConsolidateParams>,
+        Parameters(mut params): Parameters<ConsolidateParams>,
     ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
         let dbs = storage::resolve_scope_dbs(&params.scope, &self.paths);
         let mut total_result = consolidation::ConsolidationResult::default();
 
@@ -650,39 +2765,105 @@ impl MemoryServer {
             if !db_path.exists() && scope_name == "project" {
                 continue;
             }
-            let conn = match storage::init_db(&db_path) {
+            let conn = match self.db.get(&db_path) {
                 Ok(c) => c,
                 Err(_) => continue,
             };
             let result = consolidation::run_consolidation(&conn);
             total_result.conversations_consolidated += result.conversations_consolidated;
+            total_result.sessions_merged += result.sessions_merged;
             total_result.similar_merged += result.similar_merged;
             total_result.archived += result.archived;
         }
 
         Ok(CallToolResult::success(vec![Content::text(format!(
-            "## Consolidation Complete\n\n- Conversation groups consolidated: {}\n- Similar memories merged: {}\n- Total archived: {}",
+            "## Consolidation Complete\n\n- Conversation groups consolidated: {} ({} sessions)\n- Similar memories merged: {}\n- Total archived: {}",
             total_result.conversations_consolidated,
+            total_result.sessions_merged,
             total_result.similar_merged,
             total_result.archived,
         ))]))
     }
 
-    #[tool(description = "Create a manual link between two memories. Relations: relates_to, supersedes, derived_from.")]
+    #[tool(description = "Check that the full-text index matches the memories table (row counts per scope) and, with repair=true, rebuild the index where it drifted.")]
+    fn memory_verify(
+        &self,
+        Parameters(mut params): Parameters<VerifyParams>,
+    ) -> Result<CallToolResult, McpError> {
+        params.scope = match scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
+        let mut output = "## FTS Integrity\n\n".to_string();
+        let mut drifted = 0usize;
+
+        for (scope_name, db_path) in storage::resolve_scope_dbs(&params.scope, &self.paths) {
+            if !db_path.exists() && scope_name == "project" {
+                continue;
+            }
+            let conn = match self.db.get(&db_path) {
+                Ok(c) => c,
+                Err(e) => {
+                    output.push_str(&format!("- {}: error: {}\n", scope_name, e));
+                    continue;
+                }
+            };
+            let check = match storage::check_fts(&conn) {
+                Ok(c) => c,
+                Err(e) => {
+                    output.push_str(&format!("- {}: error: {}\n", scope_name, e));
+                    continue;
+                }
+            };
+            if check.in_sync() {
+                output.push_str(&format!("- {}: ok ({} rows)\n", scope_name, check.indexed));
+                continue;
+            }
+            drifted += 1;
+            output.push_str(&format!(
+                "- {}: drift — {} indexed, {} expected",
+                scope_name, check.indexed, check.expected
+            ));
+            if params.repair {
+                match storage::rebuild_fts(&conn).and_then(|_| storage::check_fts(&conn)) {
+                    Ok(after) => output.push_str(&format!("; rebuilt ({} rows)\n", after.indexed)),
+                    Err(e) => output.push_str(&format!("; rebuild failed: {}\n", e)),
+                }
+            } else {
+                output.push('\n');
+            }
+        }
+
+        if drifted > 0 && !params.repair {
+            output.push_str("\nRun again with repair=true to rebuild the index.");
+        }
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(description = "Create an explicit link between two memories in the same scope. Relations: relates_to, supersedes, derived_from, implements. Links show up in memory_get and (with include_links) memory_search, and are removed when either memory is deleted.")]
     fn memory_link(
         &self,
-        Parameters(params): Parameters<LinkParams>,
+        Parameters(mut params): Parameters<LinkParams>,
     ) -> Result<CallToolResult, McpError> {
+        params.scope = match write_scope_or_error(&params.scope) {
+            Ok(scope) => scope.to_string(),
+            Err(e) => return Ok(e),
+        };
         if params.from_id.is_empty() || params.to_id.is_empty() {
             return Ok(CallToolResult::success(vec![Content::text(
                 "Error: both from_id and to_id required.",
             )]));
         }
 
-        let valid_relations = ["relates_to", "supersedes", "derived_from"];
-        if !valid_relations.contains(&params.relation.as_str()) {
+        if !storage::LINK_RELATIONS.contains(&params.relation.as_str()) {
+            return Ok(CallToolResult::success(vec![Content::text(format!(
+                "Error: relation must be one of: {}",
+                storage::LINK_RELATIONS.join(", ")
+            ))]));
+        }
+        if params.from_id == params.to_id {
             return Ok(CallToolResult::success(vec![Content::text(
-                "Error: relation must be: relates_to, supersedes, or derived_from",
+                "Error: cannot link a memory to itself.",
             )]));
         }
 
@@ -695,7 +2876,7 @@ impl MemoryServer {
             }
         };
 
-        let conn = match storage::init_db(&db_path) {
+        let conn = match self.db.get(&db_path) {
             Ok(c) => c,
             Err(e) => {
                 return Ok(CallToolResult::success(vec![Content::text(format!(
@@ -705,6 +2886,15 @@ impl MemoryServer {
             }
         };
 
+        for id in [&params.from_id, &params.to_id] {
+            if !matches!(storage::get_memory(&conn, id), Ok(Some(_))) {
+                return Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Error: memory {} not found in scope '{}'.",
+                    id, params.scope
+                ))]));
+            }
+        }
+
         match storage::create_edge(&conn, &params.from_id, &params.to_id, &params.relation) {
             Ok(true) => Ok(CallToolResult::success(vec![Content::text(format!(
                 "Link created: {} --[{}]--> {}",
@@ -722,8 +2912,188 @@ impl MemoryServer {
     }
 }
 
-#[tool_handler]
+/// Query usada quando o prompt vem sem `topic`
+const RECALL_DEFAULT_QUERY: &str = "architecture decisions patterns conventions";
+
+/// Lista de memórias em markdown para embutir na mensagem do prompt
+fn prompt_memory_list(records: &[(String, storage::MemoryRecord)]) -> String {
+    let mut out = String::new();
+    for (scope, r) in records {
+        out.push_str(&format!(
+            "- [{}] ({}, {}) {}\n",
+            scope.to_uppercase(),
+            r.mem_type,
+            r.created_at.get(..10).unwrap_or(&r.created_at),
+            r.content.trim()
+        ));
+    }
+    out
+}
+
+fn search_hits_to_records(results: Vec<(String, search::SearchResult)>) -> Vec<(String, storage::MemoryRecord)> {
+    results
+        .into_iter()
+        .map(|(scope, r)| {
+            let record = storage::MemoryRecord {
+                id: r.id,
+                mem_type: r.mem_type,
+                content: r.content,
+                tags: r.tags,
+                created_at: r.created_at,
+                updated_at: None,
+            };
+            (scope, record)
+        })
+        .collect()
+}
+
+// Prompts: atalhos para os pickers dos clientes, sem precisar conhecer os nomes das tools
+#[prompt_router]
+impl MemoryServer {
+    #[prompt(
+        name = "recall-project-context",
+        description = "Load what the memory server knows about the current project (decisions, patterns, architecture) into the conversation"
+    )]
+    async fn recall_project_context(
+        &self,
+        Parameters(args): Parameters<RecallPromptArgs>,
+    ) -> Result<GetPromptResult, McpError> {
+        let topic = args.topic.as_deref().map(str::trim).filter(|t| !t.is_empty());
+        let dbs = self
+            .scope_dbs("both", args.project_path.as_deref())
+            .map_err(|e| McpError::invalid_params(e, None))?;
+        let (results, _) = self
+            .do_search_parallel(
+                topic.unwrap_or(RECALL_DEFAULT_QUERY).to_string(),
+                dbs,
+                10,
+                search::SearchFilters::default(),
+                SearchOptions::default(),
+            )
+            .await;
+        let results = search_hits_to_records(results);
+
+        let text = if results.is_empty() {
+            "No memories were found for this project yet. As we work, save decisions, patterns \
+             and bug fixes with `memory_save` (scope=project)."
+                .to_string()
+        } else {
+            format!(
+                "Context recalled from persistent memory{}:\n\n{}\n\
+                 Use this as background for the rest of the conversation. \
+                 Prefer project memories over personality ones when they conflict.",
+                topic.map(|t| format!(" about \"{}\"", t)).unwrap_or_default(),
+                prompt_memory_list(&results)
+            )
+        };
+        Ok(GetPromptResult {
+            description: Some(format!("{} memories recalled", results.len())),
+            messages: vec![PromptMessage::new_text(PromptMessageRole::User, text)],
+        })
+    }
+
+    #[prompt(
+        name = "summarize-decisions",
+        description = "Summarize the recorded decisions (optionally about a topic) and flag ones that contradict each other"
+    )]
+    async fn summarize_decisions(
+        &self,
+        Parameters(args): Parameters<DecisionsPromptArgs>,
+    ) -> Result<GetPromptResult, McpError> {
+        let topic = args.topic.as_deref().map(str::trim).filter(|t| !t.is_empty());
+        let scope = args.scope.unwrap_or_else(default_scope_all);
+        let dbs = self
+            .scope_dbs(&scope, None)
+            .map_err(|e| McpError::invalid_params(e, None))?;
+        let filters = search::SearchFilters {
+            mem_type: Some("decision".into()),
+            ..Default::default()
+        };
+        // Com topic: busca híbrida; sem topic: as decisões mais recentes de cada scope
+        let results = match topic {
+            Some(t) => search_hits_to_records(
+                self.do_search_parallel(t.to_string(), dbs, 20, filters, SearchOptions::default())
+                    .await
+                    .0,
+            ),
+            None => {
+                let mut all = Vec::new();
+                for (scope_name, db_path) in dbs {
+                    if !db_path.exists() && scope_name == "project" {
+                        continue;
+                    }
+                    let Ok(conn) = self.db.get(&db_path) else { continue };
+                    for m in storage::list_memories(&conn, &filters, 20, 0).unwrap_or_default() {
+                        all.push((scope_name.clone(), m));
+                    }
+                }
+                all.sort_by(|a, b| b.1.created_at.cmp(&a.1.created_at).then_with(|| a.1.id.cmp(&b.1.id)));
+                all.truncate(20);
+                all
+            }
+        };
+
+        let text = if results.is_empty() {
+            format!("No decisions are recorded in scope '{}' yet.", scope)
+        } else {
+            format!(
+                "Summarize the following recorded decisions{}. Group related ones, \
+                 keep the reasoning, and point out any that contradict or supersede each other.\n\n{}",
+                topic.map(|t| format!(" about \"{}\"", t)).unwrap_or_default(),
+                prompt_memory_list(&results)
+            )
+        };
+        Ok(GetPromptResult {
+            description: Some(format!("{} decisions found", results.len())),
+            messages: vec![PromptMessage::new_text(PromptMessageRole::User, text)],
+        })
+    }
+}
+
+// O que o #[tool_handler] geraria, com um span por chamada (tool, scope, request_id);
+// logs vão para stderr e não se misturam ao protocolo stdio
+#[prompt_handler]
 impl ServerHandler for MemoryServer {
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParams,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let request_id = next_request_id();
+        let scope = request
+            .arguments
+            .as_ref()
+            .and_then(|args| args.get("scope"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("-")
+            .to_string();
+        let span = tracing::info_span!("tool", name = %request.name, scope = %scope, request_id = %request_id);
+        let started = std::time::Instant::now();
+        let tcc = ToolCallContext::new(self, request, context);
+        let result = REQUEST_ID
+            .scope(request_id, self.tool_router.call(tcc))
+            .instrument(span.clone())
+            .await;
+        span.in_scope(|| debug!("Tool call finished in {} ms", started.elapsed().as_millis()));
+        result
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParams>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        Ok(ListToolsResult {
+            tools: self.tool_router.list_all(),
+            meta: None,
+            next_cursor: None,
+        })
+    }
+
+    fn get_tool(&self, name: &str) -> Option<Tool> {
+        self.tool_router.get(name).cloned()
+    }
+
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
             instructions: Some(
@@ -734,25 +3104,154 @@ impl ServerHandler for MemoryServer {
                  project-first consolidation, importance pre-filter on search, fixed temporal decay."
                     .into(),
             ),
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            capabilities: ServerCapabilities::builder().enable_tools().enable_prompts().build(),
             ..Default::default()
         }
     }
 }
 
+// ---- CLI ----
+
+const USAGE: &str = "\
+Usage:
+  mcp-memory-rust                       Run the MCP server (stdio, or HTTP with MCP_TRANSPORT=http)
+  mcp-memory-rust search <query> [options]
+                                        Run one search, print the results and exit
+  mcp-memory-rust --version | --help
+
+Search options:
+  --scope <scope>     global, project, personality, both, all (default: both)
+  --limit <n>         Max results (default: 5)
+  --project <path>    Project directory (default: current directory)
+  --json              Print JSON instead of markdown";
+
+/// O que fazer com os argumentos da linha de comando (sem argumentos: server MCP)
+#[derive(Debug, PartialEq)]
+enum Command {
+    Serve,
+    Version,
+    Help,
+    Search {
+        query: String,
+        scope: Option<String>,
+        limit: Option<usize>,
+        project: Option<String>,
+        json: bool,
+    },
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> std::result::Result<Command, String> {
+    let Some(first) = args.next() else {
+        return Ok(Command::Serve);
+    };
+    match first.as_str() {
+        "-V" | "--version" => return Ok(Command::Version),
+        "-h" | "--help" | "help" => return Ok(Command::Help),
+        "search" => {}
+        other => return Err(format!("unknown command '{}'", other)),
+    }
+
+    let (mut query, mut scope, mut limit, mut project, mut json) = (Vec::new(), None, None, None, false);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--scope" => scope = Some(value("--scope")?),
+            "--limit" => {
+                let raw = value("--limit")?;
+                limit = Some(raw.parse().map_err(|_| format!("invalid --limit '{}'", raw))?);
+            }
+            "--project" => project = Some(value("--project")?),
+            "--json" => json = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option '{}'", flag)),
+            word => query.push(word.to_string()),
+        }
+    }
+    if query.is_empty() {
+        return Err("search needs a query".into());
+    }
+    Ok(Command::Search { query: query.join(" "), scope, limit, project, json })
+}
+
+/// Uma busca pelo mesmo caminho da tool `memory_search`, sem subir o loop MCP
+async fn run_cli_search(command: Command) -> Result<()> {
+    let Command::Search { query, scope, limit, project, json } = command else {
+        return Ok(());
+    };
+    let paths = MemoryPaths::new()?;
+    let engine = match EmbeddingEngine::new() {
+        Ok(engine) => engine.with_cache(embedding::EmbeddingCache::open(&paths.cache_db)),
+        Err(_) => EmbeddingEngine::text_only(),
+    };
+    // Sem worker: a busca não enfileira embeddings
+    let (job_sender, _) = mpsc::channel(1);
+    let mut server = MemoryServer::new(
+        paths,
+        Arc::new(engine),
+        job_sender,
+        Arc::new(JobProgress::default()),
+        Arc::new(ann::AnnRegistry::new()),
+    );
+    // Busca manual no terminal não é recall do agente: não mexe em importance
+    server.track_access = false;
+
+    let mut params = serde_json::json!({ "query": query, "format": if json { "json" } else { "markdown" } });
+    if let Some(scope) = scope {
+        params["scope"] = scope.into();
+    }
+    if let Some(limit) = limit {
+        params["limit"] = limit.into();
+    }
+    if let Some(project) = project {
+        params["project_path"] = project.into();
+    }
+    let params: SearchParams = serde_json::from_value(params)?;
+    let result = server
+        .memory_search(Parameters(params))
+        .await
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    for content in &result.content {
+        if let Some(text) = content.as_text() {
+            println!("{}", text.text);
+        }
+    }
+    Ok(())
+}
+
 // ---- Main ----
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let command = match parse_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    match command {
+        Command::Version => {
+            println!("mcp-memory-rust {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Command::Help => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // No modo CLI só avisos vão para o stderr; o server loga em INFO
+    let level = if command == Command::Serve { tracing::Level::INFO } else { tracing::Level::WARN };
     tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::INFO.into()),
-        )
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env().add_directive(level.into()))
         .with_writer(std::io::stderr)
         .with_ansi(false)
         .init();
 
+    if command != Command::Serve {
+        return run_cli_search(command).await;
+    }
+
     info!("MCP Memory Server (Rust) v0.3 Starting...");
 
     let paths = MemoryPaths::new()?;
@@ -776,58 +3275,86 @@ async fn main() -> Result<()> {
     drop(conn_personality);
 
     // Embedding engine com lazy-load: o modelo só carrega quando houver trabalho real.
-    let engine = Arc::new(EmbeddingEngine::new()?);
-
-    // Background worker
-    let job_sender = embedding::start_background_worker(engine.clone());
-
-    // Auto-reindex: enfileirar memórias sem embedding
-    let mut startup_dropped = 0usize;
-    for (id, content) in &unindexed_personality {
-        if job_sender.try_send(EmbeddingJob {
-            db_path: paths.personality_db.to_string_lossy().to_string(),
-            record_id: id.clone(),
-            content: content.clone(),
-        }).is_err() {
-            startup_dropped += 1;
+    // Se nem o engine sobe, o server continua em modo só texto (FTS)
+    let engine = match EmbeddingEngine::new() {
+        Ok(engine) => {
+            let extra_models = embedding::extra_models_from_env(engine.model_name());
+            engine
+                .with_cache(embedding::EmbeddingCache::open(&paths.cache_db))
+                .with_workers(embedding::workers_from_env())
+                .with_extra_models(extra_models)
         }
+        Err(e) => {
+            tracing::warn!("Embedding engine unavailable, running text-only: {}", e);
+            EmbeddingEngine::text_only()
+        }
+    };
+    let engine = Arc::new(engine);
+
+    // Índices ANN por DB, compartilhados entre busca e worker
+    let ann = Arc::new(ann::AnnRegistry::new());
+
+    // Background worker. O retry já começa varrendo os DBs conhecidos: falhas
+    // persistidas de uma execução anterior não esperam um job novo do mesmo DB
+    let progress = Arc::new(JobProgress::default());
+    for db_path in [Some(paths.global_db.clone()), Some(paths.personality_db.clone()), MemoryPaths::project_db_path()]
+        .into_iter()
+        .flatten()
+        .filter(|p| p.exists())
+    {
+        progress.watch_db(&db_path.to_string_lossy());
     }
-    for (id, content) in &unindexed_global {
-        if job_sender.try_send(EmbeddingJob {
-            db_path: paths.global_db.to_string_lossy().to_string(),
-            record_id: id.clone(),
-            content: content.clone(),
-        }).is_err() {
-            startup_dropped += 1;
+    let job_sender = embedding::start_background_worker(engine.clone(), ann.clone(), progress.clone());
+
+    // Auto-reindex: enfileirar memórias sem embedding
+    let mut startup_deferred = 0usize;
+    for (db_path, unindexed) in [
+        (&paths.personality_db, &unindexed_personality),
+        (&paths.global_db, &unindexed_global),
+    ] {
+        for (id, content) in unindexed {
+            let job = EmbeddingJob {
+                db_path: db_path.to_string_lossy().to_string(),
+                record_id: id.clone(),
+                content: content.clone(),
+                request_id: String::new(),
+            };
+            if !embedding::enqueue_job(&job_sender, &progress, job) {
+                startup_deferred += 1;
+            }
         }
     }
     if total_unindexed > 0 {
         info!(
-            "Queued {} unindexed memories for background embedding (dropped: {})",
-            total_unindexed.saturating_sub(startup_dropped),
-            startup_dropped
+            "Queued {} unindexed memories for background embedding (deferred: {})",
+            total_unindexed.saturating_sub(startup_deferred),
+            startup_deferred
         );
     }
 
-    let server = MemoryServer::new(paths, engine, job_sender);
+    let dim = engine.dimension();
+    let maintenance_engine = engine.clone();
+    let server = MemoryServer::new(paths, engine, job_sender, progress, ann.clone());
     let maintenance_paths = (
         server.paths.global_db.clone(),
         server.paths.personality_db.clone(),
     );
 
-    info!("Search: hybrid (vector=0.7, text=0.3) + importance + graph 1-hop");
-    info!("Embeddings: f16 compressed (50% less storage)");
+    info!(
+        "Search: hybrid, {} fusion (vector={:.2}, text={:.2}) + importance + graph 1-hop",
+        server.scoring.fusion.name(),
+        server.scoring.weights.vector,
+        server.scoring.weights.text
+    );
+    info!("Embeddings: {} (MCP_EMBEDDING_FORMAT)", server.embedding_engine.format().name());
+    info!("Embedding workers: {} (MCP_EMBEDDING_WORKERS)", server.embedding_engine.workers());
     info!("Auto-tagging: ~100 tech keywords");
     info!("Dedup: Jaccard threshold=0.85");
-    info!("Scope weights: project=1.0, personality=0.85, global=0.7");
+    info!("Scope weights: {}", server.scoring.scope_weights.describe());
+    info!("Temporal decay: {}", server.scoring.decay.describe());
 
-    let service = server
-        .serve(stdio())
-        .await
-        .inspect_err(|e| tracing::error!("Erro ao iniciar server: {:?}", e))
-        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let transport = Transport::from_env();
 
-    info!("MCP server v0.3 rodando via stdio");
     tokio::spawn(async move {
         let _ = tokio::task::spawn_blocking(move || {
             let (global_db, personality_db) = maintenance_paths;
@@ -838,13 +3365,14 @@ async fn main() -> Result<()> {
                     info!("Migrated {} global embeddings to f16", migrated_global);
                 }
                 if let Some(result) = maintenance_global {
-                    if result.ttl_applied > 0 || result.decayed > 0 {
+                    if result.expired_pruned > 0 || result.ttl_applied > 0 || result.decayed > 0 || result.cache_pruned > 0 {
                         info!(
-                            "Auto-maintenance (global): TTL={}, decayed={}",
-                            result.ttl_applied, result.decayed
+                            "Auto-maintenance (global): expired={}, TTL={}, decayed={}, cache pruned={}",
+                            result.expired_pruned, result.ttl_applied, result.decayed, result.cache_pruned
                         );
                     }
                 }
+                ann.build(&global_db.to_string_lossy(), &conn_global, dim);
             }
             if let Ok(conn_personality) = storage::init_db(&personality_db) {
                 let migrated_personality = embedding::migrate_embeddings_to_f16(&conn_personality);
@@ -853,18 +3381,157 @@ async fn main() -> Result<()> {
                     info!("Migrated {} personality embeddings to f16", migrated_personality);
                 }
                 if let Some(result) = maintenance_personality {
-                    if result.ttl_applied > 0 || result.decayed > 0 {
+                    if result.expired_pruned > 0 || result.ttl_applied > 0 || result.decayed > 0 || result.cache_pruned > 0 {
                         info!(
-                            "Auto-maintenance (personality): TTL={}, decayed={}",
-                            result.ttl_applied, result.decayed
+                            "Auto-maintenance (personality): expired={}, TTL={}, decayed={}, cache pruned={}",
+                            result.expired_pruned, result.ttl_applied, result.decayed, result.cache_pruned
                         );
                     }
                 }
+                ann.build(&personality_db.to_string_lossy(), &conn_personality, dim);
+            }
+            let shared_pruned = maintenance_engine.cache().prune_shared();
+            if shared_pruned > 0 {
+                info!("Auto-maintenance (shared cache): pruned={}", shared_pruned);
             }
         })
         .await;
     });
-    service.waiting().await?;
+
+    match transport {
+        Transport::Stdio => {
+            let service = server
+                .serve(stdio())
+                .await
+                .inspect_err(|e| tracing::error!("Erro ao iniciar server: {:?}", e))
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+            info!("MCP server v0.3 rodando via stdio");
+            service.waiting().await?;
+        }
+        Transport::Http(addr) => serve_http(server, &addr).await?,
+    }
 
     Ok(())
 }
+
+const DEFAULT_HTTP_ADDR: &str = "127.0.0.1:8765";
+
+/// Transporte MCP escolhido por `MCP_TRANSPORT` (stdio por padrão)
+enum Transport {
+    Stdio,
+    /// Streamable HTTP (POST + SSE) no endereço de `MCP_HTTP_ADDR`
+    Http(String),
+}
+
+impl Transport {
+    fn from_env() -> Self {
+        match std::env::var("MCP_TRANSPORT").ok().as_deref().map(str::trim) {
+            Some(t) if t.eq_ignore_ascii_case("http") => Transport::Http(
+                std::env::var("MCP_HTTP_ADDR").unwrap_or_else(|_| DEFAULT_HTTP_ADDR.to_string()),
+            ),
+            Some(t) if !t.is_empty() && !t.eq_ignore_ascii_case("stdio") => {
+                tracing::warn!("MCP_TRANSPORT={} desconhecido, usando stdio", t);
+                Transport::Stdio
+            }
+            _ => Transport::Stdio,
+        }
+    }
+}
+
+/// Serve o MCP via streamable HTTP. Cada sessão ganha um clone do server:
+/// engine, fila do worker, pool de conexões e ANN são `Arc` compartilhados.
+async fn serve_http(server: MemoryServer, addr: &str) -> Result<()> {
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto::Builder,
+        service::TowerToHyperService,
+    };
+
+    let service = StreamableHttpService::new(
+        move || Ok(server.clone()),
+        Arc::new(LocalSessionManager::default()),
+        StreamableHttpServerConfig::default(),
+    );
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Falha ao abrir {}: {}", addr, e))?;
+    info!("MCP server v0.3 rodando via HTTP em http://{}", listener.local_addr()?);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Encerrando server HTTP");
+                return Ok(());
+            }
+        };
+        // Erro de accept (EMFILE, conexão abortada) é transitório: derrubar o server
+        // por isso encerraria todas as sessões ativas
+        let (stream, peer) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::warn!("Falha no accept HTTP: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(service.clone());
+        tokio::spawn(async move {
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Conexão HTTP {} encerrada: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> std::result::Result<Command, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args_commands() {
+        assert_eq!(parse(&[]), Ok(Command::Serve));
+        assert_eq!(parse(&["--version"]), Ok(Command::Version));
+        assert_eq!(parse(&["-V"]), Ok(Command::Version));
+        assert_eq!(parse(&["help"]), Ok(Command::Help));
+        assert_eq!(parse(&["serve"]), Err("unknown command 'serve'".to_string()));
+    }
+
+    #[test]
+    fn test_parse_args_search() {
+        // Palavras soltas viram uma query só, com as opções em qualquer posição
+        assert_eq!(
+            parse(&["search", "sqlite", "--limit", "3", "wal", "mode", "--json"]),
+            Ok(Command::Search {
+                query: "sqlite wal mode".into(),
+                scope: None,
+                limit: Some(3),
+                project: None,
+                json: true,
+            })
+        );
+        assert_eq!(
+            parse(&["search", "--scope", "all", "--project", "/tmp/app", "deploy"]),
+            Ok(Command::Search {
+                query: "deploy".into(),
+                scope: Some("all".into()),
+                limit: None,
+                project: Some("/tmp/app".into()),
+                json: false,
+            })
+        );
+
+        assert_eq!(parse(&["search"]), Err("search needs a query".to_string()));
+        assert_eq!(parse(&["search", "--json"]), Err("search needs a query".to_string()));
+        assert_eq!(parse(&["search", "x", "--verbose"]), Err("unknown option '--verbose'".to_string()));
+        assert_eq!(parse(&["search", "x", "--limit", "many"]), Err("invalid --limit 'many'".to_string()));
+        assert_eq!(parse(&["search", "x", "--limit"]), Err("--limit needs a value".to_string()));
+    }
+}
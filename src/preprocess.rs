@@ -0,0 +1,146 @@
+//! Preparo do texto que vai para o modelo de embedding quando o conteúdo é código.
+//!
+//! O modelo (treinado em prosa) embeda código cru mal: pontuação e identificadores
+//! colados dominam os tokens. Para conteúdo com cara de código, o texto do modelo vira
+//! "<linguagem> code: palavras", com identificadores quebrados (`parseHttpRequest`,
+//! `retry_budget` → `parse Http Request`, `retry budget`) e sem pontuação.
+//!
+//! Heurística:
+//! - blocos cercados (```` ``` ````) são código; a prosa em volta fica intacta;
+//! - sem cerca, o texto inteiro é código se a densidade de símbolos (`{}()[];=<>&|` …)
+//!   sobre os caracteres não-brancos passa de `SYMBOL_DENSITY`.
+//!
+//! Limites: código curto (< `MIN_CODE_CHARS`) ou com pouca pontuação (Python simples,
+//! YAML) passa como prosa; prosa cheia de símbolos (fórmulas, tabelas markdown) pode
+//! ser tratada como código. Comentários ficam: são linguagem natural, ajudam o modelo.
+//! Só o embedding muda — o conteúdo gravado (e o FTS) continua o original.
+
+/// Fração mínima de símbolos de código entre os caracteres não-brancos
+const SYMBOL_DENSITY: f64 = 0.10;
+/// Abaixo disso não dá para julgar pela densidade
+const MIN_CODE_CHARS: usize = 20;
+const CODE_SYMBOLS: &str = "{}()[];=<>&|:*/\\#$%^~!";
+
+/// MCP_CODE_PREPROCESS=1 liga (padrão desligado: trocar o modo exige memory_reindex)
+pub fn enabled_from_env() -> bool {
+    std::env::var("MCP_CODE_PREPROCESS")
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+/// Código sem cerca: muitos símbolos por caractere
+pub fn looks_like_code(text: &str) -> bool {
+    let (mut total, mut symbols) = (0usize, 0usize);
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        total += 1;
+        if CODE_SYMBOLS.contains(c) {
+            symbols += 1;
+        }
+    }
+    total >= MIN_CODE_CHARS && symbols as f64 / total as f64 >= SYMBOL_DENSITY
+}
+
+/// Texto para o modelo; `None` quando nada muda (prosa)
+pub fn prepare_for_embedding(text: &str) -> Option<String> {
+    if text.contains("```") {
+        return Some(prepare_fenced(text));
+    }
+    looks_like_code(text).then(|| code_to_words(text, None))
+}
+
+/// Só os blocos cercados viram palavras; a prosa entre eles é mantida
+fn prepare_fenced(text: &str) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut prose = String::new();
+    let mut code: Option<(Option<String>, String)> = None;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            match code.take() {
+                Some((lang, body)) => out.push(code_to_words(&body, lang.as_deref())),
+                None => {
+                    if !prose.trim().is_empty() {
+                        out.push(prose.trim().to_string());
+                    }
+                    prose.clear();
+                    let lang = info.split_whitespace().next().map(str::to_lowercase);
+                    code = Some((lang, String::new()));
+                }
+            }
+            continue;
+        }
+        let target = match code.as_mut() {
+            Some((_, body)) => body,
+            None => &mut prose,
+        };
+        target.push_str(line);
+        target.push('\n');
+    }
+    // Cerca sem fechamento: o resto é código
+    if let Some((lang, body)) = code {
+        out.push(code_to_words(&body, lang.as_deref()));
+    }
+    if !prose.trim().is_empty() {
+        out.push(prose.trim().to_string());
+    }
+    out.join("\n")
+}
+
+fn code_to_words(code: &str, lang: Option<&str>) -> String {
+    let words: Vec<String> = code
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|t| !t.is_empty())
+        .flat_map(split_identifier)
+        .collect();
+    format!("{} code: {}", lang.unwrap_or("source"), words.join(" "))
+}
+
+/// `snake_case`, `camelCase` e `HTTPServer` em palavras
+fn split_identifier(ident: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for part in ident.split('_').filter(|p| !p.is_empty()) {
+        let chars: Vec<char> = part.chars().collect();
+        let mut current = String::new();
+        for (i, &c) in chars.iter().enumerate() {
+            let prev = i.checked_sub(1).map(|j| chars[j]);
+            let next = chars.get(i + 1).copied();
+            // Começa palavra: minúscula→Maiúscula, ou fim de sigla (HTTPServer: P|Server)
+            let boundary = c.is_uppercase()
+                && (prev.is_some_and(|p| p.is_lowercase() || p.is_ascii_digit())
+                    || (prev.is_some_and(char::is_uppercase) && next.is_some_and(char::is_lowercase)));
+            if boundary && !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            current.push(c);
+        }
+        if !current.is_empty() {
+            words.push(current);
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_code_by_symbol_density() {
+        assert!(looks_like_code("fn main() { let x = vec![1, 2]; println!(\"{:?}\", x); }"));
+        assert!(!looks_like_code("We decided to use Postgres for the orders service, mainly for JSONB."));
+        assert!(!looks_like_code("a = b;"));
+        assert_eq!(prepare_for_embedding("Plain prose about retries and backoff."), None);
+    }
+
+    #[test]
+    fn test_prepare_splits_identifiers_and_keeps_prose() {
+        assert_eq!(split_identifier("parseHTTPRequest_v2"), ["parse", "HTTP", "Request", "v2"]);
+        let text = "Retry wrapper used by the billing client:\n```rust\nfn retry_with_backoff(maxRetries: u32) -> Result<()> {}\n```\nKeep it small.";
+        assert_eq!(
+            prepare_for_embedding(text).unwrap(),
+            "Retry wrapper used by the billing client:\n\
+             rust code: fn retry with backoff max Retries u32 Result\n\
+             Keep it small."
+        );
+    }
+}
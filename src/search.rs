@@ -1,10 +1,16 @@
-use rusqlite::Connection;
+use std::collections::HashMap;
 
-use crate::embedding::bytes_to_f32;
+use rusqlite::{types::ToSql, Connection};
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::ann::AnnScope;
+use crate::embedding::decode_embedding;
+use crate::simd;
 use crate::storage;
 
 /// Resultado de busca
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SearchResult {
     pub id: String,
     pub mem_type: String,
@@ -13,80 +19,844 @@ pub struct SearchResult {
     pub created_at: String,
     pub relevance: f64,
     pub method: String,
+    /// Trecho relevante (`snippet` na busca): termos do FTS em **negrito** ou o melhor chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// O snippet não cobre o conteúdo inteiro (memory_get traz o texto completo)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Scores de cada lado antes da fusão (BM25 normalizado e cosine, já × importance);
+    /// 0.0 quando a memória não veio por aquele lado
+    pub fts_score: f64,
+    pub emb_score: f64,
+    /// O melhor hit de embedding veio de um chunk, não do vetor da memória inteira
+    /// (`method` continua sendo o da memória: "embedding" ou "hybrid")
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub chunk_hit: bool,
+    /// Em hits de chunk: posição (`chunk_index`, base 0) e texto do chunk mais parecido
+    /// com a query, para mostrar onde a memória longa casou
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_text: Option<String>,
+    /// Decomposição do score (`explain` na busca); None em vizinhos do grafo
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explain: Option<ScoreExplain>,
+}
+
+/// Como o score de um resultado foi montado, etapa por etapa
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScoreExplain {
+    /// |bm25()| do FTS5 e normalizado x/(x+1); 0 quando não veio pelo texto
+    pub bm25_raw: f64,
+    pub bm25_normalized: f64,
+    /// Melhor cosine (memória ou chunk) contra a query; 0 quando não veio por embedding
+    pub cosine: f64,
+    pub importance: f64,
+    /// "weighted" ou "rrf"
+    pub fusion: &'static str,
+    /// Resultado da fusão, antes do decay
+    pub fused: f64,
+    pub days_old: i64,
+    /// Multiplicador do temporal decay (1.0 com recency=false)
+    pub decay: f64,
+    /// Peso do scope aplicado por último (1.0 fora da busca multi-scope)
+    pub scope_weight: f64,
+}
+
+impl ScoreExplain {
+    fn text(bm25_raw: f64, importance: f64) -> Option<Self> {
+        Some(Self {
+            bm25_raw: round4(bm25_raw),
+            bm25_normalized: round4(bm25_raw / (bm25_raw + 1.0)),
+            importance,
+            ..Self::neutral()
+        })
+    }
+
+    fn vector(cosine: f64, importance: f64) -> Option<Self> {
+        Some(Self {
+            cosine: round4(cosine),
+            importance,
+            ..Self::neutral()
+        })
+    }
+
+    fn neutral() -> Self {
+        Self { decay: 1.0, scope_weight: 1.0, ..Self::default() }
+    }
+}
+
+fn round4(v: f64) -> f64 {
+    (v * 10000.0).round() / 10000.0
+}
+
+/// Filtros opcionais aplicados nas queries de busca (FTS e embedding).
+/// Valores vazios/ausentes não restringem nada.
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilters {
+    pub mem_type: Option<String>,
+    /// Tags normalizadas (ver `storage::parse_tag_filter`), semântica AND
+    pub tags: Vec<String>,
+    /// Limites de created_at já normalizados ("YYYY-MM-DD HH:MM:SS", UTC)
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// Igualdade em chaves de primeiro nível do metadata JSON (AND)
+    pub metadata: Vec<(String, serde_json::Value)>,
+}
+
+impl SearchFilters {
+    /// Gera cláusulas extras (`AND ...`) sobre o alias `m` de `memories`,
+    /// junto com os parâmetros na ordem dos placeholders. Memórias expiradas
+    /// (`storage::NOT_EXPIRED_SQL`) ficam sempre de fora.
+    pub(crate) fn sql_clauses(&self) -> (String, Vec<Box<dyn ToSql>>) {
+        let mut sql = format!(" AND {}", storage::NOT_EXPIRED_SQL);
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(t) = self.mem_type.as_deref().filter(|t| !t.is_empty()) {
+            sql.push_str(" AND m.type = ?");
+            params.push(Box::new(t.to_string()));
+        }
+        for tag in &self.tags {
+            sql.push_str(" AND ");
+            sql.push_str(storage::TAG_MATCH_SQL);
+            params.push(Box::new(storage::tag_match_param(tag)));
+        }
+        if let Some(since) = &self.since {
+            sql.push_str(" AND datetime(m.created_at) >= ?");
+            params.push(Box::new(since.clone()));
+        }
+        if let Some(until) = &self.until {
+            sql.push_str(" AND datetime(m.created_at) <= ?");
+            params.push(Box::new(until.clone()));
+        }
+        for (key, value) in &self.metadata {
+            sql.push_str(" AND json_extract(m.metadata, ?) = ?");
+            // Chave entre aspas: aceita pontos e espaços sem virar caminho aninhado
+            params.push(Box::new(format!("$.\"{}\"", key.replace('"', ""))));
+            params.push(match value {
+                serde_json::Value::Bool(b) => Box::new(*b as i64),
+                serde_json::Value::Number(n) => match n.as_i64() {
+                    Some(i) => Box::new(i),
+                    None => Box::new(n.as_f64().unwrap_or(0.0)),
+                },
+                serde_json::Value::String(s) => Box::new(s.clone()),
+                other => Box::new(other.to_string()),
+            });
+        }
+        (sql, params)
+    }
+
+    /// Nenhum filtro ativo
+    pub fn is_empty(&self) -> bool {
+        self.mem_type.as_deref().is_none_or(str::is_empty)
+            && self.tags.is_empty()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.metadata.is_empty()
+    }
+
+    /// Aplica `metadata_filter`: objeto JSON de chave → valor escalar (string, número, bool)
+    pub fn with_metadata(mut self, raw: Option<&str>) -> Result<Self, String> {
+        let Some(raw) = raw.map(str::trim).filter(|v| !v.is_empty()) else {
+            return Ok(self);
+        };
+        let serde_json::Value::Object(map) = serde_json::from_str(raw)
+            .map_err(|e| format!("invalid metadata_filter JSON: {}", e))?
+        else {
+            return Err("metadata_filter must be a JSON object, e.g. {\"author\": \"ana\"}".to_string());
+        };
+        for (key, value) in map {
+            if value.is_object() || value.is_array() || value.is_null() {
+                return Err(format!("metadata_filter value for '{}' must be a string, number or bool", key));
+            }
+            self.metadata.push((key, value));
+        }
+        Ok(self)
+    }
+
+    /// Aplica since/until; data sem hora em `until` cobre o dia inteiro.
+    /// Datas inválidas viram erro em vez de filtro ignorado.
+    pub fn with_date_range(mut self, since: Option<&str>, until: Option<&str>) -> Result<Self, String> {
+        fn bound(name: &str, value: Option<&str>, end_of_day: bool) -> Result<Option<String>, String> {
+            let Some(raw) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+                return Ok(None);
+            };
+            let mut dt = parse_datetime(raw).ok_or_else(|| {
+                format!(
+                    "invalid {} date '{}'. Use YYYY-MM-DD or YYYY-MM-DD HH:MM:SS",
+                    name, raw
+                )
+            })?;
+            if end_of_day && raw.len() == 10 {
+                dt += chrono::Duration::days(1) - chrono::Duration::seconds(1);
+            }
+            Ok(Some(dt.format("%Y-%m-%d %H:%M:%S").to_string()))
+        }
+        self.since = bound("since", since, false)?;
+        self.until = bound("until", until, true)?;
+        if let (Some(s), Some(u)) = (&self.since, &self.until) {
+            if s > u {
+                return Err(format!("since ({}) is after until ({})", s, u));
+            }
+        }
+        Ok(self)
+    }
 }
 
-/// Cosine similarity entre dois vetores
+/// Pesos do merge híbrido (embedding vs BM25)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HybridWeights {
+    pub vector: f64,
+    pub text: f64,
+}
+
+impl Default for HybridWeights {
+    fn default() -> Self {
+        Self { vector: 0.7, text: 0.3 }
+    }
+}
+
+impl HybridWeights {
+    /// Valida (não-negativos, soma > 0) e normaliza para somar 1.0
+    pub fn new(vector: f64, text: f64) -> Self {
+        if !(vector >= 0.0 && text >= 0.0) || vector + text <= 0.0 {
+            warn!(
+                "Invalid hybrid weights (vector={}, text={}), using defaults",
+                vector, text
+            );
+            return Self::default();
+        }
+        let sum = vector + text;
+        if (sum - 1.0).abs() > 1e-9 {
+            warn!(
+                "Hybrid weights (vector={}, text={}) don't sum to 1.0, normalizing",
+                vector, text
+            );
+            return Self {
+                vector: vector / sum,
+                text: text / sum,
+            };
+        }
+        Self { vector, text }
+    }
+
+    /// Lê MCP_VECTOR_WEIGHT / MCP_TEXT_WEIGHT (default 0.7 / 0.3)
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let vector = std::env::var("MCP_VECTOR_WEIGHT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.vector);
+        let text = std::env::var("MCP_TEXT_WEIGHT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.text);
+        Self::new(vector, text)
+    }
+}
+
+/// Multiplicador de relevância por scope no merge cross-scope: o projeto atual
+/// costuma ser o mais pertinente, conversas antigas do personality o menos
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScopeWeights {
+    pub project: f64,
+    pub global: f64,
+    pub personality: f64,
+}
+
+impl Default for ScopeWeights {
+    fn default() -> Self {
+        Self {
+            project: 1.0,
+            global: 0.9,
+            personality: 0.7,
+        }
+    }
+}
+
+impl ScopeWeights {
+    /// MCP_SCOPE_WEIGHT_PROJECT / _GLOBAL / _PERSONALITY; valor inválido ou negativo
+    /// fica no padrão com aviso
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let read = |key: &str, default: f64| match std::env::var(key) {
+            Ok(raw) => match raw.trim().parse::<f64>() {
+                Ok(v) if v >= 0.0 && v.is_finite() => v,
+                _ => {
+                    warn!("Invalid {}='{}', using {}", key, raw, default);
+                    default
+                }
+            },
+            Err(_) => default,
+        };
+        Self {
+            project: read("MCP_SCOPE_WEIGHT_PROJECT", defaults.project),
+            global: read("MCP_SCOPE_WEIGHT_GLOBAL", defaults.global),
+            personality: read("MCP_SCOPE_WEIGHT_PERSONALITY", defaults.personality),
+        }
+    }
+
+    pub fn weight(&self, scope: &str) -> f64 {
+        match scope {
+            "project" => self.project,
+            "global" => self.global,
+            "personality" => self.personality,
+            _ => 0.8,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "project={}, global={}, personality={}",
+            self.project, self.global, self.personality
+        )
+    }
+}
+
+/// Estratégia de fusão dos rankings FTS e embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fusion {
+    /// weights.vector * cosine + weights.text * BM25 normalizado
+    #[default]
+    WeightedSum,
+    /// Reciprocal Rank Fusion: soma de 1/(k + rank), só depende das posições
+    Rrf,
+}
+
+impl Fusion {
+    /// Constante k do RRF (valor do paper original)
+    pub const RRF_K: f64 = 60.0;
+
+    /// Lê MCP_FUSION (`weighted` default, `rrf`)
+    pub fn from_env() -> Self {
+        match std::env::var("MCP_FUSION") {
+            Ok(v) if v.trim().eq_ignore_ascii_case("rrf") => Self::Rrf,
+            Ok(v) if !v.trim().is_empty() && !v.trim().eq_ignore_ascii_case("weighted") => {
+                warn!("Unknown MCP_FUSION '{}', using weighted", v);
+                Self::WeightedSum
+            }
+            _ => Self::WeightedSum,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::WeightedSum => "weighted",
+            Self::Rrf => "rrf",
+        }
+    }
+}
+
+/// Configuração de ranking do merge híbrido (env, fixa por processo)
+#[derive(Debug, Clone)]
+pub struct ScoringConfig {
+    pub fusion: Fusion,
+    pub weights: HybridWeights,
+    pub decay: TemporalDecay,
+    /// Aplica temporal decay no merge. Sem ele a relevância é o score ponderado
+    /// cru (cosine/BM25 normalizado), comparável entre memórias de qualquer idade.
+    pub recency: bool,
+    /// Corte de similaridade da busca por embedding (MCP_MIN_SIM)
+    pub min_sim: f64,
+    /// Peso de cada scope no merge cross-scope (aplicado no server, antes do sort final)
+    pub scope_weights: ScopeWeights,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        Self {
+            fusion: Fusion::default(),
+            weights: HybridWeights::default(),
+            decay: TemporalDecay::default(),
+            recency: true,
+            min_sim: DEFAULT_MIN_SIM,
+            scope_weights: ScopeWeights::default(),
+        }
+    }
+}
+
+impl ScoringConfig {
+    pub fn from_env() -> Self {
+        Self {
+            fusion: Fusion::from_env(),
+            weights: HybridWeights::from_env(),
+            decay: TemporalDecay::from_env(),
+            recency: true,
+            min_sim: min_sim_from_env(),
+            scope_weights: ScopeWeights::from_env(),
+        }
+    }
+
+    /// Cópia com o temporal decay ligado/desligado (flag `recency` por busca)
+    pub fn with_recency(&self, recency: bool) -> Self {
+        Self {
+            recency,
+            ..self.clone()
+        }
+    }
+}
+
+/// Similaridade para vetores unitários (convenção de tudo que é gravado e da query):
+/// o produto escalar já é o cosseno, sem recalcular normas
+pub fn dot_similarity(a: &[f32], b: &[f32]) -> f64 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    simd::dot(a, b) as f64
+}
+
+/// Ordem decrescente de relevância para `sort_by`, total mesmo com NaN
+/// (NaN conta como o pior score e vai para o fim, sem panic)
+pub fn cmp_relevance_desc(a: f64, b: f64) -> std::cmp::Ordering {
+    let key = |x: f64| if x.is_nan() { f64::NEG_INFINITY } else { x };
+    key(b).total_cmp(&key(a))
+}
+
+/// Ordem final dos resultados: relevância desc, empate por `created_at` desc e depois `id`.
+/// Sem o desempate, a ordem de iteração dos HashMap vazaria para a saída.
+pub fn cmp_results(a: &SearchResult, b: &SearchResult) -> std::cmp::Ordering {
+    cmp_relevance_desc(a.relevance, b.relevance)
+        .then_with(|| b.created_at.cmp(&a.created_at))
+        .then_with(|| a.id.cmp(&b.id))
+}
+
+/// Maximal Marginal Relevance: índices de até `k` itens, escolhendo a cada passo o que
+/// maximiza `lambda * relevância - (1 - lambda) * similaridade máxima com os já escolhidos`.
+/// Relevância é normalizada pelo maior score; item sem embedding não penaliza nem é penalizado.
+/// Entrada em ordem de relevância; empates mantêm essa ordem.
+pub fn mmr_order(relevance: &[f64], embeddings: &[Option<&[f32]>], lambda: f64, k: usize) -> Vec<usize> {
+    let max_rel = relevance.iter().copied().fold(0.0, f64::max);
+    let mut remaining: Vec<usize> = (0..relevance.len()).collect();
+    let mut picked: Vec<usize> = Vec::with_capacity(k.min(relevance.len()));
+
+    while picked.len() < k && !remaining.is_empty() {
+        let score = |i: usize| {
+            let rel = if max_rel > 0.0 { relevance[i] / max_rel } else { 0.0 };
+            let redundancy = picked
+                .iter()
+                .filter_map(|&j| Some(dot_similarity(embeddings[i]?, embeddings[j]?)))
+                .fold(0.0, f64::max);
+            lambda * rel - (1.0 - lambda) * redundancy
+        };
+        let mut best = 0;
+        let mut best_score = f64::NEG_INFINITY;
+        for (pos, &i) in remaining.iter().enumerate() {
+            let s = score(i);
+            if s > best_score {
+                best = pos;
+                best_score = s;
+            }
+        }
+        picked.push(remaining.remove(best));
+    }
+    picked
+}
+
+/// Cosine similarity entre dois vetores quaisquer (sem supor normalização)
 pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
     if a.len() != b.len() || a.is_empty() {
         return 0.0;
     }
-    let mut dot = 0.0f64;
-    let mut norm_a = 0.0f64;
-    let mut norm_b = 0.0f64;
-    for i in 0..a.len() {
-        let ai = a[i] as f64;
-        let bi = b[i] as f64;
-        dot += ai * bi;
-        norm_a += ai * ai;
-        norm_b += bi * bi;
-    }
-    let denom = norm_a.sqrt() * norm_b.sqrt();
+    let (dot, norm_a, norm_b) = simd::dot_norms(a, b);
+    let denom = (norm_a as f64).sqrt() * (norm_b as f64).sqrt();
     if denom < 1e-8 {
         return 0.0;
     }
-    dot / denom
+    dot as f64 / denom
+}
+
+/// Força do temporal decay no merge: global + overrides por tipo
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemporalDecay {
+    pub strength: f64,
+    pub per_type: HashMap<String, f64>,
+}
+
+impl Default for TemporalDecay {
+    fn default() -> Self {
+        Self {
+            strength: 0.15,
+            per_type: HashMap::new(),
+        }
+    }
 }
 
-/// Temporal decay: 1/(1+log1p(days)) com strength 0.15
-pub fn apply_temporal_decay(score: f64, created_at: &str) -> f64 {
-    const DECAY_STRENGTH: f64 = 0.15;
-    let days_old = parse_days_old(created_at);
+impl TemporalDecay {
+    /// Lê MCP_DECAY_STRENGTH (default 0.15) e MCP_DECAY_BY_TYPE
+    /// (`conversation=0.4,architecture=0`). Valores fora de [0, 1] são ignorados.
+    pub fn from_env() -> Self {
+        let mut decay = Self::default();
+        if let Ok(raw) = std::env::var("MCP_DECAY_STRENGTH") {
+            match raw.trim().parse::<f64>().ok().filter(|v| valid_strength(*v)) {
+                Some(v) => decay.strength = v,
+                None => warn!("Invalid MCP_DECAY_STRENGTH '{}', using {}", raw, decay.strength),
+            }
+        }
+        if let Ok(raw) = std::env::var("MCP_DECAY_BY_TYPE") {
+            decay.per_type = parse_decay_overrides(&raw);
+        }
+        decay
+    }
+
+    pub fn strength_for(&self, mem_type: &str) -> f64 {
+        self.per_type.get(mem_type).copied().unwrap_or(self.strength)
+    }
+
+    /// Score com decay pela idade, na força do tipo da memória
+    pub fn apply(&self, score: f64, mem_type: &str, created_at: &str) -> f64 {
+        apply_temporal_decay(score, created_at, self.strength_for(mem_type))
+    }
+
+    /// (dias de idade, multiplicador) que `apply` usaria
+    pub fn factor(&self, mem_type: &str, created_at: &str) -> (i64, f64) {
+        let days_old = parse_days_old(created_at);
+        (days_old, decay_multiplier(days_old, self.strength_for(mem_type)))
+    }
+
+    /// Descrição curta para stats/log: `0.15 (conversation=0.40)`
+    pub fn describe(&self) -> String {
+        let mut overrides: Vec<String> = self
+            .per_type
+            .iter()
+            .map(|(t, v)| format!("{}={:.2}", t, v))
+            .collect();
+        overrides.sort();
+        if overrides.is_empty() {
+            format!("{:.2}", self.strength)
+        } else {
+            format!("{:.2} ({})", self.strength, overrides.join(", "))
+        }
+    }
+}
+
+fn valid_strength(v: f64) -> bool {
+    (0.0..=1.0).contains(&v)
+}
+
+/// `tipo=força` separados por vírgula; entradas inválidas viram warning
+fn parse_decay_overrides(raw: &str) -> HashMap<String, f64> {
+    let mut map = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once('=').and_then(|(t, v)| {
+            let v = v.trim().parse::<f64>().ok().filter(|v| valid_strength(*v))?;
+            Some((t.trim().to_lowercase(), v))
+        });
+        match parsed {
+            Some((t, v)) if !t.is_empty() => {
+                map.insert(t, v);
+            }
+            _ => warn!("Ignoring invalid MCP_DECAY_BY_TYPE entry '{}'", entry),
+        }
+    }
+    map
+}
+
+/// Temporal decay: 1/(1+log1p(days)) ponderado por `strength` (0 = sem decay)
+pub fn apply_temporal_decay(score: f64, created_at: &str, strength: f64) -> f64 {
+    score * decay_multiplier(parse_days_old(created_at), strength)
+}
+
+fn decay_multiplier(days_old: i64, strength: f64) -> f64 {
     let recency = 1.0 / (1.0 + (days_old as f64).ln_1p());
-    score * (1.0 - DECAY_STRENGTH + DECAY_STRENGTH * recency)
+    1.0 - strength + strength * recency
 }
 
 fn parse_days_old(created_at: &str) -> i64 {
-    if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(created_at, "%Y-%m-%d %H:%M:%S") {
-        let now = chrono::Utc::now().naive_utc();
-        return (now - dt).num_days().max(0);
+    match parse_datetime(created_at) {
+        Some(dt) => (chrono::Utc::now().naive_utc() - dt).num_days().max(0),
+        None => 0,
+    }
+}
+
+/// Parse leniente de datas: `YYYY-MM-DD`, `YYYY-MM-DD HH:MM[:SS]`,
+/// com 'T' no lugar do espaço, ou RFC3339 (convertido para UTC)
+pub fn parse_datetime(value: &str) -> Option<chrono::NaiveDateTime> {
+    let value = value.trim();
+    for fmt in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"] {
+        if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(value, fmt) {
+            return Some(dt);
+        }
     }
-    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(created_at) {
-        let now = chrono::Utc::now();
-        return (now - dt.with_timezone(&chrono::Utc))
-            .num_days()
-            .max(0);
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(dt.with_timezone(&chrono::Utc).naive_utc());
     }
-    0
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
 }
 
-/// Busca FTS5 com scores BM25 normalizados (sem temporal decay — aplicado só no merge)
-pub fn search_fts(conn: &Connection, query: &str, limit: usize) -> Vec<SearchResult> {
-    let tokens: Vec<&str> = query.split_whitespace().filter(|t| !t.is_empty()).collect();
-    if tokens.is_empty() {
-        return vec![];
+/// Similaridade mínima padrão para um vetor contar como resultado
+pub const DEFAULT_MIN_SIM: f64 = 0.3;
+
+/// Lê MCP_MIN_SIM (default 0.3); fora de [-1, 1] volta ao default
+pub fn min_sim_from_env() -> f64 {
+    match std::env::var("MCP_MIN_SIM") {
+        Ok(raw) => match raw.trim().parse::<f64>().ok().filter(|v| (-1.0..=1.0).contains(v)) {
+            Some(v) => v,
+            None => {
+                warn!("Invalid MCP_MIN_SIM '{}', using {}", raw, DEFAULT_MIN_SIM);
+                DEFAULT_MIN_SIM
+            }
+        },
+        Err(_) => DEFAULT_MIN_SIM,
+    }
+}
+
+/// Peso da coluna `tags` no bm25 (o de `content` é 1.0): termo só na tag ajuda, mas
+/// não passa na frente de um match forte no corpo
+pub const DEFAULT_FTS_TAG_WEIGHT: f64 = 0.3;
+
+/// Lê MCP_FTS_TAG_WEIGHT (default 0.3); negativo ou inválido volta ao default
+pub fn fts_tag_weight_from_env() -> f64 {
+    match std::env::var("MCP_FTS_TAG_WEIGHT") {
+        Ok(raw) => match raw.trim().parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0) {
+            Some(v) => v,
+            None => {
+                warn!("Invalid MCP_FTS_TAG_WEIGHT '{}', using {}", raw, DEFAULT_FTS_TAG_WEIGHT);
+                DEFAULT_FTS_TAG_WEIGHT
+            }
+        },
+        Err(_) => DEFAULT_FTS_TAG_WEIGHT,
+    }
+}
+/// Pré-filtro: exclui memórias com importância muito baixa (conversations não acessadas)
+const MIN_IMPORTANCE: f64 = 0.2;
+
+/// Converte a query do usuário numa expressão MATCH do FTS5 sempre válida:
+/// `"frase entre aspas"` vira phrase query, `AND`/`OR`/`NOT` (maiúsculos) são
+/// operadores e termos soltos sem operador entre si são unidos por OR.
+/// Todo termo vai entre aspas (aspas internas dobradas), então pontuação não
+/// quebra a sintaxe; termos sem letra/dígito são descartados. None se não sobra termo
+/// ou se a query começa com NOT: o FTS5 não tem NOT unário, e descartar o operador
+/// devolveria justamente as memórias que o usuário quis excluir.
+pub(crate) fn fts_match_query(query: &str) -> Option<String> {
+    enum Tok {
+        Term(String),
+        Op(&'static str),
+    }
+
+    let mut toks = Vec::new();
+    let mut rest = query;
+    while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+        rest = &rest[start..];
+        let (raw, quoted, next) = match rest.strip_prefix('"') {
+            Some(inner) => match inner.find('"') {
+                Some(end) => (&inner[..end], true, &inner[end + 1..]),
+                None => (inner, true, ""),
+            },
+            None => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], false, &rest[end..])
+            }
+        };
+        rest = next;
+        match raw {
+            "AND" | "OR" | "NOT" if !quoted => toks.push(Tok::Op(match raw {
+                "AND" => "AND",
+                "OR" => "OR",
+                _ => "NOT",
+            })),
+            _ if raw.chars().any(char::is_alphanumeric) => {
+                toks.push(Tok::Term(format!("\"{}\"", raw.trim().replace('"', "\"\""))))
+            }
+            _ => {}
+        }
+    }
+
+    // Operador só vale entre dois termos; sem operador, OR. NOT no fim não exclui
+    // nada e pode cair, mas NOT antes do primeiro termo não tem como ser atendido
+    let mut out: Vec<String> = Vec::new();
+    let mut pending: Option<&str> = None;
+    for tok in toks {
+        match tok {
+            Tok::Op(op) => {
+                if out.is_empty() && op == "NOT" {
+                    return None;
+                }
+                if !out.is_empty() {
+                    pending = Some(op);
+                }
+            }
+            Tok::Term(term) => {
+                if !out.is_empty() {
+                    out.push(pending.take().unwrap_or("OR").to_string());
+                }
+                out.push(term);
+            }
+        }
+    }
+    if out.is_empty() {
+        None
+    } else {
+        Some(out.join(" "))
+    }
+}
+
+/// Tamanho (chars) do snippet quando não há trecho do FTS nem chunk
+const SNIPPET_CHARS: usize = 240;
+
+/// Preenche `snippet`/`truncated` de um resultado: hits de chunk usam o chunk mais
+/// parecido com a query; os demais, o `snippet()` do FTS em volta dos termos;
+/// sem nenhum dos dois, o começo do conteúdo.
+pub fn attach_snippet(conn: &Connection, query: &str, query_embedding: Option<&[f32]>, r: &mut SearchResult) {
+    let chunk = || {
+        r.chunk_text
+            .clone()
+            .or_else(|| query_embedding.and_then(|q| best_chunk(conn, &r.id, q)).map(|(_, text)| text))
+    };
+    let fts = || fts_snippet(conn, query, &r.id);
+    let snippet = if r.chunk_hit {
+        chunk().or_else(fts)
+    } else {
+        fts().or_else(chunk)
     }
+    .unwrap_or_else(|| truncate_chars(&r.content, SNIPPET_CHARS));
+
+    let plain = snippet.replace("**", "").replace('…', "");
+    r.truncated = plain.trim().chars().count() < r.content.trim().chars().count();
+    r.snippet = Some(snippet);
+}
 
-    let fts_query = tokens
-        .iter()
-        .map(|t| format!("\"{}\"", t))
-        .collect::<Vec<_>>()
-        .join(" OR ");
+fn fts_snippet(conn: &Connection, query: &str, id: &str) -> Option<String> {
+    let fts_query = fts_match_query(query)?;
+    conn.query_row(
+        "SELECT snippet(memories_fts, 0, '**', '**', '…', 32) FROM memories_fts \
+         WHERE memories_fts MATCH ? AND rowid = (SELECT rowid FROM memories WHERE id = ?)",
+        rusqlite::params![fts_query, id],
+        |row| row.get::<_, String>(0),
+    )
+    .ok()
+    .filter(|s| !s.trim().is_empty())
+}
 
-    let sql = "SELECT m.id, m.type, m.content, m.tags, m.created_at, \
-               bm25(memories_fts) as bm25_score, m.importance \
-               FROM memories_fts f \
-               JOIN memories m ON f.rowid = m.rowid \
-               WHERE memories_fts MATCH ?1 AND m.archived = 0 \
-               ORDER BY bm25_score \
-               LIMIT ?2";
+/// Chunk da memória mais parecido com a query: (chunk_index, chunk_text)
+fn best_chunk(conn: &Connection, id: &str, query: &[f32]) -> Option<(i64, String)> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT chunk_index, chunk_text, embedding FROM memory_chunks \
+             WHERE memory_id = ? AND embedding IS NOT NULL ORDER BY chunk_index",
+        )
+        .ok()?;
+    let rows = stmt
+        .query_map([id], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?))
+        })
+        .ok()?;
+    rows.flatten()
+        .filter_map(|(index, text, blob)| {
+            Some((dot_similarity(query, &decode_embedding(&blob, query.len())?), index, text))
+        })
+        .min_by(|a, b| cmp_relevance_desc(a.0, b.0))
+        .map(|(_, index, text)| (index, text))
+}
 
-    let mut stmt = match conn.prepare(sql) {
+/// Preenche `chunk_index`/`chunk_text` de um hit de chunk. Feito só para os resultados
+/// finais: o scan e o ANN guardam apenas "veio de um chunk" por memória.
+pub fn attach_chunk(conn: &Connection, query_embedding: &[f32], r: &mut SearchResult) {
+    if !r.chunk_hit {
+        return;
+    }
+    if let Some((index, text)) = best_chunk(conn, &r.id, query_embedding) {
+        r.chunk_index = Some(index);
+        r.chunk_text = Some(text);
+    }
+}
+
+/// Orçamento padrão do `memory_context`: caracteres por resultado e do bloco inteiro
+pub const CONTEXT_RESULT_CHARS: usize = 500;
+pub const CONTEXT_TOTAL_CHARS: usize = 4000;
+
+/// MCP_CONTEXT_RESULT_CHARS / MCP_CONTEXT_TOTAL_CHARS (0 = sem limite)
+pub fn context_budget_from_env() -> (usize, usize) {
+    let var = |name: &str, default: usize| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    (
+        var("MCP_CONTEXT_RESULT_CHARS", CONTEXT_RESULT_CHARS),
+        var("MCP_CONTEXT_TOTAL_CHARS", CONTEXT_TOTAL_CHARS),
+    )
+}
+
+/// Corta cada conteúdo em `per_result` caracteres (com "…") e para de incluir quando o
+/// bloco passaria de `total`. O primeiro sempre entra, cortado no total se preciso.
+pub fn fit_context<'a>(
+    contents: impl IntoIterator<Item = &'a str>,
+    per_result: usize,
+    total: usize,
+) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut used = 0;
+    for content in contents {
+        let excerpt = if per_result == 0 {
+            content.trim().to_string()
+        } else {
+            truncate_chars(content, per_result)
+        };
+        let len = excerpt.chars().count();
+        if total > 0 && used + len > total {
+            if out.is_empty() {
+                out.push(truncate_chars(content, total));
+            }
+            break;
+        }
+        used += len;
+        out.push(excerpt);
+    }
+    out
+}
+
+/// Primeiros `max` chars, cortando no último espaço e marcando com `…`
+fn truncate_chars(text: &str, max: usize) -> String {
+    let text = text.trim();
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(pos) if pos > max / 2 => &cut[..pos],
+        _ => &cut[..],
+    };
+    format!("{}…", cut.trim_end())
+}
+
+/// Busca FTS5 com scores BM25 normalizados (sem temporal decay — aplicado só no merge)
+pub fn search_fts(
+    conn: &Connection,
+    query: &str,
+    limit: usize,
+    filters: &SearchFilters,
+) -> Vec<SearchResult> {
+    let Some(fts_query) = fts_match_query(query) else {
+        return vec![];
+    };
+
+    let (filter_sql, filter_params) = filters.sql_clauses();
+    // Pesos por coluna na ordem do FTS_TABLE: content, tags
+    let sql = format!(
+        "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.tags, m.created_at, \
+         bm25(memories_fts, 1.0, {:?}) as bm25_score, m.importance \
+         FROM memories_fts f \
+         JOIN memories m ON f.rowid = m.rowid \
+         WHERE memories_fts MATCH ? AND m.archived = 0 AND m.deleted_at IS NULL{} \
+         ORDER BY bm25_score, m.created_at DESC, m.id \
+         LIMIT ?",
+        fts_tag_weight_from_env(),
+        filter_sql
+    );
+
+    let mut stmt = match conn.prepare(&sql) {
         Ok(s) => s,
         Err(_) => return vec![],
     };
 
     let fetch_limit = (limit * 3) as i64;
-    let rows = match stmt.query_map(rusqlite::params![fts_query, fetch_limit], |row| {
+    let mut params: Vec<&dyn ToSql> = vec![&fts_query];
+    params.extend(filter_params.iter().map(|p| p.as_ref()));
+    params.push(&fetch_limit);
+    let rows = match stmt.query_map(params.as_slice(), |row| {
         let bm25_raw: f64 = row.get::<_, f64>(5)?.abs();
         let bm25_normalized = bm25_raw / (bm25_raw + 1.0);
         let created_at: String = row.get::<_, Option<String>>(4)?.unwrap_or_default();
@@ -102,6 +872,14 @@ pub fn search_fts(conn: &Connection, query: &str, limit: usize) -> Vec<SearchRes
             created_at,
             relevance: score,
             method: "fts".into(),
+            snippet: None,
+            truncated: false,
+            fts_score: 0.0,
+            emb_score: 0.0,
+            chunk_hit: false,
+            chunk_index: None,
+            chunk_text: None,
+            explain: ScoreExplain::text(bm25_raw, importance),
         })
     }) {
         Ok(r) => r,
@@ -111,15 +889,174 @@ pub fn search_fts(conn: &Connection, query: &str, limit: usize) -> Vec<SearchRes
     rows.flatten().collect()
 }
 
+/// Padrões do modo regex: tamanho do texto e do programa compilado. O engine do
+/// crate `regex` não faz backtracking (tempo linear), então o risco é o tamanho.
+const REGEX_MAX_PATTERN: usize = 1000;
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Tempo máximo de varredura por DB no modo regex
+pub const REGEX_SCAN_BUDGET: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Compila o padrão da busca por regex com os limites acima
+pub fn compile_search_regex(pattern: &str) -> Result<regex::Regex, String> {
+    if pattern.chars().count() > REGEX_MAX_PATTERN {
+        return Err(format!("regex too long (max {} characters)", REGEX_MAX_PATTERN));
+    }
+    regex::RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|e| format!("invalid regex: {}", e))
+}
+
+/// Busca exata por regex no conteúdo (e nas tags, com `include_tags`), sem FTS nem
+/// embedding: para identificadores que a tokenização quebra (`foo::bar`, UUIDs).
+/// Mais recentes primeiro. O bool indica que o `budget` acabou antes do fim do scan.
+pub fn search_regex(
+    conn: &Connection,
+    re: &regex::Regex,
+    include_tags: bool,
+    limit: usize,
+    filters: &SearchFilters,
+    budget: std::time::Duration,
+) -> (Vec<SearchResult>, bool) {
+    let (filter_sql, filter_params) = filters.sql_clauses();
+    let sql = format!(
+        "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.tags, m.created_at \
+         FROM memories m \
+         WHERE m.archived = 0 AND m.deleted_at IS NULL{} \
+         ORDER BY m.created_at DESC, m.id",
+        filter_sql
+    );
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(s) => s,
+        Err(_) => return (vec![], false),
+    };
+    let params: Vec<&dyn ToSql> = filter_params.iter().map(|p| p.as_ref()).collect();
+    let mut rows = match stmt.query(params.as_slice()) {
+        Ok(r) => r,
+        Err(_) => return (vec![], false),
+    };
+
+    let started = std::time::Instant::now();
+    let mut results = Vec::new();
+    while let Ok(Some(row)) = rows.next() {
+        if results.len() >= limit {
+            break;
+        }
+        if started.elapsed() >= budget {
+            return (results, true);
+        }
+        let content: String = row.get(2).unwrap_or_default();
+        let tags: String = row.get::<_, Option<String>>(3).ok().flatten().unwrap_or_default();
+        let matched = re.is_match(&content) || (include_tags && re.is_match(&tags));
+        if !matched {
+            continue;
+        }
+        results.push(SearchResult {
+            id: row.get(0).unwrap_or_default(),
+            mem_type: row.get(1).unwrap_or_default(),
+            content,
+            tags,
+            created_at: row.get::<_, Option<String>>(4).ok().flatten().unwrap_or_default(),
+            relevance: 1.0,
+            method: "regex".into(),
+            snippet: None,
+            truncated: false,
+            fts_score: 0.0,
+            emb_score: 0.0,
+            chunk_hit: false,
+            chunk_index: None,
+            chunk_text: None,
+            explain: None,
+        });
+    }
+    (results, false)
+}
+
+/// Candidato do scan por embedding: só o que pontua e desempata (sem o conteúdo)
+struct VectorHit {
+    relevance: f64,
+    sim: f64,
+    importance: f64,
+    created_at: String,
+    chunk: bool,
+}
+
+/// Top `limit` candidatos na ordem de `cmp_results` (relevância, `created_at` desc, id)
+fn top_vector_hits(hits: HashMap<String, VectorHit>, limit: usize) -> Vec<(String, VectorHit)> {
+    let mut hits: Vec<(String, VectorHit)> = hits.into_iter().collect();
+    hits.sort_by(|(a_id, a), (b_id, b)| {
+        cmp_relevance_desc(a.relevance, b.relevance)
+            .then_with(|| b.created_at.cmp(&a.created_at))
+            .then_with(|| a_id.cmp(b_id))
+    });
+    hits.truncate(limit);
+    hits
+}
+
+/// Segunda fase do scan: busca type/content/tags só dos vencedores, mantendo a ordem
+fn hydrate_vector_hits(conn: &Connection, hits: Vec<(String, VectorHit)>) -> Vec<SearchResult> {
+    if hits.is_empty() {
+        return vec![];
+    }
+    let sql = format!(
+        "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.tags \
+         FROM memories m WHERE m.id IN ({})",
+        vec!["?"; hits.len()].join(",")
+    );
+    let params: Vec<&dyn ToSql> = hits.iter().map(|(id, _)| id as &dyn ToSql).collect();
+    let mut rows: HashMap<String, (String, String, String)> = HashMap::new();
+    if let Ok(mut stmt) = conn.prepare(&sql) {
+        if let Ok(mapped) = stmt.query_map(params.as_slice(), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                (
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                ),
+            ))
+        }) {
+            rows.extend(mapped.flatten());
+        }
+    }
+    hits.into_iter()
+        .filter_map(|(id, hit)| {
+            let (mem_type, content, tags) = rows.remove(&id)?;
+            Some(SearchResult {
+                id,
+                mem_type,
+                content,
+                tags,
+                created_at: hit.created_at,
+                // Score sem temporal decay (será aplicado uma única vez no merge)
+                relevance: hit.relevance,
+                method: "embedding".into(),
+                snippet: None,
+                truncated: false,
+                fts_score: 0.0,
+                emb_score: 0.0,
+                chunk_hit: hit.chunk,
+                chunk_index: None,
+                chunk_text: None,
+                explain: ScoreExplain::vector(hit.sim, hit.importance),
+            })
+        })
+        .collect()
+}
+
 /// Busca por embedding com pré-filtro por importância (sem temporal decay — aplicado no merge).
 /// Exclui conversations de baixa importância para reduzir scan.
+/// Em duas fases: o scan lê só id, vetor, importância e `created_at` (desempate); o
+/// conteúdo vem depois, só para os `limit` melhores.
 pub fn search_embedding(
     conn: &Connection,
     query_embedding: &[f32],
     limit: usize,
+    filters: &SearchFilters,
+    min_sim: f64,
 ) -> Vec<SearchResult> {
-    const MIN_SIM: f64 = 0.3;
-    const MIN_IMPORTANCE: f64 = 0.2;
     let memory_candidate_limit =
         std::env::var("MEMORY_EMBED_CANDIDATE_LIMIT")
             .ok()
@@ -131,127 +1068,292 @@ pub fn search_embedding(
             .and_then(|v| v.parse::<i64>().ok())
             .unwrap_or((limit.max(1) as i64) * 400);
 
-    let mut results_map: std::collections::HashMap<String, SearchResult> =
-        std::collections::HashMap::new();
-
-    // Pré-filtro: exclui memórias com importância muito baixa (conversations não acessadas)
-    if let Ok(mut stmt) = conn.prepare(
-        "SELECT id, type, content, tags, created_at, embedding, importance \
-         FROM memories WHERE embedding IS NOT NULL AND archived = 0 \
-         AND importance >= ?1 \
-         ORDER BY importance DESC, access_count DESC, updated_at DESC \
-         LIMIT ?2",
-    ) {
-        if let Ok(rows) = stmt.query_map(rusqlite::params![MIN_IMPORTANCE, memory_candidate_limit], |row| {
-            let id: String = row.get(0)?;
-            let mem_type: String = row.get(1)?;
-            let content: String = row.get(2)?;
-            let tags: String = row.get::<_, Option<String>>(3)?.unwrap_or_default();
-            let created_at: String = row.get::<_, Option<String>>(4)?.unwrap_or_default();
-            let blob: Vec<u8> = row.get(5)?;
-            let importance: f64 = row.get::<_, Option<f64>>(6)?.unwrap_or(0.5);
-            Ok((id, mem_type, content, tags, created_at, blob, importance))
-        }) {
-            for r in rows.flatten() {
-                let stored = bytes_to_f32(&r.5);
-                let sim = cosine_similarity(query_embedding, &stored);
-                if sim > MIN_SIM {
-                    // Score sem temporal decay (será aplicado uma única vez no merge)
-                    let score = sim * r.6;
-                    let entry = results_map.entry(r.0.clone()).or_insert(SearchResult {
-                        id: r.0,
-                        mem_type: r.1,
-                        content: r.2,
-                        tags: r.3,
-                        created_at: r.4,
-                        relevance: score,
-                        method: "embedding".into(),
-                    });
-                    if score > entry.relevance {
-                        entry.relevance = score;
-                    }
+    let mut best: HashMap<String, VectorHit> = HashMap::new();
+    // Vetores de outro modelo (dimensão diferente) são ignorados e contados
+    let dim = query_embedding.len();
+    let mut mismatched = 0usize;
+    // Vetores comparados vs acima do corte (debug: corte alto demais ou corpus vazio?)
+    let (mut considered, mut survived) = (0usize, 0usize);
+
+    // Filtros entram no SQL (não no HashMap) para o corte min_sim valer sobre os candidatos certos
+    let (filter_sql, filter_params) = filters.sql_clauses();
+
+    // Vetor da memória primeiro, depois os chunks; empate fica com o da memória
+    let scans = [
+        (
+            format!(
+                "SELECT m.id, m.created_at, m.embedding, m.importance \
+                 FROM memories m WHERE m.embedding IS NOT NULL AND m.archived = 0 AND m.deleted_at IS NULL \
+                 AND m.importance >= ?{} \
+                 ORDER BY m.importance DESC, m.access_count DESC, m.updated_at DESC, m.id \
+                 LIMIT ?",
+                filter_sql
+            ),
+            memory_candidate_limit,
+            false,
+        ),
+        (
+            format!(
+                "SELECT c.memory_id, m.created_at, c.embedding, m.importance \
+                 FROM memory_chunks c JOIN memories m ON c.memory_id = m.id \
+                 WHERE c.embedding IS NOT NULL AND m.archived = 0 AND m.deleted_at IS NULL \
+                 AND m.importance >= ?{} \
+                 ORDER BY m.importance DESC, m.access_count DESC, m.updated_at DESC, m.id \
+                 LIMIT ?",
+                filter_sql
+            ),
+            chunk_candidate_limit,
+            true,
+        ),
+    ];
+    for (sql, candidate_limit, chunk) in &scans {
+        let mut params: Vec<&dyn ToSql> = vec![&MIN_IMPORTANCE];
+        params.extend(filter_params.iter().map(|p| p.as_ref()));
+        params.push(candidate_limit);
+        let Ok(mut stmt) = conn.prepare(sql) else {
+            continue;
+        };
+        let Ok(mut rows) = stmt.query(params.as_slice()) else {
+            continue;
+        };
+        while let Ok(Some(row)) = rows.next() {
+            // Blob lido por referência: nada é copiado para vetores abaixo do corte
+            let stored = match row.get_ref(2) {
+                Ok(rusqlite::types::ValueRef::Blob(blob)) => decode_embedding(blob, dim),
+                _ => None,
+            };
+            let Some(stored) = stored else {
+                mismatched += 1;
+                continue;
+            };
+            let sim = dot_similarity(query_embedding, &stored);
+            considered += 1;
+            if sim <= min_sim {
+                continue;
+            }
+            survived += 1;
+            let (Ok(id), Ok(created_at)) = (row.get::<_, String>(0), row.get::<_, Option<String>>(1)) else {
+                continue;
+            };
+            let importance = row.get::<_, Option<f64>>(3).ok().flatten().unwrap_or(0.5);
+            let hit = VectorHit {
+                relevance: sim * importance,
+                sim,
+                importance,
+                created_at: created_at.unwrap_or_default(),
+                chunk: *chunk,
+            };
+            match best.get_mut(&id) {
+                Some(entry) if hit.relevance > entry.relevance => *entry = hit,
+                Some(_) => {}
+                None => {
+                    best.insert(id, hit);
                 }
             }
         }
     }
 
-    // Busca nos chunks (com pré-filtro)
-    if let Ok(mut stmt) = conn.prepare(
-        "SELECT c.memory_id, c.embedding, m.type, m.content, m.tags, m.created_at, m.importance \
-         FROM memory_chunks c JOIN memories m ON c.memory_id = m.id \
-         WHERE c.embedding IS NOT NULL AND m.archived = 0 \
-         AND m.importance >= ?1 \
-         ORDER BY m.importance DESC, m.access_count DESC, m.updated_at DESC \
-         LIMIT ?2",
-    ) {
-        if let Ok(rows) = stmt.query_map(rusqlite::params![MIN_IMPORTANCE, chunk_candidate_limit], |row| {
-            let mem_id: String = row.get(0)?;
-            let blob: Vec<u8> = row.get(1)?;
-            let mem_type: String = row.get(2)?;
-            let content: String = row.get(3)?;
-            let tags: String = row.get::<_, Option<String>>(4)?.unwrap_or_default();
-            let created_at: String = row.get::<_, Option<String>>(5)?.unwrap_or_default();
-            let importance: f64 = row.get::<_, Option<f64>>(6)?.unwrap_or(0.5);
-            Ok((mem_id, blob, mem_type, content, tags, created_at, importance))
-        }) {
-            for r in rows.flatten() {
-                let stored = bytes_to_f32(&r.1);
-                let sim = cosine_similarity(query_embedding, &stored);
-                if sim > MIN_SIM {
-                    let score = sim * r.6;
-                    let entry = results_map.entry(r.0.clone()).or_insert(SearchResult {
-                        id: r.0,
-                        mem_type: r.2,
-                        content: r.3,
-                        tags: r.4,
-                        created_at: r.5,
-                        relevance: score,
-                        method: "embedding-chunk".into(),
-                    });
-                    if score > entry.relevance {
-                        entry.relevance = score;
-                    }
-                }
-            }
+    if mismatched > 0 {
+        warn!(
+            "Skipped {} stored embeddings with unexpected dimension (expected {}); run memory_reindex",
+            mismatched, dim
+        );
+    }
+
+    debug!(
+        "Embedding scan: {} vectors considered, {} above min_sim {}",
+        considered, survived, min_sim
+    );
+
+    hydrate_vector_hits(conn, top_vector_hits(best, limit))
+}
+
+/// Busca por embedding via índice ANN: o índice dá os candidatos e o SQL confirma
+/// archived/importância/filtros. None quando o scan linear deve assumir
+/// (sem índice, ou filtros seletivos esvaziaram os candidatos).
+pub fn search_embedding_ann(
+    conn: &Connection,
+    ann: &AnnScope,
+    query_embedding: &[f32],
+    limit: usize,
+    filters: &SearchFilters,
+    min_sim: f64,
+) -> Option<Vec<SearchResult>> {
+    let k = (limit.max(1) * 20).max(50);
+    let hits = ann.registry.candidates(ann.db_key, conn, query_embedding, k)?;
+    let exhausted = hits.len() >= k;
+    let considered = hits.len();
+
+    // Melhor similaridade por memória (entre o vetor principal e os chunks)
+    let mut best: std::collections::HashMap<String, (f64, bool)> = std::collections::HashMap::new();
+    for hit in hits.into_iter().filter(|h| h.similarity > min_sim) {
+        let entry = best.entry(hit.memory_id).or_insert((hit.similarity, hit.chunk));
+        // Empate fica com o vetor da memória, independente da ordem dos candidatos
+        if hit.similarity > entry.0 || (hit.similarity == entry.0 && !hit.chunk) {
+            *entry = (hit.similarity, hit.chunk);
         }
     }
+    debug!(
+        "ANN search: {} candidates considered, {} memories above min_sim {}",
+        considered,
+        best.len(),
+        min_sim
+    );
+    if best.is_empty() {
+        return Some(vec![]);
+    }
+
+    let (filter_sql, filter_params) = filters.sql_clauses();
+    let ids: Vec<&String> = best.keys().collect();
+    let sql = format!(
+        "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.tags, m.created_at, m.importance \
+         FROM memories m WHERE m.id IN ({}) AND m.archived = 0 AND m.deleted_at IS NULL \
+         AND m.importance >= ?{}",
+        vec!["?"; ids.len()].join(","),
+        filter_sql
+    );
+    let mut params: Vec<&dyn ToSql> = ids.iter().map(|id| *id as &dyn ToSql).collect();
+    params.push(&MIN_IMPORTANCE);
+    params.extend(filter_params.iter().map(|p| p.as_ref()));
 
-    let mut results: Vec<SearchResult> = results_map.into_values().collect();
-    results.sort_by(|a, b| b.relevance.partial_cmp(&a.relevance).unwrap());
+    let mut stmt = conn.prepare(&sql).ok()?;
+    let rows = stmt
+        .query_map(params.as_slice(), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                row.get::<_, Option<f64>>(5)?.unwrap_or(0.5),
+            ))
+        })
+        .ok()?;
+    let mut results: Vec<SearchResult> = rows
+        .flatten()
+        .filter_map(|(id, mem_type, content, tags, created_at, importance)| {
+            let (sim, chunk) = *best.get(&id)?;
+            Some(SearchResult {
+                id,
+                mem_type,
+                content,
+                tags,
+                created_at,
+                // Score sem temporal decay (será aplicado uma única vez no merge)
+                relevance: sim * importance,
+                method: "embedding".into(),
+                snippet: None,
+                truncated: false,
+                fts_score: 0.0,
+                emb_score: 0.0,
+                chunk_hit: chunk,
+                chunk_index: None,
+                chunk_text: None,
+                explain: ScoreExplain::vector(sim, importance),
+            })
+        })
+        .collect();
+
+    // Filtros seletivos podem descartar quase todo o top-k: aí o scan linear é mais fiel
+    if results.len() < limit && exhausted && !filters.is_empty() {
+        return None;
+    }
+
+    results.sort_by(cmp_results);
     results.truncate(limit);
-    results
+    Some(results)
 }
 
-/// Busca híbrida: 0.7 embedding + 0.3 BM25, com 1-hop graph expansion e access_count update
-pub fn search_hybrid(
+/// Busca por embedding nos vetores de um modelo extra (`model_embeddings`): scan
+/// linear, só o vetor da memória (modelos extras não gravam chunks nem têm ANN).
+pub fn search_model_embedding(
     conn: &Connection,
-    query: &str,
-    query_embedding: Option<&[f32]>,
+    model: &str,
+    query_embedding: &[f32],
     limit: usize,
+    filters: &SearchFilters,
+    min_sim: f64,
 ) -> Vec<SearchResult> {
-    const VECTOR_WEIGHT: f64 = 0.7;
-    const TEXT_WEIGHT: f64 = 0.3;
-    const NEIGHBOR_SCORE_FACTOR: f64 = 0.5;
+    let dim = query_embedding.len();
+    let (filter_sql, filter_params) = filters.sql_clauses();
+    let sql = format!(
+        "SELECT m.id, m.created_at, e.embedding, m.importance \
+         FROM model_embeddings e JOIN memories m ON m.id = e.memory_id \
+         WHERE e.model = ? AND m.archived = 0 AND m.deleted_at IS NULL \
+         AND m.importance >= ?{}",
+        filter_sql
+    );
+    let mut params: Vec<&dyn ToSql> = vec![&model, &MIN_IMPORTANCE];
+    params.extend(filter_params.iter().map(|p| p.as_ref()));
 
-    let fts_results = search_fts(conn, query, limit);
-    let emb_results = if let Some(emb) = query_embedding {
-        search_embedding(conn, emb, limit)
-    } else {
-        vec![]
+    let Ok(mut stmt) = conn.prepare(&sql) else {
+        return vec![];
+    };
+    let Ok(rows) = stmt.query_map(params.as_slice(), |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+            row.get::<_, Vec<u8>>(2)?,
+            row.get::<_, Option<f64>>(3)?.unwrap_or(0.5),
+        ))
+    }) else {
+        return vec![];
     };
+    let hits: HashMap<String, VectorHit> = rows
+        .flatten()
+        .filter_map(|(id, created_at, blob, importance)| {
+            let sim = dot_similarity(query_embedding, &decode_embedding(&blob, dim)?);
+            (sim > min_sim).then(|| {
+                let hit = VectorHit { relevance: sim * importance, sim, importance, created_at, chunk: false };
+                (id, hit)
+            })
+        })
+        .collect();
+    hydrate_vector_hits(conn, top_vector_hits(hits, limit))
+}
+
+/// De onde vêm os vetores do lado embedding da busca híbrida
+#[derive(Clone, Copy)]
+pub enum VectorSource<'a> {
+    /// `memories.embedding` + chunks do modelo principal, via ANN quando houver índice
+    Primary(Option<&'a AnnScope<'a>>),
+    /// `model_embeddings` de um modelo extra (comparação A/B)
+    Model(&'a str),
+}
+
+/// Funde os resultados FTS e embedding (um por memória) conforme `scoring.fusion`,
+/// aplica temporal decay e ordena por relevância.
+/// RRF é normalizado por 2/(k+1), então o 1º lugar nas duas listas vale 1.0.
+fn fuse_results(fts_results: &[SearchResult], emb_results: &[SearchResult], scoring: &ScoringConfig) -> Vec<SearchResult> {
+    // Posição (1-based) de cada memória em cada lista, pelo melhor score dela
+    fn ranks(results: &[SearchResult]) -> HashMap<&str, usize> {
+        let mut sorted: Vec<&SearchResult> = results.iter().collect();
+        sorted.sort_by(|a, b| cmp_results(a, b));
+        let mut out = HashMap::new();
+        for r in sorted {
+            let next = out.len() + 1;
+            out.entry(r.id.as_str()).or_insert(next);
+        }
+        out
+    }
 
-    // Merge scores
-    let mut score_map: std::collections::HashMap<String, (f64, f64, SearchResult)> =
-        std::collections::HashMap::new();
+    let mut score_map: HashMap<String, (f64, f64, SearchResult)> = HashMap::new();
+    // Lado texto da decomposição: o resultado final fica com o do embedding
+    let mut text_explain: HashMap<&str, ScoreExplain> = HashMap::new();
 
-    for r in &fts_results {
+    for r in fts_results {
         let entry = score_map
             .entry(r.id.clone())
             .or_insert((0.0, 0.0, r.clone()));
+        if r.relevance >= entry.0 {
+            if let Some(e) = &r.explain {
+                text_explain.insert(r.id.as_str(), e.clone());
+            }
+        }
         entry.0 = entry.0.max(r.relevance);
     }
 
-    for r in &emb_results {
+    for r in emb_results {
         let entry = score_map
             .entry(r.id.clone())
             .or_insert((0.0, 0.0, r.clone()));
@@ -259,12 +1361,41 @@ pub fn search_hybrid(
         entry.2 = r.clone();
     }
 
+    let (fts_ranks, emb_ranks) = (ranks(fts_results), ranks(emb_results));
+    let rrf = |ranks: &HashMap<&str, usize>, id: &str| {
+        ranks.get(id).map_or(0.0, |&r| 1.0 / (Fusion::RRF_K + r as f64))
+    };
+
     let mut merged: Vec<SearchResult> = score_map
         .into_values()
         .map(|(fts_score, emb_score, mut data)| {
-            let raw = VECTOR_WEIGHT * emb_score + TEXT_WEIGHT * fts_score;
-            let final_score = apply_temporal_decay(raw, &data.created_at);
+            let raw = match scoring.fusion {
+                Fusion::WeightedSum => scoring.weights.vector * emb_score + scoring.weights.text * fts_score,
+                Fusion::Rrf => {
+                    (rrf(&fts_ranks, &data.id) + rrf(&emb_ranks, &data.id)) * (Fusion::RRF_K + 1.0) / 2.0
+                }
+            };
+            let (days_old, decay) = if scoring.recency {
+                scoring.decay.factor(&data.mem_type, &data.created_at)
+            } else {
+                (0, 1.0)
+            };
+            let final_score = raw * decay;
+            let mut explain = if emb_score > 0.0 { data.explain.take() } else { None }
+                .unwrap_or_else(ScoreExplain::neutral);
+            if let Some(text) = text_explain.get(data.id.as_str()) {
+                explain.bm25_raw = text.bm25_raw;
+                explain.bm25_normalized = text.bm25_normalized;
+                explain.importance = text.importance;
+            }
+            explain.fusion = scoring.fusion.name();
+            explain.fused = round4(raw);
+            explain.days_old = days_old;
+            explain.decay = round4(decay);
+            data.explain = Some(explain);
             data.relevance = (final_score * 10000.0).round() / 10000.0;
+            data.fts_score = (fts_score * 10000.0).round() / 10000.0;
+            data.emb_score = (emb_score * 10000.0).round() / 10000.0;
             if emb_score > 0.0 && fts_score > 0.0 {
                 data.method = "hybrid".into();
             }
@@ -272,12 +1403,42 @@ pub fn search_hybrid(
         })
         .collect();
 
-    merged.sort_by(|a, b| b.relevance.partial_cmp(&a.relevance).unwrap());
+    merged.sort_by(cmp_results);
+    merged
+}
+
+/// Busca híbrida: fusão (weighted sum ou RRF) de embedding + BM25 com temporal decay,
+/// com 1-hop graph expansion. Só leitura: quem devolve os resultados ao agente conta
+/// o acesso (`storage::update_access_count`) depois do corte final.
+pub fn search_hybrid(
+    conn: &Connection,
+    query: &str,
+    query_embedding: Option<&[f32]>,
+    limit: usize,
+    filters: &SearchFilters,
+    scoring: &ScoringConfig,
+    source: VectorSource,
+) -> Vec<SearchResult> {
+    const NEIGHBOR_SCORE_FACTOR: f64 = 0.5;
+
+    let fts_results = search_fts(conn, query, limit, filters);
+    let emb_results = match (query_embedding, source) {
+        (Some(emb), VectorSource::Primary(ann)) => ann
+            .and_then(|a| search_embedding_ann(conn, a, emb, limit, filters, scoring.min_sim))
+            .unwrap_or_else(|| search_embedding(conn, emb, limit, filters, scoring.min_sim)),
+        (Some(emb), VectorSource::Model(model)) => {
+            search_model_embedding(conn, model, emb, limit, filters, scoring.min_sim)
+        }
+        (None, _) => vec![],
+    };
+
+    let mut merged = fuse_results(&fts_results, &emb_results, scoring);
     merged.truncate(limit);
 
-    // Update access_count para resultados retornados
-    for r in &merged {
-        storage::update_access_count(conn, &r.id);
+    if let (Some(emb), VectorSource::Primary(_)) = (query_embedding, source) {
+        for r in &mut merged {
+            attach_chunk(conn, emb, r);
+        }
     }
 
     // 1-hop graph expansion: fetch neighbors e incluir com score reduzido
@@ -287,17 +1448,23 @@ pub fn search_hybrid(
     if !neighbor_ids.is_empty() {
         let existing_ids: std::collections::HashSet<String> =
             merged.iter().map(|r| r.id.clone()).collect();
+        // Vizinho passa pelos mesmos filtros da busca (type, tags, datas, metadata)
+        let (filter_sql, filter_params) = filters.sql_clauses();
+        let sql = format!(
+            "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.tags, m.created_at, m.importance \
+             FROM memories m WHERE m.id = ? AND m.archived = 0 AND m.deleted_at IS NULL{}",
+            filter_sql
+        );
 
         for nid in neighbor_ids.iter().take(limit) {
             if existing_ids.contains(nid) {
                 continue;
             }
             // Fetch neighbor data
-            if let Ok(mut stmt) = conn.prepare(
-                "SELECT id, type, content, tags, created_at, importance \
-                 FROM memories WHERE id = ? AND archived = 0"
-            ) {
-                if let Ok(row) = stmt.query_row(rusqlite::params![nid], |row| {
+            let mut params: Vec<&dyn ToSql> = vec![nid];
+            params.extend(filter_params.iter().map(|p| p.as_ref()));
+            if let Ok(mut stmt) = conn.prepare(&sql) {
+                if let Ok(row) = stmt.query_row(params.as_slice(), |row| {
                     let importance: f64 = row.get::<_, Option<f64>>(5)?.unwrap_or(0.5);
                     Ok(SearchResult {
                         id: row.get(0)?,
@@ -312,16 +1479,23 @@ pub fn search_hybrid(
                             .round()
                             / 10000.0,
                         method: "graph".into(),
+                        snippet: None,
+                        truncated: false,
+                        fts_score: 0.0,
+                        emb_score: 0.0,
+                        chunk_hit: false,
+                        chunk_index: None,
+                        chunk_text: None,
+                        explain: None,
                     })
                 }) {
-                    storage::update_access_count(conn, nid);
                     merged.push(row);
                 }
             }
         }
 
         // Re-sort with neighbors included
-        merged.sort_by(|a, b| b.relevance.partial_cmp(&a.relevance).unwrap());
+        merged.sort_by(cmp_results);
         merged.truncate(limit);
     }
 
@@ -332,6 +1506,17 @@ pub fn search_hybrid(
 mod tests {
     use super::*;
 
+    /// DB de busca com schema completo; o pid no nome separa execuções paralelas
+    fn test_db(name: &str) -> Connection {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-memory-search-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        storage::init_db(&path).unwrap()
+    }
+
     #[test]
     fn test_cosine_identical() {
         let a = vec![1.0, 0.0, 0.0];
@@ -350,14 +1535,845 @@ mod tests {
     #[test]
     fn test_temporal_decay_recent() {
         let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        let decayed = apply_temporal_decay(1.0, &now);
+        let decayed = apply_temporal_decay(1.0, &now, 0.15);
         assert!(decayed > 0.99);
     }
 
     #[test]
     fn test_temporal_decay_old() {
-        let decayed = apply_temporal_decay(1.0, "2020-01-01 00:00:00");
+        let decayed = apply_temporal_decay(1.0, "2020-01-01 00:00:00", 0.15);
         assert!(decayed < 1.0);
         assert!(decayed > 0.85);
     }
+
+    #[test]
+    fn test_temporal_decay_per_type() {
+        let decay = TemporalDecay {
+            strength: 0.15,
+            per_type: parse_decay_overrides("conversation=0.6, Architecture=0, bogus=2, nope"),
+        };
+        assert_eq!(decay.per_type.len(), 2);
+        let old = "2020-01-01 00:00:00";
+        assert_eq!(decay.apply(1.0, "architecture", old), 1.0);
+        assert!(decay.apply(1.0, "conversation", old) < decay.apply(1.0, "decision", old));
+        assert_eq!(decay.describe(), "0.15 (architecture=0.00, conversation=0.60)");
+    }
+
+    #[test]
+    fn test_fts_type_filter() {
+        let conn = test_db("type-filter");
+        storage::save_memory(&conn, "decision", "Use postgres for the orders service", "", &Default::default()).unwrap();
+        storage::save_memory(&conn, "note", "Postgres vacuum runs nightly on staging", "", &Default::default()).unwrap();
+
+        let all = search_fts(&conn, "postgres", 10, &SearchFilters::default());
+        assert_eq!(all.len(), 2);
+
+        let filters = SearchFilters {
+            mem_type: Some("decision".into()),
+            ..Default::default()
+        };
+        let only_decisions = search_fts(&conn, "postgres", 10, &filters);
+        assert_eq!(only_decisions.len(), 1);
+        assert_eq!(only_decisions[0].mem_type, "decision");
+
+        let empty_type = SearchFilters {
+            mem_type: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(search_fts(&conn, "postgres", 10, &empty_type).len(), 2);
+    }
+
+    #[test]
+    fn test_hybrid_recency_flag() {
+        let conn = test_db("recency");
+        let saved = storage::save_memory(&conn, "decision", "Billing runs on the ledger service", "", &Default::default()).unwrap();
+        conn.execute("UPDATE memories SET created_at = '2019-06-01 00:00:00' WHERE id = ?", [&saved.id]).unwrap();
+        // BM25 precisa de corpus: com um documento só o score é ~0
+        for filler in ["Invoices are emailed monthly", "Refunds go through support", "Taxes are computed per region"] {
+            storage::save_memory(&conn, "note", filler, "", &Default::default()).unwrap();
+        }
+
+        let scoring = ScoringConfig::default();
+        let search = |scoring: &ScoringConfig| {
+            search_hybrid(&conn, "ledger", None, 5, &SearchFilters::default(), scoring, VectorSource::Primary(None))[0].relevance
+        };
+        let fts = search_fts(&conn, "ledger", 5, &SearchFilters::default())[0].relevance;
+        let expected = (scoring.weights.text * fts * 10000.0).round() / 10000.0;
+        assert!(expected > 0.0);
+        assert_eq!(search(&scoring.with_recency(false)), expected);
+        assert!(search(&scoring) < expected);
+        // Busca é só leitura: acesso conta em quem devolve o resultado
+        let accessed: i64 = conn
+            .query_row("SELECT SUM(access_count) FROM memories", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(accessed, 0);
+    }
+
+    #[test]
+    fn test_extra_model_vectors_are_searched_separately() {
+        let conn = test_db("extra-model");
+        let billing = storage::save_memory(&conn, "decision", "Billing runs on the ledger service", "", &Default::default()).unwrap();
+        let deploy = storage::save_memory(&conn, "decision", "Deploys go out on Tuesdays", "", &Default::default()).unwrap();
+        let encode = crate::embedding::compress_embedding;
+        storage::write_model_embeddings(
+            &conn,
+            "bge-base-en-v1.5",
+            &[(billing.id.as_str(), encode(&[1.0, 0.0, 0.0])), (deploy.id.as_str(), encode(&[0.0, 1.0, 0.0]))],
+        )
+        .unwrap();
+
+        let query = [1.0f32, 0.0, 0.0];
+        let filters = SearchFilters::default();
+        let hits = search_model_embedding(&conn, "bge-base-en-v1.5", &query, 5, &filters, 0.3);
+        assert_eq!(hits.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), [billing.id.as_str()]);
+        assert!(search_model_embedding(&conn, "nomic-embed-text-v1.5", &query, 5, &filters, 0.3).is_empty());
+
+        // O modelo principal não vê os vetores do extra (nenhuma memória tem embedding principal)
+        let scoring = ScoringConfig::default();
+        let primary = search_hybrid(&conn, "tuesdays", Some(&query), 5, &filters, &scoring, VectorSource::Primary(None));
+        assert!(primary.iter().all(|r| r.method == "fts"), "{:?}", primary);
+        let extra = search_hybrid(&conn, "tuesdays", Some(&query), 5, &filters, &scoring, VectorSource::Model("bge-base-en-v1.5"));
+        assert!(extra.iter().any(|r| r.id == billing.id && r.method == "embedding"), "{:?}", extra);
+    }
+
+    #[test]
+    fn test_explain_breaks_down_hybrid_score() {
+        let conn = test_db("explain");
+        let billing = storage::save_memory(&conn, "decision", "Billing runs on the ledger service", "", &Default::default()).unwrap();
+        for filler in ["Invoices are emailed monthly", "Refunds go through support", "Taxes are computed per region"] {
+            storage::save_memory(&conn, "note", filler, "", &Default::default()).unwrap();
+        }
+        conn.execute("UPDATE memories SET created_at = '2020-01-01 00:00:00' WHERE id = ?", [&billing.id]).unwrap();
+        storage::write_model_embeddings(&conn, "m", &[(billing.id.as_str(), crate::embedding::compress_embedding(&[1.0, 0.0]))])
+            .unwrap();
+
+        let scoring = ScoringConfig::default();
+        let results = search_hybrid(&conn, "ledger", Some(&[1.0, 0.0]), 5, &SearchFilters::default(), &scoring, VectorSource::Model("m"));
+        let top = &results[0];
+        assert_eq!((top.id.as_str(), top.method.as_str()), (billing.id.as_str(), "hybrid"));
+        let e = top.explain.as_ref().unwrap();
+        assert!(e.bm25_raw > 0.0);
+        assert!((e.bm25_normalized - e.bm25_raw / (e.bm25_raw + 1.0)).abs() < 1e-3);
+        assert!((e.cosine - 1.0).abs() < 1e-3);
+        assert_eq!(e.fusion, "weighted");
+        let fused = scoring.weights.vector * top.emb_score + scoring.weights.text * top.fts_score;
+        assert!((e.fused - fused).abs() < 1e-3, "{:?}", e);
+        assert!(e.days_old > 365 && e.decay < 1.0);
+        assert!((top.relevance - e.fused * e.decay).abs() < 1e-3, "{:?}", e);
+    }
+
+    #[test]
+    fn test_tied_scores_order_is_deterministic() {
+        let conn = test_db("tie-break");
+        // Mesmo tamanho e mesma frequência do termo: BM25 empata entre todos
+        let mut ids = Vec::new();
+        for (word, created_at) in [
+            ("alpha", "2024-01-01 00:00:00"),
+            ("bravo", "2024-03-01 00:00:00"),
+            ("delta", "2024-03-01 00:00:00"),
+            ("gamma", "2024-02-01 00:00:00"),
+        ] {
+            let saved = storage::save_memory(&conn, "note", &format!("ledger {} entry", word), "", &Default::default()).unwrap();
+            conn.execute("UPDATE memories SET created_at = ? WHERE id = ?", [created_at, saved.id.as_str()]).unwrap();
+            ids.push((created_at, saved.id));
+        }
+        for filler in ["Invoices are emailed monthly", "Refunds go through support"] {
+            storage::save_memory(&conn, "note", filler, "", &Default::default()).unwrap();
+        }
+
+        let scoring = ScoringConfig::default().with_recency(false);
+        let run = || -> Vec<String> {
+            search_hybrid(&conn, "ledger", None, 10, &SearchFilters::default(), &scoring, VectorSource::Primary(None))
+                .into_iter()
+                .map(|r| r.id)
+                .collect()
+        };
+        let first = run();
+        assert_eq!(first, run());
+
+        // created_at desc, depois id
+        ids.sort_by(|a, b| b.0.cmp(a.0).then_with(|| a.1.cmp(&b.1)));
+        let expected: Vec<String> = ids.into_iter().map(|(_, id)| id).collect();
+        assert_eq!(first, expected);
+    }
+
+    #[test]
+    fn test_rrf_fusion_ordering() {
+        let hit = |id: &str, relevance: f64| SearchResult {
+            id: id.into(),
+            mem_type: "note".into(),
+            content: id.into(),
+            tags: String::new(),
+            created_at: String::new(),
+            relevance,
+            method: String::new(),
+            snippet: None,
+            truncated: false,
+            fts_score: 0.0,
+            emb_score: 0.0,
+            chunk_hit: false,
+            chunk_index: None,
+            chunk_text: None,
+            explain: None,
+        };
+        // A: 1º no FTS, 3º no embedding; B: só embedding (1º); C: 2º nos dois
+        let fts = vec![hit("a", 0.95), hit("c", 0.2)];
+        let emb = vec![hit("b", 0.9), hit("c", 0.85), hit("a", 0.3)];
+        let order = |scoring: &ScoringConfig| -> Vec<String> {
+            fuse_results(&fts, &emb, scoring).into_iter().map(|r| r.id).collect()
+        };
+
+        let weighted = ScoringConfig { recency: false, ..Default::default() };
+        assert_eq!(order(&weighted), ["c", "b", "a"]);
+
+        // RRF ignora a escala dos scores: só as posições contam
+        let rrf = ScoringConfig { fusion: Fusion::Rrf, ..weighted };
+        assert_eq!(order(&rrf), ["a", "c", "b"]);
+        let top = &fuse_results(&fts, &emb, &rrf)[0];
+        assert_eq!(top.method, "hybrid");
+        assert_eq!((top.fts_score, top.emb_score), (0.95, 0.3));
+        assert!(top.relevance > 0.98 && top.relevance < 1.0);
+    }
+
+    #[test]
+    fn test_nan_relevance_sorts_last_without_panic() {
+        let hit = |id: &str, relevance: f64| SearchResult {
+            id: id.into(),
+            mem_type: "note".into(),
+            content: id.into(),
+            tags: String::new(),
+            created_at: String::new(),
+            relevance,
+            method: String::new(),
+            snippet: None,
+            truncated: false,
+            fts_score: 0.0,
+            emb_score: 0.0,
+            chunk_hit: false,
+            chunk_index: None,
+            chunk_text: None,
+            explain: None,
+        };
+        let mut results = [hit("nan", f64::NAN), hit("low", 0.1), hit("high", 0.9)];
+        results.sort_by(|a, b| cmp_relevance_desc(a.relevance, b.relevance));
+        let ids: Vec<&str> = results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["high", "low", "nan"]);
+
+        // Merge híbrido com score degenerado também não derruba a busca
+        let fts = vec![hit("nan", f64::NAN), hit("a", 0.5)];
+        let emb = vec![hit("b", f64::NAN), hit("a", 0.7)];
+        let merged = fuse_results(&fts, &emb, &ScoringConfig::default());
+        assert_eq!(merged[0].id, "a");
+        let rrf = ScoringConfig { fusion: Fusion::Rrf, ..Default::default() };
+        assert_eq!(fuse_results(&fts, &emb, &rrf).len(), 3);
+    }
+
+    #[test]
+    fn test_mmr_pushes_near_duplicates_down() {
+        let relevance = [1.0, 0.95, 0.7];
+        let (x, y): (&[f32], &[f32]) = (&[1.0, 0.0], &[0.0, 1.0]);
+        let embeddings = [Some(x), Some(x), Some(y)];
+        assert_eq!(mmr_order(&relevance, &embeddings, 1.0, 3), [0, 1, 2]);
+        assert_eq!(mmr_order(&relevance, &embeddings, 0.5, 3), [0, 2, 1]);
+        assert_eq!(mmr_order(&relevance, &embeddings, 0.5, 2), [0, 2]);
+        // Sem embeddings: só relevância
+        assert_eq!(mmr_order(&relevance, &[None, None, None], 0.3, 3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_snippets() {
+        let conn = test_db("snippet");
+        let long = format!(
+            "{} The retry budget for the payments gateway is three attempts. {}",
+            "Background context about the team. ".repeat(20),
+            "More unrelated trailing notes. ".repeat(20)
+        );
+        let saved = storage::save_memory(&conn, "decision", &long, "", &Default::default()).unwrap();
+        storage::save_memory(&conn, "note", "Short note on gateways", "", &Default::default()).unwrap();
+
+        let mut hits = search_fts(&conn, "retry budget", 5, &SearchFilters::default());
+        let hit = hits.iter_mut().find(|r| r.id == saved.id).unwrap();
+        attach_snippet(&conn, "retry budget", None, hit);
+        let snippet = hit.snippet.as_deref().unwrap();
+        assert!(snippet.contains("**retry**") && snippet.contains("**budget**"), "{}", snippet);
+        assert!(hit.truncated);
+
+        // Chunk hit: devolve o chunk mais parecido com a query
+        for (i, (text, v)) in [("first part", [1.0f32, 0.0]), ("second part", [0.0, 1.0])].iter().enumerate() {
+            conn.execute(
+                "INSERT INTO memory_chunks (id, memory_id, chunk_index, chunk_text, embedding) VALUES (?, ?, ?, ?, ?)",
+                rusqlite::params![format!("c{}", i), saved.id, i as i64, text, crate::embedding::compress_embedding(v)],
+            )
+            .unwrap();
+        }
+        let mut chunk_hit = hit.clone();
+        chunk_hit.chunk_hit = true;
+        attach_snippet(&conn, "unmatched words", Some(&[0.0, 1.0]), &mut chunk_hit);
+        assert_eq!(chunk_hit.snippet.as_deref(), Some("second part"));
+
+        // Conteúdo curto inteiro: não truncado
+        let mut short = search_fts(&conn, "gateways", 5, &SearchFilters::default()).remove(0);
+        attach_snippet(&conn, "zzz", None, &mut short);
+        assert_eq!((short.snippet.as_deref(), short.truncated), (Some("Short note on gateways"), false));
+        assert_eq!(truncate_chars("alpha beta gamma", 12), "alpha beta…");
+    }
+
+    #[test]
+    fn test_fts_match_query_syntax() {
+        assert_eq!(fts_match_query("rust tokio").as_deref(), Some("\"rust\" OR \"tokio\""));
+        assert_eq!(
+            fts_match_query("\"error handling\" AND rust NOT python").as_deref(),
+            Some("\"error handling\" AND \"rust\" NOT \"python\"")
+        );
+        // Operadores soltos nas pontas, minúsculos ou repetidos não quebram a sintaxe
+        assert_eq!(fts_match_query("AND rust OR").as_deref(), Some("\"rust\""));
+        // NOT unário: melhor nenhum resultado do que devolver o termo excluído
+        assert_eq!(fts_match_query("NOT python"), None);
+        assert_eq!(fts_match_query("AND NOT python rust"), None);
+        assert_eq!(fts_match_query("rust NOT").as_deref(), Some("\"rust\""));
+        assert_eq!(fts_match_query("rust and tokio").as_deref(), Some("\"rust\" OR \"and\" OR \"tokio\""));
+        assert_eq!(fts_match_query("a\"b (c)").as_deref(), Some("\"a\"\"b\" OR \"(c)\""));
+        assert_eq!(fts_match_query("?! -- ()"), None);
+        assert_eq!(fts_match_query("  "), None);
+    }
+
+    #[test]
+    fn test_fts_phrase_and_boolean() {
+        let conn = test_db("fts-boolean");
+        for content in [
+            "Centralize error handling in the API layer",
+            "Handling of retries when an error is logged",
+            "Rust services use tokio for async IO",
+            "Python workers use asyncio",
+        ] {
+            storage::save_memory(&conn, "note", content, "", &Default::default()).unwrap();
+        }
+        let count = |q: &str| search_fts(&conn, q, 10, &SearchFilters::default()).len();
+
+        assert_eq!(count("error handling"), 2);
+        assert_eq!(count("\"error handling\""), 1);
+        assert_eq!(count("rust AND tokio"), 1);
+        assert_eq!(count("rust AND asyncio"), 0);
+        assert_eq!(count("use NOT python"), 1);
+        assert_eq!(count("\"unterminated phrase"), 0);
+        assert_eq!(count("*** ::"), 0);
+    }
+
+    #[test]
+    fn test_tag_filter_matches_whole_tags() {
+        let conn = test_db("tag-filter");
+        storage::save_memory(&conn, "note", "Endpoint latency budget", "api,backend", &Default::default()).unwrap();
+        storage::save_memory(&conn, "note", "Endpoint prototype was rapid", "rapid", &Default::default()).unwrap();
+
+        let filters = SearchFilters {
+            tags: storage::parse_tag_filter("API"),
+            ..Default::default()
+        };
+        let results = search_fts(&conn, "endpoint", 10, &filters);
+        assert_eq!(results.len(), 1);
+        assert!(results[0].tags.contains("backend"));
+
+        // AND: todas as tags precisam estar presentes
+        let both = SearchFilters {
+            tags: storage::parse_tag_filter("api, backend"),
+            ..Default::default()
+        };
+        let listed = storage::list_memories(&conn, &both, 10, 0).unwrap();
+        assert_eq!(listed.len(), 1);
+        let mixed = SearchFilters {
+            tags: storage::parse_tag_filter("api,rapid"),
+            ..Default::default()
+        };
+        let none = storage::list_memories(&conn, &mixed, 10, 0).unwrap();
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_hybrid_weights_normalize() {
+        let w = HybridWeights::new(2.0, 2.0);
+        assert!((w.vector - 0.5).abs() < 1e-9);
+        assert!((w.text - 0.5).abs() < 1e-9);
+        assert_eq!(HybridWeights::new(0.6, 0.4), HybridWeights { vector: 0.6, text: 0.4 });
+    }
+
+    #[test]
+    fn test_scope_weights_prefer_project() {
+        let weights = ScopeWeights::default();
+        assert!(weights.weight("project") > weights.weight("global"));
+        assert!(weights.weight("global") > weights.weight("personality"));
+        assert_eq!(weights.describe(), "project=1, global=0.9, personality=0.7");
+    }
+
+    #[test]
+    fn test_hybrid_weights_invalid_fallback() {
+        assert_eq!(HybridWeights::new(-1.0, 0.5), HybridWeights::default());
+        assert_eq!(HybridWeights::new(0.0, 0.0), HybridWeights::default());
+        assert_eq!(HybridWeights::new(f64::NAN, 0.5), HybridWeights::default());
+    }
+
+    #[test]
+    fn test_embedding_search_skips_other_dimensions() {
+        let conn = test_db("dim-skip");
+        let a = storage::save_memory(&conn, "note", "current model vector", "", &Default::default()).unwrap();
+        let b = storage::save_memory(&conn, "note", "older model vector", "", &Default::default()).unwrap();
+        let query = vec![0.5f32; 8];
+        for (id, emb) in [(&a.id, vec![0.5f32; 8]), (&b.id, vec![0.5f32; 16])] {
+            conn.execute(
+                "UPDATE memories SET embedding = ? WHERE id = ?",
+                rusqlite::params![crate::embedding::compress_embedding(&emb), id],
+            )
+            .unwrap();
+        }
+
+        let results = search_embedding(&conn, &query, 10, &SearchFilters::default(), DEFAULT_MIN_SIM);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, a.id);
+    }
+
+    #[test]
+    fn test_embedding_min_sim_cutoff() {
+        let conn = test_db("min-sim");
+        let saved = storage::save_memory(&conn, "decision", "loosely related vector", "", &Default::default()).unwrap();
+        // cos = 0.25 com a query [1, 0]: abaixo do default, acima de um corte mais frouxo
+        let v = [0.25f32, (1.0f32 - 0.0625).sqrt()];
+        conn.execute(
+            "UPDATE memories SET embedding = ? WHERE id = ?",
+            rusqlite::params![crate::embedding::compress_embedding(&v), saved.id],
+        )
+        .unwrap();
+        let query = [1.0f32, 0.0];
+        assert!(search_embedding(&conn, &query, 5, &SearchFilters::default(), DEFAULT_MIN_SIM).is_empty());
+        assert_eq!(search_embedding(&conn, &query, 5, &SearchFilters::default(), 0.2).len(), 1);
+    }
+
+    #[test]
+    fn test_chunk_hit_keeps_memory_method() {
+        let conn = test_db("chunk-merge");
+        let query = [1.0f32, 0.0];
+        let set_embedding = |id: &str, v: [f32; 2]| {
+            conn.execute(
+                "UPDATE memories SET embedding = ? WHERE id = ?",
+                rusqlite::params![crate::embedding::compress_embedding(&v), id],
+            )
+            .unwrap();
+        };
+        let add_chunk = |id: &str, v: [f32; 2]| {
+            conn.execute(
+                "INSERT INTO memory_chunks (id, memory_id, chunk_index, chunk_text, embedding) VALUES (?, ?, 0, 'c', ?)",
+                rusqlite::params![format!("{}_c0", id), id, crate::embedding::compress_embedding(&v)],
+            )
+            .unwrap();
+        };
+        // Chunk melhor que o vetor da memória; e empate exato entre os dois
+        let better = storage::save_memory(&conn, "note", "chunk wins here", "", &Default::default()).unwrap().id;
+        set_embedding(&better, [0.6, 0.8]);
+        add_chunk(&better, [1.0, 0.0]);
+        let tied = storage::save_memory(&conn, "note", "tie goes to memory", "", &Default::default()).unwrap().id;
+        set_embedding(&tied, [0.8, 0.6]);
+        add_chunk(&tied, [0.8, 0.6]);
+
+        let results = search_embedding(&conn, &query, 5, &SearchFilters::default(), 0.1);
+        let find = |id: &str| results.iter().find(|r| r.id == id).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.method == "embedding"));
+        assert!(find(&better).chunk_hit);
+        // cos 1.0 do chunk, não 0.6 do vetor principal: passa o "tied" (0.8)
+        assert!(find(&better).relevance > find(&tied).relevance);
+        assert!(!find(&tied).chunk_hit);
+    }
+
+    #[test]
+    fn test_chunk_hit_reports_matched_chunk() {
+        let conn = test_db("chunk-index");
+        let id = storage::save_memory(&conn, "note", "long runbook about deploys", "", &Default::default())
+            .unwrap()
+            .id;
+        conn.execute(
+            "UPDATE memories SET embedding = ? WHERE id = ?",
+            rusqlite::params![crate::embedding::compress_embedding(&[0.0, 1.0]), id],
+        )
+        .unwrap();
+        for (i, (text, v)) in [("intro", [0.0f32, 1.0]), ("rollback steps", [1.0, 0.0]), ("appendix", [0.6, 0.8])]
+            .iter()
+            .enumerate()
+        {
+            conn.execute(
+                "INSERT INTO memory_chunks (id, memory_id, chunk_index, chunk_text, embedding) VALUES (?, ?, ?, ?, ?)",
+                rusqlite::params![format!("{}_c{}", id, i), id, i as i64, text, crate::embedding::compress_embedding(v)],
+            )
+            .unwrap();
+        }
+
+        let scoring = ScoringConfig::default();
+        let results = search_hybrid(
+            &conn,
+            "zzz",
+            Some(&[1.0, 0.0]),
+            5,
+            &SearchFilters::default(),
+            &scoring,
+            VectorSource::Primary(None),
+        );
+        assert!(results[0].chunk_hit);
+        assert_eq!(results[0].chunk_index, Some(1));
+        assert_eq!(results[0].chunk_text.as_deref(), Some("rollback steps"));
+
+        // Hit pelo vetor da memória: sem chunk
+        let results = search_hybrid(
+            &conn,
+            "zzz",
+            Some(&[0.0, 1.0]),
+            5,
+            &SearchFilters::default(),
+            &scoring,
+            VectorSource::Primary(None),
+        );
+        assert!(!results[0].chunk_hit);
+        assert_eq!(results[0].chunk_index, None);
+    }
+
+    #[test]
+    fn test_graph_neighbors_respect_filters() {
+        let conn = test_db("graph-filters");
+        let opts = storage::SaveOptions { dedup_threshold: Some(1.0), ..Default::default() };
+        let hit = storage::save_memory(&conn, "decision", "Adopt ledger sharding", "", &opts).unwrap();
+        let same = storage::save_memory(&conn, "decision", "Shard keys are account ids", "", &opts).unwrap();
+        let other = storage::save_memory(&conn, "note", "Sharding meeting notes", "", &opts).unwrap();
+        storage::create_edge(&conn, &hit.id, &same.id, "relates_to").unwrap();
+        storage::create_edge(&conn, &hit.id, &other.id, "relates_to").unwrap();
+
+        let filters = SearchFilters { mem_type: Some("decision".into()), ..Default::default() };
+        let scoring = ScoringConfig::default();
+        let results = search_hybrid(&conn, "ledger", None, 5, &filters, &scoring, VectorSource::Primary(None));
+        let ids: Vec<&str> = results.iter().map(|r| r.id.as_str()).collect();
+        assert!(ids.contains(&same.id.as_str()));
+        assert!(!ids.contains(&other.id.as_str()));
+        assert!(results.iter().all(|r| r.mem_type == "decision"));
+    }
+
+    #[test]
+    fn test_date_range_filter() {
+        let conn = test_db("date-range");
+        let old = storage::save_memory(&conn, "note", "Release checklist drafted", "", &Default::default()).unwrap();
+        storage::save_memory(&conn, "note", "Release branch cut today", "", &Default::default()).unwrap();
+        conn.execute(
+            "UPDATE memories SET created_at = '2024-03-10 15:30:00' WHERE id = ?",
+            [&old.id],
+        )
+        .unwrap();
+
+        let march = SearchFilters::default()
+            .with_date_range(Some("2024-03-01"), Some("2024-03-10"))
+            .unwrap();
+        let results = search_fts(&conn, "release", 10, &march);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, old.id);
+        assert_eq!(storage::list_memories(&conn, &march, 10, 0).unwrap().len(), 1);
+
+        let recent = SearchFilters::default()
+            .with_date_range(Some("2024-03-10T16:00:00Z"), None)
+            .unwrap();
+        assert_eq!(search_fts(&conn, "release", 10, &recent).len(), 1);
+
+        assert!(SearchFilters::default().with_date_range(Some("last week"), None).is_err());
+        assert!(SearchFilters::default()
+            .with_date_range(Some("2024-03-10"), Some("2024-03-01"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_ann_search_matches_linear_scan() {
+        let conn = test_db("ann");
+        let mut ids = Vec::new();
+        for i in 0..30 {
+            let saved = storage::save_memory(
+                &conn,
+                if i % 2 == 0 { "note" } else { "decision" },
+                &format!("ann memory number {}", i),
+                "",
+                &Default::default(),
+            )
+            .unwrap();
+            let emb: Vec<f32> = (0..8).map(|d| ((i * 7 + d * 3) % 11) as f32 + 1.0).collect();
+            let emb = crate::embedding::l2_normalized(emb);
+            conn.execute(
+                "UPDATE memories SET embedding = ? WHERE id = ?",
+                rusqlite::params![crate::embedding::compress_embedding(&emb), saved.id],
+            )
+            .unwrap();
+            ids.push(saved.id);
+        }
+
+        let registry = crate::ann::AnnRegistry::new();
+        let scope = AnnScope { registry: &registry, db_key: "test" };
+        let query: Vec<f32> = (0..8).map(|d| ((d * 3) % 11) as f32 + 1.0).collect();
+        let query = crate::embedding::l2_normalized(query);
+        let linear = search_embedding(&conn, &query, 5, &SearchFilters::default(), DEFAULT_MIN_SIM);
+        let indexed = search_embedding_ann(&conn, &scope, &query, 5, &SearchFilters::default(), DEFAULT_MIN_SIM).unwrap();
+        assert_eq!(linear[0].id, indexed[0].id);
+
+        // Memória apagada por fora: o índice percebe a mudança e é refeito
+        conn.execute("DELETE FROM memories WHERE id = ?", [&linear[0].id]).unwrap();
+        let after = search_embedding_ann(&conn, &scope, &query, 5, &SearchFilters::default(), DEFAULT_MIN_SIM).unwrap();
+        assert!(after.iter().all(|r| r.id != linear[0].id));
+    }
+
+    #[test]
+    fn test_int8_quantization_recall() {
+        use crate::embedding::{decode_embedding, l2_normalized, EmbeddingFormat};
+
+        // Vetores pseudo-aleatórios determinísticos (LCG)
+        let mut seed = 42u64;
+        let mut next = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((seed >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+        };
+        const DIM: usize = 384;
+        let corpus: Vec<Vec<f32>> = (0..300).map(|_| l2_normalized((0..DIM).map(|_| next()).collect())).collect();
+        // Conjunto rotulado: cada query é um documento com ruído, o rótulo é o documento
+        let queries: Vec<(usize, Vec<f32>)> = (0..60)
+            .map(|i| {
+                let target = i * 5;
+                let q = corpus[target].iter().map(|x| x + next() * 0.08).collect();
+                (target, l2_normalized(q))
+            })
+            .collect();
+
+        let top_k = |docs: &[Vec<f32>], q: &[f32], k: usize| -> Vec<usize> {
+            let relevance: Vec<f64> = docs.iter().map(|d| dot_similarity(q, d)).collect();
+            let mut idx: Vec<usize> = (0..docs.len()).collect();
+            idx.sort_by(|&a, &b| cmp_relevance_desc(relevance[a], relevance[b]));
+            idx.truncate(k);
+            idx
+        };
+        let stored = |format: EmbeddingFormat| -> Vec<Vec<f32>> {
+            corpus.iter().map(|v| decode_embedding(&format.encode(v), DIM).unwrap()).collect()
+        };
+
+        let (f16_docs, int8_docs) = (stored(EmbeddingFormat::F16), stored(EmbeddingFormat::Int8));
+        let mut hits = [0usize; 2];
+        let mut overlap = 0usize;
+        for (label, q) in &queries {
+            for (slot, docs) in [&f16_docs, &int8_docs].into_iter().enumerate() {
+                if top_k(docs, q, 1)[0] == *label {
+                    hits[slot] += 1;
+                }
+            }
+            let exact = top_k(&f16_docs, q, 10);
+            overlap += top_k(&int8_docs, q, 10).iter().filter(|i| exact.contains(i)).count();
+        }
+        let recall_at_10 = overlap as f64 / (queries.len() * 10) as f64;
+        // Medido: top-1 idêntico e recall@10 ~0.99 com metade do espaço do f16
+        assert_eq!(hits[1], hits[0], "int8 top-1 {}/{} vs f16 {}/{}", hits[1], queries.len(), hits[0], queries.len());
+        assert!(recall_at_10 >= 0.9, "int8 recall@10 = {:.3}", recall_at_10);
+        assert!(EmbeddingFormat::Int8.encode(&corpus[0]).len() < EmbeddingFormat::F16.encode(&corpus[0]).len());
+
+        // Blobs int8 e f16 convivem no mesmo DB
+        let conn = test_db("int8");
+        for (format, content) in [(EmbeddingFormat::Int8, "quantized"), (EmbeddingFormat::F16, "half")] {
+            let saved = storage::save_memory(&conn, "note", content, "", &Default::default()).unwrap();
+            conn.execute(
+                "UPDATE memories SET embedding = ? WHERE id = ?",
+                rusqlite::params![format.encode(&corpus[0]), saved.id],
+            )
+            .unwrap();
+        }
+        let results = search_embedding(&conn, &corpus[0], 5, &SearchFilters::default(), DEFAULT_MIN_SIM);
+        assert_eq!(results.len(), 2);
+        assert!((results[0].relevance - results[1].relevance).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_metadata_filter() {
+        assert!(SearchFilters::default().with_metadata(Some("[1]")).is_err());
+        assert!(SearchFilters::default().with_metadata(Some(r#"{"a": {"b": 1}}"#)).is_err());
+
+        let conn = test_db("metadata-filter");
+        for (content, meta) in [
+            ("retry policy from the docs", r#"{"source": "docs", "confidence": 0.9, "reviewed": true}"#),
+            ("retry policy from a chat", r#"{"source": "chat", "confidence": 0.4}"#),
+            ("retry policy without metadata", ""),
+        ] {
+            let meta = storage::normalize_metadata(meta).unwrap();
+            let opts = storage::SaveOptions { metadata: meta.as_deref(), dedup_threshold: Some(1.0), ..Default::default() };
+            storage::save_memory(&conn, "note", content, "", &opts).unwrap();
+        }
+
+        let search = |filter: &str| -> Vec<String> {
+            let filters = SearchFilters::default().with_metadata(Some(filter)).unwrap();
+            search_fts(&conn, "retry", 10, &filters).into_iter().map(|r| r.content).collect()
+        };
+        assert_eq!(search(r#"{"source": "docs"}"#), ["retry policy from the docs"]);
+        assert_eq!(search(r#"{"confidence": 0.4}"#), ["retry policy from a chat"]);
+        assert_eq!(search(r#"{"reviewed": true, "source": "docs"}"#).len(), 1);
+        assert!(search(r#"{"source": "web"}"#).is_empty());
+    }
+
+    #[test]
+    fn test_search_regex_matches_exact_identifiers_by_recency() {
+        let conn = test_db("regex");
+        let uuid = "3f2b9c1e-8a4d-4e7b-9c0a-1d2e3f4a5b6c";
+        conn.execute_batch(&format!(
+            "INSERT INTO memories (id, type, content, tags, created_at) VALUES
+             ('old', 'note', 'call storage::save_memory before indexing', '', '2024-01-01 00:00:00'),
+             ('new', 'note', 'storage::save_memory now returns the record', 'api', '2024-06-01 00:00:00'),
+             ('uuid', 'bug', 'request {} failed', 'incident', '2024-03-01 00:00:00'),
+             ('tagged', 'note', 'unrelated text', 'storage-layer', '2024-02-01 00:00:00');",
+            uuid
+        ))
+        .unwrap();
+        let budget = REGEX_SCAN_BUDGET;
+
+        let re = compile_search_regex(r"storage::save_memory").unwrap();
+        let (hits, timed_out) = search_regex(&conn, &re, false, 10, &SearchFilters::default(), budget);
+        let ids: Vec<&str> = hits.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["new", "old"]);
+        assert!(!timed_out);
+        assert_eq!(hits[0].method, "regex");
+
+        let re = compile_search_regex(r"[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}").unwrap();
+        let (hits, _) = search_regex(&conn, &re, false, 10, &SearchFilters::default(), budget);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, "uuid");
+
+        // tags só entram com include_tags; filtros e limit valem como na busca normal
+        let re = compile_search_regex(r"^storage").unwrap();
+        assert_eq!(search_regex(&conn, &re, false, 10, &SearchFilters::default(), budget).0.len(), 1);
+        assert_eq!(search_regex(&conn, &re, true, 10, &SearchFilters::default(), budget).0.len(), 2);
+        let re = compile_search_regex(r"storage").unwrap();
+        assert_eq!(search_regex(&conn, &re, true, 1, &SearchFilters::default(), budget).0[0].id, "new");
+        let bugs = SearchFilters { mem_type: Some("bug".into()), ..Default::default() };
+        assert!(search_regex(&conn, &re, true, 10, &bugs, budget).0.is_empty());
+
+        // budget esgotado devolve o que já achou e avisa
+        let (hits, timed_out) = search_regex(&conn, &re, true, 10, &SearchFilters::default(), std::time::Duration::ZERO);
+        assert!(hits.is_empty() && timed_out);
+
+        assert!(compile_search_regex("(unclosed").unwrap_err().contains("invalid regex"));
+        assert!(compile_search_regex(&"a".repeat(2000)).unwrap_err().contains("too long"));
+        assert!(compile_search_regex(r"\w{1000}{1000}").is_err());
+    }
+
+    #[test]
+    fn test_fts_body_match_outranks_tag_match() {
+        let conn = test_db("fts-weights");
+        let opts = storage::SaveOptions { dedup_threshold: Some(1.0), ..Default::default() };
+        storage::save_memory(&conn, "note", "Weekly sync notes about hiring", "kafka", &opts).unwrap();
+        let body = storage::save_memory(
+            &conn,
+            "note",
+            "Consumer lag grows when kafka partitions are rebalanced during deploys; pin the group id",
+            "",
+            &opts,
+        )
+        .unwrap();
+
+        let hits = search_fts(&conn, "kafka", 5, &SearchFilters::default());
+        assert_eq!(hits.len(), 2, "a tag match still counts");
+        assert_eq!(hits[0].id, body.id);
+    }
+
+    #[test]
+    fn test_fit_context_caps_each_result_and_total() {
+        let log = "line of a pasted build log ".repeat(100);
+        let short = "Use WAL mode for SQLite";
+
+        let out = fit_context([log.as_str(), short], 100, 1000);
+        assert_eq!(out.len(), 2);
+        assert!(out[0].ends_with('…') && out[0].chars().count() <= 101);
+        assert_eq!(out[1], short);
+
+        // o total corta os resultados seguintes; o primeiro entra mesmo sozinho
+        let out = fit_context([log.as_str(), log.as_str(), log.as_str()], 500, 1200);
+        assert_eq!(out.len(), 2);
+        let out = fit_context([log.as_str()], 500, 50);
+        assert_eq!(out.len(), 1);
+        assert!(out[0].chars().count() <= 51);
+
+        // 0 desliga os limites
+        assert_eq!(fit_context([log.as_str()], 0, 0), vec![log.trim().to_string()]);
+    }
+
+    #[test]
+    fn test_embedding_scan_hydrates_only_top_hits_in_order() {
+        let conn = test_db("two-phase");
+        let query = [1.0f32, 0.0];
+        let mut ids = Vec::new();
+        for (i, v) in [[0.6f32, 0.8], [1.0, 0.0], [0.8, 0.6], [0.8, 0.6]].iter().enumerate() {
+            let id = storage::save_memory(&conn, "note", &format!("vector memory {}", i), "", &Default::default())
+                .unwrap()
+                .id;
+            conn.execute(
+                "UPDATE memories SET embedding = ?, importance = 0.5, created_at = ? WHERE id = ?",
+                rusqlite::params![crate::embedding::compress_embedding(v), format!("2024-01-0{}", i + 1), id],
+            )
+            .unwrap();
+            ids.push(id);
+        }
+        let results = search_embedding(&conn, &query, 2, &SearchFilters::default(), 0.1);
+        // Empate (0.8) desempata pelo created_at mais novo, como cmp_results
+        let got: Vec<&str> = results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(got, [ids[1].as_str(), ids[3].as_str()]);
+        assert_eq!(results[1].content, "vector memory 3");
+        assert_eq!(results[1].created_at, "2024-01-04");
+    }
+
+    /// Benchmark: `cargo test --release bench_embedding_scan -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_embedding_scan() {
+        let conn = test_db("bench-scan");
+        let dim = 384;
+        let body = "2024-05-01 INFO request handled, latency figures attached\n".repeat(400);
+        let tx = conn.unchecked_transaction().unwrap();
+        for i in 0..2000usize {
+            let v: Vec<f32> = (0..dim).map(|j| ((i * 31 + j * 7) % 97) as f32 - 48.0).collect();
+            tx.execute(
+                "INSERT INTO memories (id, type, content, tags, embedding, importance) VALUES (?, 'note', ?, '', ?, 0.5)",
+                rusqlite::params![
+                    format!("m{}", i),
+                    format!("{} {}", i, body),
+                    crate::embedding::compress_embedding(&crate::embedding::l2_normalized(v))
+                ],
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+        let query = crate::embedding::l2_normalized((0..dim).map(|j| (j % 13) as f32).collect());
+        let rounds = 20;
+
+        // Antes: uma fase, com o conteúdo de toda linha materializado
+        let started = std::time::Instant::now();
+        let mut bytes = 0usize;
+        for _ in 0..rounds {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.tags, m.created_at, \
+                     m.embedding, m.importance FROM memories m WHERE m.embedding IS NOT NULL \
+                     AND m.archived = 0 AND m.deleted_at IS NULL AND m.importance >= 0.2 \
+                     ORDER BY m.importance DESC, m.access_count DESC, m.updated_at DESC, m.id LIMIT 2000",
+                )
+                .unwrap();
+            let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(2)?, r.get::<_, Vec<u8>>(5)?))).unwrap();
+            for (content, blob) in rows.flatten() {
+                bytes += content.len();
+                std::hint::black_box(dot_similarity(&query, &decode_embedding(&blob, dim).unwrap()));
+            }
+        }
+        let single = started.elapsed();
+
+        let started = std::time::Instant::now();
+        for _ in 0..rounds {
+            std::hint::black_box(search_embedding(&conn, &query, 10, &SearchFilters::default(), 0.0));
+        }
+        let two_phase = started.elapsed();
+        println!(
+            "2000 memories (~{} KB content each), {} rounds: content in scan {:?} ({} MB read), two-phase {:?}",
+            body.len() / 1024,
+            rounds,
+            single / rounds,
+            bytes / rounds as usize / (1024 * 1024),
+            two_phase / rounds
+        );
+    }
 }
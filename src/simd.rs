@@ -0,0 +1,211 @@
+//! Produto escalar e normas em f32 vetorizados (caminho quente da busca por embedding).
+//!
+//! x86_64: AVX2 + FMA detectados em runtime. Demais alvos (ou CPU sem AVX2):
+//! fallback com 8 acumuladores independentes, que o LLVM vetoriza (NEON/SSE).
+
+const LANES: usize = 8;
+
+/// (a·b, a·a, b·b) sobre o prefixo comum dos dois vetores
+pub fn dot_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // SAFETY: features verificadas acima
+            return unsafe { dot_norms_avx2(a, b) };
+        }
+    }
+    dot_norms_lanes(a, b)
+}
+
+/// Produto escalar a·b, sem acumular as normas (vetores já normalizados dispensam)
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // SAFETY: features verificadas acima
+            return unsafe { dot_avx2(a, b) };
+        }
+    }
+    dot_lanes(a, b)
+}
+
+fn dot_lanes(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    let mut d = [0.0f32; LANES];
+    let mut ca = a.chunks_exact(LANES);
+    let mut cb = b.chunks_exact(LANES);
+    for (xa, xb) in (&mut ca).zip(&mut cb) {
+        for i in 0..LANES {
+            d[i] += xa[i] * xb[i];
+        }
+    }
+    let mut dot = d.iter().sum::<f32>();
+    for (x, y) in ca.remainder().iter().zip(cb.remainder()) {
+        dot += x * y;
+    }
+    dot
+}
+
+fn dot_norms_lanes(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    let mut d = [0.0f32; LANES];
+    let mut na = [0.0f32; LANES];
+    let mut nb = [0.0f32; LANES];
+    let mut ca = a.chunks_exact(LANES);
+    let mut cb = b.chunks_exact(LANES);
+    for (xa, xb) in (&mut ca).zip(&mut cb) {
+        for i in 0..LANES {
+            d[i] += xa[i] * xb[i];
+            na[i] += xa[i] * xa[i];
+            nb[i] += xb[i] * xb[i];
+        }
+    }
+    let (mut dot, mut sa, mut sb) = (d.iter().sum::<f32>(), na.iter().sum::<f32>(), nb.iter().sum::<f32>());
+    for (x, y) in ca.remainder().iter().zip(cb.remainder()) {
+        dot += x * y;
+        sa += x * x;
+        sb += y * y;
+    }
+    (dot, sa, sb)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_norms_avx2(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    use std::arch::x86_64::*;
+
+    unsafe fn hsum(v: __m256) -> f32 {
+        let mut buf = [0.0f32; LANES];
+        _mm256_storeu_ps(buf.as_mut_ptr(), v);
+        buf.iter().sum()
+    }
+
+    let n = a.len().min(b.len());
+    let chunks = n / LANES;
+    let mut d = _mm256_setzero_ps();
+    let mut na = _mm256_setzero_ps();
+    let mut nb = _mm256_setzero_ps();
+    for i in 0..chunks {
+        let va = _mm256_loadu_ps(a.as_ptr().add(i * LANES));
+        let vb = _mm256_loadu_ps(b.as_ptr().add(i * LANES));
+        d = _mm256_fmadd_ps(va, vb, d);
+        na = _mm256_fmadd_ps(va, va, na);
+        nb = _mm256_fmadd_ps(vb, vb, nb);
+    }
+    let (mut dot, mut sa, mut sb) = (hsum(d), hsum(na), hsum(nb));
+    for i in chunks * LANES..n {
+        dot += a[i] * b[i];
+        sa += a[i] * a[i];
+        sb += b[i] * b[i];
+    }
+    (dot, sa, sb)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    let n = a.len().min(b.len());
+    let chunks = n / LANES;
+    let mut d = _mm256_setzero_ps();
+    for i in 0..chunks {
+        let va = _mm256_loadu_ps(a.as_ptr().add(i * LANES));
+        let vb = _mm256_loadu_ps(b.as_ptr().add(i * LANES));
+        d = _mm256_fmadd_ps(va, vb, d);
+    }
+    let mut buf = [0.0f32; LANES];
+    _mm256_storeu_ps(buf.as_mut_ptr(), d);
+    let mut dot: f32 = buf.iter().sum();
+    for i in chunks * LANES..n {
+        dot += a[i] * b[i];
+    }
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vectors(n: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        (0..n)
+            .map(|_| {
+                (0..dim)
+                    .map(|_| {
+                        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                        ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Referência em f64 (a implementação escalar original)
+    fn cosine_f64(a: &[f32], b: &[f32]) -> f64 {
+        let (mut d, mut na, mut nb) = (0.0f64, 0.0f64, 0.0f64);
+        for (x, y) in a.iter().zip(b) {
+            d += *x as f64 * *y as f64;
+            na += *x as f64 * *x as f64;
+            nb += *y as f64 * *y as f64;
+        }
+        d / (na.sqrt() * nb.sqrt())
+    }
+
+    #[test]
+    fn test_simd_matches_f64_reference() {
+        for dim in [3, 8, 13, 384, 768] {
+            let vs = vectors(20, dim, dim as u64);
+            for pair in vs.chunks_exact(2) {
+                let (d, na, nb) = dot_norms(&pair[0], &pair[1]);
+                let cos = d as f64 / ((na as f64).sqrt() * (nb as f64).sqrt());
+                assert!((cos - cosine_f64(&pair[0], &pair[1])).abs() < 1e-6, "dim {}", dim);
+                let (ld, _, _) = dot_norms_lanes(&pair[0], &pair[1]);
+                assert!((ld - d).abs() < 1e-4);
+                // Kernel só do produto escalar: mesmo resultado, sem as normas
+                assert!((dot(&pair[0], &pair[1]) - d).abs() < 1e-4);
+                assert!((dot_lanes(&pair[0], &pair[1]) - d).abs() < 1e-4);
+            }
+        }
+    }
+
+    /// Benchmark: `cargo test --release bench_cosine -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_cosine_384() {
+        let corpus = vectors(5000, 384, 1);
+        let query = &vectors(1, 384, 2)[0];
+        let rounds = 20;
+
+        let started = std::time::Instant::now();
+        let mut acc = 0.0f64;
+        for _ in 0..rounds {
+            for v in &corpus {
+                acc += cosine_f64(query, v);
+            }
+        }
+        let scalar = started.elapsed();
+
+        let started = std::time::Instant::now();
+        let mut acc_simd = 0.0f64;
+        for _ in 0..rounds {
+            for v in &corpus {
+                let (d, na, nb) = dot_norms(query, v);
+                acc_simd += (d / (na.sqrt() * nb.sqrt())) as f64;
+            }
+        }
+        let simd = started.elapsed();
+
+        let total = (rounds * corpus.len()) as f64;
+        println!(
+            "384-dim cosine: scalar f64 {:.1} M/s, simd f32 {:.1} M/s ({:.1}x) [checksum {:.3} / {:.3}]",
+            total / scalar.as_secs_f64() / 1e6,
+            total / simd.as_secs_f64() / 1e6,
+            scalar.as_secs_f64() / simd.as_secs_f64(),
+            acc,
+            acc_simd
+        );
+    }
+}
@@ -1,22 +1,51 @@
 use std::path::{Path, PathBuf};
 use anyhow::Result;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
 use sha2::{Sha256, Digest};
 
+/// Onde ficam os DBs compartilhados, em ordem: `MCP_DATA_DIR`, `$XDG_DATA_HOME/mcp-memoria`,
+/// `~/.mcp-memoria/data`. Um `~/.mcp-memoria/data` que já existe ganha do XDG, para quem
+/// já tem memórias lá não ficar com um DB vazio ao definir `XDG_DATA_HOME`.
+/// Valores vazios são ignorados; `XDG_DATA_HOME` relativo também (a spec exige absoluto).
+pub fn resolve_data_dir(
+    mcp_data_dir: Option<PathBuf>,
+    xdg_data_home: Option<PathBuf>,
+    home: Option<PathBuf>,
+) -> Option<PathBuf> {
+    if let Some(dir) = mcp_data_dir.filter(|d| !d.as_os_str().is_empty()) {
+        return Some(dir);
+    }
+    let legacy = home.map(|h| h.join(".mcp-memoria").join("data"));
+    if let Some(legacy) = legacy.as_ref().filter(|l| l.is_dir()) {
+        return Some(legacy.clone());
+    }
+    xdg_data_home
+        .filter(|x| x.is_absolute())
+        .map(|x| x.join("mcp-memoria"))
+        .or(legacy)
+}
+
 /// Diretórios e paths dos DBs
 pub struct MemoryPaths {
     pub global_db: PathBuf,
     pub personality_db: PathBuf,
+    /// Cache de embeddings compartilhado entre scopes
+    pub cache_db: PathBuf,
     pub data_dir: PathBuf,
 }
 
 impl MemoryPaths {
+    /// Diretório de dados (ver `resolve_data_dir`), criado se não existir
     pub fn new() -> Result<Self> {
-        let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("home dir not found"))?;
-        let data_dir = home.join(".mcp-memoria").join("data");
+        let env = |key: &str| std::env::var_os(key).map(PathBuf::from);
+        let data_dir = resolve_data_dir(env("MCP_DATA_DIR"), env("XDG_DATA_HOME"), dirs::home_dir())
+            .ok_or_else(|| anyhow::anyhow!("home dir not found (set MCP_DATA_DIR)"))?;
+        std::fs::create_dir_all(&data_dir)?;
         Ok(Self {
             global_db: data_dir.join("global.db"),
             personality_db: data_dir.join("personality.db"),
+            cache_db: data_dir.join("cache.db"),
             data_dir,
         })
     }
@@ -30,16 +59,113 @@ impl MemoryPaths {
                     .ok()
                     .map(|p| p.to_string_lossy().to_string())
             })?;
-        Some(Path::new(&cwd).join(".mcp-memoria").join("project.db"))
+        Some(Self::project_db_in(Path::new(&cwd)))
+    }
+
+    /// DB de projeto dentro de um diretório: `<dir>/.mcp-memoria/project.db`
+    pub fn project_db_in(dir: &Path) -> PathBuf {
+        dir.join(".mcp-memoria").join("project.db")
+    }
+
+    /// DB de projeto de um checkout informado pelo usuário; o diretório precisa existir
+    pub fn project_db_at(project_path: &str) -> std::result::Result<PathBuf, String> {
+        let dir = Path::new(project_path.trim());
+        if !dir.is_dir() {
+            return Err(format!("project_path '{}' is not an existing directory", project_path));
+        }
+        Ok(Self::project_db_in(dir))
+    }
+
+    /// Confere se o DB de projeto pode ser aberto/criado para escrita em `db_path`.
+    /// Pega os casos comuns (path é diretório, projeto inexistente, diretório só leitura)
+    /// antes do `init_db`, com uma mensagem que aponta a saída.
+    pub fn check_project_db(db_path: &Path) -> std::result::Result<(), String> {
+        match Self::project_db_problem(db_path) {
+            None => Ok(()),
+            Some(r) => Err(format!(
+                "cannot create project DB at {}: {}. Use scope='global' or scope='personality', \
+                 or set MCP_PROJECT_DIR to a writable project directory",
+                db_path.display(),
+                r
+            )),
+        }
+    }
+
+    fn project_db_problem(db_path: &Path) -> Option<String> {
+        if db_path.is_dir() {
+            return Some("path is a directory".into());
+        }
+        let memoria_dir = db_path.parent()?;
+        if db_path.exists() {
+            if std::fs::OpenOptions::new().append(true).open(db_path).is_err() {
+                return Some("database file is read-only".into());
+            }
+            // WAL cria -wal/-shm ao lado do DB
+            return (!dir_writable(memoria_dir)).then(|| format!("{} is not writable", memoria_dir.display()));
+        }
+        if memoria_dir.exists() {
+            if !memoria_dir.is_dir() {
+                return Some(format!("{} is not a directory", memoria_dir.display()));
+            }
+            return (!dir_writable(memoria_dir)).then(|| format!("{} is not writable", memoria_dir.display()));
+        }
+        let project_dir = memoria_dir.parent()?;
+        if !project_dir.is_dir() {
+            return Some(format!("project directory {} does not exist", project_dir.display()));
+        }
+        (!dir_writable(project_dir)).then(|| format!("project directory {} is not writable", project_dir.display()))
+    }
+}
+
+/// Testa escrita criando (e apagando) um arquivo: o bit de permissão não diz tudo
+/// (root, ACL, montagem read-only)
+fn dir_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".mcp-memory-probe-{}", std::process::id()));
+    match std::fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        // Sobra de um probe interrompido: o diretório aceitou escrita
+        Err(e) => e.kind() == std::io::ErrorKind::AlreadyExists,
     }
 }
 
-/// Resolve scope para lista de (nome, path)
+/// Resolve scope para lista de (nome, path), com o projeto do cwd
 pub fn resolve_scope_dbs(scope: &str, paths: &MemoryPaths) -> Vec<(String, PathBuf)> {
-    match scope {
+    resolve_scope_dbs_with(scope, paths, MemoryPaths::project_db_path())
+}
+
+/// Scopes aceitos pelas tools
+pub const SCOPES: [&str; 5] = ["global", "project", "personality", "both", "all"];
+
+/// Nome canônico do scope; aceita caixa/espaços diferentes e alguns aliases
+/// ("project-only", "everything"...). Desconhecido vira erro em vez de lista vazia.
+pub fn canonical_scope(scope: &str) -> std::result::Result<&'static str, String> {
+    let normalized = scope.trim().to_lowercase();
+    let canonical = match normalized.as_str() {
+        "global" | "global-only" => "global",
+        "project" | "project-only" => "project",
+        "personality" | "personality-only" | "personal" => "personality",
+        "both" | "global+project" | "project+global" => "both",
+        "all" | "everything" | "*" => "all",
+        _ => {
+            return Err(format!("unknown scope '{}'; valid: {}", scope, SCOPES.join(", ")));
+        }
+    };
+    Ok(canonical)
+}
+
+/// Resolve scope com um DB de projeto explícito (None = sem projeto)
+pub fn resolve_scope_dbs_with(
+    scope: &str,
+    paths: &MemoryPaths,
+    project_db: Option<PathBuf>,
+) -> Vec<(String, PathBuf)> {
+    match canonical_scope(scope).unwrap_or_default() {
         "global" => vec![("global".into(), paths.global_db.clone())],
         "project" => {
-            if let Some(p) = MemoryPaths::project_db_path() {
+            if let Some(p) = project_db {
                 vec![("project".into(), p)]
             } else {
                 vec![]
@@ -48,7 +174,7 @@ pub fn resolve_scope_dbs(scope: &str, paths: &MemoryPaths) -> Vec<(String, PathB
         "personality" => vec![("personality".into(), paths.personality_db.clone())],
         "both" => {
             let mut dbs = vec![("global".into(), paths.global_db.clone())];
-            if let Some(p) = MemoryPaths::project_db_path() {
+            if let Some(p) = project_db {
                 dbs.push(("project".into(), p));
             }
             dbs
@@ -58,7 +184,7 @@ pub fn resolve_scope_dbs(scope: &str, paths: &MemoryPaths) -> Vec<(String, PathB
                 ("global".into(), paths.global_db.clone()),
                 ("personality".into(), paths.personality_db.clone()),
             ];
-            if let Some(p) = MemoryPaths::project_db_path() {
+            if let Some(p) = project_db {
                 dbs.push(("project".into(), p));
             }
             dbs
@@ -73,8 +199,8 @@ pub fn init_db(db_path: &Path) -> Result<Connection> {
         std::fs::create_dir_all(parent)?;
     }
 
-    let conn = Connection::open(db_path)?;
-    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;")?;
+    let conn = open_connection(db_path)?;
+    conn.execute_batch("PRAGMA journal_mode=WAL;")?;
 
     conn.execute_batch(
         "CREATE TABLE IF NOT EXISTS memories (
@@ -87,7 +213,11 @@ pub fn init_db(db_path: &Path) -> Result<Connection> {
             embedding BLOB,
             access_count INTEGER DEFAULT 0,
             importance FLOAT DEFAULT 0.5,
-            archived INTEGER DEFAULT 0
+            archived INTEGER DEFAULT 0,
+            deleted_at DATETIME,
+            metadata TEXT,
+            content_zstd BLOB,
+            expires_at DATETIME
         );
 
         CREATE TABLE IF NOT EXISTS memory_chunks (
@@ -107,6 +237,14 @@ pub fn init_db(db_path: &Path) -> Result<Connection> {
             PRIMARY KEY (text_hash, model)
         );
 
+        CREATE TABLE IF NOT EXISTS model_embeddings (
+            memory_id TEXT NOT NULL,
+            model TEXT NOT NULL,
+            embedding BLOB NOT NULL,
+            PRIMARY KEY (memory_id, model),
+            FOREIGN KEY (memory_id) REFERENCES memories(id) ON DELETE CASCADE
+        );
+
         CREATE TABLE IF NOT EXISTS memory_edges (
             from_id TEXT NOT NULL,
             to_id TEXT NOT NULL,
@@ -117,6 +255,21 @@ pub fn init_db(db_path: &Path) -> Result<Connection> {
             FOREIGN KEY (to_id) REFERENCES memories(id) ON DELETE CASCADE
         );
 
+        CREATE TABLE IF NOT EXISTS embedding_failures (
+            record_id TEXT PRIMARY KEY,
+            db_path TEXT NOT NULL,
+            error TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 1,
+            last_attempt DATETIME DEFAULT CURRENT_TIMESTAMP,
+            next_retry_at DATETIME NOT NULL,
+            FOREIGN KEY (record_id) REFERENCES memories(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS schema_meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+
         CREATE INDEX IF NOT EXISTS idx_type ON memories(type);
         CREATE INDEX IF NOT EXISTS idx_created ON memories(created_at);
         CREATE INDEX IF NOT EXISTS idx_chunks_memory ON memory_chunks(memory_id);
@@ -124,44 +277,243 @@ pub fn init_db(db_path: &Path) -> Result<Connection> {
         CREATE INDEX IF NOT EXISTS idx_edges_to ON memory_edges(to_id);",
     )?;
 
-    // Migrate existing DBs: add columns if missing
-    migrate_add_column(&conn, "memories", "access_count", "INTEGER DEFAULT 0");
-    migrate_add_column(&conn, "memories", "importance", "FLOAT DEFAULT 0.5");
-    migrate_add_column(&conn, "memories", "archived", "INTEGER DEFAULT 0");
-
-    // Index on archived (after migration ensures column exists)
-    let _ = conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_archived ON memories(archived);");
+    // DBs antigos: colunas novas via migrações versionadas
+    let applied = run_migrations(&conn)?;
+    if applied > 0 {
+        tracing::info!(
+            "Applied {} schema migrations to {} (now v{})",
+            applied,
+            db_path.display(),
+            SCHEMA_VERSION
+        );
+    }
 
     // Backfill importance by type (only for default 0.5 values from migration)
     backfill_importance(&conn);
 
+    // Embeddings de versões antigas: normaliza uma vez (trigger de FTS recriado logo abaixo)
+    if get_meta(&conn, NORMALIZED_KEY).is_none() {
+        let _ = conn.execute_batch("DROP TRIGGER IF EXISTS memories_au;");
+        let tx = conn.unchecked_transaction()?;
+        let normalized = crate::embedding::normalize_stored_embeddings(&tx);
+        set_meta(&tx, NORMALIZED_KEY, "1")?;
+        tx.commit()?;
+        if normalized > 0 {
+            tracing::info!("Normalized {} stored embeddings in {}", normalized, db_path.display());
+        }
+    }
+
+    // Triggers antigos indexavam toda linha; os atuais deixam a lixeira fora do FTS
+    if get_meta(&conn, SOFT_DELETE_KEY).is_none() {
+        conn.execute_batch(
+            "DROP TRIGGER IF EXISTS memories_ai;
+             DROP TRIGGER IF EXISTS memories_ad;
+             DROP TRIGGER IF EXISTS memories_au;",
+        )?;
+        set_meta(&conn, SOFT_DELETE_KEY, "1")?;
+    }
+
     // FTS5
+    conn.execute_batch(FTS_TABLE)?;
     conn.execute_batch(
-        "CREATE VIRTUAL TABLE IF NOT EXISTS memories_fts USING fts5(
-            content, tags, content='memories', content_rowid='rowid'
-        );
-
-        CREATE TRIGGER IF NOT EXISTS memories_ai AFTER INSERT ON memories BEGIN
+        "CREATE TRIGGER IF NOT EXISTS memories_ai AFTER INSERT ON memories
+        WHEN NEW.deleted_at IS NULL BEGIN
             INSERT INTO memories_fts(rowid, content, tags)
-            VALUES (NEW.rowid, NEW.content, NEW.tags);
-        END;
-
-        CREATE TRIGGER IF NOT EXISTS memories_ad AFTER DELETE ON memories BEGIN
-            INSERT INTO memories_fts(memories_fts, rowid, content, tags)
-            VALUES('delete', OLD.rowid, OLD.content, OLD.tags);
+            VALUES (NEW.rowid, memory_content(NEW.content, NEW.content_zstd), NEW.tags);
         END;
 
-        CREATE TRIGGER IF NOT EXISTS memories_au AFTER UPDATE ON memories BEGIN
+        CREATE TRIGGER IF NOT EXISTS memories_ad AFTER DELETE ON memories
+        WHEN OLD.deleted_at IS NULL BEGIN
             INSERT INTO memories_fts(memories_fts, rowid, content, tags)
-            VALUES('delete', OLD.rowid, OLD.content, OLD.tags);
-            INSERT INTO memories_fts(rowid, content, tags)
-            VALUES (NEW.rowid, NEW.content, NEW.tags);
+            VALUES('delete', OLD.rowid, memory_content(OLD.content, OLD.content_zstd), OLD.tags);
         END;",
     )?;
+    conn.execute_batch(FTS_UPDATE_TRIGGER)?;
+
+    conn.execute(
+        "INSERT OR IGNORE INTO schema_meta (key, value) VALUES (?, 0)",
+        [VECTOR_GENERATION_KEY],
+    )?;
+    conn.execute_batch(VECTOR_GENERATION_TRIGGERS)?;
+
+    Ok(conn)
+}
+
+/// Espera padrão por um lock de escrita antes de SQLITE_BUSY, em ms
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// MCP_BUSY_TIMEOUT_MS (padrão 5000)
+pub fn busy_timeout_from_env() -> std::time::Duration {
+    let ms = std::env::var("MCP_BUSY_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_BUSY_TIMEOUT_MS);
+    std::time::Duration::from_millis(ms)
+}
+
+/// Abre uma conexão de um DB de memórias já inicializado: funções SQL, foreign keys e
+/// busy_timeout. Tools, worker e hook escrevem no mesmo arquivo; sem o timeout, quem
+/// chega com o lock ocupado falha na hora com SQLITE_BUSY em vez de esperar a vez.
+pub fn open_connection(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(busy_timeout_from_env())?;
+    register_functions(&conn)?;
+    conn.execute_batch("PRAGMA foreign_keys=ON;")?;
+    Ok(conn)
+}
+
+/// Transação que pega o lock de escrita no BEGIN. Uma transação deferred que lê e
+/// depois escreve pode receber SQLITE_BUSY sem passar pelo busy_timeout (outro
+/// writer commitou depois da leitura); com IMMEDIATE a espera acontece no começo.
+pub fn write_transaction(conn: &Connection) -> Result<rusqlite::Transaction<'_>> {
+    Ok(rusqlite::Transaction::new_unchecked(conn, rusqlite::TransactionBehavior::Immediate)?)
+}
+
+/// Registra `memory_content(content, content_zstd)` na conexão: o texto da memória,
+/// descomprimido quando gravado em `content_zstd` (ver `compress`)
+pub fn register_functions(conn: &Connection) -> Result<()> {
+    use rusqlite::functions::FunctionFlags;
+    conn.create_scalar_function(
+        "memory_content",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC | FunctionFlags::SQLITE_INNOCUOUS,
+        |ctx| match ctx.get::<Option<Vec<u8>>>(1)? {
+            Some(bytes) => crate::compress::decompress(&bytes)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into())),
+            None => ctx.get::<String>(0),
+        },
+    )?;
+    Ok(())
+}
 
+/// Valores de (content, content_zstd) para gravar um texto: a partir de
+/// MCP_COMPRESS_THRESHOLD bytes vai comprimido e `content` fica vazio
+pub fn content_columns(text: &str) -> (&str, Option<Vec<u8>>) {
+    match crate::compress::compress_if_large(text, crate::compress::threshold_from_env()) {
+        Some(bytes) => ("", Some(bytes)),
+        None => (text, None),
+    }
+}
+
+/// Abre (criando se preciso) o cache.db compartilhado: só a tabela embedding_cache
+pub fn open_cache_db(path: &Path) -> Result<Connection> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(busy_timeout_from_env())?;
+    conn.execute_batch(
+        "PRAGMA journal_mode=WAL;
+        CREATE TABLE IF NOT EXISTS embedding_cache (
+            text_hash TEXT NOT NULL,
+            model TEXT NOT NULL,
+            embedding BLOB NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (text_hash, model)
+        );",
+    )?;
     Ok(conn)
 }
 
+/// Conexões ociosas guardadas por DB; o excedente é fechado ao devolver
+const MAX_IDLE_PER_DB: usize = 4;
+
+/// Cache de conexões por caminho de DB. O schema (init_db) roda só na
+/// primeira abertura de cada caminho; as seguintes só aplicam os PRAGMAs.
+#[derive(Default)]
+pub struct ConnectionPool {
+    idle: std::sync::Mutex<std::collections::HashMap<PathBuf, Vec<Connection>>>,
+    initialized: std::sync::Mutex<std::collections::HashSet<PathBuf>>,
+}
+
+impl ConnectionPool {
+    pub fn get(self: &std::sync::Arc<Self>, path: &Path) -> Result<PooledConnection> {
+        let conn = if path.exists() {
+            let reused = self
+                .idle
+                .lock()
+                .ok()
+                .and_then(|mut idle| idle.get_mut(path).and_then(Vec::pop));
+            match reused {
+                Some(conn) => conn,
+                None => self.open(path)?,
+            }
+        } else {
+            // Arquivo removido por fora: conexões antigas apontam para o inode apagado
+            self.forget(path);
+            self.open(path)?
+        };
+        Ok(PooledConnection {
+            conn: Some(conn),
+            path: path.to_path_buf(),
+            pool: self.clone(),
+        })
+    }
+
+    fn open(&self, path: &Path) -> Result<Connection> {
+        let first = self.initialized.lock().map(|set| !set.contains(path)).unwrap_or(true);
+        if first {
+            let conn = init_db(path)?;
+            if let Ok(mut set) = self.initialized.lock() {
+                set.insert(path.to_path_buf());
+            }
+            return Ok(conn);
+        }
+        open_connection(path)
+    }
+
+    fn forget(&self, path: &Path) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.remove(path);
+        }
+        if let Ok(mut set) = self.initialized.lock() {
+            set.remove(path);
+        }
+    }
+
+    fn release(&self, path: PathBuf, conn: Connection) {
+        // Transação esquecida aberta não volta para o pool
+        if !conn.is_autocommit() {
+            return;
+        }
+        if let Ok(mut idle) = self.idle.lock() {
+            let conns = idle.entry(path).or_default();
+            if conns.len() < MAX_IDLE_PER_DB {
+                conns.push(conn);
+            }
+        }
+    }
+}
+
+/// Conexão emprestada do pool; volta para ele no drop
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    path: PathBuf,
+    pool: std::sync::Arc<ConnectionPool>,
+}
+
+impl std::ops::Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("pooled connection")
+    }
+}
+
+impl std::ops::DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("pooled connection")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.release(std::mem::take(&mut self.path), conn);
+        }
+    }
+}
+
 /// Backfill importance para memórias que ficaram com default 0.5
 fn backfill_importance(conn: &Connection) {
     // Só executa se há memórias com importance=0.5 que deveriam ter outro valor
@@ -207,23 +559,342 @@ fn backfill_importance(conn: &Connection) {
     }
 
     // Recriar trigger
-    let _ = conn.execute_batch(
-        "CREATE TRIGGER IF NOT EXISTS memories_au AFTER UPDATE ON memories BEGIN
-            INSERT INTO memories_fts(memories_fts, rowid, content, tags)
-            VALUES('delete', OLD.rowid, OLD.content, OLD.tags);
-            INSERT INTO memories_fts(rowid, content, tags)
-            VALUES (NEW.rowid, NEW.content, NEW.tags);
-        END;"
-    );
+    let _ = conn.execute_batch(FTS_UPDATE_TRIGGER);
+}
+
+/// Tabela FTS5: porter sobre unicode61 (stemming + remoção de diacríticos),
+/// então `run` acha `running` e `acao` acha `ação`
+const FTS_TABLE: &str = "CREATE VIRTUAL TABLE IF NOT EXISTS memories_fts USING fts5(
+    content, tags, content='memories', content_rowid='rowid',
+    tokenize='porter unicode61'
+);";
+
+/// Reindexa o FTS a partir de memories, fora a lixeira. Não usa o 'rebuild' do FTS5:
+/// ele leria a coluna crua, vazia nas linhas comprimidas.
+const FTS_REBUILD: &str = "INSERT INTO memories_fts(memories_fts) VALUES('delete-all');
+    INSERT INTO memories_fts(rowid, content, tags)
+    SELECT rowid, memory_content(content, content_zstd), tags FROM memories WHERE deleted_at IS NULL;";
+
+/// Trigger de UPDATE do FTS: só linhas fora da lixeira ficam indexadas, então
+/// soft delete tira do índice e restore devolve
+const FTS_UPDATE_TRIGGER: &str =
+    "CREATE TRIGGER IF NOT EXISTS memories_au AFTER UPDATE ON memories BEGIN
+        INSERT INTO memories_fts(memories_fts, rowid, content, tags)
+        SELECT 'delete', OLD.rowid, memory_content(OLD.content, OLD.content_zstd), OLD.tags
+        WHERE OLD.deleted_at IS NULL;
+        INSERT INTO memories_fts(rowid, content, tags)
+        SELECT NEW.rowid, memory_content(NEW.content, NEW.content_zstd), NEW.tags
+        WHERE NEW.deleted_at IS NULL;
+    END;";
+
+// ---- Migrações ----
+
+/// Passos em ordem: `MIGRATIONS[i]` leva o schema da versão i+1 para i+2.
+/// A v1 é o schema original (memories sem ranking nem lixeira). Cada passo é
+/// idempotente: DBs criados pelo `CREATE TABLE` atual já têm as colunas.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    migrate_v2_ranking,
+    migrate_v3_soft_delete,
+    migrate_v4_metadata,
+    migrate_v5_porter_fts,
+    migrate_v6_compressed_content,
+    migrate_v7_expiry,
+];
+
+/// Versão do schema que este binário produz (guardada em PRAGMA user_version)
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64 + 1;
+
+/// v2: colunas de ranking e arquivamento
+fn migrate_v2_ranking(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "memories", "access_count", "INTEGER DEFAULT 0")?;
+    add_column_if_missing(conn, "memories", "importance", "FLOAT DEFAULT 0.5")?;
+    add_column_if_missing(conn, "memories", "archived", "INTEGER DEFAULT 0")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_archived ON memories(archived);")?;
+    Ok(())
+}
+
+/// v3: lixeira (soft delete)
+fn migrate_v3_soft_delete(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "memories", "deleted_at", "DATETIME")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_deleted ON memories(deleted_at);")?;
+    Ok(())
+}
+
+/// v4: metadata JSON livre por memória
+fn migrate_v4_metadata(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "memories", "metadata", "TEXT")
+}
+
+/// v5: FTS com o tokenizer porter. Tokenizer não muda com ALTER: recria a
+/// tabela e reindexa. DB novo ainda não tem FTS aqui; o init_db cria depois.
+fn migrate_v5_porter_fts(conn: &Connection) -> Result<()> {
+    let has_fts: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = 'memories_fts')",
+        [],
+        |r| r.get(0),
+    )?;
+    if !has_fts {
+        return Ok(());
+    }
+    conn.execute_batch("DROP TABLE memories_fts;")?;
+    conn.execute_batch(FTS_TABLE)?;
+    // Coluna content_zstd só existe a partir da v6: rebuild nativo sobre o texto cru
+    conn.execute_batch(
+        "INSERT INTO memories_fts(memories_fts) VALUES('rebuild');
+         INSERT INTO memories_fts(memories_fts, rowid, content, tags)
+         SELECT 'delete', rowid, content, tags FROM memories WHERE deleted_at IS NOT NULL;",
+    )?;
+    Ok(())
+}
+
+/// v6: conteúdo grande comprimido (zstd) em `content_zstd`. Os triggers de FTS
+/// passam a ler via `memory_content`; os antigos saem e o init_db recria.
+fn migrate_v6_compressed_content(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "memories", "content_zstd", "BLOB")?;
+    conn.execute_batch(
+        "DROP TRIGGER IF EXISTS memories_ai;
+         DROP TRIGGER IF EXISTS memories_ad;
+         DROP TRIGGER IF EXISTS memories_au;",
+    )?;
+    Ok(())
+}
+
+/// v7: expiração por memória (`expires_at`, ver `NOT_EXPIRED_SQL` e `prune_expired`)
+fn migrate_v7_expiry(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "memories", "expires_at", "DATETIME")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_expires ON memories(expires_at);")?;
+    Ok(())
+}
+
+/// Contagem do índice FTS contra o esperado (todas as memórias fora da lixeira)
+#[derive(Debug, Default, Serialize)]
+pub struct FtsCheck {
+    pub expected: i64,
+    pub indexed: i64,
+}
+
+impl FtsCheck {
+    pub fn in_sync(&self) -> bool {
+        self.expected == self.indexed
+    }
+}
+
+/// Linhas no índice FTS. A tabela é external-content: `COUNT(*)` nela lê `memories`,
+/// então conta pela `_docsize` (uma linha por documento indexado).
+fn count_fts_rows(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("SELECT COUNT(*) FROM memories_fts_docsize", [], |r| r.get(0))?)
+}
+
+/// Detecta escrita que passou por fora dos triggers (só pela contagem: conteúdo
+/// divergente com o mesmo número de linhas não aparece aqui)
+pub fn check_fts(conn: &Connection) -> Result<FtsCheck> {
+    let expected: i64 = conn.query_row("SELECT COUNT(*) FROM memories WHERE deleted_at IS NULL", [], |r| r.get(0))?;
+    Ok(FtsCheck { expected, indexed: count_fts_rows(conn)? })
+}
+
+/// Reindexa o FTS inteiro a partir de memories
+pub fn rebuild_fts(conn: &Connection) -> Result<()> {
+    conn.execute_batch(FTS_REBUILD)?;
+    Ok(())
+}
+
+/// Versão do schema do DB (0 = anterior ao versionamento)
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("PRAGMA user_version", [], |r| r.get(0))?)
+}
+
+/// Aplica as migrações pendentes, cada uma na sua transação junto com o bump
+/// de versão. Retorna quantas foram aplicadas.
+pub fn run_migrations(conn: &Connection) -> Result<usize> {
+    let current = schema_version(conn)?;
+    let mut applied = 0;
+    for (i, step) in MIGRATIONS.iter().enumerate() {
+        let version = i as i64 + 2;
+        if current >= version {
+            continue;
+        }
+        let tx = conn.unchecked_transaction()?;
+        step(&tx)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let found = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .flatten()
+        .any(|name| name == column);
+    Ok(found)
+}
+
+/// ALTER TABLE só quando a coluna falta (SQLite não tem ADD COLUMN IF NOT EXISTS)
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, col_type: &str) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, col_type))?;
+    }
+    Ok(())
+}
+
+/// Chave do schema_meta com o modelo que gerou os embeddings do DB
+pub const EMBEDDING_MODEL_KEY: &str = "embedding_model";
+/// Formato dos blobs novos ("f16" ou "int8"); blobs antigos seguem legíveis pelo tamanho
+pub const EMBEDDING_FORMAT_KEY: &str = "embedding_format";
+/// Texto que gerou os embeddings principais: "content" ou "type_tags_content" (MCP_EMBED_TAGS)
+pub const EMBEDDING_TEXT_KEY: &str = "embedding_text";
+/// Preprocess de código no texto dos embeddings: "on" ou "off" (MCP_CODE_PREPROCESS)
+pub const EMBEDDING_CODE_KEY: &str = "embedding_code_preprocess";
+/// Marca do schema_meta: embeddings gravados já estão normalizados (L2 = 1)
+pub const NORMALIZED_KEY: &str = "embeddings_normalized";
+/// Marca que os triggers de FTS já ignoram a lixeira (`deleted_at`)
+const SOFT_DELETE_KEY: &str = "fts_soft_delete";
+/// Geração dos vetores do DB, incrementada pelos `VECTOR_GENERATION_TRIGGERS`
+const VECTOR_GENERATION_KEY: &str = "vector_generation";
+
+/// Qualquer mudança em vetor de memória/chunk (ou na lixeira), de qualquer processo,
+/// incrementa a geração: o índice ANN compara um número em vez de contar linhas
+const VECTOR_GENERATION_TRIGGERS: &str = "
+    CREATE TRIGGER IF NOT EXISTS memories_vec_ai AFTER INSERT ON memories
+    WHEN NEW.embedding IS NOT NULL BEGIN
+        UPDATE schema_meta SET value = value + 1 WHERE key = 'vector_generation';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS memories_vec_au AFTER UPDATE OF embedding, deleted_at ON memories
+    WHEN OLD.embedding IS NOT NEW.embedding OR OLD.deleted_at IS NOT NEW.deleted_at BEGIN
+        UPDATE schema_meta SET value = value + 1 WHERE key = 'vector_generation';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS memories_vec_ad AFTER DELETE ON memories
+    WHEN OLD.embedding IS NOT NULL BEGIN
+        UPDATE schema_meta SET value = value + 1 WHERE key = 'vector_generation';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS chunks_vec_ai AFTER INSERT ON memory_chunks
+    WHEN NEW.embedding IS NOT NULL BEGIN
+        UPDATE schema_meta SET value = value + 1 WHERE key = 'vector_generation';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS chunks_vec_au AFTER UPDATE OF embedding ON memory_chunks BEGIN
+        UPDATE schema_meta SET value = value + 1 WHERE key = 'vector_generation';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS chunks_vec_ad AFTER DELETE ON memory_chunks
+    WHEN OLD.embedding IS NOT NULL BEGIN
+        UPDATE schema_meta SET value = value + 1 WHERE key = 'vector_generation';
+    END;";
+
+/// Geração atual dos vetores (muda a cada escrita de embedding, delete ou restore)
+pub fn vector_generation(conn: &Connection) -> i64 {
+    conn.query_row(
+        "SELECT CAST(value AS INTEGER) FROM schema_meta WHERE key = ?",
+        [VECTOR_GENERATION_KEY],
+        |r| r.get(0),
+    )
+    .unwrap_or(0)
+}
+
+pub fn get_meta(conn: &Connection, key: &str) -> Option<String> {
+    conn.query_row("SELECT value FROM schema_meta WHERE key = ?", [key], |r| r.get(0))
+        .optional()
+        .ok()
+        .flatten()
+}
+
+pub fn set_meta(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO schema_meta (key, value) VALUES (?, ?) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        rusqlite::params![key, value],
+    )?;
+    Ok(())
+}
+
+/// Registra o formato usado na última gravação de embeddings
+pub fn record_embedding_format(conn: &Connection, format: &str) {
+    if get_meta(conn, EMBEDDING_FORMAT_KEY).as_deref() != Some(format) {
+        let _ = set_meta(conn, EMBEDDING_FORMAT_KEY, format);
+    }
+}
+
+/// Registra o modelo na primeira gravação de embedding; se o DB já tem outro
+/// modelo registrado, mantém o antigo (até um reindex) e avisa sobre a mistura.
+pub fn record_embedding_model(conn: &Connection, model: &str) {
+    match get_meta(conn, EMBEDDING_MODEL_KEY) {
+        None => {
+            let _ = set_meta(conn, EMBEDDING_MODEL_KEY, model);
+        }
+        Some(stored) if stored != model => {
+            tracing::warn!(
+                "DB embeddings were created with {} but active model is {}; run memory_reindex",
+                stored,
+                model
+            );
+        }
+        Some(_) => {}
+    }
+}
+
+/// Valor registrado de uma configuração do texto dos embeddings; o primeiro registro
+/// vale até um reindex. DB sem registro mas com embeddings vem de antes da opção
+/// existir, então fica com `legacy`.
+fn recorded_embedding_setting(conn: &Connection, key: &str, mode: &str, legacy: &str) -> String {
+    match get_meta(conn, key) {
+        Some(stored) => stored,
+        None => {
+            let has_vectors: bool = conn
+                .query_row("SELECT EXISTS(SELECT 1 FROM memories WHERE embedding IS NOT NULL)", [], |r| r.get(0))
+                .unwrap_or(false);
+            let first = if has_vectors { legacy } else { mode };
+            let _ = set_meta(conn, key, first);
+            first.to_string()
+        }
+    }
+}
+
+/// Registra o modo de texto dos embeddings, como `record_embedding_model`
+/// (sem registro e com vetores: "content")
+pub fn record_embedding_text(conn: &Connection, mode: &str) {
+    let stored = recorded_embedding_setting(conn, EMBEDDING_TEXT_KEY, mode, "content");
+    if stored != mode {
+        tracing::warn!(
+            "DB embeddings were built from {} but active text mode is {}; run memory_reindex",
+            stored,
+            mode
+        );
+    }
+}
+
+/// Registra se o texto dos embeddings passou pelo preprocess de código
+/// (sem registro e com vetores: "off", anterior ao preprocess)
+pub fn record_embedding_code(conn: &Connection, mode: &str) {
+    let stored = recorded_embedding_setting(conn, EMBEDDING_CODE_KEY, mode, "off");
+    if stored != mode {
+        tracing::warn!(
+            "DB embeddings were built with code preprocessing {} but it is now {}; run memory_reindex",
+            stored,
+            mode
+        );
+    }
 }
 
-/// Migração segura: adiciona coluna se não existir
-fn migrate_add_column(conn: &Connection, table: &str, column: &str, col_type: &str) {
-    let sql = format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, col_type);
-    let _ = conn.execute_batch(&sql); // ignora erro se já existe
+/// Descarta todos os embeddings (memórias + chunks) e registra o novo modelo.
+/// Usado pelo reindex quando o modelo ativo difere do registrado.
+pub fn reset_embeddings(conn: &Connection, model: &str) -> Result<usize> {
+    let cleared = conn.execute(
+        "UPDATE memories SET embedding = NULL WHERE embedding IS NOT NULL",
+        [],
+    )?;
+    conn.execute("DELETE FROM memory_chunks", [])?;
+    set_meta(conn, EMBEDDING_MODEL_KEY, model)?;
+    Ok(cleared)
 }
 
-/// Gera ID único (sha256[:16] de type:content:timestamp)
+/// Gera ID único (`sha256[:16]` de type:content:timestamp). Inclui o timestamp, então
+/// o mesmo conteúdo salvo duas vezes tem IDs diferentes: quem junta repetições é o dedup.
+/// Com `idempotency_key` o save usa `idempotent_id` no lugar.
 pub fn generate_id(content: &str, mem_type: &str) -> String {
     let now = chrono::Utc::now().to_rfc3339();
     let input = format!("{}:{}:{}", mem_type, content, now);
@@ -232,6 +903,14 @@ pub fn generate_id(content: &str, mem_type: &str) -> String {
     format!("{:x}", hasher.finalize())[..16].to_string()
 }
 
+/// ID determinístico de uma idempotency key (`sha256[:16]` de "idempotency:key"):
+/// o retry de um save com a mesma key cai na mesma linha
+pub fn idempotent_id(key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("idempotency:{}", key).as_bytes());
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
+
 /// Calcula importance base por tipo
 pub fn base_importance(mem_type: &str) -> f64 {
     match mem_type {
@@ -247,69 +926,439 @@ pub fn base_importance(mem_type: &str) -> f64 {
     }
 }
 
+/// Opções de save além de tipo/conteúdo/tags
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions<'a> {
+    /// Embedding do conteúdo: habilita dedup semântico por cosine; sem ele, Jaccard
+    pub embedding: Option<&'a [f32]>,
+    /// Threshold de dedup (ver `dedup::effective_threshold`): None/0.0 = 0.85, 1.0 = só exact
+    pub dedup_threshold: Option<f64>,
+    /// Objeto JSON já validado (ver `normalize_metadata`); no dedup substitui o existente
+    pub metadata: Option<&'a str>,
+    /// O que fazer com a memória existente quando há duplicata
+    pub dedup_strategy: DedupStrategy,
+    /// Chave do cliente para saves exactly-once: vira o ID (`idempotent_id`) e, se a
+    /// memória já existe, o save é aplicado nela conforme `dedup_strategy`
+    pub idempotency_key: Option<&'a str>,
+    /// Expira em N dias (None/0 = nunca). No dedup só um `ttl_days` novo troca o prazo;
+    /// sem ele a memória existente mantém o dela
+    pub ttl_days: Option<u32>,
+    /// Limite do conteúdo gravado em bytes, valendo também para o resultado do merge
+    /// (0 = sem limite; ver `max_content_len_from_env`)
+    pub max_content_len: usize,
+}
+
+/// Estratégia quando o save encontra duplicata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DedupStrategy {
+    /// Conteúdo novo substitui o existente (comportamento original)
+    #[default]
+    Replace,
+    /// Acrescenta as linhas novas ao existente e une as tags
+    Merge,
+    /// Existente fica intacto; só devolve o ID
+    Keep,
+}
+
+impl DedupStrategy {
+    /// Vazio = replace
+    pub fn parse(raw: &str) -> std::result::Result<Self, String> {
+        match raw.trim().to_lowercase().as_str() {
+            "" | "replace" => Ok(Self::Replace),
+            "merge" => Ok(Self::Merge),
+            "keep" => Ok(Self::Keep),
+            other => Err(format!("unknown dedup_strategy '{}' (use replace, merge or keep)", other)),
+        }
+    }
+}
+
+/// Junta conteúdos no merge: linhas do novo que ainda não existem (comparando
+/// sem espaços nas pontas) vão para o fim, na ordem em que aparecem
+fn merge_content(existing: &str, new: &str) -> String {
+    let mut seen: std::collections::HashSet<&str> = existing.lines().map(str::trim).collect();
+    let mut merged = existing.trim_end().to_string();
+    for line in new.lines() {
+        let key = line.trim();
+        if key.is_empty() || !seen.insert(key) {
+            continue;
+        }
+        merged.push('\n');
+        merged.push_str(line.trim_end());
+    }
+    merged
+}
+
+/// Limite padrão do conteúdo salvo, em bytes
+pub const DEFAULT_MAX_CONTENT_LEN: usize = 100 * 1024;
+
+/// MCP_MAX_CONTENT_LEN (bytes; padrão 100 KB, 0 = sem limite)
+pub fn max_content_len_from_env() -> usize {
+    std::env::var("MCP_MAX_CONTENT_LEN")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CONTENT_LEN)
+}
+
+/// Corta o conteúdo no limite (em fronteira de char), para quem grava sem poder recusar
+/// (o hook de sessão); 0 = sem limite
+pub fn truncate_content(content: &str, max: usize) -> &str {
+    if max == 0 || content.len() <= max {
+        return content;
+    }
+    let mut end = max;
+    while !content.is_char_boundary(end) {
+        end -= 1;
+    }
+    &content[..end]
+}
+
+/// Recusa conteúdo acima do limite antes do dedup/insert: um paste de megabytes
+/// viraria centenas de chunks e um batch de embedding que trava o worker
+pub fn check_content_len(content: &str, max: usize) -> Result<(), String> {
+    if max == 0 || content.len() <= max {
+        return Ok(());
+    }
+    Err(format!(
+        "content is {} bytes, above the {} byte limit (MCP_MAX_CONTENT_LEN). \
+         Split it into smaller memories or save a summary",
+        content.len(),
+        max
+    ))
+}
+
+/// Valida metadata do usuário: precisa ser um objeto JSON. Vazio = sem metadata.
+/// Retorna o JSON compacto que vai para a coluna.
+pub fn normalize_metadata(raw: &str) -> Result<Option<String>, String> {
+    if raw.trim().is_empty() {
+        return Ok(None);
+    }
+    match serde_json::from_str::<serde_json::Value>(raw) {
+        Ok(value @ serde_json::Value::Object(_)) => Ok(Some(value.to_string())),
+        Ok(_) => Err("metadata must be a JSON object".to_string()),
+        Err(e) => Err(format!("invalid metadata JSON: {}", e)),
+    }
+}
+
+/// Memória não expirada (`expires_at` vazio ou no futuro), sobre o alias `m`.
+/// Expiradas somem de busca, listagem e dedup antes de o `prune_expired` apagá-las.
+pub const NOT_EXPIRED_SQL: &str = "(m.expires_at IS NULL OR m.expires_at > datetime('now'))";
+
+/// Padrão de MCP_CONVERSATION_TTL_DAYS: conversas do hook expiram em 30 dias
+pub const DEFAULT_CONVERSATION_TTL_DAYS: u32 = 30;
+
+/// MCP_CONVERSATION_TTL_DAYS (0 = conversas do hook não expiram)
+pub fn conversation_ttl_from_env() -> u32 {
+    std::env::var("MCP_CONVERSATION_TTL_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CONVERSATION_TTL_DAYS)
+}
+
+/// `expires_at` para um TTL em dias, no formato do `datetime('now')` do SQLite
+/// (comparável como texto); 0 = sem expiração
+pub fn expires_at_after(ttl_days: u32) -> Option<String> {
+    if ttl_days == 0 {
+        return None;
+    }
+    let at = chrono::Utc::now() + chrono::Duration::days(ttl_days as i64);
+    Some(at.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
 /// Salva memória com dedup check, auto-tags e importance
 pub fn save_memory(
     conn: &Connection,
     mem_type: &str,
     content: &str,
     tags: &str,
+    opts: &SaveOptions,
 ) -> Result<SaveResult> {
+    check_content_len(content, opts.max_content_len).map_err(anyhow::Error::msg)?;
     // Auto-tag
     let auto_tags = crate::autotag::extract_tags(content);
     let final_tags = crate::autotag::merge_tags(tags, &auto_tags);
     let importance = base_importance(mem_type);
+    let embedding = opts.embedding;
+    let metadata = opts.metadata;
+    let expires_at = opts.ttl_days.and_then(expires_at_after);
+    let threshold = crate::dedup::effective_threshold(opts.dedup_threshold);
+    let new_id = || match opts.idempotency_key {
+        Some(key) => idempotent_id(key),
+        None => generate_id(content, mem_type),
+    };
 
-    // Dedup check
+    // Retry com a mesma key: a memória já existe, o save vale sobre ela. Sem ela, o
+    // fluxo normal segue (o dedup por conteúdo ainda pode achar outra memória; o
+    // retry então acha a mesma de novo)
+    if let Some(key) = opts.idempotency_key {
+        let id = idempotent_id(key);
+        let exists: bool = conn
+            .query_row("SELECT 1 FROM memories WHERE id = ? AND deleted_at IS NULL", [&id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if exists {
+            return apply_to_existing(conn, id, "idempotency_key", content, final_tags, importance, opts);
+        }
+    }
+
+    // Dedup check
     if mem_type != "conversation" {
-        if let Some(existing_id) =
-            crate::dedup::find_duplicate(conn, content, mem_type, 0.85)
+        if let Some(existing) =
+            crate::dedup::find_duplicate(conn, content, mem_type, threshold, embedding)
         {
-            conn.execute(
-                "UPDATE memories SET content = ?, tags = ?, updated_at = datetime('now'), \
-                 importance = MAX(importance, ?) WHERE id = ?",
-                rusqlite::params![content, final_tags, importance, existing_id],
-            )?;
-            return Ok(SaveResult {
-                id: existing_id,
-                dedup: "updated".into(),
-            });
+            return apply_to_existing(conn, existing.id, existing.method, content, final_tags, importance, opts);
         }
 
-        // Se há similar com Jaccard 0.5-0.84, criar edge relates_to
-        if let Some(related_id) =
-            crate::dedup::find_duplicate(conn, content, mem_type, 0.5)
+        // Se há similar com 0.5 até o threshold, criar edge relates_to
+        if let Some(related) =
+            crate::dedup::find_duplicate(conn, content, mem_type, threshold.min(0.5), embedding)
         {
             // Será linkado depois do insert
-            let mem_id = generate_id(content, mem_type);
+            let mem_id = new_id();
+            let (stored, zstd) = content_columns(content);
             conn.execute(
-                "INSERT OR REPLACE INTO memories (id, type, content, tags, updated_at, importance) \
-                 VALUES (?, ?, ?, ?, datetime('now'), ?)",
-                rusqlite::params![mem_id, mem_type, content, final_tags, importance],
+                "INSERT OR REPLACE INTO memories (id, type, content, content_zstd, tags, updated_at, importance, metadata, expires_at) \
+                 VALUES (?, ?, ?, ?, ?, datetime('now'), ?, ?, ?)",
+                rusqlite::params![mem_id, mem_type, stored, zstd, final_tags, importance, metadata, expires_at],
             )?;
-            let _ = create_edge(conn, &mem_id, &related_id, "relates_to");
-            return Ok(SaveResult {
-                id: mem_id,
-                dedup: "new".into(),
-            });
+            let _ = create_edge(conn, &mem_id, &related.id, "relates_to");
+            return saved_result(conn, mem_id, "new", "");
         }
     }
 
-    let mem_id = generate_id(content, mem_type);
+    let mem_id = new_id();
+    let (stored, zstd) = content_columns(content);
+    conn.execute(
+        "INSERT OR REPLACE INTO memories (id, type, content, content_zstd, tags, updated_at, importance, metadata, expires_at) \
+         VALUES (?, ?, ?, ?, ?, datetime('now'), ?, ?, ?)",
+        rusqlite::params![mem_id, mem_type, stored, zstd, final_tags, importance, metadata, expires_at],
+    )?;
+
+    saved_result(conn, mem_id, "new", "")
+}
+
+/// Aplica o save numa memória que já existe (duplicata ou mesma idempotency key)
+/// conforme a `dedup_strategy`
+fn apply_to_existing(
+    conn: &Connection,
+    id: String,
+    method: &str,
+    content: &str,
+    final_tags: String,
+    importance: f64,
+    opts: &SaveOptions,
+) -> Result<SaveResult> {
+    let (stored_content, stored_tags): (String, Option<String>) = conn.query_row(
+        "SELECT memory_content(content, content_zstd), tags FROM memories WHERE id = ?",
+        rusqlite::params![id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let (new_content, new_tags) = match opts.dedup_strategy {
+        DedupStrategy::Keep => {
+            return saved_result(conn, id, "kept", method);
+        }
+        DedupStrategy::Replace => (content.to_string(), final_tags),
+        DedupStrategy::Merge => (
+            merge_content(&stored_content, content),
+            crate::autotag::merge_tags(&stored_tags.unwrap_or_default(), &parse_tag_filter(&final_tags)),
+        ),
+    };
+    // O merge cresce a memória a cada save: o limite vale para o resultado, não só para a entrada
+    check_content_len(&new_content, opts.max_content_len).map_err(|e| anyhow::anyhow!("merged {}", e))?;
+    let (stored, zstd) = content_columns(&new_content);
+    let expires_at = opts.ttl_days.and_then(expires_at_after);
     conn.execute(
-        "INSERT OR REPLACE INTO memories (id, type, content, tags, updated_at, importance) \
-         VALUES (?, ?, ?, ?, datetime('now'), ?)",
-        rusqlite::params![mem_id, mem_type, content, final_tags, importance],
+        "UPDATE memories SET content = ?, content_zstd = ?, tags = ?, updated_at = datetime('now'), \
+         importance = MAX(importance, ?), metadata = COALESCE(?, metadata), expires_at = COALESCE(?, expires_at) WHERE id = ?",
+        rusqlite::params![stored, zstd, new_tags, importance, opts.metadata, expires_at, id],
     )?;
+    if new_content != stored_content {
+        clear_model_embeddings(conn, &id)?;
+    }
+    saved_result(conn, id, "updated", method)
+}
 
+/// Relê a linha gravada para devolver timestamps e conteúdo final
+fn saved_result(conn: &Connection, id: String, dedup: &str, method: &str) -> Result<SaveResult> {
+    let record = get_memory_record(conn, &id)?
+        .ok_or_else(|| anyhow::anyhow!("memory {} vanished after save", id))?;
+    let expires_at = conn.query_row("SELECT expires_at FROM memories WHERE id = ?", [&id], |r| r.get(0))?;
     Ok(SaveResult {
-        id: mem_id,
-        dedup: "new".into(),
+        id,
+        dedup: dedup.into(),
+        dedup_method: method.into(),
+        record,
+        expires_at,
     })
 }
 
 pub struct SaveResult {
     pub id: String,
+    /// "new", "updated" (replace/merge) ou "kept" (duplicata intacta)
     pub dedup: String,
+    /// Método que achou a duplicata ("exact", "embedding", "jaccard", ou "idempotency_key"
+    /// no retry com a mesma key); vazio se nova
+    pub dedup_method: String,
+    /// Linha como ficou gravada (no merge, o conteúdo combinado): é o conteúdo dela
+    /// que vai para o embedding
+    pub record: MemoryRecord,
+    /// Quando a memória expira (None = nunca)
+    pub expires_at: Option<String>,
+}
+
+/// Item de `save_memory_batch`; metadata já validada com `normalize_metadata`
+#[derive(Debug, Clone, Default)]
+pub struct BatchItem {
+    pub mem_type: String,
+    pub content: String,
+    pub tags: String,
+    pub embedding: Option<Vec<f32>>,
+    pub metadata: Option<String>,
+}
+
+/// Salva vários itens numa única transação. O dedup roda item a item e enxerga
+/// os anteriores do mesmo batch; qualquer erro desfaz o batch inteiro.
+pub fn save_memory_batch(
+    conn: &Connection,
+    items: &[BatchItem],
+    dedup_threshold: Option<f64>,
+    dedup_strategy: DedupStrategy,
+    max_content_len: usize,
+) -> Result<Vec<SaveResult>> {
+    let tx = write_transaction(conn)?;
+    let mut results = Vec::with_capacity(items.len());
+    for item in items {
+        let opts = SaveOptions {
+            embedding: item.embedding.as_deref(),
+            dedup_threshold,
+            metadata: item.metadata.as_deref(),
+            dedup_strategy,
+            idempotency_key: None,
+            ttl_days: None,
+            max_content_len,
+        };
+        results.push(save_memory(&tx, &item.mem_type, &item.content, &item.tags, &opts)?);
+    }
+    tx.commit()?;
+    Ok(results)
+}
+
+/// Atualiza conteúdo e/ou tags de uma memória existente, mantendo o ID.
+/// Conteúdo novo zera embedding e chunks (caller re-enfileira); só tags preserva o embedding.
+/// Retorna None se o ID não existe.
+pub fn update_memory(
+    conn: &Connection,
+    id: &str,
+    content: Option<&str>,
+    tags: Option<&str>,
+) -> Result<Option<UpdateResult>> {
+    let existing_tags: Option<Option<String>> = conn
+        .query_row(
+            "SELECT tags FROM memories WHERE id = ? AND deleted_at IS NULL",
+            rusqlite::params![id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let existing_tags = match existing_tags {
+        Some(t) => t.unwrap_or_default(),
+        None => return Ok(None),
+    };
+
+    let base_tags = tags.unwrap_or(&existing_tags);
+    match content {
+        Some(content) => {
+            let auto_tags = crate::autotag::extract_tags(content);
+            let final_tags = crate::autotag::merge_tags(base_tags, &auto_tags);
+            let (stored, zstd) = content_columns(content);
+            conn.execute(
+                "UPDATE memories SET content = ?, content_zstd = ?, tags = ?, updated_at = datetime('now'), \
+                 embedding = NULL WHERE id = ?",
+                rusqlite::params![stored, zstd, final_tags, id],
+            )?;
+            conn.execute(
+                "DELETE FROM memory_chunks WHERE memory_id = ?",
+                rusqlite::params![id],
+            )?;
+            clear_model_embeddings(conn, id)?;
+            Ok(Some(UpdateResult {
+                tags: final_tags,
+                content_changed: true,
+            }))
+        }
+        None => {
+            let final_tags = crate::autotag::merge_tags(base_tags, &[]);
+            conn.execute(
+                "UPDATE memories SET tags = ?, updated_at = datetime('now') WHERE id = ?",
+                rusqlite::params![final_tags, id],
+            )?;
+            Ok(Some(UpdateResult {
+                tags: final_tags,
+                content_changed: false,
+            }))
+        }
+    }
+}
+
+pub struct UpdateResult {
+    pub tags: String,
+    pub content_changed: bool,
+}
+
+/// Manda uma memória para a lixeira. False se não existe ou já está lá.
+pub fn soft_delete_memory(conn: &Connection, id: &str) -> Result<bool> {
+    let n = conn.execute(
+        "UPDATE memories SET deleted_at = datetime('now') WHERE id = ? AND deleted_at IS NULL",
+        [id],
+    )?;
+    Ok(n > 0)
+}
+
+/// Tira uma memória da lixeira. False se não está lá.
+pub fn restore_memory(conn: &Connection, id: &str) -> Result<bool> {
+    let n = conn.execute(
+        "UPDATE memories SET deleted_at = NULL WHERE id = ? AND deleted_at IS NOT NULL",
+        [id],
+    )?;
+    Ok(n > 0)
+}
+
+/// Remove de vez (linha, chunks e edges), esteja ou não na lixeira
+pub fn purge_memory(conn: &Connection, id: &str) -> Result<bool> {
+    let n = conn.execute("DELETE FROM memories WHERE id = ?", [id])?;
+    Ok(n > 0)
+}
+
+/// Bulk delete pelos mesmos filtros do list (inclui archived). Sem `apply`, só conta.
+/// Soft delete por padrão; `purge` remove de vez, inclusive o que já está na lixeira.
+/// Retorna os IDs atingidos.
+pub fn delete_matching(
+    conn: &Connection,
+    filters: &crate::search::SearchFilters,
+    purge: bool,
+    apply: bool,
+) -> Result<Vec<String>> {
+    let (filter_sql, params) = filters.sql_clauses();
+    let trash_sql = if purge { "" } else { " AND m.deleted_at IS NULL" };
+    let sql = format!("SELECT m.id FROM memories m WHERE 1 = 1{}{}", trash_sql, filter_sql);
+    let ids: Vec<String> = {
+        let mut stmt = conn.prepare(&sql)?;
+        let param_refs: Vec<&dyn rusqlite::types::ToSql> = params.iter().map(|p| p.as_ref()).collect();
+        let rows = stmt.query_map(param_refs.as_slice(), |r| r.get(0))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    if !apply || ids.is_empty() {
+        return Ok(ids);
+    }
+
+    let tx = write_transaction(conn)?;
+    for id in &ids {
+        if purge {
+            purge_memory(&tx, id)?;
+        } else {
+            soft_delete_memory(&tx, id)?;
+        }
+    }
+    tx.commit()?;
+    Ok(ids)
 }
 
 /// Cria edge entre duas memórias
@@ -321,6 +1370,49 @@ pub fn create_edge(conn: &Connection, from_id: &str, to_id: &str, relation: &str
     Ok(inserted > 0)
 }
 
+/// Relações aceitas em links manuais
+pub const LINK_RELATIONS: &[&str] = &["relates_to", "supersedes", "derived_from", "implements"];
+
+/// Memória ligada por um edge, vista a partir de uma das pontas
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkedMemory {
+    pub id: String,
+    pub relation: String,
+    /// "outgoing" (esta --rel--> outra) ou "incoming" (outra --rel--> esta)
+    pub direction: &'static str,
+    pub mem_type: String,
+    pub content: String,
+}
+
+/// Links (nas duas direções) de uma memória, com tipo e conteúdo da outra ponta
+pub fn get_links(conn: &Connection, id: &str) -> Vec<LinkedMemory> {
+    let sql = "SELECT e.to_id, e.relation, 'outgoing', m.type, memory_content(m.content, m.content_zstd) \
+               FROM memory_edges e JOIN memories m ON m.id = e.to_id \
+               WHERE e.from_id = ?1 AND m.deleted_at IS NULL \
+               UNION ALL \
+               SELECT e.from_id, e.relation, 'incoming', m.type, memory_content(m.content, m.content_zstd) \
+               FROM memory_edges e JOIN memories m ON m.id = e.from_id \
+               WHERE e.to_id = ?1 AND m.deleted_at IS NULL";
+    let mut stmt = match conn.prepare(sql) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    let rows = stmt.query_map([id], |row| {
+        let direction: String = row.get(2)?;
+        Ok(LinkedMemory {
+            id: row.get(0)?,
+            relation: row.get(1)?,
+            direction: if direction == "outgoing" { "outgoing" } else { "incoming" },
+            mem_type: row.get(3)?,
+            content: row.get(4)?,
+        })
+    });
+    match rows {
+        Ok(r) => r.flatten().collect(),
+        Err(_) => vec![],
+    }
+}
+
 /// Incrementa access_count e atualiza importance
 pub fn update_access_count(conn: &Connection, id: &str) {
     let _ = conn.execute(
@@ -370,37 +1462,66 @@ pub fn get_edge_neighbors(conn: &Connection, ids: &[String]) -> Vec<String> {
         .collect()
 }
 
-/// Lista memórias recentes (exclui archived por padrão)
+/// Condição SQL que casa uma tag inteira na coluna `tags` (comma-separated) do alias `m`.
+/// Delimita por vírgulas para que a tag `api` não case com `rapid`. Parâmetro: `,tag,`.
+pub const TAG_MATCH_SQL: &str = "instr(',' || lower(ifnull(m.tags, '')) || ',', ?) > 0";
+
+/// Quebra um filtro de tags comma-separated em tags normalizadas (trim, espaços
+/// colapsados, lowercase), na mesma forma que `autotag::merge_tags` grava
+pub fn parse_tag_filter(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(|t| crate::autotag::normalize_tag(t, true))
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Parâmetro de bind para `TAG_MATCH_SQL`
+pub fn tag_match_param(tag: &str) -> String {
+    format!(",{},", tag)
+}
+
+/// Lista memórias recentes (exclui archived por padrão), ordenadas por `created_at DESC, id`.
+/// `tags` usa semântica AND: a memória precisa ter todas as tags.
+/// Para paginar entre vários DBs, peça `offset + limit` com offset 0 em cada um
+/// e aplique o offset depois do merge (mesma ordenação).
 pub fn list_memories(
     conn: &Connection,
-    mem_type: Option<&str>,
+    filters: &crate::search::SearchFilters,
     limit: i64,
+    offset: i64,
 ) -> Result<Vec<MemoryRecord>> {
-    let mut results = Vec::new();
+    let (filter_sql, mut params) = filters.sql_clauses();
+    let sql = format!(
+        "SELECT m.id, m.type, memory_content(m.content, m.content_zstd), m.tags, m.created_at, m.updated_at FROM memories m \
+         WHERE m.archived = 0 AND m.deleted_at IS NULL{} ORDER BY m.created_at DESC, m.id LIMIT ? OFFSET ?",
+        filter_sql
+    );
+    params.push(Box::new(limit));
+    params.push(Box::new(offset));
 
-    if let Some(t) = mem_type {
-        let mut stmt = conn.prepare(
-            "SELECT id, type, content, tags, created_at FROM memories \
-             WHERE type = ? AND archived = 0 ORDER BY updated_at DESC LIMIT ?",
-        )?;
-        let rows = stmt.query_map(rusqlite::params![t, limit], map_memory_row)?;
-        for r in rows {
-            results.push(r?);
-        }
-    } else {
-        let mut stmt = conn.prepare(
-            "SELECT id, type, content, tags, created_at FROM memories \
-             WHERE archived = 0 ORDER BY updated_at DESC LIMIT ?",
-        )?;
-        let rows = stmt.query_map(rusqlite::params![limit], map_memory_row)?;
-        for r in rows {
-            results.push(r?);
-        }
+    let mut stmt = conn.prepare(&sql)?;
+    let param_refs: Vec<&dyn rusqlite::types::ToSql> = params.iter().map(|p| p.as_ref()).collect();
+    let rows = stmt.query_map(param_refs.as_slice(), map_memory_row)?;
+    let mut results = Vec::new();
+    for r in rows {
+        results.push(r?);
     }
 
     Ok(results)
 }
 
+/// Uma memória no formato da lista (qualquer estado: archived e lixeira também)
+pub fn get_memory_record(conn: &Connection, id: &str) -> Result<Option<MemoryRecord>> {
+    let record = conn
+        .query_row(
+            "SELECT id, type, memory_content(content, content_zstd), tags, created_at, updated_at FROM memories WHERE id = ?",
+            [id],
+            map_memory_row,
+        )
+        .optional()?;
+    Ok(record)
+}
+
 fn map_memory_row(row: &rusqlite::Row) -> rusqlite::Result<MemoryRecord> {
     Ok(MemoryRecord {
         id: row.get(0)?,
@@ -408,29 +1529,299 @@ fn map_memory_row(row: &rusqlite::Row) -> rusqlite::Result<MemoryRecord> {
         content: row.get(2)?,
         tags: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
         created_at: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+        updated_at: row.get(5)?,
     })
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct MemoryRecord {
     pub id: String,
     pub mem_type: String,
     pub content: String,
     pub tags: String,
     pub created_at: String,
+    /// Última escrita (edição, merge do dedup); a ordenação da lista continua por `created_at`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+/// Registro completo de uma memória (sem o blob de embedding)
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryDetail {
+    pub id: String,
+    pub mem_type: String,
+    pub content: String,
+    pub tags: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub importance: f64,
+    pub access_count: i64,
+    pub archived: bool,
+    pub has_embedding: bool,
+    /// Quando foi para a lixeira (None = ativa)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    /// Objeto JSON livre (fonte, autor, confiança...)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Quando expira (None = nunca); expirada some da busca até o prune apagar
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+const DETAIL_COLUMNS: &str = "id, type, memory_content(content, content_zstd), tags, created_at, updated_at, importance, \
+     access_count, archived, embedding IS NOT NULL, deleted_at, metadata, expires_at";
+
+fn row_to_detail(row: &rusqlite::Row) -> rusqlite::Result<MemoryDetail> {
+    Ok(MemoryDetail {
+        id: row.get(0)?,
+        mem_type: row.get(1)?,
+        content: row.get(2)?,
+        tags: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        created_at: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+        updated_at: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+        importance: row.get::<_, Option<f64>>(6)?.unwrap_or(0.5),
+        access_count: row.get::<_, Option<i64>>(7)?.unwrap_or(0),
+        archived: row.get::<_, Option<i64>>(8)?.unwrap_or(0) != 0,
+        has_embedding: row.get(9)?,
+        deleted_at: row.get(10)?,
+        metadata: row
+            .get::<_, Option<String>>(11)?
+            .and_then(|raw| serde_json::from_str(&raw).ok()),
+        expires_at: row.get(12)?,
+    })
+}
+
+/// Busca uma memória por ID (inclui archived e lixeira)
+pub fn get_memory(conn: &Connection, id: &str) -> Result<Option<MemoryDetail>> {
+    let sql = format!("SELECT {} FROM memories WHERE id = ?", DETAIL_COLUMNS);
+    let detail = conn
+        .query_row(&sql, rusqlite::params![id], row_to_detail)
+        .optional()?;
+    Ok(detail)
+}
+
+/// Blob de embedding gravado para uma memória (None se ausente ou ainda pendente)
+pub fn get_embedding_blob(conn: &Connection, id: &str) -> Option<Vec<u8>> {
+    conn.query_row("SELECT embedding FROM memories WHERE id = ?", [id], |r| r.get(0))
+        .optional()
+        .ok()
+        .flatten()
+        .flatten()
+}
+
+/// Todas as memórias do DB (inclui archived, não a lixeira) para export, agrupáveis por tipo
+pub fn export_memories(conn: &Connection) -> Result<Vec<MemoryDetail>> {
+    let sql = format!(
+        "SELECT {} FROM memories WHERE deleted_at IS NULL ORDER BY type, created_at, id",
+        DETAIL_COLUMNS
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], row_to_detail)?;
+    let mut out = Vec::new();
+    for r in rows {
+        out.push(r?);
+    }
+    Ok(out)
+}
+
+/// Resultado de `move_memory`
+#[derive(Debug, Clone)]
+pub struct MoveOutcome {
+    pub content: String,
+    /// Embedding veio junto; sem ele o caller re-enfileira
+    pub has_embedding: bool,
+    pub chunks: usize,
+    /// Vetores de modelos extras (`model_embeddings`) que vieram junto
+    pub model_vectors: usize,
+    /// Edges não atravessam DBs: links da memória ficam para trás
+    pub links_dropped: usize,
+}
+
+/// Move uma memória (linha completa + chunks) de `src` para `dst`, preservando ID,
+/// timestamps, importance e embeddings. Insere no destino numa transação e só então
+/// apaga da origem; se o delete falhar, desfaz a cópia, então a memória nunca fica
+/// nos dois DBs. Embeddings de modelo diferente do destino são descartados; os de
+/// modelos extras levam o nome do modelo na linha e sempre vão junto.
+/// Retorna None se o ID não existe na origem (ou está na lixeira).
+pub fn move_memory(src: &Connection, dst: &Connection, id: &str) -> Result<Option<MoveOutcome>> {
+    let Some(m) = get_memory(src, id)?.filter(|m| m.deleted_at.is_none()) else {
+        return Ok(None);
+    };
+    if get_memory(dst, id)?.is_some() {
+        anyhow::bail!("memory {} already exists in the destination", id);
+    }
+
+    let src_model = get_meta(src, EMBEDDING_MODEL_KEY);
+    let dst_model = get_meta(dst, EMBEDDING_MODEL_KEY);
+    let keep_vectors = match (&src_model, &dst_model) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    };
+    let embedding = get_embedding_blob(src, id).filter(|_| keep_vectors);
+
+    let chunks: Vec<(String, i64, String, Option<Vec<u8>>)> = {
+        let mut stmt = src.prepare(
+            "SELECT id, chunk_index, chunk_text, embedding FROM memory_chunks WHERE memory_id = ?",
+        )?;
+        let rows = stmt.query_map([id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    let model_vectors: Vec<(String, Vec<u8>)> = {
+        let mut stmt = src.prepare("SELECT model, embedding FROM model_embeddings WHERE memory_id = ?")?;
+        let rows = stmt.query_map([id], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    let links_dropped: i64 = src.query_row(
+        "SELECT COUNT(*) FROM memory_edges WHERE from_id = ?1 OR to_id = ?1",
+        [id],
+        |r| r.get(0),
+    )?;
+
+    let (stored, zstd) = content_columns(&m.content);
+    let tx = write_transaction(dst)?;
+    tx.execute(
+        "INSERT INTO memories (id, type, content, content_zstd, tags, created_at, updated_at, embedding, \
+         access_count, importance, archived, metadata, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        rusqlite::params![
+            m.id, m.mem_type, stored, zstd, m.tags, m.created_at, m.updated_at, embedding,
+            m.access_count, m.importance, m.archived as i64, m.metadata.as_ref().map(|v| v.to_string()),
+            m.expires_at
+        ],
+    )?;
+    for (chunk_id, index, text, blob) in &chunks {
+        tx.execute(
+            "INSERT INTO memory_chunks (id, memory_id, chunk_index, chunk_text, embedding) \
+             VALUES (?, ?, ?, ?, ?)",
+            rusqlite::params![chunk_id, id, index, text, blob.as_ref().filter(|_| keep_vectors)],
+        )?;
+    }
+    for (model, blob) in &model_vectors {
+        tx.execute(
+            "INSERT INTO model_embeddings (memory_id, model, embedding) VALUES (?, ?, ?)",
+            rusqlite::params![id, model, blob],
+        )?;
+    }
+    if embedding.is_some() {
+        if let (Some(model), None) = (&src_model, &dst_model) {
+            record_embedding_model(&tx, model);
+        }
+    }
+    tx.commit()?;
+
+    if let Err(e) = src.execute("DELETE FROM memories WHERE id = ?", [id]) {
+        let _ = dst.execute("DELETE FROM memories WHERE id = ?", [id]);
+        return Err(e.into());
+    }
+
+    Ok(Some(MoveOutcome {
+        content: m.content,
+        has_embedding: embedding.is_some(),
+        chunks: chunks.len(),
+        model_vectors: model_vectors.len(),
+        links_dropped: links_dropped as usize,
+    }))
+}
+
+/// Quantas tags `by_tag` mostra
+const TOP_TAGS: usize = 20;
+
+/// Ordena por contagem (desc, empate por nome)
+pub fn sort_tag_counts(counts: std::collections::HashMap<String, i64>) -> Vec<(String, i64)> {
+    let mut tags: Vec<(String, i64)> = counts.into_iter().collect();
+    tags.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    tags
+}
+
+/// Top `TOP_TAGS` tags por contagem
+fn top_tags(counts: std::collections::HashMap<String, i64>) -> Vec<(String, i64)> {
+    let mut tags = sort_tag_counts(counts);
+    tags.truncate(TOP_TAGS);
+    tags
+}
+
+/// Contagem por tag das memórias ativas: `api,backend` conta para as duas
+pub fn tag_counts(conn: &Connection) -> std::collections::HashMap<String, i64> {
+    let mut counts = std::collections::HashMap::new();
+    if let Ok(mut stmt) = conn.prepare(
+        &format!(
+            "SELECT m.tags FROM memories m WHERE m.archived = 0 AND m.deleted_at IS NULL AND ifnull(m.tags, '') != '' AND {}",
+            NOT_EXPIRED_SQL
+        ),
+    ) {
+        if let Ok(rows) = stmt.query_map([], |row| row.get::<_, String>(0)) {
+            for tags in rows.flatten() {
+                for tag in parse_tag_filter(&tags) {
+                    *counts.entry(tag).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    counts
+}
+
+/// Soma das estatísticas de vários DBs (seção "All scopes")
+#[derive(Debug, Default, Serialize)]
+pub struct StatsTotals {
+    pub total: i64,
+    pub expired: i64,
+    pub archived: i64,
+    pub trashed: i64,
+    pub indexed: i64,
+    pub chunks: i64,
+    pub by_tag: Vec<(String, i64)>,
+}
+
+pub fn aggregate_stats<'a>(stats: impl IntoIterator<Item = &'a DbStats>) -> StatsTotals {
+    let mut totals = StatsTotals::default();
+    let mut tags = std::collections::HashMap::new();
+    for s in stats {
+        totals.total += s.total;
+        totals.expired += s.expired;
+        totals.archived += s.archived;
+        totals.trashed += s.trashed;
+        totals.indexed += s.indexed;
+        totals.chunks += s.chunks;
+        // Soma as contagens completas: o top de cada DB perderia tags que só
+        // entram no top somando os escopos
+        for (tag, n) in &s.tag_counts {
+            *tags.entry(tag.clone()).or_insert(0) += n;
+        }
+    }
+    totals.by_tag = top_tags(tags);
+    totals
 }
 
 /// Estatísticas do DB
-pub fn get_stats(conn: &Connection) -> DbStats {
+/// Expiradas ainda não removidas pelo prune ficam fora de total, indexed e by_type
+/// (a busca já as ignora) e aparecem só em `expired`
+pub fn get_stats(conn: &Connection, expected_dim: usize) -> DbStats {
     let total: i64 = conn
-        .query_row("SELECT COUNT(*) FROM memories WHERE archived = 0", [], |r| r.get(0))
+        .query_row(
+            &format!("SELECT COUNT(*) FROM memories m WHERE m.archived = 0 AND m.deleted_at IS NULL AND {}", NOT_EXPIRED_SQL),
+            [],
+            |r| r.get(0),
+        )
+        .unwrap_or(0);
+    let expired: i64 = conn
+        .query_row(
+            &format!("SELECT COUNT(*) FROM memories m WHERE m.archived = 0 AND m.deleted_at IS NULL AND NOT {}", NOT_EXPIRED_SQL),
+            [],
+            |r| r.get(0),
+        )
         .unwrap_or(0);
     let archived: i64 = conn
-        .query_row("SELECT COUNT(*) FROM memories WHERE archived = 1", [], |r| r.get(0))
+        .query_row("SELECT COUNT(*) FROM memories WHERE archived = 1 AND deleted_at IS NULL", [], |r| r.get(0))
+        .unwrap_or(0);
+    let trashed: i64 = conn
+        .query_row("SELECT COUNT(*) FROM memories WHERE deleted_at IS NOT NULL", [], |r| r.get(0))
         .unwrap_or(0);
     let indexed: i64 = conn
         .query_row(
-            "SELECT COUNT(*) FROM memories WHERE embedding IS NOT NULL AND archived = 0",
+            &format!(
+                "SELECT COUNT(*) FROM memories m WHERE m.embedding IS NOT NULL AND m.archived = 0 AND m.deleted_at IS NULL AND {}",
+                NOT_EXPIRED_SQL
+            ),
             [],
             |r| r.get(0),
         )
@@ -444,11 +1835,13 @@ pub fn get_stats(conn: &Connection) -> DbStats {
     let edges: i64 = conn
         .query_row("SELECT COUNT(*) FROM memory_edges", [], |r| r.get(0))
         .unwrap_or(0);
+    let fts_rows = count_fts_rows(conn).unwrap_or(0);
 
     let mut by_type = Vec::new();
-    if let Ok(mut stmt) = conn.prepare(
-        "SELECT type, COUNT(*) FROM memories WHERE archived = 0 GROUP BY type"
-    ) {
+    if let Ok(mut stmt) = conn.prepare(&format!(
+        "SELECT m.type, COUNT(*) FROM memories m WHERE m.archived = 0 AND m.deleted_at IS NULL AND {} GROUP BY m.type",
+        NOT_EXPIRED_SQL
+    )) {
         if let Ok(mapped) = stmt.query_map([], |row| {
             Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
         }) {
@@ -458,45 +1851,232 @@ pub fn get_stats(conn: &Connection) -> DbStats {
         }
     }
 
+    let tag_counts = tag_counts(conn);
     DbStats {
+        by_tag: top_tags(tag_counts.clone()),
+        tag_counts,
         total,
+        expired,
         archived,
+        trashed,
         indexed,
         chunks,
         cache_entries: cache,
         edges,
+        fts_rows,
         by_type,
+        embedding_model: get_meta(conn, EMBEDDING_MODEL_KEY),
+        embedding_format: get_meta(conn, EMBEDDING_FORMAT_KEY),
+        embedding_text: get_meta(conn, EMBEDDING_TEXT_KEY),
+        embedding_code: get_meta(conn, EMBEDDING_CODE_KEY),
+        schema_version: schema_version(conn).unwrap_or(0),
+        dimension_mismatches: count_dimension_mismatches(conn, expected_dim),
+        embedding_failures: count_embedding_failures(conn),
+        model_vectors: count_model_embeddings(conn),
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct DbStats {
     pub total: i64,
+    /// Vencidas (`expires_at`) que o prune ainda não removeu
+    pub expired: i64,
     pub archived: i64,
+    /// Na lixeira (soft delete), restauráveis
+    pub trashed: i64,
     pub indexed: i64,
     pub chunks: i64,
     pub cache_entries: i64,
     pub edges: i64,
+    /// Documentos no índice FTS; deve ser `total + expired + archived` (ver `check_fts`)
+    pub fts_rows: i64,
+    /// Top `TOP_TAGS` tags (memórias ativas) por contagem
+    pub by_tag: Vec<(String, i64)>,
+    /// Contagem de todas as tags, para `aggregate_stats` somar antes de cortar o top
+    #[serde(skip)]
+    pub tag_counts: std::collections::HashMap<String, i64>,
     pub by_type: Vec<(String, i64)>,
+    pub embedding_model: Option<String>,
+    pub embedding_format: Option<String>,
+    /// Modo de texto dos embeddings (ver `EMBEDDING_TEXT_KEY`)
+    pub embedding_text: Option<String>,
+    /// Preprocess de código dos embeddings (ver `EMBEDDING_CODE_KEY`)
+    pub embedding_code: Option<String>,
+    pub schema_version: i64,
+    pub dimension_mismatches: i64,
+    /// Jobs de embedding que falharam e aguardam retry
+    pub embedding_failures: i64,
+    /// Vetores por modelo extra (MCP_EMBEDDING_EXTRA_MODELS)
+    pub model_vectors: Vec<(String, i64)>,
+}
+
+/// Tamanhos válidos de blob para a dimensão: f16, int8 e f32 legado só no modelo padrão
+fn dimension_blob_lens(dim: usize) -> [i64; 3] {
+    let f16_len = (dim * 2) as i64;
+    let int8_len = crate::embedding::int8_blob_len(dim) as i64;
+    if dim == crate::embedding::DEFAULT_DIMENSION {
+        [f16_len, int8_len, (dim * 4) as i64]
+    } else {
+        [f16_len, int8_len, f16_len]
+    }
+}
+
+/// Conta embeddings (memórias + chunks) cujo tamanho não corresponde à dimensão
+/// esperada em f16 nem em f32: sobras de outro modelo, que precisam de reindex
+pub fn count_dimension_mismatches(conn: &Connection, dim: usize) -> i64 {
+    let lens = dimension_blob_lens(dim);
+    let count = |table: &str| -> i64 {
+        conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE embedding IS NOT NULL AND length(embedding) NOT IN (?, ?, ?)",
+                table
+            ),
+            lens,
+            |r| r.get(0),
+        )
+        .unwrap_or(0)
+    };
+    count("memories") + count("memory_chunks")
+}
+
+/// Descarta embeddings com dimensão inesperada para o reindex refazê-los
+pub fn clear_dimension_mismatches(conn: &Connection, dim: usize) -> Result<usize> {
+    let lens = dimension_blob_lens(dim);
+    let cleared = conn.execute(
+        "UPDATE memories SET embedding = NULL \
+         WHERE embedding IS NOT NULL AND length(embedding) NOT IN (?1, ?2, ?3)",
+        lens,
+    )?;
+    conn.execute(
+        "DELETE FROM memory_chunks \
+         WHERE embedding IS NOT NULL AND length(embedding) NOT IN (?1, ?2, ?3)",
+        lens,
+    )?;
+    Ok(cleared)
+}
+
+// ---- Falhas de embedding ----
+
+/// Tentativas antes de desistir (o memory_reindex ainda reenfileira)
+pub const MAX_EMBEDDING_ATTEMPTS: i64 = 5;
+/// Backoff exponencial: 30s, 60s, 120s... até 1h
+const RETRY_BASE_SECS: i64 = 30;
+const RETRY_MAX_SECS: i64 = 3600;
+
+/// Registra (ou incrementa) a falha de um job de embedding
+pub fn record_embedding_failure(conn: &Connection, record_id: &str, db_path: &str, error: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO embedding_failures (record_id, db_path, error, attempts, next_retry_at) \
+         VALUES (?1, ?2, ?3, 1, datetime('now', '+' || ?4 || ' seconds')) \
+         ON CONFLICT(record_id) DO UPDATE SET \
+             error = excluded.error, \
+             db_path = excluded.db_path, \
+             attempts = attempts + 1, \
+             last_attempt = CURRENT_TIMESTAMP, \
+             next_retry_at = datetime('now', '+' || min(?4 * (1 << attempts), ?5) || ' seconds')",
+        rusqlite::params![record_id, db_path, error, RETRY_BASE_SECS, RETRY_MAX_SECS],
+    )?;
+    Ok(())
+}
+
+/// Job concluído: a falha pendente (se houver) deixa de existir
+pub fn clear_embedding_failure(conn: &Connection, record_id: &str) -> Result<()> {
+    conn.execute("DELETE FROM embedding_failures WHERE record_id = ?", [record_id])?;
+    Ok(())
+}
+
+/// Falhas com backoff vencido e tentativas restantes: (id, content)
+pub fn due_embedding_failures(conn: &Connection, limit: usize) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT f.record_id, memory_content(m.content, m.content_zstd) FROM embedding_failures f \
+         JOIN memories m ON m.id = f.record_id \
+         WHERE f.attempts < ?1 AND f.next_retry_at <= datetime('now') \
+           AND m.embedding IS NULL AND m.deleted_at IS NULL \
+         ORDER BY f.next_retry_at LIMIT ?2",
+    )?;
+    let rows = stmt.query_map(rusqlite::params![MAX_EMBEDDING_ATTEMPTS, limit as i64], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    Ok(rows.flatten().collect())
+}
+
+/// Falhas ainda sem embedding (inclui as que esgotaram as tentativas)
+fn count_embedding_failures(conn: &Connection) -> i64 {
+    conn.query_row(
+        "SELECT COUNT(*) FROM embedding_failures f JOIN memories m ON m.id = f.record_id \
+         WHERE m.embedding IS NULL AND m.deleted_at IS NULL",
+        [],
+        |r| r.get(0),
+    )
+    .unwrap_or(0)
 }
 
 /// Reindex: enfileira memórias sem embedding
 pub fn get_unindexed_memories(conn: &Connection) -> Result<Vec<(String, String)>> {
-    let mut stmt =
-        conn.prepare("SELECT id, content FROM memories WHERE embedding IS NULL AND archived = 0")?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT m.id, memory_content(m.content, m.content_zstd) FROM memories m \
+         WHERE m.embedding IS NULL AND m.archived = 0 AND m.deleted_at IS NULL AND {}",
+        NOT_EXPIRED_SQL
+    ))?;
     let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
     Ok(rows.flatten().collect())
 }
 
+/// Grava (ou substitui) vetores de um modelo extra numa transação
+pub fn write_model_embeddings(conn: &Connection, model: &str, rows: &[(&str, Vec<u8>)]) -> Result<()> {
+    let tx = write_transaction(conn)?;
+    for (memory_id, blob) in rows {
+        tx.execute(
+            "INSERT OR REPLACE INTO model_embeddings (memory_id, model, embedding) VALUES (?, ?, ?)",
+            rusqlite::params![memory_id, model, blob],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Descarta os vetores dos modelos extras de uma memória cujo conteúdo mudou:
+/// sem a linha, o reindex (`missing_model_embeddings`) refaz se o worker falhar
+pub fn clear_model_embeddings(conn: &Connection, id: &str) -> Result<()> {
+    conn.execute("DELETE FROM model_embeddings WHERE memory_id = ?", [id])?;
+    Ok(())
+}
+
+/// Memórias ativas já indexadas no principal sem vetor do modelo extra (backfill do reindex)
+pub fn missing_model_embeddings(conn: &Connection, model: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT m.id, memory_content(m.content, m.content_zstd) FROM memories m \
+         WHERE m.embedding IS NOT NULL AND m.archived = 0 AND m.deleted_at IS NULL \
+         AND NOT EXISTS (SELECT 1 FROM model_embeddings e WHERE e.memory_id = m.id AND e.model = ?)",
+    )?;
+    let rows = stmt.query_map([model], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(rows.flatten().collect())
+}
+
+/// Vetores por modelo extra (ativos e arquivados, fora da lixeira)
+fn count_model_embeddings(conn: &Connection) -> Vec<(String, i64)> {
+    let Ok(mut stmt) = conn.prepare(
+        "SELECT e.model, COUNT(*) FROM model_embeddings e JOIN memories m ON m.id = e.memory_id \
+         WHERE m.deleted_at IS NULL GROUP BY e.model ORDER BY e.model",
+    ) else {
+        return Vec::new();
+    };
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map(|rows| rows.flatten().collect())
+        .unwrap_or_default()
+}
+
 /// Compact: VACUUM + rebuild FTS + apply TTL
 pub fn compact_db(conn: &Connection, scope: &str) -> Result<CompactResult> {
     let result = CompactResult {
+        expired_pruned: prune_expired(conn),
         ttl_applied: apply_ttl(conn, scope),
         decayed: apply_importance_decay(conn),
+        cache_pruned: prune_embedding_cache(conn),
     };
 
-    // Rebuild FTS
-    let _ = conn.execute_batch("INSERT INTO memories_fts(memories_fts) VALUES('rebuild');");
+    // Rebuild FTS (relê a tabela inteira; a lixeira sai logo em seguida)
+    let _ = conn.execute_batch(FTS_REBUILD);
     conn.execute_batch("VACUUM;")?;
 
     Ok(result)
@@ -504,8 +2084,69 @@ pub fn compact_db(conn: &Connection, scope: &str) -> Result<CompactResult> {
 
 #[derive(Debug, Default)]
 pub struct CompactResult {
+    /// Memórias com `expires_at` vencido apagadas de vez
+    pub expired_pruned: i64,
     pub ttl_applied: i64,
     pub decayed: i64,
+    pub cache_pruned: i64,
+}
+
+/// O que `compact_db` faria, sem alterar nada
+#[derive(Debug, Default)]
+pub struct CompactPreview {
+    pub result: CompactResult,
+    /// Páginas livres que o VACUUM devolveria; estimativa (o rebuild do FTS também mexe no tamanho)
+    pub reclaimable_bytes: i64,
+}
+
+/// Roda os mesmos passos de `compact_db` dentro de um savepoint desfeito no fim:
+/// as contagens batem com as reais (o TTL roda antes do decay e muda o que decai).
+pub fn compact_preview(conn: &Connection, scope: &str) -> Result<CompactPreview> {
+    conn.execute_batch("SAVEPOINT compact_preview")?;
+    let result = CompactResult {
+        expired_pruned: prune_expired(conn),
+        ttl_applied: apply_ttl(conn, scope),
+        decayed: apply_importance_decay(conn),
+        cache_pruned: prune_embedding_cache(conn),
+    };
+    conn.execute_batch("ROLLBACK TO compact_preview; RELEASE compact_preview")?;
+
+    let free_pages: i64 = conn.query_row("PRAGMA freelist_count", [], |r| r.get(0))?;
+    let page_size: i64 = conn.query_row("PRAGMA page_size", [], |r| r.get(0))?;
+    Ok(CompactPreview {
+        result,
+        reclaimable_bytes: free_pages * page_size,
+    })
+}
+
+/// Limita o embedding_cache a MCP_CACHE_MAX_ENTRIES (padrão 50000), mantendo as mais novas
+pub fn prune_embedding_cache(conn: &Connection) -> i64 {
+    let max_entries: i64 = std::env::var("MCP_CACHE_MAX_ENTRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(50_000);
+    prune_embedding_cache_to(conn, max_entries)
+}
+
+fn prune_embedding_cache_to(conn: &Connection, max_entries: i64) -> i64 {
+    // Empate no created_at (resolução de segundos): rowid maior = gravada depois
+    conn.execute(
+        "DELETE FROM embedding_cache WHERE rowid IN ( \
+             SELECT rowid FROM embedding_cache \
+             ORDER BY created_at DESC, rowid DESC LIMIT -1 OFFSET ?)",
+        [max_entries.max(0)],
+    )
+    .unwrap_or(0) as i64
+}
+
+/// Apaga de vez (linha, chunks e edges) as memórias com `expires_at` vencido,
+/// inclusive as que estão na lixeira
+pub fn prune_expired(conn: &Connection) -> i64 {
+    conn.execute(
+        "DELETE FROM memories WHERE expires_at IS NOT NULL AND expires_at <= datetime('now')",
+        [],
+    )
+    .unwrap_or(0) as i64
 }
 
 /// Aplica TTL baseado no scope
@@ -557,3 +2198,964 @@ fn apply_importance_decay(conn: &Connection) -> i64 {
         [],
     ).unwrap_or(0) as i64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// DB temporário isolado por teste (e por processo: execuções simultâneas do cargo test não dividem o arquivo)
+    fn test_db(name: &str) -> Connection {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-memory-storage-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        init_db(&path).unwrap()
+    }
+
+    /// Pool temporário: caminho isolado por teste e por processo
+    fn test_pool_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-memory-pool-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_connection_pool_reuses_connections() {
+        let pool = std::sync::Arc::new(ConnectionPool::default());
+        let path = test_pool_path("reuse");
+        let id = {
+            let conn = pool.get(&path).unwrap();
+            save_memory(&conn, "note", "pooled write", "", &SaveOptions::default()).unwrap().id
+        };
+        assert_eq!(pool.idle.lock().unwrap()[&path].len(), 1);
+
+        // Duas ao mesmo tempo: a segunda é nova (sem re-rodar o schema) e enxerga os dados
+        let a = pool.get(&path).unwrap();
+        let b = pool.get(&path).unwrap();
+        assert!(get_memory(&b, &id).unwrap().is_some());
+        drop(a);
+        drop(b);
+        assert_eq!(pool.idle.lock().unwrap()[&path].len(), 2);
+
+        // DB apagado por fora: recria o schema em vez de usar a conexão velha
+        std::fs::remove_file(&path).unwrap();
+        let conn = pool.get(&path).unwrap();
+        assert!(get_memory(&conn, &id).unwrap().is_none());
+        assert!(save_memory(&conn, "note", "after recreate", "", &SaveOptions::default()).is_ok());
+    }
+
+    /// Benchmark: `cargo test --release bench_connection_pool -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_connection_pool() {
+        let pool = std::sync::Arc::new(ConnectionPool::default());
+        let path = test_pool_path("bench");
+        {
+            let conn = pool.get(&path).unwrap();
+            for i in 0..200 {
+                let content = format!("benchmark memory {} about sqlite connection reuse", i);
+                save_memory(&conn, "note", &content, "bench", &SaveOptions::default()).unwrap();
+            }
+        }
+        let filters = crate::search::SearchFilters::default();
+        let rounds = 200;
+
+        let started = std::time::Instant::now();
+        for _ in 0..rounds {
+            let conn = init_db(&path).unwrap();
+            crate::search::search_fts(&conn, "sqlite reuse", 10, &filters);
+        }
+        let cold = started.elapsed();
+
+        let started = std::time::Instant::now();
+        for _ in 0..rounds {
+            let conn = pool.get(&path).unwrap();
+            crate::search::search_fts(&conn, "sqlite reuse", 10, &filters);
+        }
+        let warm = started.elapsed();
+
+        println!(
+            "text search: init_db per call {:.0} us, pooled {:.0} us ({:.1}x)",
+            cold.as_secs_f64() * 1e6 / rounds as f64,
+            warm.as_secs_f64() * 1e6 / rounds as f64,
+            cold.as_secs_f64() / warm.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn test_save_memory_batch_dedups_within_batch() {
+        let conn = test_db("save-batch");
+        let item = |content: &str| BatchItem {
+            mem_type: "decision".into(),
+            content: content.into(),
+            ..Default::default()
+        };
+        let results = save_memory_batch(
+            &conn,
+            &[
+                item("Use postgres for the billing service"),
+                item("Adopt feature flags for risky deploys"),
+                item("Use postgres for the billing service"),
+            ],
+            None,
+            DedupStrategy::Replace,
+            0,
+        )
+        .unwrap();
+        let outcomes: Vec<&str> = results.iter().map(|r| r.dedup.as_str()).collect();
+        assert_eq!(outcomes, ["new", "new", "updated"]);
+        assert_eq!(results[0].id, results[2].id);
+
+        let total: i64 = conn.query_row("SELECT COUNT(*) FROM memories", [], |r| r.get(0)).unwrap();
+        assert_eq!(total, 2);
+    }
+
+    #[test]
+    fn test_concurrent_writers_lose_no_saves() {
+        let path = std::env::temp_dir().join(format!("mcp-memory-storage-concurrent-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        drop(init_db(&path).unwrap());
+        let (writers, per_writer) = (6, 20);
+
+        let mut handles: Vec<std::thread::JoinHandle<Vec<String>>> = (0..writers)
+            .map(|w| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let conn = open_connection(&path).unwrap();
+                    let opts = SaveOptions { dedup_threshold: Some(1.0), ..Default::default() };
+                    let mut errors = Vec::new();
+                    for i in 0..per_writer {
+                        let content = format!("writer {} note {} about lock contention", w, i);
+                        let saved = if i % 5 == 0 {
+                            // Batch: lê (dedup) antes de escrever dentro da mesma transação
+                            let item = BatchItem { mem_type: "note".into(), content, ..Default::default() };
+                            save_memory_batch(&conn, &[item], Some(1.0), DedupStrategy::Replace, 0).map(|_| ())
+                        } else {
+                            save_memory(&conn, "note", &content, "", &opts).map(|_| ())
+                        };
+                        if let Err(e) = saved {
+                            errors.push(e.to_string());
+                        }
+                    }
+                    errors
+                })
+            })
+            .collect();
+
+        // Worker de embedding em paralelo: UPDATE + chunks numa transação
+        let worker_path = path.clone();
+        handles.push(std::thread::spawn(move || {
+            let conn = open_connection(&worker_path).unwrap();
+            let mut errors = Vec::new();
+            for round in 0..30 {
+                let ids: Vec<String> = conn
+                    .prepare("SELECT id FROM memories WHERE embedding IS NULL LIMIT 5")
+                    .and_then(|mut stmt| stmt.query_map([], |r| r.get(0))?.collect())
+                    .unwrap_or_default();
+                let written = write_transaction(&conn).and_then(|tx| {
+                    for id in &ids {
+                        tx.execute("UPDATE memories SET embedding = x'00' WHERE id = ?", [id])?;
+                        tx.execute(
+                            "INSERT OR REPLACE INTO memory_chunks (id, memory_id, chunk_index, chunk_text) VALUES (?, ?, 0, 'c')",
+                            rusqlite::params![format!("{}_{}", id, round), id],
+                        )?;
+                    }
+                    tx.commit()?;
+                    Ok(())
+                });
+                if let Err(e) = written {
+                    errors.push(e.to_string());
+                }
+            }
+            errors
+        }));
+
+        let errors: Vec<String> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        assert!(errors.is_empty(), "writes failed under contention: {:?}", errors);
+        let conn = open_connection(&path).unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM memories", [], |r| r.get(0)).unwrap();
+        assert_eq!(count, (writers * per_writer) as i64);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_check_content_len_limit() {
+        let limit = 1024;
+        assert!(check_content_len(&"a".repeat(limit), limit).is_ok());
+        let err = check_content_len(&"a".repeat(limit + 1), limit).unwrap_err();
+        assert!(err.contains("1025 bytes") && err.contains("MCP_MAX_CONTENT_LEN"));
+        // 0 desliga; o limite é em bytes, não caracteres
+        assert!(check_content_len(&"a".repeat(1 << 20), 0).is_ok());
+        assert!(check_content_len(&"é".repeat(600), limit).is_err());
+        assert_eq!(DEFAULT_MAX_CONTENT_LEN, 102_400);
+    }
+
+    #[test]
+    fn test_check_project_db_reports_unusable_paths() {
+        let dir = std::env::temp_dir().join(format!("mcp_check_project_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = MemoryPaths::project_db_in(&dir);
+        assert!(MemoryPaths::check_project_db(&db).is_ok());
+
+        // project.db existente como diretório
+        std::fs::create_dir_all(&db).unwrap();
+        let err = MemoryPaths::check_project_db(&db).unwrap_err();
+        assert!(err.contains("path is a directory") && err.contains("scope='global'"), "{}", err);
+
+        let missing = MemoryPaths::project_db_in(&dir.join("gone"));
+        let err = MemoryPaths::check_project_db(&missing).unwrap_err();
+        assert!(err.contains("does not exist") && err.contains("MCP_PROJECT_DIR"), "{}", err);
+
+        // Diretório sem bit de escrita: o veredito tem que bater com o init_db de verdade
+        // (como root a escrita passa, e o check não pode recusar)
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = dir.join("locked");
+            std::fs::create_dir_all(&locked).unwrap();
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o555)).unwrap();
+            let db = MemoryPaths::project_db_in(&locked);
+            let checked = MemoryPaths::check_project_db(&db);
+            assert_eq!(checked.is_ok(), init_db(&db).is_ok(), "{:?}", checked);
+            assert!(!std::fs::read_dir(&locked).unwrap().flatten().any(|e| e.file_name().to_string_lossy().starts_with(".mcp-memory-probe")));
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_canonical_scope_aliases_and_typos() {
+        assert_eq!(canonical_scope("project-only"), Ok("project"));
+        assert_eq!(canonical_scope(" ALL "), Ok("all"));
+        assert_eq!(canonical_scope("everything"), Ok("all"));
+        let err = canonical_scope("proj").unwrap_err();
+        assert_eq!(err, "unknown scope 'proj'; valid: global, project, personality, both, all");
+    }
+
+    #[test]
+    fn test_project_db_at_other_checkout() {
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            MemoryPaths::project_db_at(&dir.to_string_lossy()).unwrap(),
+            dir.join(".mcp-memoria").join("project.db")
+        );
+        assert!(MemoryPaths::project_db_at("/nonexistent/checkout").is_err());
+
+        let paths = MemoryPaths {
+            global_db: "g.db".into(),
+            personality_db: "p.db".into(),
+            cache_db: "c.db".into(),
+            data_dir: ".".into(),
+        };
+        let dbs = resolve_scope_dbs_with("both", &paths, Some("other/project.db".into()));
+        assert_eq!(dbs[1], ("project".to_string(), PathBuf::from("other/project.db")));
+        assert!(resolve_scope_dbs_with("project", &paths, None).is_empty());
+        assert_eq!(resolve_scope_dbs_with("Project-Only", &paths, Some("p.db".into()))[0].0, "project");
+    }
+
+    #[test]
+    fn test_resolve_data_dir_order() {
+        let home = std::env::temp_dir().join(format!("mcp-memory-home-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&home);
+        let xdg = PathBuf::from("/xdg/data");
+
+        assert_eq!(
+            resolve_data_dir(Some("/custom".into()), Some(xdg.clone()), Some(home.clone())),
+            Some(PathBuf::from("/custom"))
+        );
+        assert_eq!(
+            resolve_data_dir(Some("".into()), Some(xdg.clone()), Some(home.clone())),
+            Some(xdg.join("mcp-memoria"))
+        );
+        // XDG relativo não vale: cai no ~/.mcp-memoria/data
+        let legacy = home.join(".mcp-memoria").join("data");
+        assert_eq!(resolve_data_dir(None, Some("rel".into()), Some(home.clone())), Some(legacy.clone()));
+        // Dados antigos já existentes ganham do XDG
+        std::fs::create_dir_all(&legacy).unwrap();
+        assert_eq!(resolve_data_dir(None, Some(xdg), Some(home.clone())), Some(legacy));
+        assert_eq!(resolve_data_dir(None, None, None), None);
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn test_compact_preview_matches_compact() {
+        let conn = test_db("compact-preview");
+        for (i, mem_type) in ["conversation", "conversation", "context", "decision"].iter().enumerate() {
+            let saved = save_memory(&conn, mem_type, &format!("old session number {}", i), "", &Default::default()).unwrap();
+            conn.execute(
+                "UPDATE memories SET updated_at = '2020-01-01 00:00:00' WHERE id = ?",
+                [&saved.id],
+            )
+            .unwrap();
+        }
+        let snapshot = || -> Vec<(i64, f64)> {
+            let mut stmt = conn.prepare("SELECT archived, importance FROM memories ORDER BY id").unwrap();
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().map(|r| r.unwrap()).collect()
+        };
+        let before = snapshot();
+        let preview = compact_preview(&conn, "personality").unwrap();
+        assert_eq!(snapshot(), before, "preview must not modify rows");
+
+        let result = compact_db(&conn, "personality").unwrap();
+        assert_eq!(
+            (preview.result.ttl_applied, preview.result.decayed, preview.result.cache_pruned),
+            (result.ttl_applied, result.decayed, result.cache_pruned)
+        );
+        // Conversations arquivadas pelo TTL não decaem depois
+        assert_eq!((result.ttl_applied, result.decayed), (2, 1));
+    }
+
+    #[test]
+    fn test_compressed_content_reads_transparently() {
+        let conn = test_db("zstd-content");
+        let log = "2024-05-01 WARN payment gateway timeout after 30s, retrying\n".repeat(100);
+        let packed = crate::compress::compress_if_large(&log, 1024).unwrap();
+        conn.execute(
+            "INSERT INTO memories (id, type, content, content_zstd, tags) VALUES ('big', 'note', '', ?, 'logs')",
+            [&packed],
+        )
+        .unwrap();
+
+        assert_eq!(get_memory(&conn, "big").unwrap().unwrap().content, log);
+        assert_eq!(list_memories(&conn, &Default::default(), 10, 0).unwrap()[0].content, log);
+        // O trigger indexa o texto descomprimido; o rebuild também
+        let hits = || crate::search::search_fts(&conn, "gateway timeout", 5, &Default::default());
+        assert_eq!(hits()[0].content, log);
+        rebuild_fts(&conn).unwrap();
+        assert!(check_fts(&conn).unwrap().in_sync());
+        assert_eq!(hits().len(), 1);
+
+        // Edição curta volta para texto cru e limpa o blob
+        update_memory(&conn, "big", Some("gateway fixed"), None).unwrap();
+        let (raw, zstd): (String, Option<Vec<u8>>) = conn
+            .query_row("SELECT content, content_zstd FROM memories WHERE id = 'big'", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!((raw.as_str(), zstd), ("gateway fixed", None));
+        assert!(crate::search::search_fts(&conn, "timeout", 5, &Default::default()).is_empty());
+    }
+
+    #[test]
+    fn test_check_fts_detects_and_repairs_drift() {
+        let conn = test_db("fts-check");
+        let ids: Vec<String> = ["first note about caching", "second note about retries", "third note"]
+            .iter()
+            .map(|content| save_memory(&conn, "note", content, "", &SaveOptions::default()).unwrap().id)
+            .collect();
+        soft_delete_memory(&conn, &ids[0]).unwrap();
+        let check = check_fts(&conn).unwrap();
+        assert!(check.in_sync(), "{:?}", check);
+        assert_eq!(check.expected, 2);
+        assert_eq!(get_stats(&conn, 384).fts_rows, 2);
+
+        // Escrita por fora dos triggers
+        conn.execute_batch("DROP TRIGGER memories_ai;").unwrap();
+        conn.execute(
+            "INSERT INTO memories (id, type, content, tags) VALUES ('bulk', 'note', 'bulk import row', '')",
+            [],
+        )
+        .unwrap();
+        let drift = check_fts(&conn).unwrap();
+        assert_eq!((drift.expected, drift.indexed), (3, 2));
+
+        rebuild_fts(&conn).unwrap();
+        assert!(check_fts(&conn).unwrap().in_sync());
+    }
+
+    #[test]
+    fn test_dedup_strategies() {
+        let conn = test_db("dedup-strategy");
+        let original = "Deploy checklist\nRun migrations first\nWarm the cache";
+        let id = save_memory(&conn, "pattern", original, "deploy", &SaveOptions::default()).unwrap().id;
+        let stored = |conn: &Connection| -> (String, String) {
+            conn.query_row("SELECT memory_content(content, content_zstd), tags FROM memories WHERE id = ?", [&id], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+        };
+        let with = |strategy| SaveOptions { dedup_strategy: strategy, ..Default::default() };
+
+        // keep: existente intacto
+        let kept = save_memory(&conn, "pattern", original, "other", &with(DedupStrategy::Keep)).unwrap();
+        assert_eq!((kept.id.as_str(), kept.dedup.as_str()), (id.as_str(), "kept"));
+        assert_eq!(stored(&conn).0, original);
+
+        // merge: só linhas novas no fim, tags unidas
+        let terse = "Deploy checklist\nRun migrations first\nNotify the channel";
+        let merged = save_memory(
+            &conn,
+            "pattern",
+            terse,
+            "release",
+            &SaveOptions { dedup_threshold: Some(0.5), ..with(DedupStrategy::Merge) },
+        )
+        .unwrap();
+        assert_eq!(merged.id, id);
+        let (content, tags) = stored(&conn);
+        assert_eq!(content, format!("{}\nNotify the channel", original));
+        // record relido: conteúdo final e tags como gravados, com timestamps
+        assert_eq!(merged.record.content, content);
+        assert_eq!(merged.record.tags, tags);
+        assert!(!merged.record.created_at.is_empty() && merged.record.updated_at.is_some());
+        assert!(tags.contains("deploy") && tags.contains("release"), "{}", tags);
+
+        assert_eq!(DedupStrategy::parse("").unwrap(), DedupStrategy::Replace);
+        assert!(DedupStrategy::parse("append").is_err());
+    }
+
+    #[test]
+    fn test_merge_respects_max_content_len() {
+        let conn = test_db("merge-limit");
+        let original = "Deploy checklist\nRun migrations first\nWarm the cache";
+        let limited = |max_content_len| SaveOptions {
+            dedup_threshold: Some(0.5),
+            dedup_strategy: DedupStrategy::Merge,
+            max_content_len,
+            ..Default::default()
+        };
+        let id = save_memory(&conn, "pattern", original, "", &limited(60)).unwrap().id;
+
+        // Cada entrada cabe no limite; o merge passaria dele e é recusado sem mexer na memória
+        let more = "Deploy checklist\nRun migrations first\nNotify the channel";
+        assert!(more.len() <= 60);
+        let err = save_memory(&conn, "pattern", more, "", &limited(60)).err().expect("merge over the limit").to_string();
+        assert!(err.starts_with("merged content is") && err.contains("MCP_MAX_CONTENT_LEN"), "{}", err);
+        assert_eq!(get_memory_record(&conn, &id).unwrap().unwrap().content, original);
+
+        let merged = save_memory(&conn, "pattern", more, "", &limited(0)).unwrap();
+        assert_eq!((merged.id.as_str(), merged.dedup.as_str()), (id.as_str(), "updated"));
+        assert_eq!(truncate_content("aé", 2), "a");
+    }
+
+    #[test]
+    fn test_idempotency_key_makes_retries_update() {
+        let conn = test_db("idempotency");
+        let opts = |key| SaveOptions { idempotency_key: Some(key), dedup_threshold: Some(1.0), ..Default::default() };
+
+        let first = save_memory(&conn, "decision", "Use WAL mode", "sqlite", &opts("req-42")).unwrap();
+        assert_eq!((first.id.as_str(), first.dedup.as_str()), (idempotent_id("req-42").as_str(), "new"));
+
+        // Retry com conteúdo diferente (dedup exato não pegaria): mesma linha, atualizada
+        let retry = save_memory(&conn, "decision", "Use WAL mode for SQLite", "sqlite", &opts("req-42")).unwrap();
+        assert_eq!(retry.id, first.id);
+        assert_eq!((retry.dedup.as_str(), retry.dedup_method.as_str()), ("updated", "idempotency_key"));
+        assert_eq!(retry.record.content, "Use WAL mode for SQLite");
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM memories", [], |r| r.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        // Outra key, outro ID; sem key, o ID continua vindo de generate_id
+        assert_ne!(save_memory(&conn, "decision", "Pin the toolchain", "", &opts("req-43")).unwrap().id, first.id);
+        let plain = save_memory(&conn, "note", "no key here", "", &SaveOptions::default()).unwrap();
+        assert_ne!(plain.id, idempotent_id("no key here"));
+    }
+
+    #[test]
+    fn test_record_embedding_text_treats_legacy_vectors_as_content() {
+        let conn = test_db("embedding-text");
+        record_embedding_text(&conn, "type_tags_content");
+        assert_eq!(get_meta(&conn, EMBEDDING_TEXT_KEY).as_deref(), Some("type_tags_content"));
+
+        // DB com vetores gravados antes do registro existir
+        let legacy = test_db("embedding-text-legacy");
+        let saved = save_memory(&legacy, "note", "old vector", "", &SaveOptions::default()).unwrap();
+        legacy
+            .execute("UPDATE memories SET embedding = x'00' WHERE id = ?", [&saved.id])
+            .unwrap();
+        record_embedding_text(&legacy, "type_tags_content");
+        assert_eq!(get_meta(&legacy, EMBEDDING_TEXT_KEY).as_deref(), Some("content"));
+        record_embedding_code(&legacy, "on");
+        assert_eq!(get_meta(&legacy, EMBEDDING_CODE_KEY).as_deref(), Some("off"));
+    }
+
+    #[test]
+    fn test_expired_memories_hidden_then_pruned() {
+        let conn = test_db("expiry");
+        let opts = SaveOptions { ttl_days: Some(7), ..Default::default() };
+        let saved = save_memory(&conn, "note", "standup notes from monday", "", &opts).unwrap();
+        assert!(saved.expires_at.is_some());
+        // Duplicata salva sem ttl_days (merge ou replace) não apaga o prazo
+        let merge = SaveOptions { dedup_strategy: DedupStrategy::Merge, ..Default::default() };
+        let resaved = save_memory(&conn, "note", "standup notes from monday", "", &merge).unwrap();
+        assert_eq!((resaved.id.as_str(), resaved.dedup.as_str()), (saved.id.as_str(), "updated"));
+        assert_eq!(resaved.expires_at, saved.expires_at);
+        let kept = save_memory(&conn, "note", "standup format stays weekly", "", &SaveOptions::default()).unwrap();
+        assert_eq!(kept.expires_at, None);
+
+        // Vence antes do prune: some da busca e da lista, mas a linha continua lá
+        conn.execute("UPDATE memories SET expires_at = datetime('now', '-1 minute') WHERE id = ?", [&saved.id])
+            .unwrap();
+        let filters = crate::search::SearchFilters::default();
+        let hits: Vec<String> = crate::search::search_fts(&conn, "standup", 5, &filters).into_iter().map(|r| r.id).collect();
+        assert_eq!(hits, [kept.id.as_str()]);
+        let listed: Vec<String> = list_memories(&conn, &filters, 10, 0).unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(listed, [kept.id.as_str()]);
+
+        // O mesmo conteúdo salvo de novo não cai na expirada
+        let again = save_memory(&conn, "note", "standup notes from monday", "", &SaveOptions::default()).unwrap();
+        assert_eq!(again.dedup, "new");
+        assert_ne!(again.id, saved.id);
+
+        // Stats contam a vencida à parte, e o FTS continua batendo com a soma
+        let stats = get_stats(&conn, 384);
+        assert_eq!((stats.total, stats.expired), (2, 1));
+        assert_eq!(stats.by_type, [("note".to_string(), 2)]);
+        assert_eq!(stats.fts_rows, stats.total + stats.expired + stats.archived);
+
+        let result = compact_db(&conn, "global").unwrap();
+        assert_eq!(result.expired_pruned, 1);
+        assert!(get_memory(&conn, &saved.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_save_normalizes_tags() {
+        let conn = test_db("tag-normalize");
+        let saved = save_memory(&conn, "note", "plain note", "API, api , api", &SaveOptions::default()).unwrap();
+        assert_eq!(saved.record.tags, "api");
+        let filters = crate::search::SearchFilters { tags: parse_tag_filter(" Api "), ..Default::default() };
+        assert_eq!(crate::search::search_fts(&conn, "plain", 5, &filters).len(), 1);
+    }
+
+    #[test]
+    fn test_tag_counts_split_and_sort() {
+        let conn = test_db("tag-counts");
+        for (i, tags) in ["backend,api", "backend", "back-end,api", "backend"].iter().enumerate() {
+            let content = format!("memory number {} tagged {}", i, tags);
+            save_memory(&conn, "note", &content, tags, &SaveOptions {
+                dedup_threshold: Some(1.0),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let counts = tag_counts(&conn);
+        let sorted = sort_tag_counts(counts);
+        let top: Vec<(&str, i64)> = sorted.iter().take(3).map(|(t, n)| (t.as_str(), *n)).collect();
+        assert_eq!(top, [("backend", 3), ("api", 2), ("back-end", 1)]);
+    }
+
+    #[test]
+    fn test_list_pagination_no_gaps_or_dupes() {
+        let conn = test_db("list-offset");
+        for content in ["alpha one", "beta two", "gamma three", "delta four", "epsilon five"] {
+            save_memory(&conn, "note", content, "", &SaveOptions::default()).unwrap();
+        }
+
+        let all = list_memories(&conn, &Default::default(), 10, 0).unwrap();
+        assert_eq!(all.len(), 5);
+
+        let mut paged = list_memories(&conn, &Default::default(), 2, 0).unwrap();
+        paged.extend(list_memories(&conn, &Default::default(), 2, 2).unwrap());
+        paged.extend(list_memories(&conn, &Default::default(), 2, 4).unwrap());
+        let all_ids: Vec<&str> = all.iter().map(|m| m.id.as_str()).collect();
+        let paged_ids: Vec<&str> = paged.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(all_ids, paged_ids);
+    }
+
+    #[test]
+    fn test_list_exposes_updated_at_without_reordering() {
+        let conn = test_db("list-updated-at");
+        let old = save_memory(&conn, "note", "old note about queues", "", &SaveOptions::default()).unwrap();
+        let new = save_memory(&conn, "note", "new note about caches", "", &SaveOptions::default()).unwrap();
+        conn.execute(
+            "UPDATE memories SET created_at = '2024-01-01 00:00:00', updated_at = '2024-01-01 00:00:00' WHERE id = ?",
+            [&old.id],
+        )
+        .unwrap();
+        update_memory(&conn, &old.id, Some("old note about queues, revised"), None).unwrap();
+
+        let listed = list_memories(&conn, &Default::default(), 10, 0).unwrap();
+        assert_eq!(listed.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), [new.id.as_str(), old.id.as_str()]);
+        let revised = &listed[1];
+        assert_eq!(revised.created_at, "2024-01-01 00:00:00");
+        assert!(revised.updated_at.as_deref().is_some_and(|u| u > "2024-01-01 00:00:00"), "{:?}", revised);
+    }
+
+    #[test]
+    fn test_embedding_model_recorded_and_reset() {
+        let conn = test_db("model-meta");
+        record_embedding_model(&conn, "all-MiniLM-L6-v2");
+        // Modelo diferente não sobrescreve o registrado
+        record_embedding_model(&conn, "bge-small-en-v1.5");
+        assert_eq!(
+            get_meta(&conn, EMBEDDING_MODEL_KEY).as_deref(),
+            Some("all-MiniLM-L6-v2")
+        );
+
+        let saved = save_memory(&conn, "note", "model switch test", "", &SaveOptions::default()).unwrap();
+        conn.execute(
+            "UPDATE memories SET embedding = x'00' WHERE id = ?",
+            [&saved.id],
+        )
+        .unwrap();
+        assert_eq!(reset_embeddings(&conn, "bge-small-en-v1.5").unwrap(), 1);
+        assert_eq!(get_stats(&conn, 384).embedding_model.as_deref(), Some("bge-small-en-v1.5"));
+        assert_eq!(get_unindexed_memories(&conn).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_dimension_mismatches_counted() {
+        let conn = test_db("dim-mismatch");
+        let a = save_memory(&conn, "note", "vector from current model", "", &SaveOptions::default()).unwrap();
+        let b = save_memory(&conn, "note", "vector from an older model", "", &SaveOptions::default()).unwrap();
+        let current = crate::embedding::compress_embedding(&[0.1f32; 384]);
+        let other = crate::embedding::compress_embedding(&[0.1f32; 512]);
+        conn.execute("UPDATE memories SET embedding = ? WHERE id = ?", rusqlite::params![current, a.id]).unwrap();
+        conn.execute("UPDATE memories SET embedding = ? WHERE id = ?", rusqlite::params![other, b.id]).unwrap();
+        assert_eq!(get_stats(&conn, 384).dimension_mismatches, 1);
+        assert_eq!(get_stats(&conn, 512).dimension_mismatches, 1);
+        assert_eq!(clear_dimension_mismatches(&conn, 384).unwrap(), 1);
+        assert_eq!(get_stats(&conn, 384).dimension_mismatches, 0);
+    }
+
+    #[test]
+    fn test_export_includes_archived_grouped_by_type() {
+        let conn = test_db("export");
+        let note = save_memory(&conn, "note", "export note about caching", "", &SaveOptions::default()).unwrap();
+        save_memory(&conn, "decision", "export decision to use sqlite", "", &SaveOptions::default()).unwrap();
+        conn.execute("UPDATE memories SET archived = 1 WHERE id = ?", [&note.id]).unwrap();
+
+        let exported = export_memories(&conn).unwrap();
+        let types: Vec<&str> = exported.iter().map(|m| m.mem_type.as_str()).collect();
+        assert_eq!(types, vec!["decision", "note"]);
+        assert!(exported[1].archived);
+    }
+
+    #[test]
+    fn test_init_db_normalizes_legacy_embeddings() {
+        let path = std::env::temp_dir().join(format!("mcp-memory-storage-normalize-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = init_db(&path).unwrap();
+        let saved = save_memory(&conn, "note", "legacy vector", "", &SaveOptions::default()).unwrap();
+        conn.execute(
+            "UPDATE memories SET embedding = ? WHERE id = ?",
+            rusqlite::params![crate::embedding::compress_embedding(&[3.0, 4.0]), saved.id],
+        )
+        .unwrap();
+        // Simula DB de versão anterior: sem a marca de normalização
+        conn.execute("DELETE FROM schema_meta WHERE key = ?", [NORMALIZED_KEY]).unwrap();
+        drop(conn);
+
+        let conn = init_db(&path).unwrap();
+        let blob: Vec<u8> = conn
+            .query_row("SELECT embedding FROM memories WHERE id = ?", [&saved.id], |r| r.get(0))
+            .unwrap();
+        let v = crate::embedding::decompress_embedding(&blob);
+        assert!((v[0] - 0.6).abs() < 1e-3 && (v[1] - 0.8).abs() < 1e-3);
+        assert_eq!(get_meta(&conn, NORMALIZED_KEY).as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_links_listed_and_cascaded() {
+        let conn = test_db("links");
+        let old = save_memory(&conn, "decision", "Use REST for the public API", "", &SaveOptions::default()).unwrap();
+        let new = save_memory(&conn, "decision", "Switch the public API to gRPC", "", &SaveOptions::default()).unwrap();
+        assert!(create_edge(&conn, &new.id, &old.id, "supersedes").unwrap());
+
+        let from_new = get_links(&conn, &new.id);
+        assert_eq!(from_new.len(), 1);
+        assert_eq!((from_new[0].id.as_str(), from_new[0].direction), (old.id.as_str(), "outgoing"));
+        assert_eq!(get_links(&conn, &old.id)[0].direction, "incoming");
+
+        // Apagar qualquer ponta remove o link (ON DELETE CASCADE)
+        conn.execute("DELETE FROM memories WHERE id = ?", [&old.id]).unwrap();
+        assert!(get_links(&conn, &new.id).is_empty());
+        let edges: i64 = conn.query_row("SELECT COUNT(*) FROM memory_edges", [], |r| r.get(0)).unwrap();
+        assert_eq!(edges, 0);
+    }
+
+    #[test]
+    fn test_content_change_drops_extra_model_vectors() {
+        let conn = test_db("model-vectors-stale");
+        let opts = SaveOptions { dedup_threshold: Some(1.0), idempotency_key: Some("k"), ..Default::default() };
+        let saved = save_memory(&conn, "note", "Billing runs nightly", "", &opts).unwrap();
+        let index = |conn: &Connection| {
+            conn.execute("UPDATE memories SET embedding = x'00' WHERE id = ?", [&saved.id]).unwrap();
+            write_model_embeddings(conn, "m", &[(saved.id.as_str(), vec![0u8])]).unwrap();
+        };
+        let missing = |conn: &Connection| missing_model_embeddings(conn, "m").unwrap().len();
+
+        index(&conn);
+        assert_eq!(missing(&conn), 0);
+        update_memory(&conn, &saved.id, Some("Billing runs hourly"), None).unwrap();
+        conn.execute("UPDATE memories SET embedding = x'00' WHERE id = ?", [&saved.id]).unwrap();
+        assert_eq!(missing(&conn), 1);
+
+        // Re-save pela mesma key com outro conteúdo: idem
+        index(&conn);
+        save_memory(&conn, "note", "Billing runs every 15 minutes", "", &opts).unwrap();
+        assert_eq!(missing(&conn), 1);
+    }
+
+    #[test]
+    fn test_move_memory_preserves_row() {
+        let src = test_db("move-src");
+        let dst = test_db("move-dst");
+        let opts = SaveOptions { ttl_days: Some(30), ..Default::default() };
+        let saved = save_memory(&src, "decision", "Deploy with blue-green switches", "ops", &opts).unwrap();
+        src.execute(
+            "UPDATE memories SET created_at = '2024-01-02 03:04:05', embedding = ? WHERE id = ?",
+            rusqlite::params![crate::embedding::compress_embedding(&[0.6, 0.8]), saved.id],
+        )
+        .unwrap();
+        src.execute(
+            "INSERT INTO memory_chunks (id, memory_id, chunk_index, chunk_text) VALUES ('c1', ?, 0, 'Deploy')",
+            [&saved.id],
+        )
+        .unwrap();
+        write_model_embeddings(&src, "extra", &[(saved.id.as_str(), vec![1u8, 2])]).unwrap();
+
+        let moved = move_memory(&src, &dst, &saved.id).unwrap().unwrap();
+        assert!(moved.has_embedding);
+        assert_eq!((moved.chunks, moved.model_vectors), (1, 1));
+        assert!(missing_model_embeddings(&dst, "extra").unwrap().is_empty());
+        assert!(count_model_embeddings(&src).is_empty());
+        assert!(get_memory(&src, &saved.id).unwrap().is_none());
+        let m = get_memory(&dst, &saved.id).unwrap().unwrap();
+        assert_eq!(m.created_at, "2024-01-02 03:04:05");
+        assert!(m.has_embedding);
+        assert!(m.expires_at.is_some());
+        assert_eq!(m.expires_at, saved.expires_at);
+
+        // Já existe no destino: nada muda na origem
+        let again = save_memory(&src, "note", "Rollback needs the previous image tag", "", &SaveOptions::default()).unwrap();
+        dst.execute("INSERT INTO memories (id, type, content) VALUES (?, 'note', 'x')", [&again.id]).unwrap();
+        assert!(move_memory(&src, &dst, &again.id).is_err());
+        assert!(get_memory(&src, &again.id).unwrap().is_some());
+        assert!(move_memory(&src, &dst, "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_soft_delete_hides_and_restores() {
+        let conn = test_db("trash");
+        let saved = save_memory(&conn, "note", "Staging uses the eu-west bucket", "", &SaveOptions::default()).unwrap();
+        let fts_hits = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM memories_fts WHERE memories_fts MATCH 'bucket'", [], |r| r.get(0))
+                .unwrap()
+        };
+        let listed = |conn: &Connection| list_memories(conn, &Default::default(), 10, 0).unwrap().len();
+        assert_eq!((fts_hits(&conn), listed(&conn)), (1, 1));
+
+        assert!(soft_delete_memory(&conn, &saved.id).unwrap());
+        assert!(!soft_delete_memory(&conn, &saved.id).unwrap());
+        assert_eq!((fts_hits(&conn), listed(&conn)), (0, 0));
+        assert_eq!(get_stats(&conn, 384).trashed, 1);
+        assert!(get_memory(&conn, &saved.id).unwrap().unwrap().deleted_at.is_some());
+
+        // Rebuild do FTS no compact não ressuscita a lixeira
+        compact_db(&conn, "global").unwrap();
+        assert_eq!(fts_hits(&conn), 0);
+
+        assert!(restore_memory(&conn, &saved.id).unwrap());
+        assert_eq!((fts_hits(&conn), listed(&conn)), (1, 1));
+
+        soft_delete_memory(&conn, &saved.id).unwrap();
+        assert!(purge_memory(&conn, &saved.id).unwrap());
+        assert!(get_memory(&conn, &saved.id).unwrap().is_none());
+        assert_eq!(fts_hits(&conn), 0);
+    }
+
+    #[test]
+    fn test_delete_matching_by_type_and_tag() {
+        let conn = test_db("bulk");
+        let opts = SaveOptions { dedup_threshold: Some(1.0), ..Default::default() };
+        save_memory(&conn, "note", "Experiment one baseline numbers", "exp,perf", &opts).unwrap();
+        save_memory(&conn, "note", "Experiment two tuned numbers", "exp", &opts).unwrap();
+        save_memory(&conn, "note", "Unrelated note about lunch", "", &opts).unwrap();
+        save_memory(&conn, "decision", "Keep the experiment flag off", "exp", &opts).unwrap();
+
+        let filters = crate::search::SearchFilters {
+            mem_type: Some("note".into()),
+            tags: parse_tag_filter("EXP"),
+            ..Default::default()
+        };
+        // Sem confirmar: só conta
+        assert_eq!(delete_matching(&conn, &filters, false, false).unwrap().len(), 2);
+        assert_eq!(get_stats(&conn, 384).trashed, 0);
+
+        assert_eq!(delete_matching(&conn, &filters, false, true).unwrap().len(), 2);
+        assert_eq!(get_stats(&conn, 384).trashed, 2);
+        assert_eq!(get_stats(&conn, 384).total, 2);
+
+        // purge também pega o que já estava na lixeira
+        assert_eq!(delete_matching(&conn, &filters, true, true).unwrap().len(), 2);
+        assert_eq!(get_stats(&conn, 384).trashed, 0);
+    }
+
+    #[test]
+    fn test_stats_by_tag_and_totals() {
+        let a = test_db("tags-a");
+        let b = test_db("tags-b");
+        let opts = SaveOptions { dedup_threshold: Some(1.0), ..Default::default() };
+        save_memory(&a, "note", "first", "api,backend", &opts).unwrap();
+        save_memory(&a, "note", "second", "API", &opts).unwrap();
+        save_memory(&b, "note", "third", "backend", &opts).unwrap();
+        save_memory(&b, "note", "fourth", "", &opts).unwrap();
+
+        let (sa, sb) = (get_stats(&a, 384), get_stats(&b, 384));
+        assert_eq!(sa.by_tag, [("api".to_string(), 2), ("backend".to_string(), 1)]);
+
+        let totals = aggregate_stats([&sa, &sb]);
+        assert_eq!(totals.total, 4);
+        assert_eq!(totals.by_tag, [("api".to_string(), 2), ("backend".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_aggregate_stats_sums_tags_outside_each_top() {
+        let a = test_db("tags-top-a");
+        let b = test_db("tags-top-b");
+        let opts = SaveOptions { dedup_threshold: Some(1.0), ..Default::default() };
+        // Em cada DB, `shared` empata com TOP_TAGS tags de nome menor e fica fora do top;
+        // somando os dois DBs, é a maior
+        for (conn, prefix) in [(&a, "a"), (&b, "b")] {
+            for i in 0..TOP_TAGS {
+                let tag = format!("{}{:02}", prefix, i);
+                save_memory(conn, "note", &format!("{} one", tag), &tag, &opts).unwrap();
+                save_memory(conn, "note", &format!("{} two", tag), &tag, &opts).unwrap();
+            }
+            save_memory(conn, "note", &format!("{} shared one", prefix), "shared", &opts).unwrap();
+            save_memory(conn, "note", &format!("{} shared two", prefix), "shared", &opts).unwrap();
+        }
+
+        let (sa, sb) = (get_stats(&a, 384), get_stats(&b, 384));
+        assert!(!sa.by_tag.iter().any(|(t, _)| t == "shared"));
+        let totals = aggregate_stats([&sa, &sb]);
+        assert_eq!(totals.by_tag.len(), TOP_TAGS);
+        assert_eq!(totals.by_tag[0], ("shared".to_string(), 4));
+    }
+
+    #[test]
+    fn test_embedding_failures_backoff() {
+        let conn = test_db("failures");
+        let id = save_memory(&conn, "note", "content", "", &SaveOptions::default()).unwrap().id;
+
+        record_embedding_failure(&conn, &id, "db", "model error").unwrap();
+        // Backoff ainda não venceu
+        assert!(due_embedding_failures(&conn, 10).unwrap().is_empty());
+        assert_eq!(get_stats(&conn, 384).embedding_failures, 1);
+
+        conn.execute("UPDATE embedding_failures SET next_retry_at = datetime('now', '-1 second')", []).unwrap();
+        assert_eq!(due_embedding_failures(&conn, 10).unwrap(), [(id.clone(), "content".to_string())]);
+
+        // Tentativas esgotadas: não volta mais para o retry automático
+        for _ in 1..MAX_EMBEDDING_ATTEMPTS {
+            record_embedding_failure(&conn, &id, "db", "db locked").unwrap();
+        }
+        conn.execute("UPDATE embedding_failures SET next_retry_at = datetime('now', '-1 second')", []).unwrap();
+        assert!(due_embedding_failures(&conn, 10).unwrap().is_empty());
+
+        clear_embedding_failure(&conn, &id).unwrap();
+        assert_eq!(get_stats(&conn, 384).embedding_failures, 0);
+    }
+
+    #[test]
+    fn test_prune_embedding_cache_keeps_newest() {
+        let conn = test_db("cache-prune");
+        for i in 0..5 {
+            conn.execute(
+                "INSERT INTO embedding_cache (text_hash, model, embedding, created_at) \
+                 VALUES (?1, 'm', x'00', datetime('now', ?2))",
+                rusqlite::params![format!("h{}", i), format!("-{} days", 5 - i)],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(prune_embedding_cache_to(&conn, 3), 2);
+        let mut stmt = conn.prepare("SELECT text_hash FROM embedding_cache ORDER BY text_hash").unwrap();
+        let kept: Vec<String> = stmt.query_map([], |r| r.get(0)).unwrap().flatten().collect();
+        assert_eq!(kept, ["h2", "h3", "h4"]);
+
+        // Abaixo do limite: nada a fazer
+        assert_eq!(prune_embedding_cache_to(&conn, 10), 0);
+    }
+
+    #[test]
+    fn test_fts_porter_stemming() {
+        let conn = test_db("porter");
+        save_memory(&conn, "note", "running the nightly migrations", "", &SaveOptions::default()).unwrap();
+        save_memory(&conn, "note", "ação de deploy concluída", "", &SaveOptions::default()).unwrap();
+        let filters = crate::search::SearchFilters::default();
+        assert_eq!(crate::search::search_fts(&conn, "run", 5, &filters).len(), 1);
+        assert_eq!(crate::search::search_fts(&conn, "migration", 5, &filters).len(), 1);
+        assert_eq!(crate::search::search_fts(&conn, "acao", 5, &filters).len(), 1);
+    }
+
+    #[test]
+    fn test_migrates_v1_db() {
+        let path = std::env::temp_dir().join(format!("mcp-memory-v1-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            // Schema original: sem ranking, arquivamento nem lixeira; FTS indexando tudo
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE memories (
+                    id TEXT PRIMARY KEY,
+                    type TEXT NOT NULL,
+                    content TEXT NOT NULL,
+                    tags TEXT,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    embedding BLOB
+                );
+                CREATE VIRTUAL TABLE memories_fts USING fts5(
+                    content, tags, content='memories', content_rowid='rowid'
+                );
+                CREATE TRIGGER memories_ai AFTER INSERT ON memories BEGIN
+                    INSERT INTO memories_fts(rowid, content, tags) VALUES (NEW.rowid, NEW.content, NEW.tags);
+                END;
+                INSERT INTO memories (id, type, content, tags, created_at)
+                VALUES ('old1', 'decision', 'use sqlite for storage', 'db', '2024-01-02 03:04:05');",
+            )
+            .unwrap();
+        }
+
+        let conn = init_db(&path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        for column in ["access_count", "importance", "archived", "deleted_at"] {
+            assert!(has_column(&conn, "memories", column).unwrap(), "{}", column);
+        }
+
+        let (content, created_at, importance): (String, String, f64) = conn
+            .query_row(
+                "SELECT content, created_at, importance FROM memories WHERE id = 'old1'",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(content, "use sqlite for storage");
+        assert_eq!(created_at, "2024-01-02 03:04:05");
+        assert_eq!(importance, 0.8); // backfill por tipo
+        assert_eq!(get_stats(&conn, 384).total, 1);
+        // FTS recriado com porter e reindexado
+        let stemmed: i64 = conn
+            .query_row("SELECT COUNT(*) FROM memories_fts WHERE memories_fts MATCH 'uses'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(stemmed, 1);
+
+        // Reabrir não reaplica nada
+        drop(conn);
+        let conn = init_db(&path).unwrap();
+        assert_eq!(run_migrations(&conn).unwrap(), 0);
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        assert_eq!(normalize_metadata("  ").unwrap(), None);
+        assert!(normalize_metadata("[1, 2]").is_err());
+        assert!(normalize_metadata("{broken").is_err());
+
+        let conn = test_db("metadata");
+        let meta = normalize_metadata(r#"{"source": "https://example.com", "confidence": 0.9}"#).unwrap();
+        let opts = SaveOptions { metadata: meta.as_deref(), ..Default::default() };
+        let saved = save_memory(&conn, "note", "metadata carrier", "", &opts).unwrap();
+
+        let m = get_memory(&conn, &saved.id).unwrap().unwrap();
+        let metadata = m.metadata.unwrap();
+        assert_eq!(metadata["source"], "https://example.com");
+        assert_eq!(metadata["confidence"], 0.9);
+        assert_eq!(export_memories(&conn).unwrap()[0].metadata.as_ref(), Some(&metadata));
+    }
+}
@@ -0,0 +1,119 @@
+/// Resumo extrativo de sessões (sem LLM): TF-IDF sobre os turnos.
+///
+/// Cada turno é um documento. Palavras frequentes num turno e raras no resto
+/// da sessão pesam mais; daí saem as palavras-chave da sessão e os turnos mais
+/// representativos (maior soma de TF-IDF, normalizada pelo tamanho).
+use std::collections::{HashMap, HashSet};
+
+const STOPWORDS: &[&str] = &[
+    "the", "and", "for", "are", "but", "not", "you", "all", "any", "can", "had", "her", "was",
+    "one", "our", "out", "has", "have", "this", "that", "with", "from", "they", "will", "would",
+    "there", "their", "what", "about", "which", "when", "make", "like", "just", "into", "than",
+    "then", "them", "these", "some", "could", "other", "also", "how", "why", "does", "did",
+    "should", "here", "need", "want", "let", "its", "it's", "i'm", "use", "using", "now",
+    "yes", "okay", "thanks", "thank", "please",
+    "com", "para", "por", "uma", "que", "não", "nao", "como", "mais", "isso", "esse", "essa",
+    "este", "esta", "pra", "ele", "ela", "dos", "das", "nos", "nas", "sem", "tem", "ser",
+];
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_' && c != '\'')
+        .map(|w| w.trim_matches('\'').to_lowercase())
+        .filter(|w| w.chars().count() >= 3 && !w.chars().all(|c| c.is_ascii_digit()))
+        .filter(|w| !STOPWORDS.contains(&w.as_str()))
+        .collect()
+}
+
+/// Pesos TF-IDF por documento
+fn tfidf(docs: &[&str]) -> Vec<HashMap<String, f64>> {
+    let tokenized: Vec<Vec<String>> = docs.iter().map(|d| tokenize(d)).collect();
+    let mut df: HashMap<&str, usize> = HashMap::new();
+    for tokens in &tokenized {
+        let unique: HashSet<&str> = tokens.iter().map(String::as_str).collect();
+        for t in unique {
+            *df.entry(t).or_insert(0) += 1;
+        }
+    }
+    let n = docs.len() as f64;
+    tokenized
+        .iter()
+        .map(|tokens| {
+            let mut tf: HashMap<String, f64> = HashMap::new();
+            for t in tokens {
+                *tf.entry(t.clone()).or_insert(0.0) += 1.0;
+            }
+            let len = tokens.len().max(1) as f64;
+            for (term, w) in tf.iter_mut() {
+                // idf suavizado: termo presente em todos os turnos ainda pesa um pouco
+                let idf = (1.0 + n / df[term.as_str()] as f64).ln();
+                *w = *w / len * idf;
+            }
+            tf
+        })
+        .collect()
+}
+
+/// As `k` palavras com maior TF-IDF somado na sessão
+pub fn keywords(docs: &[&str], k: usize) -> Vec<String> {
+    let mut total: HashMap<String, f64> = HashMap::new();
+    for weights in tfidf(docs) {
+        for (term, w) in weights {
+            *total.entry(term).or_insert(0.0) += w;
+        }
+    }
+    let mut ranked: Vec<(String, f64)> = total.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked.into_iter().take(k).map(|(term, _)| term).collect()
+}
+
+/// Índices dos `k` documentos mais representativos, em ordem cronológica.
+/// Documentos quase vazios (sem termos úteis) nunca entram.
+pub fn representative(docs: &[&str], k: usize) -> Vec<usize> {
+    let weights = tfidf(docs);
+    let mut scored: Vec<(usize, f64)> = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| !w.is_empty())
+        .map(|(i, w)| {
+            // Soma sobre termos distintos: favorece turnos ricos sem premiar só tamanho
+            let score = w.values().sum::<f64>() * (w.len() as f64).sqrt();
+            (i, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let mut picked: Vec<usize> = scored.into_iter().take(k).map(|(i, _)| i).collect();
+    picked.sort_unstable();
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keywords_prefer_distinctive_terms() {
+        let docs = [
+            "please fix the flaky postgres migration test",
+            "the postgres migration fails on ci with a lock timeout",
+            "ok thanks",
+            "now add retries to the webhook dispatcher",
+        ];
+        let kw = keywords(&docs, 4);
+        assert!(kw.contains(&"postgres".to_string()), "{:?}", kw);
+        assert!(kw.contains(&"migration".to_string()), "{:?}", kw);
+        assert!(!kw.iter().any(|k| k == "the" || k == "thanks"));
+    }
+
+    #[test]
+    fn test_representative_skips_filler() {
+        let docs = [
+            "ok",
+            "design the caching layer for embeddings with an lru and ttl eviction policy",
+            "yes",
+            "thanks!",
+            "switch the ann index to hnsw and benchmark recall against brute force",
+        ];
+        assert_eq!(representative(&docs, 2), [1, 4]);
+        assert!(representative(&["ok", "yes"], 3).is_empty());
+    }
+}